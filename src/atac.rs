@@ -1,4 +1,7 @@
+pub mod cell_calling;
 pub mod commands;
+pub mod coverage;
 pub mod defaults;
+pub mod gene_activity;
 pub mod index;
 pub mod process;
@@ -0,0 +1,610 @@
+//! Cell calling for scATAC-seq data.
+//!
+//! `alevin-fry atac generate-permit-list` only supports correcting barcodes against
+//! an unfiltered permit list, so every barcode above `--min-reads` ends up in the
+//! sorted BED file. The functions here run after `alevin-fry atac sort` and call
+//! cells from the per-barcode unique fragment counts (optionally jointly with a
+//! per-barcode TSS enrichment score), mirroring the knee / expect-cells /
+//! forced-cells modes that alevin-fry offers for RNA data.
+
+use crate::utils::af_utils::CellFilterMethod;
+use anyhow::{Context, bail};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// half-width of the window around a TSS whose cut sites count as "central".
+const TSS_CENTER_FLANK: u64 = 50;
+/// inner and outer distance of the background windows flanking each TSS.
+const TSS_BG_INNER: u64 = 1900;
+const TSS_BG_OUTER: u64 = 2000;
+
+/// maximum number of refinement rounds in the knee-finding procedure.
+const MAX_KNEE_ITERATIONS: usize = 100;
+
+/// Per-barcode statistics collected from the fragment (BED) file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct BarcodeMetrics {
+    pub unique_fragments: u64,
+    pub read_pairs: u64,
+    pub tss_center_cuts: u64,
+    pub tss_flank_cuts: u64,
+}
+
+impl BarcodeMetrics {
+    /// ArchR-style TSS enrichment: the per-base cut density in the +/- 50bp window
+    /// around TSSs over the per-base density in the 100bp windows 1.9-2kb away.
+    pub fn tss_enrichment(&self) -> f64 {
+        2.0 * self.tss_center_cuts as f64 / (self.tss_flank_cuts as f64 + 1.0)
+    }
+}
+
+/// Summary of a cell calling run, recorded in `simpleaf_process_log.json`.
+#[derive(Debug, Serialize)]
+pub(crate) struct CellCallingSummary {
+    pub method: String,
+    pub num_barcodes: usize,
+    pub num_cells: usize,
+    pub min_cell_fragments: u64,
+    pub median_cell_fragments: u64,
+    pub used_tss_enrichment: bool,
+    pub min_tss_enrichment: Option<f64>,
+    pub median_cell_tss_enrichment: Option<f64>,
+}
+
+/// Sorted TSS positions (0-based) keyed by chromosome name.
+pub(crate) type TssIndex = HashMap<String, Vec<u64>>;
+
+fn is_gtf_like(p: &Path) -> bool {
+    let name = p.to_string_lossy().to_lowercase();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.ends_with(".gtf") || name.ends_with(".gff") || name.ends_with(".gff3")
+}
+
+/// Read TSS positions from either a GTF/GFF file (transcript starts, falling back
+/// to gene starts if there are no transcript records) or a BED file (the
+/// strand-aware start of each interval).
+pub(crate) fn read_tss_file(p: &Path) -> anyhow::Result<TssIndex> {
+    let (reader, _fmt) = niffler::from_path(p)
+        .with_context(|| format!("could not open TSS annotation file {}", p.display()))?;
+    let br = BufReader::new(reader);
+    let gtf = is_gtf_like(p);
+
+    let mut transcript_tss = TssIndex::new();
+    let mut gene_tss = TssIndex::new();
+    for (lnum, line) in br.lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", p.display()))?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("track") {
+            continue;
+        }
+        let toks: Vec<&str> = line.split('\t').collect();
+        if gtf {
+            if toks.len() < 7 {
+                bail!(
+                    "line {} of GTF file {} has fewer than 7 columns",
+                    lnum + 1,
+                    p.display()
+                );
+            }
+            let target = match toks[2] {
+                "transcript" => &mut transcript_tss,
+                "gene" => &mut gene_tss,
+                _ => continue,
+            };
+            // GTF coordinates are 1-based and inclusive
+            let start: u64 = toks[3].parse().with_context(|| {
+                format!("invalid start on line {} of {}", lnum + 1, p.display())
+            })?;
+            let end: u64 = toks[4]
+                .parse()
+                .with_context(|| format!("invalid end on line {} of {}", lnum + 1, p.display()))?;
+            let one_based = if toks[6] == "-" { end } else { start };
+            let pos = one_based.checked_sub(1).with_context(|| {
+                format!(
+                    "line {} of GTF file {} has a 0 coordinate, but GTF coordinates are 1-based",
+                    lnum + 1,
+                    p.display()
+                )
+            })?;
+            target.entry(toks[0].to_string()).or_default().push(pos);
+        } else {
+            if toks.len() < 3 {
+                bail!(
+                    "line {} of BED file {} has fewer than 3 columns",
+                    lnum + 1,
+                    p.display()
+                );
+            }
+            // BED coordinates are 0-based and half-open
+            let start: u64 = toks[1].parse().with_context(|| {
+                format!("invalid start on line {} of {}", lnum + 1, p.display())
+            })?;
+            let end: u64 = toks[2]
+                .parse()
+                .with_context(|| format!("invalid end on line {} of {}", lnum + 1, p.display()))?;
+            let minus = toks.get(5).is_some_and(|s| *s == "-");
+            let pos = if minus {
+                end.checked_sub(1).with_context(|| {
+                    format!(
+                        "line {} of BED file {} has an end coordinate of 0",
+                        lnum + 1,
+                        p.display()
+                    )
+                })?
+            } else {
+                start
+            };
+            transcript_tss
+                .entry(toks[0].to_string())
+                .or_default()
+                .push(pos);
+        }
+    }
+
+    let mut tss = if transcript_tss.is_empty() {
+        gene_tss
+    } else {
+        transcript_tss
+    };
+    if tss.is_empty() {
+        bail!("no TSS positions could be read from {}", p.display());
+    }
+    for v in tss.values_mut() {
+        v.sort_unstable();
+        v.dedup();
+    }
+    Ok(tss)
+}
+
+/// Where a single Tn5 cut site falls relative to the nearest TSS.
+#[derive(Debug, PartialEq)]
+enum CutClass {
+    Center,
+    Flank,
+    Other,
+}
+
+fn classify_cut(tss: &[u64], pos: u64) -> CutClass {
+    let lo = pos.saturating_sub(TSS_BG_OUTER);
+    let first = tss.partition_point(|t| *t < lo);
+    let mut best = u64::MAX;
+    for t in &tss[first..] {
+        if *t > pos + TSS_BG_OUTER {
+            break;
+        }
+        best = best.min(t.abs_diff(pos));
+    }
+    if best <= TSS_CENTER_FLANK {
+        CutClass::Center
+    } else if (TSS_BG_INNER..=TSS_BG_OUTER).contains(&best) {
+        CutClass::Flank
+    } else {
+        CutClass::Other
+    }
+}
+
+/// Stream the fragment file produced by `alevin-fry atac sort` and gather
+/// per-barcode metrics. Each record is `chr start end barcode count`.
+pub(crate) fn collect_barcode_metrics(
+    bed: &Path,
+    tss: Option<&TssIndex>,
+) -> anyhow::Result<HashMap<String, BarcodeMetrics>> {
+    let (reader, _fmt) = niffler::from_path(bed)
+        .with_context(|| format!("could not open fragment file {}", bed.display()))?;
+    let br = BufReader::new(reader);
+
+    let mut metrics: HashMap<String, BarcodeMetrics> = HashMap::new();
+    for (lnum, line) in br.lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", bed.display()))?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut toks = line.split('\t');
+        let (Some(chr), Some(start), Some(end), Some(bc)) =
+            (toks.next(), toks.next(), toks.next(), toks.next())
+        else {
+            bail!(
+                "line {} of fragment file {} has fewer than 4 columns",
+                lnum + 1,
+                bed.display()
+            );
+        };
+        let count: u64 = match toks.next() {
+            Some(c) => c.parse().with_context(|| {
+                format!("invalid count on line {} of {}", lnum + 1, bed.display())
+            })?,
+            None => 1,
+        };
+
+        let m = metrics.entry(bc.to_string()).or_default();
+        m.unique_fragments += 1;
+        m.read_pairs += count;
+
+        if let Some(chr_tss) = tss.and_then(|t| t.get(chr)) {
+            let start: u64 = start.parse().with_context(|| {
+                format!("invalid start on line {} of {}", lnum + 1, bed.display())
+            })?;
+            let end: u64 = end.parse().with_context(|| {
+                format!("invalid end on line {} of {}", lnum + 1, bed.display())
+            })?;
+            for cut in [start, end.saturating_sub(1)] {
+                match classify_cut(chr_tss, cut) {
+                    CutClass::Center => m.tss_center_cuts += 1,
+                    CutClass::Flank => m.tss_flank_cuts += 1,
+                    CutClass::Other => {}
+                }
+            }
+        }
+    }
+    Ok(metrics)
+}
+
+/// Find the knee of the (descending) count curve in log-log space using the
+/// iterative distance method employed by alevin-fry. Returns the number of
+/// barcodes up to and including the knee.
+pub(crate) fn find_knee(sorted_counts: &[u64]) -> usize {
+    let n = sorted_counts.len();
+    if n < 3 {
+        return n;
+    }
+    let xs: Vec<f64> = (1..=n).map(|r| (r as f64).ln()).collect();
+    let ys: Vec<f64> = sorted_counts
+        .iter()
+        .map(|c| (*c.max(&1) as f64).ln())
+        .collect();
+
+    let mut last = n - 1;
+    let mut knee = last;
+    for _ in 0..MAX_KNEE_ITERATIONS {
+        let (x0, y0) = (xs[0], ys[0]);
+        let (x1, y1) = (xs[last], ys[last]);
+        let (dx, dy) = (x1 - x0, y1 - y0);
+        let norm = (dx * dx + dy * dy).sqrt();
+        if norm == 0.0 {
+            break;
+        }
+        let mut best = (0usize, f64::MIN);
+        for i in 0..=last {
+            let d = (dy * xs[i] - dx * ys[i] + x1 * y0 - y1 * x0).abs() / norm;
+            if d > best.1 {
+                best = (i, d);
+            }
+        }
+        let new_knee = best.0;
+        let new_last = (new_knee.max(1) * 5).min(n - 1);
+        if new_knee == knee && new_last == last {
+            break;
+        }
+        knee = new_knee;
+        last = new_last;
+    }
+    knee + 1
+}
+
+/// The minimum count a barcode must reach to be called a cell when `expected`
+/// cells are anticipated (the robust-max / 10 rule used by alevin-fry).
+pub(crate) fn expect_cells_threshold(sorted_counts: &[u64], expected: usize) -> u64 {
+    if sorted_counts.is_empty() {
+        return 1;
+    }
+    let robust_ind = ((expected as f64) * 0.99).round() as usize;
+    let robust_freq = sorted_counts[robust_ind.min(sorted_counts.len() - 1)];
+    ((robust_freq as f64 / 10.0).round() as u64).max(1)
+}
+
+/// The number of barcodes (from the front of the descending `sorted_counts`)
+/// that are retained by `method`, along with a description of the method.
+pub(crate) fn num_barcodes_passing(
+    sorted_counts: &[u64],
+    method: &CellFilterMethod,
+) -> anyhow::Result<(usize, String)> {
+    let (n, desc) = match method {
+        CellFilterMethod::ForceCells(n) => (*n, format!("forced_cells({})", n)),
+        CellFilterMethod::ExpectCells(n) => {
+            let thresh = expect_cells_threshold(sorted_counts, *n);
+            (
+                sorted_counts.partition_point(|c| *c >= thresh),
+                format!("expect_cells({})", n),
+            )
+        }
+        CellFilterMethod::KneeFinding => (find_knee(sorted_counts), String::from("knee")),
+        m => bail!(
+            "cell filter method {:?} is not supported for ATAC cell calling",
+            m
+        ),
+    };
+    Ok((n.min(sorted_counts.len()), desc))
+}
+
+/// Decide which barcodes are cells. Barcodes are first ranked by unique fragment
+/// count and thresholded according to `method`; if `min_tss_enrichment` is given,
+/// barcodes passing the fragment threshold must also reach that TSS enrichment.
+pub(crate) fn call_cells(
+    metrics: &HashMap<String, BarcodeMetrics>,
+    method: &CellFilterMethod,
+    min_tss_enrichment: Option<f64>,
+) -> anyhow::Result<(Vec<String>, CellCallingSummary)> {
+    let mut ranked: Vec<(&String, &BarcodeMetrics)> = metrics.iter().collect();
+    // sort by count (descending) and then barcode, so the output is deterministic
+    ranked.sort_by(|a, b| {
+        b.1.unique_fragments
+            .cmp(&a.1.unique_fragments)
+            .then_with(|| a.0.cmp(b.0))
+    });
+    let counts: Vec<u64> = ranked.iter().map(|(_, m)| m.unique_fragments).collect();
+
+    let (num_passing, method_str) = num_barcodes_passing(&counts, method)?;
+
+    let mut cells = Vec::with_capacity(num_passing);
+    let mut cell_frags = Vec::with_capacity(num_passing);
+    let mut cell_tsse = Vec::with_capacity(num_passing);
+    for (bc, m) in ranked.iter().take(num_passing) {
+        let tsse = m.tss_enrichment();
+        if let Some(min_e) = min_tss_enrichment
+            && tsse < min_e
+        {
+            continue;
+        }
+        cells.push((*bc).clone());
+        cell_frags.push(m.unique_fragments);
+        cell_tsse.push(tsse);
+    }
+
+    if cells.is_empty() {
+        warn!("ATAC cell calling did not retain any barcodes.");
+    }
+
+    let median_cell_tss_enrichment = if min_tss_enrichment.is_some() && !cell_tsse.is_empty() {
+        cell_tsse.sort_by(|a, b| a.total_cmp(b));
+        Some(cell_tsse[cell_tsse.len() / 2])
+    } else {
+        None
+    };
+    let summary = CellCallingSummary {
+        method: method_str,
+        num_barcodes: ranked.len(),
+        num_cells: cells.len(),
+        min_cell_fragments: cell_frags.last().copied().unwrap_or(0),
+        median_cell_fragments: cell_frags.get(cell_frags.len() / 2).copied().unwrap_or(0),
+        used_tss_enrichment: min_tss_enrichment.is_some(),
+        min_tss_enrichment,
+        median_cell_tss_enrichment,
+    };
+    Ok((cells, summary))
+}
+
+/// Write the called barcodes (one per line) to `out`.
+pub(crate) fn write_cell_barcodes(cells: &[String], out: &Path) -> anyhow::Result<()> {
+    let f = std::fs::File::create(out)
+        .with_context(|| format!("could not create {}", out.display()))?;
+    let mut w = BufWriter::new(f);
+    for bc in cells {
+        writeln!(w, "{}", bc)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write the per-barcode metrics table, flagging called cells, to `out`.
+pub(crate) fn write_barcode_metrics(
+    metrics: &HashMap<String, BarcodeMetrics>,
+    cells: &HashSet<&str>,
+    with_tss: bool,
+    out: &Path,
+) -> anyhow::Result<()> {
+    let f = std::fs::File::create(out)
+        .with_context(|| format!("could not create {}", out.display()))?;
+    let mut w = BufWriter::new(f);
+    let mut rows: Vec<(&String, &BarcodeMetrics)> = metrics.iter().collect();
+    rows.sort_by(|a, b| {
+        b.1.unique_fragments
+            .cmp(&a.1.unique_fragments)
+            .then_with(|| a.0.cmp(b.0))
+    });
+    if with_tss {
+        writeln!(
+            w,
+            "barcode\tunique_fragments\tread_pairs\ttss_center_cuts\ttss_flank_cuts\ttss_enrichment\tis_cell"
+        )?;
+    } else {
+        writeln!(w, "barcode\tunique_fragments\tread_pairs\tis_cell")?;
+    }
+    for (bc, m) in rows {
+        let is_cell = cells.contains(bc.as_str());
+        if with_tss {
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}\t{}\t{:.4}\t{}",
+                bc,
+                m.unique_fragments,
+                m.read_pairs,
+                m.tss_center_cuts,
+                m.tss_flank_cuts,
+                m.tss_enrichment(),
+                is_cell
+            )?;
+        } else {
+            writeln!(
+                w,
+                "{}\t{}\t{}\t{}",
+                bc, m.unique_fragments, m.read_pairs, is_cell
+            )?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Copy the records of `bed` whose barcode is in `cells` to `out`, gzip
+/// compressing the output if `compress` is set. Returns the number of
+/// fragments written.
+pub(crate) fn filter_fragments(
+    bed: &Path,
+    cells: &HashSet<&str>,
+    out: &Path,
+    compress: bool,
+) -> anyhow::Result<u64> {
+    let (reader, _fmt) = niffler::from_path(bed)
+        .with_context(|| format!("could not open fragment file {}", bed.display()))?;
+    let br = BufReader::new(reader);
+
+    let f = std::fs::File::create(out)
+        .with_context(|| format!("could not create {}", out.display()))?;
+    let mut w: Box<dyn Write> = if compress {
+        Box::new(BufWriter::new(flate2::write::GzEncoder::new(
+            f,
+            flate2::Compression::default(),
+        )))
+    } else {
+        Box::new(BufWriter::new(f))
+    };
+
+    let mut kept = 0u64;
+    for line in br.lines() {
+        let line = line.with_context(|| format!("could not read {}", bed.display()))?;
+        if let Some(bc) = line.split('\t').nth(3)
+            && cells.contains(bc)
+        {
+            writeln!(w, "{}", line)?;
+            kept += 1;
+        }
+    }
+    w.flush()?;
+    drop(w);
+    info!(
+        "wrote {} fragments from called cells to {}",
+        kept,
+        out.display()
+    );
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn metrics_from_counts(counts: &[u64]) -> HashMap<String, BarcodeMetrics> {
+        counts
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                (
+                    format!("BC{:04}", i),
+                    BarcodeMetrics {
+                        unique_fragments: *c,
+                        read_pairs: *c,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn knee_separates_cells_from_background() {
+        let mut counts = vec![5000u64; 200];
+        counts.extend(std::iter::repeat_n(20u64, 5000));
+        let knee = find_knee(&counts);
+        assert!(
+            (195..=205).contains(&knee),
+            "unexpected knee position {knee}"
+        );
+    }
+
+    #[test]
+    fn expect_cells_uses_robust_max_threshold() {
+        let counts = vec![1000, 900, 800, 120, 90, 5];
+        // robust index = round(3 * 0.99) = 3 -> 120 / 10 = 12
+        assert_eq!(expect_cells_threshold(&counts, 3), 12);
+    }
+
+    #[test]
+    fn call_cells_forced_takes_top_barcodes() {
+        let metrics = metrics_from_counts(&[10, 500, 30, 400]);
+        let (cells, summary) =
+            call_cells(&metrics, &CellFilterMethod::ForceCells(2), None).expect("call cells");
+        assert_eq!(cells, vec!["BC0001".to_string(), "BC0003".to_string()]);
+        assert_eq!(summary.num_cells, 2);
+        assert_eq!(summary.min_cell_fragments, 400);
+    }
+
+    #[test]
+    fn call_cells_applies_tss_enrichment_jointly() {
+        let mut metrics = metrics_from_counts(&[500, 400]);
+        metrics.get_mut("BC0000").unwrap().tss_center_cuts = 100;
+        metrics.get_mut("BC0000").unwrap().tss_flank_cuts = 9;
+        metrics.get_mut("BC0001").unwrap().tss_center_cuts = 1;
+        metrics.get_mut("BC0001").unwrap().tss_flank_cuts = 9;
+        let (cells, summary) =
+            call_cells(&metrics, &CellFilterMethod::ForceCells(2), Some(4.0)).expect("call cells");
+        assert_eq!(cells, vec!["BC0000".to_string()]);
+        assert_eq!(summary.median_cell_tss_enrichment, Some(20.0));
+    }
+
+    #[test]
+    fn collect_metrics_and_filter_fragments_roundtrip() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let bed = td.path().join("map.bed");
+        fs::write(
+            &bed,
+            "chr1\t1000\t1200\tAAAA\t2\nchr1\t2950\t3100\tAAAA\t1\nchr1\t5000\t5100\tCCCC\t1\n",
+        )
+        .expect("failed to write bed");
+        let tss_bed = td.path().join("tss.bed");
+        fs::write(&tss_bed, "chr1\t1020\t1021\tg1\t0\t+\n").expect("failed to write tss");
+
+        let tss = read_tss_file(&tss_bed).expect("read tss");
+        let metrics = collect_barcode_metrics(&bed, Some(&tss)).expect("collect metrics");
+        let a = &metrics["AAAA"];
+        assert_eq!(a.unique_fragments, 2);
+        assert_eq!(a.read_pairs, 3);
+        // cut at 1000 is 20bp from the TSS; cut at 2950 is 1930bp away
+        assert_eq!(a.tss_center_cuts, 1);
+        assert_eq!(a.tss_flank_cuts, 1);
+
+        let cells: HashSet<&str> = ["AAAA"].into_iter().collect();
+        let out = td.path().join("map.cells.bed");
+        let kept = filter_fragments(&bed, &cells, &out, false).expect("filter");
+        assert_eq!(kept, 2);
+        let content = fs::read_to_string(&out).expect("read filtered");
+        assert!(!content.contains("CCCC"));
+    }
+
+    #[test]
+    fn read_tss_from_gtf_is_strand_aware() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let gtf = td.path().join("genes.gtf");
+        fs::write(
+            &gtf,
+            "chr1\tsrc\tgene\t100\t500\t.\t+\t.\tgene_id \"g1\";\n\
+             chr1\tsrc\ttranscript\t100\t500\t.\t+\t.\tgene_id \"g1\";\n\
+             chr2\tsrc\ttranscript\t100\t500\t.\t-\t.\tgene_id \"g2\";\n",
+        )
+        .expect("failed to write gtf");
+        let tss = read_tss_file(&gtf).expect("read gtf");
+        assert_eq!(tss["chr1"], vec![99]);
+        assert_eq!(tss["chr2"], vec![499]);
+    }
+
+    #[test]
+    fn read_tss_rejects_zero_coordinates() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let gtf = td.path().join("genes.gtf");
+        fs::write(
+            &gtf,
+            "chr1\tsrc\ttranscript\t100\t500\t.\t+\t.\tgene_id \"g1\";\n\
+             chr1\tsrc\ttranscript\t0\t500\t.\t+\t.\tgene_id \"g2\";\n",
+        )
+        .expect("failed to write gtf");
+        let err = read_tss_file(&gtf).expect_err("0 start must be rejected");
+        assert!(format!("{err:#}").contains("line 2"));
+
+        let bed = td.path().join("tss.bed");
+        fs::write(&bed, "chr1\t0\t0\tt1\t0\t-\n").expect("failed to write bed");
+        let err = read_tss_file(&bed).expect_err("0 end on minus strand must be rejected");
+        assert!(format!("{err:#}").contains("line 1"));
+    }
+}
@@ -2,7 +2,7 @@ use crate::atac::defaults::{AtacIndexParams, DefaultAtacParams};
 use crate::defaults::{DefaultMappingParams, DefaultParams};
 use crate::utils::chem_utils::{ExpectedOri, QueryInRegistry};
 use clap::{
-    Args, Subcommand, ValueEnum,
+    ArgGroup, Args, Subcommand, ValueEnum,
     builder::{ArgPredicate, PossibleValue},
 };
 use std::fmt;
@@ -115,6 +115,14 @@ impl AtacChemistry {
         }
     }
 
+    /// The length of the cell barcode; all of the 10x ATAC chemistries use
+    /// 16bp barcodes.
+    pub fn barcode_length(&self) -> u32 {
+        match self {
+            Self::TenxV11 | Self::TenxV2 | Self::TenxMulti => 16,
+        }
+    }
+
     pub fn as_str(&self) -> &str {
         match self {
             AtacChemistry::TenxV11 => "10x-atac-v1",
@@ -129,10 +137,9 @@ impl std::str::FromStr for AtacChemistry {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "10xv1" => Ok(AtacChemistry::TenxV11),
-            "10xv11" => Ok(AtacChemistry::TenxV11),
-            "10xv2" => Ok(AtacChemistry::TenxV2),
-            "10xmulti" => Ok(AtacChemistry::TenxMulti),
+            "10xv1" | "10xv11" | "10x-v1" | "10x-atac-v1" => Ok(AtacChemistry::TenxV11),
+            "10xv2" | "10x-v2" | "10x-atac-v2" => Ok(AtacChemistry::TenxV2),
+            "10xmulti" | "10x-multi" | "10x-arc-atac-v1" => Ok(AtacChemistry::TenxMulti),
             t => Err(format!("invalid atac chemistry : {t}")),
         }
     }
@@ -156,7 +163,9 @@ impl clap::ValueEnum for AtacChemistry {
 #[command(arg_required_else_help = true)]
 pub enum AtacCommand {
     Index(IndexOpts),
-    Process(ProcessOpts),
+    Process(Box<ProcessOpts>),
+    GeneActivity(GeneActivityOpts),
+    Coverage(CoverageOpts),
 }
 
 /// build a piscem index over the genome for
@@ -224,11 +233,48 @@ pub struct IndexOpts {
     pub output: PathBuf,
 }
 
+/// A stage of `simpleaf atac process`, in the order in which the stages run.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AtacStage {
+    /// map the reads with piscem (writes `af_map`)
+    Map,
+    /// generate the permit list with alevin-fry
+    Gpl,
+    /// sort and deduplicate the mapped records into the BED file
+    Sort,
+    /// call cells (only part of the run with --knee, --expect-cells or --forced-cells)
+    Cells,
+    /// call peaks with macs3 (only part of the run with --call-peaks)
+    Peaks,
+}
+
+impl AtacStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AtacStage::Map => "map",
+            AtacStage::Gpl => "gpl",
+            AtacStage::Sort => "sort",
+            AtacStage::Cells => "cells",
+            AtacStage::Peaks => "peaks",
+        }
+    }
+}
+
+impl fmt::Display for AtacStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 /// process a scATAC-seq sample by performing
 /// mapping, barcode correction, and sorted
 /// (deduplicated) BED file generation.
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("cell_calling")
+    .args(["knee", "expect_cells", "forced_cells"])
+))]
 pub struct ProcessOpts {
     /// path to index
     #[arg(short = 'i', long = "index", help_heading = "Mapping Options")]
@@ -283,9 +329,10 @@ pub struct ProcessOpts {
     )]
     pub barcode_reads: Vec<PathBuf>,
 
-    /// chemistry
+    /// chemistry; one of the builtin 10x chemistries (10x-v1, 10x-v2, 10x-multi),
+    /// or the name of a registered chemistry whose `meta.protocol_type` is `atac`
     #[arg(short, long)]
-    pub chemistry: AtacChemistry,
+    pub chemistry: String,
 
     /// the length of the barcode read from which to extract the barcode
     /// (usually this is the length of the entire read, and reads shorter
@@ -309,6 +356,27 @@ pub struct ProcessOpts {
     #[arg(long)]
     pub call_peaks: bool,
 
+    /// comma-separated list of the stages to run; the outputs of the stages
+    /// that are not run, but that a requested stage depends upon, are reused
+    /// from a previous run in the same output directory
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        conflicts_with_all = ["start_from", "stop_after"],
+        help_heading = "Stage Options"
+    )]
+    pub stages: Option<Vec<AtacStage>>,
+
+    /// skip the stages before this one, reusing their outputs from a previous
+    /// run in the same output directory
+    #[arg(long, value_enum, help_heading = "Stage Options")]
+    pub start_from: Option<AtacStage>,
+
+    /// do not run the stages after this one
+    #[arg(long, value_enum, help_heading = "Stage Options")]
+    pub stop_after: Option<AtacStage>,
+
     /// The expected orientation of the barcodes in the permit list relative
     /// to the barcodes extracted from the reads. If this is "fw", it is expected
     /// that the sequences will match directly, if "rc" it is expected the reverse
@@ -342,6 +410,38 @@ pub struct ProcessOpts {
     )]
     pub min_reads: usize,
 
+    /// call cells by finding the knee in the curve of unique fragments per barcode
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub knee: bool,
+
+    /// call cells given the expected number of cells in the sample
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub expect_cells: Option<usize>,
+
+    /// call exactly this many cells (the barcodes with the most unique fragments)
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub forced_cells: Option<usize>,
+
+    /// GTF/GFF or BED file providing transcription start sites. If provided, a
+    /// TSS enrichment score is computed for every barcode and called cells must
+    /// also pass --min-tss-enrichment
+    #[arg(long, requires = "cell_calling", help_heading = "Cell Calling Options")]
+    pub tss: Option<PathBuf>,
+
+    /// minimum TSS enrichment score for a barcode to be called a cell; only used with --tss
+    #[arg(
+        long,
+        default_value_t = 4.0,
+        requires = "tss",
+        help_heading = "Cell Calling Options"
+    )]
+    pub min_tss_enrichment: f64,
+
+    /// additionally write the fragments of the called cells to `map.cells.bed`
+    /// (or `map.cells.bed.gz` with --compress); peaks are then called on these fragments
+    #[arg(long, requires = "cell_calling", help_heading = "Cell Calling Options")]
+    pub filter_fragments: bool,
+
     /// compress the output mapping bed file.
     #[arg(long, help_heading = "Advanced Options")]
     pub compress: bool,
@@ -422,3 +522,177 @@ pub struct ProcessOpts {
     #[arg(long, help_heading = "Peak Caller Options", default_value_t = 50)]
     pub extsize: usize,
 }
+
+/// How fragments are scored against genes when computing gene activity.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GeneActivityMethod {
+    /// count the fragments overlapping the gene body plus promoter window
+    GeneBody,
+    /// also count nearby fragments, weighted by exp(-distance / decay length)
+    DistanceWeighted,
+}
+
+impl fmt::Display for GeneActivityMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneActivityMethod::GeneBody => write!(f, "gene-body"),
+            GeneActivityMethod::DistanceWeighted => write!(f, "distance-weighted"),
+        }
+    }
+}
+
+/// compute a gene activity (cell x gene) matrix from the
+/// fragments of a processed scATAC-seq sample.
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct GeneActivityOpts {
+    /// the fragment (BED) file written by `simpleaf atac process`
+    /// (e.g. `af_process/map.bed` or the called-cell fragments)
+    #[arg(short, long, display_order = 1)]
+    pub fragments: PathBuf,
+
+    /// the GTF/GFF annotation from which gene coordinates are read
+    #[arg(short, long, display_order = 2)]
+    pub gtf: PathBuf,
+
+    /// output directory where the gene activity matrix will be written
+    #[arg(short, long, display_order = 3)]
+    pub output: PathBuf,
+
+    /// only score the barcodes listed in this file (one per line, e.g. the
+    /// `af_process/cells.txt` written by cell calling); by default every
+    /// barcode in the fragment file is scored
+    #[arg(short, long, display_order = 4)]
+    pub cells: Option<PathBuf>,
+
+    /// how fragments are scored against genes
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t = GeneActivityMethod::GeneBody,
+        help_heading = "Scoring Options"
+    )]
+    pub method: GeneActivityMethod,
+
+    /// the size of the promoter window added upstream of each gene's TSS
+    #[arg(long, default_value_t = 2000, help_heading = "Scoring Options")]
+    pub upstream: u64,
+
+    /// the number of bases to extend each gene past its 3' end
+    #[arg(long, default_value_t = 0, help_heading = "Scoring Options")]
+    pub downstream: u64,
+
+    /// with `--method distance-weighted`, the largest distance from the gene
+    /// body plus promoter window at which a fragment still counts
+    #[arg(long, default_value_t = 100_000, help_heading = "Scoring Options")]
+    pub max_distance: u64,
+
+    /// with `--method distance-weighted`, the distance over which a fragment's
+    /// weight decays by a factor of e
+    #[arg(long, default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..), help_heading = "Scoring Options")]
+    pub decay_length: u64,
+
+    /// only score genes with one of these (comma-separated) `gene_type` or
+    /// `gene_biotype` attributes, e.g. protein_coding; by default all genes
+    /// are scored
+    #[arg(long, value_delimiter = ',', help_heading = "Scoring Options")]
+    pub biotypes: Vec<String>,
+
+    /// also write the gene activity matrix as an h5ad file
+    #[arg(long, display_order = 5)]
+    pub anndata_out: bool,
+}
+
+/// The signal that is binned into coverage tracks.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageMode {
+    /// the Tn5 insertion (cut) sites at both ends of each fragment
+    CutSites,
+    /// the full span of each fragment
+    Fragments,
+}
+
+impl fmt::Display for CoverageMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageMode::CutSites => write!(f, "cut-sites"),
+            CoverageMode::Fragments => write!(f, "fragments"),
+        }
+    }
+}
+
+/// How the binned coverage of each group is scaled.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageNormalization {
+    /// raw counts per bin
+    None,
+    /// counts per million fragments (or cut sites) of the group
+    Cpm,
+    /// counts per kilobase of bin per million fragments (or cut sites) of the group
+    Rpkm,
+}
+
+impl fmt::Display for CoverageNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageNormalization::None => write!(f, "none"),
+            CoverageNormalization::Cpm => write!(f, "cpm"),
+            CoverageNormalization::Rpkm => write!(f, "rpkm"),
+        }
+    }
+}
+
+/// write per-group (pseudobulk) bigWig coverage tracks
+/// from the fragments of a processed scATAC-seq sample.
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct CoverageOpts {
+    /// the fragment (BED) file written by `simpleaf atac process`
+    #[arg(short, long, display_order = 1)]
+    pub fragments: PathBuf,
+
+    /// output directory where one `<group>.bw` file per group will be written
+    #[arg(short, long, display_order = 2)]
+    pub output: PathBuf,
+
+    /// a two-column TSV (no header) assigning barcodes to groups (e.g. clusters);
+    /// barcodes not listed are ignored. If not provided, all barcodes are
+    /// written to a single track (`all.bw`)
+    #[arg(short, long, display_order = 3)]
+    pub groups: Option<PathBuf>,
+
+    /// chromosome sizes (a `chrom.sizes` or FASTA `.fai` file); if not provided,
+    /// the last fragment end on each chromosome is used
+    #[arg(long, display_order = 4)]
+    pub chrom_sizes: Option<PathBuf>,
+
+    /// the signal to bin into each track
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t = CoverageMode::CutSites,
+        help_heading = "Track Options"
+    )]
+    pub mode: CoverageMode,
+
+    /// how the coverage of each group is normalized
+    #[arg(
+        short,
+        long,
+        value_enum,
+        default_value_t = CoverageNormalization::Cpm,
+        help_heading = "Track Options"
+    )]
+    pub normalize: CoverageNormalization,
+
+    /// the size (in bases) of the bins in which coverage is computed
+    #[arg(short, long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..), help_heading = "Track Options")]
+    pub bin_size: u32,
+
+    /// smooth each track with a moving average over this many bases
+    /// (rounded down to a whole number of bins; 0 disables smoothing)
+    #[arg(long, default_value_t = 0, help_heading = "Track Options")]
+    pub smooth_length: u32,
+}
@@ -0,0 +1,424 @@
+//! Pseudobulk coverage tracks for scATAC-seq data.
+//!
+//! The fragments of each group of barcodes (e.g. a cluster) are binned into
+//! either Tn5 cut-site counts (the two fragment ends) or fragment coverage,
+//! optionally normalized (CPM / RPKM) and smoothed, and written as one bigWig
+//! file per group with the native writer in [`crate::utils::bigwig`].
+
+use crate::atac::commands::{CoverageMode, CoverageNormalization, CoverageOpts};
+use crate::core::io;
+use crate::utils::bigwig::{self, Interval};
+use anyhow::{Context, bail};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// the group name used when no barcode groups are given
+const ALL_BARCODES_GROUP: &str = "all";
+
+/// Binned signal of one group of barcodes.
+#[derive(Default)]
+struct GroupCoverage {
+    /// per chromosome, the (sparse) count in each bin
+    bins: HashMap<String, HashMap<u32, f64>>,
+    /// number of fragments or cut sites counted (the library size for normalization)
+    events: u64,
+    barcodes: usize,
+}
+
+/// Read a barcode -> group table (two tab-separated columns, no header).
+fn read_groups(p: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let content =
+        std::fs::read_to_string(p).with_context(|| format!("could not read {}", p.display()))?;
+    let mut groups = HashMap::new();
+    for (lnum, line) in content.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((bc, group)) = line.split_once('\t') else {
+            bail!(
+                "line {} of {} does not have a barcode and a group column",
+                lnum + 1,
+                p.display()
+            );
+        };
+        let group = group.split('\t').next().unwrap_or_default();
+        groups.insert(bc.to_string(), group.to_string());
+    }
+    if groups.is_empty() {
+        bail!("no barcode groups could be read from {}", p.display());
+    }
+    Ok(groups)
+}
+
+/// Read chromosome sizes from a `chrom.sizes` or FASTA index (`.fai`) file,
+/// i.e. the first two tab-separated columns.
+pub(crate) fn read_chrom_sizes(p: &Path) -> anyhow::Result<Vec<(String, u32)>> {
+    let content =
+        std::fs::read_to_string(p).with_context(|| format!("could not read {}", p.display()))?;
+    let mut sizes = Vec::new();
+    for (lnum, line) in content.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut toks = line.split('\t');
+        let (Some(name), Some(size)) = (toks.next(), toks.next()) else {
+            bail!(
+                "line {} of {} has fewer than 2 columns",
+                lnum + 1,
+                p.display()
+            );
+        };
+        let size: u32 = size
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid size on line {} of {}", lnum + 1, p.display()))?;
+        sizes.push((name.to_string(), size));
+    }
+    Ok(sizes)
+}
+
+/// Turn a group name into a safe file name.
+fn group_file_stem(group: &str) -> String {
+    group
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Smooth the bins of one chromosome with a centered moving average over
+/// `window` bins (no smoothing if `window` <= 1).
+fn smooth_bins(bins: &HashMap<u32, f64>, window: u32, nbins: u32) -> HashMap<u32, f64> {
+    if window <= 1 {
+        return bins.clone();
+    }
+    let half = window / 2;
+    let width = (2 * half + 1) as f64;
+    let mut out: HashMap<u32, f64> = HashMap::with_capacity(bins.len() * window as usize);
+    for (b, v) in bins {
+        let lo = b.saturating_sub(half);
+        let hi = b.saturating_add(half).min(nbins.saturating_sub(1));
+        for i in lo..=hi {
+            *out.entry(i).or_default() += v / width;
+        }
+    }
+    out
+}
+
+/// Convert the (scaled) bins of one chromosome into sorted runs, merging
+/// adjacent bins with the same value and clipping the last bin to the
+/// chromosome end.
+fn bins_to_intervals(
+    bins: &HashMap<u32, f64>,
+    bin_size: u32,
+    chrom_size: u32,
+    scale: f64,
+) -> Vec<Interval> {
+    let mut keys: Vec<u32> = bins.keys().copied().collect();
+    keys.sort_unstable();
+    let mut out: Vec<Interval> = Vec::with_capacity(keys.len());
+    for b in keys {
+        let start = b.saturating_mul(bin_size);
+        if start >= chrom_size {
+            break;
+        }
+        let end = start.saturating_add(bin_size).min(chrom_size);
+        let value = (bins[&b] * scale) as f32;
+        if value == 0.0 {
+            continue;
+        }
+        match out.last_mut() {
+            Some(last) if last.end == start && last.value == value => last.end = end,
+            _ => out.push(Interval { start, end, value }),
+        }
+    }
+    out
+}
+
+/// Write one bigWig coverage track per barcode group from the fragments of an
+/// ATAC sample.
+pub fn coverage(opts: &CoverageOpts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+
+    let groups = match &opts.groups {
+        Some(p) => {
+            let g = read_groups(p)?;
+            info!("read {} barcode groups from {}", g.len(), p.display());
+            Some(g)
+        }
+        None => None,
+    };
+
+    let (reader, _fmt) = niffler::from_path(&opts.fragments)
+        .with_context(|| format!("could not open fragment file {}", opts.fragments.display()))?;
+    let br = BufReader::new(reader);
+
+    let bin_size = opts.bin_size;
+    let mut coverage: HashMap<String, GroupCoverage> = HashMap::new();
+    let mut seen_barcodes: HashSet<String> = HashSet::new();
+    let mut max_end: HashMap<String, u32> = HashMap::new();
+    for (lnum, line) in br.lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", opts.fragments.display()))?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut toks = line.split('\t');
+        let (Some(chr), Some(fstart), Some(fend), Some(bc)) =
+            (toks.next(), toks.next(), toks.next(), toks.next())
+        else {
+            bail!(
+                "line {} of fragment file {} has fewer than 4 columns",
+                lnum + 1,
+                opts.fragments.display()
+            );
+        };
+        let group = match &groups {
+            Some(g) => match g.get(bc) {
+                Some(group) => group.as_str(),
+                None => continue,
+            },
+            None => ALL_BARCODES_GROUP,
+        };
+        let fstart: u32 = fstart.parse().with_context(|| {
+            format!(
+                "invalid start on line {} of {}",
+                lnum + 1,
+                opts.fragments.display()
+            )
+        })?;
+        let fend: u32 = fend.parse().with_context(|| {
+            format!(
+                "invalid end on line {} of {}",
+                lnum + 1,
+                opts.fragments.display()
+            )
+        })?;
+        if fend <= fstart {
+            continue;
+        }
+
+        let gc = coverage.entry(group.to_string()).or_default();
+        if !seen_barcodes.contains(bc) {
+            seen_barcodes.insert(bc.to_string());
+            gc.barcodes += 1;
+        }
+        let me = max_end.entry(chr.to_string()).or_default();
+        *me = (*me).max(fend);
+        let chr_bins = gc.bins.entry(chr.to_string()).or_default();
+        match opts.mode {
+            CoverageMode::CutSites => {
+                for cut in [fstart, fend - 1] {
+                    *chr_bins.entry(cut / bin_size).or_default() += 1.0;
+                }
+                gc.events += 2;
+            }
+            CoverageMode::Fragments => {
+                for b in fstart / bin_size..=(fend - 1) / bin_size {
+                    *chr_bins.entry(b).or_default() += 1.0;
+                }
+                gc.events += 1;
+            }
+        }
+    }
+    if coverage.is_empty() {
+        bail!(
+            "no fragments of the requested barcodes were found in {}",
+            opts.fragments.display()
+        );
+    }
+
+    let chrom_sizes = match &opts.chrom_sizes {
+        Some(p) => read_chrom_sizes(p)?,
+        None => {
+            warn!(
+                "no --chrom-sizes were given; chromosome lengths will be approximated by the last fragment end on each chromosome."
+            );
+            let mut sizes: Vec<(String, u32)> = max_end.into_iter().collect();
+            sizes.sort();
+            sizes
+        }
+    };
+    let size_of: HashMap<&str, u32> = chrom_sizes.iter().map(|(n, s)| (n.as_str(), *s)).collect();
+    let smooth_window = opts.smooth_length / bin_size;
+
+    let mut group_names: Vec<&String> = coverage.keys().collect();
+    group_names.sort();
+    let mut group_info = Vec::with_capacity(group_names.len());
+    for group in group_names {
+        let gc = &coverage[group];
+        let scale = match opts.normalize {
+            CoverageNormalization::None => 1.0,
+            CoverageNormalization::Cpm => 1e6 / gc.events as f64,
+            CoverageNormalization::Rpkm => 1e6 / gc.events as f64 * 1000.0 / bin_size as f64,
+        };
+        let mut intervals: HashMap<String, Vec<Interval>> = HashMap::new();
+        for (chr, bins) in &gc.bins {
+            let Some(&size) = size_of.get(chr.as_str()) else {
+                warn!(
+                    "chromosome {} is not listed in the chromosome sizes; skipping its fragments.",
+                    chr
+                );
+                continue;
+            };
+            let nbins = size.div_ceil(bin_size);
+            let smoothed = smooth_bins(bins, smooth_window, nbins);
+            let ivs = bins_to_intervals(&smoothed, bin_size, size, scale);
+            if !ivs.is_empty() {
+                intervals.insert(chr.clone(), ivs);
+            }
+        }
+        let out = opts.output.join(format!("{}.bw", group_file_stem(group)));
+        bigwig::write_bigwig(&out, &chrom_sizes, &intervals, bin_size.saturating_mul(4))?;
+        info!(
+            "wrote coverage of group {} ({} barcodes) to {}",
+            group,
+            gc.barcodes,
+            out.display()
+        );
+        group_info.push(json!({
+            "group": group,
+            "barcodes": gc.barcodes,
+            "events": gc.events,
+            "scale_factor": scale,
+            "bigwig": out,
+        }));
+    }
+
+    io::write_json_pretty_atomic(
+        &opts.output.join("simpleaf_coverage_log.json"),
+        &json!({
+            "time_info": { "total_time": start.elapsed().as_secs_f64() },
+            "fragments": opts.fragments,
+            "groups_file": opts.groups,
+            "chrom_sizes": opts.chrom_sizes,
+            "mode": opts.mode.to_string(),
+            "normalize": opts.normalize.to_string(),
+            "bin_size": bin_size,
+            "smooth_length": opts.smooth_length,
+            "groups": group_info,
+        }),
+    )?;
+    info!(
+        "coverage tracks written to {} in {:#?}",
+        opts.output.display(),
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn opts(td: &Path) -> CoverageOpts {
+        CoverageOpts {
+            fragments: td.join("frags.bed"),
+            output: td.join("out"),
+            groups: None,
+            chrom_sizes: None,
+            mode: CoverageMode::CutSites,
+            normalize: CoverageNormalization::None,
+            bin_size: 10,
+            smooth_length: 0,
+        }
+    }
+
+    #[test]
+    fn bins_merge_into_runs() {
+        let bins: HashMap<u32, f64> = [(0, 1.0), (1, 1.0), (3, 2.0), (9, 1.0)].into();
+        let ivs = bins_to_intervals(&bins, 10, 95, 0.5);
+        assert_eq!(
+            ivs,
+            vec![
+                Interval {
+                    start: 0,
+                    end: 20,
+                    value: 0.5
+                },
+                Interval {
+                    start: 30,
+                    end: 40,
+                    value: 1.0
+                },
+                Interval {
+                    start: 90,
+                    end: 95,
+                    value: 0.5
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn smoothing_spreads_signal_over_the_window() {
+        let bins: HashMap<u32, f64> = [(0, 3.0), (5, 3.0)].into();
+        let s = smooth_bins(&bins, 3, 6);
+        assert_eq!(s.get(&0), Some(&1.0));
+        assert_eq!(s.get(&1), Some(&1.0));
+        assert_eq!(s.get(&4), Some(&1.0));
+        assert_eq!(s.get(&6), None);
+        assert_eq!(s.len(), 4);
+    }
+
+    #[test]
+    fn one_track_is_written_per_group() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(
+            td.path().join("frags.bed"),
+            "chr1\t5\t25\tAAAA\t1\nchr1\t12\t18\tCCCC\t1\nchr1\t40\t60\tGGGG\t1\n",
+        )
+        .expect("failed to write fragments");
+        fs::write(
+            td.path().join("groups.tsv"),
+            "AAAA\tT cells\nCCCC\tT cells\nTTTT\tB/NK\n",
+        )
+        .expect("failed to write groups");
+        fs::write(td.path().join("sizes"), "chr1\t100\nchr2\t50\n").expect("failed to write sizes");
+
+        let mut o = opts(td.path());
+        o.groups = Some(td.path().join("groups.tsv"));
+        o.chrom_sizes = Some(td.path().join("sizes"));
+        o.normalize = CoverageNormalization::Cpm;
+        coverage(&o).expect("coverage");
+
+        assert!(o.output.join("T_cells.bw").is_file());
+        assert!(!o.output.join("B_NK.bw").exists());
+        let log: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(o.output.join("simpleaf_coverage_log.json")).expect("log"),
+        )
+        .expect("valid json");
+        let groups = log["groups"].as_array().expect("groups");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0]["barcodes"], 2);
+        assert_eq!(groups[0]["events"], 4);
+        assert_eq!(groups[0]["scale_factor"], 250_000.0);
+    }
+
+    #[test]
+    fn fragment_mode_covers_every_overlapped_bin() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(td.path().join("frags.bed"), "chr1\t5\t25\tAAAA\t1\n")
+            .expect("failed to write fragments");
+        let mut o = opts(td.path());
+        o.mode = CoverageMode::Fragments;
+        coverage(&o).expect("coverage");
+        assert!(o.output.join("all.bw").is_file());
+        let log: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(o.output.join("simpleaf_coverage_log.json")).expect("log"),
+        )
+        .expect("valid json");
+        assert_eq!(log["groups"][0]["events"], 1);
+    }
+}
@@ -0,0 +1,722 @@
+//! Gene activity scores for scATAC-seq data.
+//!
+//! A gene activity matrix summarizes the chromatin accessibility around each gene
+//! as a cell × gene count matrix, so that ATAC data can be compared with (or have
+//! labels transferred from) scRNA-seq references. Two scoring schemes are offered:
+//!
+//! * `gene-body` (Signac-style): each fragment overlapping the gene body extended by
+//!   an upstream promoter window counts once towards that gene.
+//! * `distance-weighted`: fragments also count when they fall near (but outside of)
+//!   that region, down-weighted exponentially with their distance to it.
+//!
+//! The output is written in the layout of an alevin-fry quantification directory
+//! (`alevin/quants_mat.mtx` with its row and column files, plus a
+//! `gene_id_to_name.tsv` and per-cell `featureDump.txt`), so it can be converted to
+//! an h5ad like RNA output.
+
+use crate::atac::commands::{GeneActivityMethod, GeneActivityOpts};
+use crate::core::io;
+use crate::utils::mtx_utils;
+use anyhow::{Context, bail};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// A gene read from the annotation, with 0-based, half-open coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Gene {
+    pub id: String,
+    pub name: String,
+    pub chr: String,
+    pub start: u64,
+    pub end: u64,
+    pub reverse: bool,
+}
+
+impl Gene {
+    /// The gene body extended by `upstream` bases before the TSS and
+    /// `downstream` bases after the TES (strand-aware).
+    fn scoring_region(&self, upstream: u64, downstream: u64) -> (u64, u64) {
+        if self.reverse {
+            (self.start.saturating_sub(downstream), self.end + upstream)
+        } else {
+            (self.start.saturating_sub(upstream), self.end + downstream)
+        }
+    }
+}
+
+/// Extract the value of `key` from the attribute column of a GTF (`key "value";`)
+/// or GFF3 (`key=value;`) record.
+fn gtf_attribute<'a>(attrs: &'a str, key: &str) -> Option<&'a str> {
+    for field in attrs.split(';') {
+        let field = field.trim();
+        let (k, v) = match field.split_once([' ', '=']) {
+            Some(kv) => kv,
+            None => continue,
+        };
+        if k == key {
+            return Some(v.trim().trim_matches('"'));
+        }
+    }
+    None
+}
+
+/// Read the genes of a GTF/GFF annotation. Gene records are used where present;
+/// genes without one take their extent from their transcript and exon records.
+/// If `biotypes` is non-empty, only genes whose `gene_type` / `gene_biotype`
+/// is listed are kept.
+pub(crate) fn read_genes(gtf: &Path, biotypes: &[String]) -> anyhow::Result<Vec<Gene>> {
+    let (reader, _fmt) = niffler::from_path(gtf)
+        .with_context(|| format!("could not open annotation file {}", gtf.display()))?;
+    let br = BufReader::new(reader);
+
+    // genes in the order they first appear, plus whether they came from a gene record
+    let mut genes: Vec<(Gene, bool)> = Vec::new();
+    let mut gene_idx: HashMap<String, usize> = HashMap::new();
+    let mut excluded: HashSet<String> = HashSet::new();
+    for (lnum, line) in br.lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", gtf.display()))?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() < 9 {
+            bail!(
+                "line {} of annotation file {} has fewer than 9 columns",
+                lnum + 1,
+                gtf.display()
+            );
+        }
+        let is_gene = match toks[2] {
+            "gene" => true,
+            "transcript" | "exon" => false,
+            _ => continue,
+        };
+        let attrs = toks[8];
+        let Some(id) = gtf_attribute(attrs, "gene_id") else {
+            bail!(
+                "line {} of annotation file {} has no gene_id attribute",
+                lnum + 1,
+                gtf.display()
+            );
+        };
+        if excluded.contains(id) {
+            continue;
+        }
+        if !biotypes.is_empty() {
+            let bt = gtf_attribute(attrs, "gene_type").or(gtf_attribute(attrs, "gene_biotype"));
+            if !bt.is_some_and(|bt| biotypes.iter().any(|b| b == bt)) {
+                excluded.insert(id.to_string());
+                continue;
+            }
+        }
+        // GTF coordinates are 1-based and inclusive
+        let start: u64 = toks[3]
+            .parse()
+            .with_context(|| format!("invalid start on line {} of {}", lnum + 1, gtf.display()))?;
+        let end: u64 = toks[4]
+            .parse()
+            .with_context(|| format!("invalid end on line {} of {}", lnum + 1, gtf.display()))?;
+        let gene = Gene {
+            id: id.to_string(),
+            name: gtf_attribute(attrs, "gene_name").unwrap_or(id).to_string(),
+            chr: toks[0].to_string(),
+            start: start.saturating_sub(1),
+            end,
+            reverse: toks[6] == "-",
+        };
+        match gene_idx.get(id) {
+            Some(&i) => {
+                let (g, from_gene_record) = &mut genes[i];
+                if is_gene {
+                    *g = gene;
+                    *from_gene_record = true;
+                } else if !*from_gene_record {
+                    g.start = g.start.min(gene.start);
+                    g.end = g.end.max(gene.end);
+                }
+            }
+            None => {
+                gene_idx.insert(id.to_string(), genes.len());
+                genes.push((gene, is_gene));
+            }
+        }
+    }
+    if genes.is_empty() {
+        bail!("no genes could be read from {}", gtf.display());
+    }
+    Ok(genes.into_iter().map(|(g, _)| g).collect())
+}
+
+/// Per-chromosome index of the scoring regions of each gene, sorted by start.
+struct RegionIndex {
+    /// (start, end, gene index) per chromosome
+    regions: HashMap<String, Vec<(u64, u64, usize)>>,
+    /// the length of the longest region, used to bound the overlap search
+    max_len: u64,
+}
+
+impl RegionIndex {
+    fn new(genes: &[Gene], upstream: u64, downstream: u64) -> Self {
+        let mut regions: HashMap<String, Vec<(u64, u64, usize)>> = HashMap::new();
+        let mut max_len = 0;
+        for (i, g) in genes.iter().enumerate() {
+            let (s, e) = g.scoring_region(upstream, downstream);
+            max_len = max_len.max(e - s);
+            regions.entry(g.chr.clone()).or_default().push((s, e, i));
+        }
+        for v in regions.values_mut() {
+            v.sort_unstable();
+        }
+        Self { regions, max_len }
+    }
+
+    /// Call `f(gene, distance)` for every region within `max_dist` of the
+    /// fragment `[start, end)`; the distance is 0 for overlapping regions.
+    fn for_each_near<F: FnMut(usize, u64)>(
+        &self,
+        chr: &str,
+        start: u64,
+        end: u64,
+        max_dist: u64,
+        mut f: F,
+    ) {
+        let Some(regions) = self.regions.get(chr) else {
+            return;
+        };
+        let lo = start.saturating_sub(max_dist + self.max_len);
+        let first = regions.partition_point(|r| r.0 < lo);
+        for &(s, e, gi) in &regions[first..] {
+            if s >= end + max_dist {
+                break;
+            }
+            // distance between the closest bases of the fragment and the region
+            let dist = if e <= start {
+                start - e + 1
+            } else if s >= end {
+                s - end + 1
+            } else {
+                0
+            };
+            if dist <= max_dist {
+                f(gi, dist);
+            }
+        }
+    }
+}
+
+/// Read the barcodes (first column) of a cell list.
+fn read_cell_list(p: &Path) -> anyhow::Result<Vec<String>> {
+    let content =
+        std::fs::read_to_string(p).with_context(|| format!("could not read {}", p.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|l| l.split('\t').next())
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// The accumulated gene activity scores.
+pub(crate) struct GeneActivity {
+    pub barcodes: Vec<String>,
+    /// (barcode index, gene index) -> score
+    pub scores: HashMap<(usize, usize), f64>,
+    /// the number of fragments of each barcode, and how many of them were
+    /// assigned to at least one gene
+    pub barcode_fragments: Vec<u64>,
+    pub barcode_assigned_fragments: Vec<u64>,
+    pub num_fragments: u64,
+    pub num_assigned_fragments: u64,
+}
+
+/// Score the fragments of `bed` (`chr start end barcode [count]`) against `genes`.
+/// If `cells` is given, only those barcodes are scored (in that order); otherwise
+/// every barcode in the fragment file is, in sorted order.
+pub(crate) fn score_fragments(
+    bed: &Path,
+    genes: &[Gene],
+    cells: Option<Vec<String>>,
+    opts: &GeneActivityOpts,
+) -> anyhow::Result<GeneActivity> {
+    let index = RegionIndex::new(genes, opts.upstream, opts.downstream);
+    let max_dist = match opts.method {
+        GeneActivityMethod::GeneBody => 0,
+        GeneActivityMethod::DistanceWeighted => opts.max_distance,
+    };
+
+    let restrict = cells.is_some();
+    let mut barcodes = cells.unwrap_or_default();
+    let mut bc_idx: HashMap<String, usize> = barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.clone(), i))
+        .collect();
+
+    let (reader, _fmt) = niffler::from_path(bed)
+        .with_context(|| format!("could not open fragment file {}", bed.display()))?;
+    let br = BufReader::new(reader);
+
+    let mut scores: HashMap<(usize, usize), f64> = HashMap::new();
+    let mut barcode_fragments = vec![0u64; barcodes.len()];
+    let mut barcode_assigned_fragments = vec![0u64; barcodes.len()];
+    let mut num_fragments = 0u64;
+    let mut num_assigned_fragments = 0u64;
+    for (lnum, line) in br.lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", bed.display()))?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut toks = line.split('\t');
+        let (Some(chr), Some(start), Some(end), Some(bc)) =
+            (toks.next(), toks.next(), toks.next(), toks.next())
+        else {
+            bail!(
+                "line {} of fragment file {} has fewer than 4 columns",
+                lnum + 1,
+                bed.display()
+            );
+        };
+        let ci = match bc_idx.get(bc) {
+            Some(&i) => i,
+            None if restrict => continue,
+            None => {
+                bc_idx.insert(bc.to_string(), barcodes.len());
+                barcodes.push(bc.to_string());
+                barcode_fragments.push(0);
+                barcode_assigned_fragments.push(0);
+                barcodes.len() - 1
+            }
+        };
+        let start: u64 = start
+            .parse()
+            .with_context(|| format!("invalid start on line {} of {}", lnum + 1, bed.display()))?;
+        let end: u64 = end
+            .parse()
+            .with_context(|| format!("invalid end on line {} of {}", lnum + 1, bed.display()))?;
+
+        num_fragments += 1;
+        barcode_fragments[ci] += 1;
+        let mut assigned = false;
+        index.for_each_near(chr, start, end, max_dist, |gi, dist| {
+            let w = match opts.method {
+                GeneActivityMethod::GeneBody => 1.0,
+                GeneActivityMethod::DistanceWeighted => {
+                    (-(dist as f64) / opts.decay_length as f64).exp()
+                }
+            };
+            *scores.entry((ci, gi)).or_default() += w;
+            assigned = true;
+        });
+        if assigned {
+            num_assigned_fragments += 1;
+            barcode_assigned_fragments[ci] += 1;
+        }
+    }
+
+    if !restrict {
+        // report barcodes in sorted order
+        let mut order: Vec<usize> = (0..barcodes.len()).collect();
+        order.sort_unstable_by(|a, b| barcodes[*a].cmp(&barcodes[*b]));
+        let mut new_idx = vec![0usize; barcodes.len()];
+        for (new, old) in order.iter().enumerate() {
+            new_idx[*old] = new;
+        }
+        scores = scores
+            .into_iter()
+            .map(|((c, g), v)| ((new_idx[c], g), v))
+            .collect();
+        barcode_fragments = order.iter().map(|i| barcode_fragments[*i]).collect();
+        barcode_assigned_fragments = order
+            .iter()
+            .map(|i| barcode_assigned_fragments[*i])
+            .collect();
+        barcodes = order.into_iter().map(|i| barcodes[i].clone()).collect();
+    }
+
+    Ok(GeneActivity {
+        barcodes,
+        scores,
+        barcode_fragments,
+        barcode_assigned_fragments,
+        num_fragments,
+        num_assigned_fragments,
+    })
+}
+
+/// Write the gene activity matrix (cells as rows, genes as columns) and its
+/// annotations in the layout of an alevin-fry quantification directory.
+fn write_activity_matrix(
+    out: &Path,
+    activity: &GeneActivity,
+    genes: &[Gene],
+    integer: bool,
+) -> anyhow::Result<()> {
+    let (mtx, rows, cols) = mtx_utils::quant_mat_paths(out);
+    let alevin = out.join("alevin");
+    std::fs::create_dir_all(&alevin)
+        .with_context(|| format!("could not create {}", alevin.display()))?;
+
+    let mut entries: Vec<(&(usize, usize), &f64)> = activity.scores.iter().collect();
+    entries.sort_unstable_by_key(|(k, _)| **k);
+    let mut mtx_w = BufWriter::new(
+        std::fs::File::create(&mtx)
+            .with_context(|| format!("could not create {}", mtx.display()))?,
+    );
+    let field = if integer { "integer" } else { "real" };
+    writeln!(mtx_w, "%%MatrixMarket matrix coordinate {} general", field)?;
+    writeln!(
+        mtx_w,
+        "{}\t{}\t{}",
+        activity.barcodes.len(),
+        genes.len(),
+        entries.len()
+    )?;
+    for ((c, g), v) in entries {
+        if integer {
+            writeln!(mtx_w, "{}\t{}\t{}", c + 1, g + 1, *v as u64)?;
+        } else {
+            writeln!(mtx_w, "{}\t{}\t{:.6}", c + 1, g + 1, v)?;
+        }
+    }
+    mtx_w.flush()?;
+
+    let mut rows_w = BufWriter::new(
+        std::fs::File::create(&rows)
+            .with_context(|| format!("could not create {}", rows.display()))?,
+    );
+    for bc in &activity.barcodes {
+        writeln!(rows_w, "{}", bc)?;
+    }
+    rows_w.flush()?;
+
+    let mut cols_w = BufWriter::new(
+        std::fs::File::create(&cols)
+            .with_context(|| format!("could not create {}", cols.display()))?,
+    );
+    let g2n = out.join("gene_id_to_name.tsv");
+    let mut g2n_w = BufWriter::new(
+        std::fs::File::create(&g2n)
+            .with_context(|| format!("could not create {}", g2n.display()))?,
+    );
+    for g in genes {
+        writeln!(cols_w, "{}", g.id)?;
+        writeln!(g2n_w, "{}\t{}", g.id, g.name)?;
+    }
+    cols_w.flush()?;
+    g2n_w.flush()?;
+    Ok(())
+}
+
+/// Write the per-cell `featureDump.txt` that the h5ad conversion adds to `obs`,
+/// with the columns of `alevin-fry quant` filled in from the fragments:
+/// `CorrectedReads` holds the fragments of the barcode, `MappedReads` those
+/// assigned to a gene, and `DeduplicatedReads` the total activity score. The
+/// fragments were already deduplicated by `alevin-fry atac sort`, so `DedupRate`
+/// is always 0.
+fn write_feature_dump(out: &Path, activity: &GeneActivity, num_genes: usize) -> anyhow::Result<()> {
+    let mut row_scores: Vec<Vec<f64>> = vec![Vec::new(); activity.barcodes.len()];
+    for ((c, _), v) in &activity.scores {
+        row_scores[*c].push(*v);
+    }
+
+    let dump = out.join("featureDump.txt");
+    let mut w = BufWriter::new(
+        std::fs::File::create(&dump)
+            .with_context(|| format!("could not create {}", dump.display()))?,
+    );
+    writeln!(
+        w,
+        "CB\tCorrectedReads\tMappedReads\tDeduplicatedReads\tMappingRate\tDedupRate\tMeanByMax\tNumGenesExpressed\tNumGenesOverMean"
+    )?;
+    for (i, bc) in activity.barcodes.iter().enumerate() {
+        let frags = activity.barcode_fragments[i];
+        let assigned = activity.barcode_assigned_fragments[i];
+        let scores = &row_scores[i];
+        let total: f64 = scores.iter().sum();
+        let max = scores.iter().copied().fold(0.0, f64::max);
+        let mean = if num_genes > 0 {
+            total / num_genes as f64
+        } else {
+            0.0
+        };
+        let mapping_rate = if frags > 0 {
+            assigned as f64 / frags as f64
+        } else {
+            0.0
+        };
+        let mean_by_max = if max > 0.0 { mean / max } else { 0.0 };
+        let over_mean = scores.iter().filter(|v| **v > mean).count();
+        writeln!(
+            w,
+            "{}\t{}\t{}\t{:.6}\t{:.6}\t0\t{:.6}\t{}\t{}",
+            bc,
+            frags,
+            assigned,
+            total,
+            mapping_rate,
+            mean_by_max,
+            scores.len(),
+            over_mean
+        )?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Convert the output directory to an h5ad. Besides the matrix, `featureDump.txt`
+/// and `quant.json` (whose `usa_mode` it reads), the conversion requires a
+/// `collate.json` and a `generate_permit_list.json` to exist. Of those it only reads
+/// `multi_barcode` from `collate.json`, and stores both verbatim in `uns`, so they
+/// are written with no other content.
+fn convert_to_anndata(out: &Path) -> anyhow::Result<()> {
+    io::write_json_pretty_atomic(
+        &out.join("collate.json"),
+        &json!({ "multi_barcode": false }),
+    )?;
+    io::write_json_pretty_atomic(&out.join("generate_permit_list.json"), &json!({}))?;
+    let opath = out.join("alevin").join("quants.h5ad");
+    af_anndata::convert_csr_to_anndata(out, &opath)
+}
+
+/// Compute a gene activity matrix from the fragments of an ATAC sample.
+pub fn gene_activity(opts: &GeneActivityOpts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+
+    let genes = read_genes(&opts.gtf, &opts.biotypes)?;
+    info!("read {} genes from {}", genes.len(), opts.gtf.display());
+
+    let cells = match &opts.cells {
+        Some(p) => {
+            let cells = read_cell_list(p)?;
+            if cells.is_empty() {
+                bail!("no cell barcodes could be read from {}", p.display());
+            }
+            info!(
+                "scoring the {} barcodes listed in {}",
+                cells.len(),
+                p.display()
+            );
+            Some(cells)
+        }
+        None => None,
+    };
+
+    let activity = score_fragments(&opts.fragments, &genes, cells, opts)?;
+    if activity.num_fragments == 0 {
+        warn!(
+            "no fragments of the requested barcodes were found in {}",
+            opts.fragments.display()
+        );
+    }
+    let frac = if activity.num_fragments > 0 {
+        activity.num_assigned_fragments as f64 / activity.num_fragments as f64
+    } else {
+        0.0
+    };
+    info!(
+        "assigned {} of {} fragments ({:.2}%) to genes across {} barcodes.",
+        activity.num_assigned_fragments,
+        activity.num_fragments,
+        100.0 * frac,
+        activity.barcodes.len()
+    );
+
+    let integer = opts.method == GeneActivityMethod::GeneBody;
+    write_activity_matrix(&opts.output, &activity, &genes, integer)?;
+    write_feature_dump(&opts.output, &activity, genes.len())?;
+
+    // the scoring parameters, and the `usa_mode` read by the h5ad conversion
+    let method = opts.method.to_string();
+    io::write_json_pretty_atomic(
+        &opts.output.join("quant.json"),
+        &json!({
+            "usa_mode": false,
+            "gene_activity": {
+                "method": method,
+                "upstream": opts.upstream,
+                "downstream": opts.downstream,
+                "max_distance": opts.max_distance,
+                "decay_length": opts.decay_length,
+                "biotypes": opts.biotypes,
+            },
+            "num_genes": genes.len(),
+            "num_quantified_cells": activity.barcodes.len(),
+        }),
+    )?;
+
+    let mut convert_duration = None;
+    if opts.anndata_out {
+        let convert_start = Instant::now();
+        convert_to_anndata(&opts.output)?;
+        convert_duration = Some(convert_start.elapsed());
+    }
+
+    io::write_json_pretty_atomic(
+        &opts.output.join("simpleaf_gene_activity_log.json"),
+        &json!({
+            "time_info": {
+                "total_time": start.elapsed().as_secs_f64(),
+                "conversion_time": convert_duration.map(|d| d.as_secs_f64()),
+            },
+            "fragments": opts.fragments,
+            "gtf": opts.gtf,
+            "method": method,
+            "num_genes": genes.len(),
+            "num_barcodes": activity.barcodes.len(),
+            "num_fragments": activity.num_fragments,
+            "num_assigned_fragments": activity.num_assigned_fragments,
+        }),
+    )?;
+    info!(
+        "gene activity matrix written to {} in {:#?}",
+        opts.output.display(),
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const GTF: &str = "\
+chr1\tsrc\tgene\t1001\t2000\t.\t+\t.\tgene_id \"G1\"; gene_name \"Alpha\"; gene_type \"protein_coding\";
+chr1\tsrc\ttranscript\t1001\t2000\t.\t+\t.\tgene_id \"G1\"; transcript_id \"T1\";
+chr1\tsrc\ttranscript\t5001\t6000\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; gene_type \"lncRNA\";
+chr1\tsrc\texon\t5001\t6500\t.\t-\t.\tgene_id \"G2\"; transcript_id \"T2\"; gene_type \"lncRNA\";
+";
+
+    fn opts(td: &Path, method: GeneActivityMethod) -> GeneActivityOpts {
+        GeneActivityOpts {
+            fragments: td.join("frags.bed"),
+            gtf: td.join("genes.gtf"),
+            output: td.join("out"),
+            cells: None,
+            method,
+            upstream: 500,
+            downstream: 0,
+            max_distance: 1000,
+            decay_length: 1000,
+            biotypes: vec![],
+            anndata_out: false,
+        }
+    }
+
+    #[test]
+    fn genes_are_read_from_gene_and_transcript_records() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let gtf = td.path().join("genes.gtf");
+        fs::write(&gtf, GTF).expect("failed to write gtf");
+
+        let genes = read_genes(&gtf, &[]).expect("genes");
+        assert_eq!(genes.len(), 2);
+        assert_eq!((genes[0].name.as_str(), genes[0].start), ("Alpha", 1000));
+        // no gene record and no gene_name: extent from transcript/exons, name from id
+        assert_eq!(genes[1].name, "G2");
+        assert_eq!((genes[1].start, genes[1].end), (5000, 6500));
+        assert!(genes[1].reverse);
+
+        let pc = read_genes(&gtf, &["protein_coding".to_string()]).expect("genes");
+        assert_eq!(pc.len(), 1);
+        assert_eq!(pc[0].id, "G1");
+    }
+
+    #[test]
+    fn promoter_window_is_strand_aware() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(td.path().join("genes.gtf"), GTF).expect("failed to write gtf");
+        fs::write(
+            td.path().join("frags.bed"),
+            // G1's promoter (+ strand), G2's promoter (- strand, so past its end),
+            // just past G2's 3' end, and an unannotated chromosome
+            "chr1\t600\t700\tAAAA\t1\nchr1\t6600\t6700\tCCCC\t2\nchr1\t4600\t4700\tCCCC\t1\nchr2\t1\t50\tAAAA\t1\n",
+        )
+        .expect("failed to write fragments");
+        let o = opts(td.path(), GeneActivityMethod::GeneBody);
+        let genes = read_genes(&o.gtf, &[]).expect("genes");
+        let act = score_fragments(&o.fragments, &genes, None, &o).expect("scores");
+
+        assert_eq!(act.barcodes, vec!["AAAA", "CCCC"]);
+        assert_eq!(act.num_fragments, 4);
+        assert_eq!(act.num_assigned_fragments, 2);
+        assert_eq!(act.scores.get(&(0, 0)), Some(&1.0));
+        assert_eq!(act.scores.get(&(1, 1)), Some(&1.0));
+        assert_eq!(act.scores.len(), 2);
+    }
+
+    #[test]
+    fn distance_weighting_decays_with_distance() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(td.path().join("genes.gtf"), GTF).expect("failed to write gtf");
+        fs::write(
+            td.path().join("frags.bed"),
+            "chr1\t1500\t1600\tAAAA\t1\nchr1\t2100\t2200\tAAAA\t1\n",
+        )
+        .expect("failed to write fragments");
+        let o = opts(td.path(), GeneActivityMethod::DistanceWeighted);
+        let genes = read_genes(&o.gtf, &[]).expect("genes");
+        let act =
+            score_fragments(&o.fragments, &genes, Some(vec!["AAAA".into()]), &o).expect("scores");
+        let s = act.scores[&(0, 0)];
+        assert!((s - (1.0 + (-0.101f64).exp())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn output_matches_quant_directory_layout() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(td.path().join("genes.gtf"), GTF).expect("failed to write gtf");
+        fs::write(td.path().join("frags.bed"), "chr1\t1500\t1600\tAAAA\t1\n")
+            .expect("failed to write fragments");
+        let cells = td.path().join("cells.txt");
+        fs::write(&cells, "AAAA\nGGGG\n").expect("failed to write cells");
+        let mut o = opts(td.path(), GeneActivityMethod::GeneBody);
+        o.cells = Some(cells);
+        gene_activity(&o).expect("gene activity");
+
+        let (mtx, rows, cols) = mtx_utils::quant_mat_paths(&o.output);
+        assert_eq!(
+            mtx_utils::mtx_row_sums(&mtx).expect("row sums"),
+            vec![1.0, 0.0]
+        );
+        assert_eq!(fs::read_to_string(rows).expect("rows"), "AAAA\nGGGG\n");
+        assert_eq!(fs::read_to_string(cols).expect("cols"), "G1\nG2\n");
+        assert_eq!(
+            fs::read_to_string(o.output.join("gene_id_to_name.tsv")).expect("g2n"),
+            "G1\tAlpha\nG2\tG2\n"
+        );
+        assert!(o.output.join("quant.json").is_file());
+        // only written for the h5ad conversion
+        for f in ["collate.json", "generate_permit_list.json"] {
+            assert!(!o.output.join(f).exists());
+        }
+
+        let dump = fs::read_to_string(o.output.join("featureDump.txt")).expect("feature dump");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("CB\tCorrectedReads\tMappedReads"));
+        assert!(lines[1].starts_with("AAAA\t1\t1\t1.000000\t1.000000\t0\t"));
+        assert!(lines[2].starts_with("GGGG\t0\t0\t0.000000\t0.000000\t0\t"));
+    }
+
+    #[test]
+    fn anndata_output_is_written() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        fs::write(td.path().join("genes.gtf"), GTF).expect("failed to write gtf");
+        fs::write(
+            td.path().join("frags.bed"),
+            "chr1\t1500\t1600\tAAAA\t1\nchr1\t5500\t5600\tCCCC\t1\n",
+        )
+        .expect("failed to write fragments");
+        let mut o = opts(td.path(), GeneActivityMethod::DistanceWeighted);
+        o.anndata_out = true;
+        gene_activity(&o).expect("gene activity with --anndata-out");
+        assert!(o.output.join("alevin").join("quants.h5ad").is_file());
+    }
+}
@@ -1,13 +1,17 @@
-use crate::atac::commands::ProcessOpts;
+use crate::atac::cell_calling;
+use crate::atac::commands::{AtacStage, ProcessOpts};
 use crate::core::{context, exec, index_meta, io, runtime};
+use crate::utils::af_utils;
+use crate::utils::chem_registry::ChemistryRegistry;
 use crate::utils::chem_utils::ExpectedOri;
 use crate::utils::chem_utils::QueryInRegistry;
-use crate::utils::chem_utils::get_single_custom_chem_from_file;
 use crate::utils::constants::CHEMISTRIES_PATH;
 use crate::utils::{prog_utils, prog_utils::ReqProgs};
 use anyhow;
 use anyhow::{Context, bail};
+use seq_geom_parser::{GeoLen, GeoTagType};
 use serde_json::json;
+use std::collections::{BTreeSet, HashSet};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
@@ -29,6 +33,11 @@ struct SortStageOutput {
     sort_cmd: String,
 }
 
+struct CellCallStageOutput {
+    cell_call_duration_secs: f64,
+    num_cells: usize,
+}
+
 struct MacsStageOutput {
     macs_duration_secs: f64,
     macs_cmd: String,
@@ -150,6 +159,66 @@ fn add_read_args(map_cmd: &mut std::process::Command, opts: &ProcessOpts) -> any
     Ok(())
 }
 
+/// Resolve the chemistry requested with `--chemistry` against the builtin ATAC
+/// chemistries and the chemistry registry.
+pub(crate) fn resolve_chemistry(
+    af_home_path: &Path,
+    opts: &ProcessOpts,
+) -> anyhow::Result<af_utils::Chemistry> {
+    let custom_chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    af_utils::Chemistry::from_atac_str(&custom_chem_p, &opts.chemistry)
+}
+
+/// The length of the cell barcode in the barcode read. For custom chemistries this
+/// is taken from the registered geometry, which must describe a single fixed-length
+/// barcode (`b[N]`) at the start of its read; otherwise `--barcode-length` is used.
+fn barcode_length(chem: &af_utils::Chemistry, opts: &ProcessOpts) -> anyhow::Result<u32> {
+    let af_utils::Chemistry::Custom(cc) = chem else {
+        return Ok(opts.barcode_length);
+    };
+    let geom = af_utils::extract_geometry(cc.geometry()).with_context(|| {
+        format!(
+            "could not parse the geometry of the ATAC chemistry {}",
+            cc.name()
+        )
+    })?;
+    let unsupported = || {
+        anyhow::anyhow!(
+            "The geometry {} of the ATAC chemistry {} is not supported; it must contain a single fixed-length barcode (b[N]) at the start of the barcode read, and no UMI or sample barcode.",
+            cc.geometry(),
+            cc.name()
+        )
+    };
+    let mut bc_len = None;
+    for read in [&geom.read1, &geom.read2] {
+        for (i, part) in read.parts.iter().enumerate() {
+            match (&part.tag, &part.len) {
+                (GeoTagType::Barcode, GeoLen::Fixed(l)) if i == 0 && bc_len.is_none() => {
+                    bc_len = Some(*l);
+                }
+                (
+                    GeoTagType::Barcode
+                    | GeoTagType::NumberedBarcode(_)
+                    | GeoTagType::SampleBarcode
+                    | GeoTagType::Umi,
+                    _,
+                ) => return Err(unsupported()),
+                _ => {}
+            }
+        }
+    }
+    let bc_len = bc_len.ok_or_else(unsupported)?;
+    if opts.barcode_length != bc_len {
+        info!(
+            "using the barcode length {} from the geometry of {} (rather than {})",
+            bc_len,
+            cc.name(),
+            opts.barcode_length
+        );
+    }
+    Ok(bc_len)
+}
+
 pub(crate) fn check_progs<P: AsRef<Path>>(
     af_home_path: P,
     opts: &ProcessOpts,
@@ -185,7 +254,7 @@ pub(crate) fn check_progs<P: AsRef<Path>>(
         Err(e) => return Err(e),
     }
 
-    if opts.call_peaks {
+    if selected_stages(opts)?.contains(&AtacStage::Peaks) {
         let macs_prog_info = rp
             .macs
             .as_ref()
@@ -205,19 +274,15 @@ pub(crate) fn check_progs<P: AsRef<Path>>(
     Ok(())
 }
 
-// NOTE: we assume that check_progs has already been called and so version constraints have
-// already been checked.
-pub(crate) fn map_reads(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<MapStageOutput> {
-    let rp: ReqProgs = context::load_required_programs(af_home_path)?;
-
-    let piscem_prog_info = rp
-        .piscem
-        .as_ref()
-        .context("piscem program info is missing; please run `simpleaf set-paths`.")?;
-
-    let index_base = index_meta::resolve_atac_piscem_index_base(opts.index.clone())?;
-    prog_utils::check_piscem_index_files(index_base.as_path())?;
-
+/// Build the `piscem map-sc-atac` command that maps the reads against the index
+/// at `index_base`, writing the RAD file to `map_output`.
+fn build_map_cmd(
+    piscem_prog_info: &prog_utils::ProgInfo,
+    index_base: &Path,
+    map_output: &Path,
+    opts: &ProcessOpts,
+    bc_len: u32,
+) -> anyhow::Result<std::process::Command> {
     // using a piscem index
     let mut piscem_map_cmd =
         std::process::Command::new(format!("{}", &piscem_prog_info.exe_path.display()));
@@ -233,6 +298,9 @@ pub(crate) fn map_reads(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Resu
         .arg("--bin-overlap")
         .arg(opts.bin_overlap.to_string());
 
+    // the barcode length of the chemistry (which may come from a custom geometry)
+    piscem_map_cmd.arg("--bclen").arg(bc_len.to_string());
+
     // if the user requested more threads than can be used
     let (threads, capped_at) = runtime::cap_threads(opts.threads);
     if let Some(max_threads) = capped_at {
@@ -244,12 +312,11 @@ pub(crate) fn map_reads(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Resu
     }
 
     // location of output directory, number of threads
-    let map_output = opts.output.join("af_map");
     piscem_map_cmd
         .arg("--threads")
         .arg(threads.to_string())
         .arg("-o")
-        .arg(&map_output);
+        .arg(map_output);
 
     // add either the paired-end or single-end read arguments
     add_read_args(&mut piscem_map_cmd, opts)?;
@@ -276,6 +343,30 @@ being used by simpleaf"#,
         }
     }
 
+    Ok(piscem_map_cmd)
+}
+
+// NOTE: we assume that check_progs has already been called and so version constraints have
+// already been checked.
+pub(crate) fn map_reads(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<MapStageOutput> {
+    let rp: ReqProgs = context::load_required_programs(af_home_path)?;
+
+    let piscem_prog_info = rp
+        .piscem
+        .as_ref()
+        .context("piscem program info is missing; please run `simpleaf set-paths`.")?;
+
+    let chem = resolve_chemistry(af_home_path, opts)?;
+    let bc_len = barcode_length(&chem, opts)?;
+    invalidate_stages(opts, AtacStage::Map)?;
+
+    let index_base = index_meta::resolve_atac_piscem_index_base(opts.index.clone())?;
+    prog_utils::check_piscem_index_files(index_base.as_path())?;
+
+    let map_output = opts.output.join("af_map");
+    let mut piscem_map_cmd =
+        build_map_cmd(piscem_prog_info, &index_base, &map_output, opts, bc_len)?;
+
     let map_cmd_string = prog_utils::get_cmd_line_string(&piscem_map_cmd);
     info!("map command : {}", map_cmd_string);
 
@@ -285,7 +376,7 @@ being used by simpleaf"#,
     info!("mapping completed successfully in {:#?}", map_duration);
 
     let af_process_info_file = opts.output.join("simpleaf_process_log.json");
-    let af_process_info = json!({
+    let mut af_process_info = json!({
         "time_info" : {
         "map_time" : map_duration.as_secs_f64(),
     },
@@ -295,9 +386,12 @@ being used by simpleaf"#,
         "map_info" : {
         "mapper" : "piscem",
         "map_cmd" : map_cmd_string,
-        "map_outdir": map_output
-    }
+        "map_outdir": map_output,
+        "chemistry": chem.as_str(),
+        "barcode_length": bc_len
+    },
     });
+    record_stage(&mut af_process_info, opts, AtacStage::Map);
 
     // write the relevant info about
     // our run to file.
@@ -320,8 +414,12 @@ fn macs_call_peaks(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<Ma
         .context("macs program info is missing; please run `simpleaf set-paths`.")?;
 
     let gpl_dir = opts.output.join("af_process");
-    let bedsuf = if opts.compress { ".bed.gz" } else { ".bed" };
-    let bed_input = gpl_dir.join(format!("map{}", bedsuf));
+    // call peaks on the fragments of the called cells if those were written
+    let bed_input = if opts.filter_fragments {
+        called_bed_path(opts)
+    } else {
+        sorted_bed_path(opts)
+    };
     let peaks_output = gpl_dir.join("macs");
     let mut macs_cmd =
         std::process::Command::new(format!("{}", &macs_prog_info.exe_path.display()));
@@ -345,6 +443,7 @@ fn macs_call_peaks(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<Ma
 
     let macs_cmd_string = prog_utils::get_cmd_line_string(&macs_cmd);
     info!("macs3 command : {}", macs_cmd_string);
+    invalidate_stages(opts, AtacStage::Peaks)?;
 
     let macs_start = Instant::now();
     exec::run_checked(&mut macs_cmd, "[atac::macs]")?;
@@ -365,6 +464,7 @@ fn macs_call_peaks(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<Ma
 
     af_process_info["time_info"]["macs_time"] = json!(macs_duration.as_secs_f64());
     af_process_info["cmd_info"]["macs_cmd"] = json!(macs_cmd_string);
+    record_stage(&mut af_process_info, opts, AtacStage::Peaks);
 
     // write the relevant info about
     // our run to file.
@@ -378,21 +478,436 @@ fn macs_call_peaks(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<Ma
     })
 }
 
-pub(crate) fn gen_bed(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<()> {
+/// The sorted and deduplicated BED file written by `alevin-fry atac sort`.
+pub(crate) fn sorted_bed_path(opts: &ProcessOpts) -> PathBuf {
+    let bedsuf = if opts.compress { ".bed.gz" } else { ".bed" };
+    opts.output
+        .join("af_process")
+        .join(format!("map{}", bedsuf))
+}
+
+/// The BED file restricted to called cells (written with `--filter-fragments`).
+fn called_bed_path(opts: &ProcessOpts) -> PathBuf {
+    let bedsuf = if opts.compress { ".bed.gz" } else { ".bed" };
+    opts.output
+        .join("af_process")
+        .join(format!("map.cells{}", bedsuf))
+}
+
+/// The cell calling method requested on the command line, if any.
+fn cell_filter_method(opts: &ProcessOpts) -> Option<af_utils::CellFilterMethod> {
+    if opts.knee {
+        Some(af_utils::CellFilterMethod::KneeFinding)
+    } else if let Some(n) = opts.expect_cells {
+        Some(af_utils::CellFilterMethod::ExpectCells(n))
+    } else {
+        opts.forced_cells
+            .map(af_utils::CellFilterMethod::ForceCells)
+    }
+}
+
+/// Call cells from the sorted BED file, writing the called barcodes
+/// (`cells.txt`) and per-barcode metrics (`barcode_metrics.tsv`) to the
+/// `af_process` directory, and optionally the fragments of the called cells.
+fn call_cells(
+    opts: &ProcessOpts,
+    method: &af_utils::CellFilterMethod,
+) -> anyhow::Result<CellCallStageOutput> {
+    let gpl_dir = opts.output.join("af_process");
+    let bed = sorted_bed_path(opts);
+
+    invalidate_stages(opts, AtacStage::Cells)?;
+    let start = Instant::now();
+    let tss = match &opts.tss {
+        Some(p) => {
+            info!("reading TSS annotation from {}", p.display());
+            Some(cell_calling::read_tss_file(p)?)
+        }
+        None => None,
+    };
+    let metrics = cell_calling::collect_barcode_metrics(&bed, tss.as_ref())?;
+    let min_tsse = tss.as_ref().map(|_| opts.min_tss_enrichment);
+    let (cells, summary) = cell_calling::call_cells(&metrics, method, min_tsse)?;
+    info!(
+        "called {} cells out of {} barcodes (method: {}).",
+        summary.num_cells, summary.num_barcodes, summary.method
+    );
+
+    let cells_file = gpl_dir.join("cells.txt");
+    cell_calling::write_cell_barcodes(&cells, &cells_file)?;
+    let cell_set: HashSet<&str> = cells.iter().map(|c| c.as_str()).collect();
+    let metrics_file = gpl_dir.join("barcode_metrics.tsv");
+    cell_calling::write_barcode_metrics(&metrics, &cell_set, tss.is_some(), &metrics_file)?;
+
+    let called_bed = if opts.filter_fragments {
+        let out = called_bed_path(opts);
+        cell_calling::filter_fragments(&bed, &cell_set, &out, opts.compress)?;
+        Some(out)
+    } else {
+        None
+    };
+    let duration = start.elapsed();
+    info!("cell calling completed successfully in {:#?}", duration);
+
+    let af_process_info_file = opts.output.join("simpleaf_process_log.json");
+    let mut af_process_info = io::read_json_file(&af_process_info_file)?;
+    af_process_info["time_info"]["cell_calling_time"] = json!(duration.as_secs_f64());
+    af_process_info["cell_calling_info"] = json!({
+        "summary": summary,
+        "cells_file": cells_file,
+        "metrics_file": metrics_file,
+        "tss_file": opts.tss,
+        "called_bed": called_bed,
+    });
+    record_stage(&mut af_process_info, opts, AtacStage::Cells);
+    io::write_json_pretty_atomic(&af_process_info_file, &af_process_info)?;
+
+    Ok(CellCallStageOutput {
+        cell_call_duration_secs: duration.as_secs_f64(),
+        num_cells: summary.num_cells,
+    })
+}
+
+/// Generate the permit list, then sort and deduplicate the mapped records into
+/// the output BED file, calling cells if a cell calling method was requested.
+pub(crate) fn gen_sorted_bed(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<()> {
     let gpl = af_gpl(af_home_path, opts)?;
     let sort = af_sort(af_home_path, opts)?;
-    let macs = macs_call_peaks(af_home_path, opts)?;
+    if let Some(method) = cell_filter_method(opts) {
+        let cc = call_cells(opts, &method)?;
+        info!(
+            "ATAC cell calling retained {} cells ({:.2}s).",
+            cc.num_cells, cc.cell_call_duration_secs
+        );
+    }
     info!(
-        "ATAC downstream stages completed (gpl: {:.2}s, sort: {:.2}s, macs: {:.2}s).",
-        gpl.gpl_duration_secs, sort.sort_duration_secs, macs.macs_duration_secs
+        "ATAC BED generation completed (gpl: {:.2}s, sort: {:.2}s).",
+        gpl.gpl_duration_secs, sort.sort_duration_secs
     );
     info!(
-        "ATAC commands: gpl=`{}`, sort=`{}`, macs=`{}`",
-        gpl.gpl_cmd, sort.sort_cmd, macs.macs_cmd
+        "ATAC commands: gpl=`{}`, sort=`{}`",
+        gpl.gpl_cmd, sort.sort_cmd
     );
     Ok(())
 }
 
+/// The process log shared by every stage.
+fn process_log_path(opts: &ProcessOpts) -> PathBuf {
+    opts.output.join("simpleaf_process_log.json")
+}
+
+/// The stages making up a full run with the given options: cell calling only
+/// runs if a cell calling method was requested, and peak calling only with
+/// `--call-peaks`.
+fn pipeline_stages(opts: &ProcessOpts) -> Vec<AtacStage> {
+    let mut stages = vec![AtacStage::Map, AtacStage::Gpl, AtacStage::Sort];
+    if cell_filter_method(opts).is_some() {
+        stages.push(AtacStage::Cells);
+    }
+    if opts.call_peaks {
+        stages.push(AtacStage::Peaks);
+    }
+    stages
+}
+
+/// The stages to run, in order: those listed with `--stages`, or the stages
+/// of the full run between `--start-from` and `--stop-after`.
+pub(crate) fn selected_stages(opts: &ProcessOpts) -> anyhow::Result<Vec<AtacStage>> {
+    let pipeline = pipeline_stages(opts);
+    if let Some(stages) = &opts.stages {
+        let selected: BTreeSet<AtacStage> = stages.iter().copied().collect();
+        if selected.contains(&AtacStage::Cells) && cell_filter_method(opts).is_none() {
+            bail!(
+                "the `cells` stage requires a cell calling method (--knee, --expect-cells or --forced-cells)."
+            );
+        }
+        return Ok(selected.into_iter().collect());
+    }
+
+    for (flag, stage) in [
+        ("--start-from", opts.start_from),
+        ("--stop-after", opts.stop_after),
+    ] {
+        if let Some(stage) = stage
+            && !pipeline.contains(&stage)
+        {
+            bail!(
+                "{} {} was given, but the `{}` stage is not part of this run; {}.",
+                flag,
+                stage,
+                stage,
+                if stage == AtacStage::Peaks {
+                    "pass --call-peaks to call peaks"
+                } else {
+                    "pass --knee, --expect-cells or --forced-cells to call cells"
+                }
+            );
+        }
+    }
+    let first = opts.start_from.unwrap_or(AtacStage::Map);
+    let last = opts.stop_after.unwrap_or(AtacStage::Peaks);
+    if first > last {
+        bail!(
+            "--start-from {} comes after --stop-after {}; no stage would be run.",
+            first,
+            last
+        );
+    }
+    Ok(pipeline
+        .into_iter()
+        .filter(|s| *s >= first && *s <= last)
+        .collect())
+}
+
+/// The files that must exist for the outputs of `stage` to be reused.
+fn stage_outputs(opts: &ProcessOpts, stage: AtacStage) -> Vec<PathBuf> {
+    let gpl_dir = opts.output.join("af_process");
+    match stage {
+        AtacStage::Map => vec![opts.output.join("af_map").join("map.rad")],
+        AtacStage::Gpl => vec![gpl_dir.join("generate_permit_list.json")],
+        AtacStage::Sort => vec![sorted_bed_path(opts)],
+        AtacStage::Cells => {
+            let mut outs = vec![gpl_dir.join("cells.txt")];
+            if opts.filter_fragments {
+                outs.push(called_bed_path(opts));
+            }
+            outs
+        }
+        AtacStage::Peaks => vec![gpl_dir.join("macs_peaks.narrowPeak")],
+    }
+}
+
+/// The options that determine the outputs of `stage`. They are recorded in the
+/// process log when the stage completes, and its outputs are only reused by a
+/// later run with the same options.
+fn stage_options(opts: &ProcessOpts, stage: AtacStage) -> serde_json::Value {
+    match stage {
+        AtacStage::Map => json!({
+            "index": opts.index,
+            "reads1": opts.reads1,
+            "reads2": opts.reads2,
+            "reads": opts.reads,
+            "barcode_reads": opts.barcode_reads,
+            "chemistry": opts.chemistry,
+            "barcode_length": opts.barcode_length,
+            "ignore_ambig_hits": opts.ignore_ambig_hits,
+            "no_poison": opts.no_poison,
+            "use_chr": opts.use_chr,
+            "bin_size": opts.bin_size,
+            "bin_overlap": opts.bin_overlap,
+            "no_tn5_shift": opts.no_tn5_shift,
+            "check_kmer_orphan": opts.check_kmer_orphan,
+            "max_ec_card": opts.max_ec_card,
+            "max_hit_occ": opts.max_hit_occ,
+            "max_hit_occ_recover": opts.max_hit_occ_recover,
+            "max_read_occ": opts.max_read_occ,
+        }),
+        AtacStage::Gpl => json!({
+            "chemistry": opts.chemistry,
+            "permit_barcode_ori": opts.permit_barcode_ori,
+            "unfiltered_pl": opts.unfiltered_pl,
+            "min_reads": opts.min_reads,
+        }),
+        AtacStage::Sort => json!({ "compress": opts.compress }),
+        AtacStage::Cells => json!({
+            "method": cell_filter_method(opts).map(|m| format!("{:?}", m)),
+            "tss": opts.tss,
+            "min_tss_enrichment": opts.min_tss_enrichment,
+            "filter_fragments": opts.filter_fragments,
+        }),
+        AtacStage::Peaks => json!({
+            "filter_fragments": opts.filter_fragments,
+            "gsize": opts.gsize.as_arg_str(),
+            "qvalue": opts.qvalue,
+            "extsize": opts.extsize,
+        }),
+    }
+}
+
+/// The stages recorded as completed in the process log.
+fn logged_stages(af_process_info: &serde_json::Value) -> Vec<AtacStage> {
+    af_process_info["completed_stages"]
+        .as_array()
+        .map(|stages| {
+            stages
+                .iter()
+                .filter_map(|s| s.as_str())
+                .filter_map(|s| <AtacStage as clap::ValueEnum>::from_str(s, false).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Mark `stage` as completed with the current options in the process log. The
+/// stages after it were computed from the previous outputs of `stage`, so they
+/// are no longer considered completed.
+fn record_stage(af_process_info: &mut serde_json::Value, opts: &ProcessOpts, stage: AtacStage) {
+    let mut stages: Vec<AtacStage> = logged_stages(af_process_info)
+        .into_iter()
+        .filter(|s| *s < stage)
+        .collect();
+    stages.push(stage);
+    af_process_info["completed_stages"] =
+        json!(stages.iter().map(|s| s.as_str()).collect::<Vec<_>>());
+    af_process_info["stage_options"][stage.as_str()] = stage_options(opts, stage);
+}
+
+/// Before `stage` (re)writes its outputs, forget that it and every later stage
+/// completed, so that an interrupted run is never mistaken for a complete one.
+fn invalidate_stages(opts: &ProcessOpts, stage: AtacStage) -> anyhow::Result<()> {
+    let log_file = process_log_path(opts);
+    if !log_file.is_file() {
+        return Ok(());
+    }
+    let mut af_process_info = io::read_json_file(&log_file)?;
+    let stages = logged_stages(&af_process_info);
+    if stages.iter().all(|s| *s < stage) {
+        return Ok(());
+    }
+    let kept: Vec<&str> = stages
+        .iter()
+        .filter(|s| **s < stage)
+        .map(|s| s.as_str())
+        .collect();
+    af_process_info["completed_stages"] = json!(kept);
+    io::write_json_pretty_atomic(&log_file, &af_process_info)
+}
+
+/// The stages of a previous run in the output directory whose outputs can be
+/// reused: they are recorded as completed with the current options, their
+/// outputs still exist, and so can the outputs of the stages before them in this
+/// run (which they were computed from).
+fn reusable_stages(opts: &ProcessOpts) -> anyhow::Result<BTreeSet<AtacStage>> {
+    let log_file = process_log_path(opts);
+    if !log_file.is_file() {
+        return Ok(BTreeSet::new());
+    }
+    let af_process_info = io::read_json_file(&log_file)?;
+    let logged = logged_stages(&af_process_info);
+    let mut reusable = BTreeSet::new();
+    for stage in pipeline_stages(opts) {
+        if !logged.contains(&stage) {
+            break;
+        }
+        if af_process_info["stage_options"][stage.as_str()] != stage_options(opts, stage) {
+            info!(
+                "the `{}` stage of the previous run used different options, so its outputs (and those of the later stages) will not be reused.",
+                stage
+            );
+            break;
+        }
+        if !stage_outputs(opts, stage).iter().all(|p| p.is_file()) {
+            break;
+        }
+        reusable.insert(stage);
+    }
+    Ok(reusable)
+}
+
+/// Check that every stage a selected stage depends upon is either run before
+/// it or can be reused from a previous run.
+fn check_stage_plan(
+    pipeline: &[AtacStage],
+    selected: &[AtacStage],
+    mut reusable: BTreeSet<AtacStage>,
+) -> anyhow::Result<()> {
+    let mut all: BTreeSet<AtacStage> = pipeline.iter().copied().collect();
+    all.extend(selected.iter().copied());
+    for stage in all {
+        if selected.contains(&stage) {
+            // running a stage invalidates the outputs of every later stage
+            reusable.retain(|s| *s < stage);
+            reusable.insert(stage);
+        } else if let Some(dependent) = selected.iter().find(|s| **s > stage)
+            && !reusable.contains(&stage)
+        {
+            bail!(
+                "the `{}` stage depends on the outputs of the `{}` stage, but no `{}` stage completed with the same options was found in the output directory; include it in the stages to run (e.g. --start-from {}).",
+                dependent,
+                stage,
+                stage,
+                stage
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_stage(af_home_path: &Path, opts: &ProcessOpts, stage: AtacStage) -> anyhow::Result<()> {
+    match stage {
+        AtacStage::Map => {
+            let map = map_reads(af_home_path, opts)?;
+            info!(
+                "ATAC map stage complete: output={} duration={:.2}s cmd={}",
+                map.map_output.display(),
+                map.map_duration_secs,
+                map.map_cmd
+            );
+        }
+        AtacStage::Gpl => {
+            let gpl = af_gpl(af_home_path, opts)?;
+            info!(
+                "ATAC permit list generation completed ({:.2}s): `{}`",
+                gpl.gpl_duration_secs, gpl.gpl_cmd
+            );
+        }
+        AtacStage::Sort => {
+            let sort = af_sort(af_home_path, opts)?;
+            info!(
+                "ATAC BED generation completed ({:.2}s): `{}`",
+                sort.sort_duration_secs, sort.sort_cmd
+            );
+        }
+        AtacStage::Cells => {
+            let method = cell_filter_method(opts)
+                .context("the `cells` stage requires a cell calling method.")?;
+            let cc = call_cells(opts, &method)?;
+            info!(
+                "ATAC cell calling retained {} cells ({:.2}s).",
+                cc.num_cells, cc.cell_call_duration_secs
+            );
+        }
+        AtacStage::Peaks => {
+            let macs = macs_call_peaks(af_home_path, opts)?;
+            info!(
+                "ATAC peak calling completed ({:.2}s): `{}`",
+                macs.macs_duration_secs, macs.macs_cmd
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run the selected stages of `simpleaf atac process`, reusing the outputs of
+/// the stages they depend upon from a previous run when those are not run.
+pub(crate) fn process(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<()> {
+    let selected = selected_stages(opts)?;
+    let reusable = reusable_stages(opts)?;
+    check_stage_plan(&pipeline_stages(opts), &selected, reusable.clone())?;
+
+    let skipped: Vec<&str> = reusable
+        .iter()
+        .filter(|s| selected.first().is_some_and(|first| *s < first))
+        .map(|s| s.as_str())
+        .collect();
+    if !skipped.is_empty() {
+        info!(
+            "reusing the outputs of the completed stages: {}",
+            skipped.join(", ")
+        );
+    }
+
+    for stage in selected {
+        run_stage(af_home_path, opts, stage).with_context(|| {
+            format!(
+                "the `{}` stage failed; after fixing the problem, rerun with `--start-from {}` to reuse the outputs of the completed stages",
+                stage, stage
+            )
+        })?;
+    }
+    Ok(())
+}
+
 // NOTE: we assume that check_progs has already been called and so version constraints have
 // already been checked.
 fn af_sort(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<SortStageOutput> {
@@ -431,6 +946,7 @@ fn af_sort(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<SortStageO
 
     let sort_cmd_string = prog_utils::get_cmd_line_string(&af_sort);
     info!("sort command : {}", sort_cmd_string);
+    invalidate_stages(opts, AtacStage::Sort)?;
 
     let af_sort_start = Instant::now();
     exec::run_checked(&mut af_sort, "[atac::af_sort]")?;
@@ -451,6 +967,7 @@ fn af_sort(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<SortStageO
 
     af_process_info["time_info"]["sort_time"] = json!(af_sort_duration.as_secs_f64());
     af_process_info["cmd_info"]["sort_cmd"] = json!(sort_cmd_string);
+    record_stage(&mut af_process_info, opts, AtacStage::Sort);
 
     // write the relevant info about
     // our run to file.
@@ -463,6 +980,72 @@ fn af_sort(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<SortStageO
     })
 }
 
+/// The orientation of the ATAC barcodes on the reads relative to the permit list
+/// (`fw` or `rc`), as passed to `alevin-fry atac generate-permit-list`. This is
+/// `--permit-barcode-ori` if given, and otherwise the `barcode_ori` recorded in
+/// the registry for the chemistry.
+pub(crate) fn permit_barcode_ori(
+    af_home_path: &Path,
+    opts: &ProcessOpts,
+    chem: &af_utils::Chemistry,
+) -> anyhow::Result<&'static str> {
+    let custom_chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let permit_bc_ori = if let Some(ori) = &opts.permit_barcode_ori {
+        info!("Using user-provided permitlist barcode orientation");
+        match ori {
+            ExpectedOri::Forward => "fw",
+            ExpectedOri::Reverse => "rc",
+            _ => "rc",
+        }
+    } else {
+        info!("Fetching permitlits barcode orientation from file");
+        let mut pbco = "rc";
+        // custom chemistries were read from the registry when they were resolved
+        let registry = ChemistryRegistry::layered(&custom_chem_p);
+        let chem_obj = match chem {
+            af_utils::Chemistry::Custom(cc) => Some(cc.as_ref().clone()),
+            _ if registry.exists() => registry.lookup(chem.registry_key())?.map(|(chem, _)| chem),
+            _ => {
+                warn!(
+                    "Couldn't find expected chemistry registry {} so can't check if barcodes should be reverse complemented.",
+                    custom_chem_p.display()
+                );
+                None
+            }
+        };
+        if let Some(chem_obj) = chem_obj {
+            if let Some(serde_json::Value::Object(meta_obj)) = chem_obj.meta() {
+                let fw_str = serde_json::Value::String(String::from("forward"));
+                let dir_str = meta_obj.get("barcode_ori").unwrap_or(&fw_str);
+                match dir_str.as_str() {
+                    Some("reverse") => {
+                        info!("\treverse-complement");
+                        pbco = "rc";
+                    }
+                    Some("forward") => {
+                        info!("\tforward");
+                        pbco = "fw";
+                    }
+                    Some(s) => {
+                        warn!("barcode_ori \"{}\" is unknown; assuming forward.", s);
+                    }
+                    None => {
+                        warn!(
+                            "couldn't interpret value associated with \"barcode_ori\" as a string; assuming forward."
+                        );
+                    }
+                }
+            } else {
+                warn!(
+                    "No meta field present for the chemistry so can't check if barcodes should be reverse complemented."
+                );
+            }
+        }
+        pbco
+    };
+    Ok(permit_bc_ori)
+}
+
 // NOTE: we assume that check_progs has already been called and so version constraints have
 // already been checked.
 fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOutput> {
@@ -473,9 +1056,9 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
         .as_ref()
         .context("alevin-fry program info is missing; please run `simpleaf set-paths`.")?;
 
+    let chem = resolve_chemistry(af_home_path, opts)?;
     let filter_meth_opt;
 
-    use crate::utils::af_utils;
     // based on the filtering method
     let pl_file = &opts.unfiltered_pl;
     {
@@ -506,8 +1089,7 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
             // using 10xv2, 10xv3, or 10x-multi
 
             // check the chemistry
-            let rc = af_utils::Chemistry::Atac(opts.chemistry);
-            let pl_res = af_utils::get_permit_if_absent(af_home_path, &rc)?;
+            let pl_res = af_utils::get_permit_if_absent(af_home_path, &chem)?;
             let min_cells = opts.min_reads;
             match pl_res {
                 af_utils::PermitListResult::DownloadSuccessful(p)
@@ -520,7 +1102,7 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
                 af_utils::PermitListResult::UnregisteredChemistry => {
                     bail!(
                         "Cannot automatically obtain an unfiltered permit list for an unregistered chemistry : {}.",
-                        opts.chemistry.as_str()
+                        chem.as_str()
                     );
                 }
             }
@@ -532,56 +1114,7 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
         );
     }*/
 
-    // see if we need to reverse complement barcodes
-    let custom_chem_p = af_home_path.join(CHEMISTRIES_PATH);
-    let permit_bc_ori = if let Some(ori) = &opts.permit_barcode_ori {
-        info!("Using user-provided permitlist barcode orientation");
-        match ori {
-            ExpectedOri::Forward => "fw",
-            ExpectedOri::Reverse => "rc",
-            _ => "rc",
-        }
-    } else {
-        info!("Fetching permitlits barcode orientation from file");
-        let mut pbco = "rc";
-        if custom_chem_p.is_file() {
-            let chem_key = opts.chemistry.registry_key();
-            if let Some(chem_obj) = get_single_custom_chem_from_file(&custom_chem_p, chem_key)? {
-                if let Some(serde_json::Value::Object(meta_obj)) = chem_obj.meta() {
-                    let fw_str = serde_json::Value::String(String::from("forward"));
-                    let dir_str = meta_obj.get("barcode_ori").unwrap_or(&fw_str);
-                    match dir_str.as_str() {
-                        Some("reverse") => {
-                            info!("\treverse-complement");
-                            pbco = "rc";
-                        }
-                        Some("forward") => {
-                            info!("\tforward");
-                            pbco = "fw";
-                        }
-                        Some(s) => {
-                            warn!("barcode_ori \"{}\" is unknown; assuming forward.", s);
-                        }
-                        None => {
-                            warn!(
-                                "couldn't interpret value associated with \"barcode_ori\" as a string; assuming forward."
-                            );
-                        }
-                    }
-                } else {
-                    warn!(
-                        "No meta field present for the chemistry so can't check if barcodes should be reverse complemented."
-                    );
-                }
-            }
-        } else {
-            warn!(
-                "Couldn't find expected chemistry registry {} so can't check if barcodes should be reverse complemented.",
-                custom_chem_p.display()
-            );
-        }
-        pbco
-    };
+    let permit_bc_ori = permit_barcode_ori(af_home_path, opts, &chem)?;
 
     let map_file = opts.output.join("af_map");
     let mut af_gpl = std::process::Command::new(format!("{}", &af_prog_info.exe_path.display()));
@@ -621,6 +1154,7 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
 
     let gpl_cmd_string = prog_utils::get_cmd_line_string(&af_gpl);
     info!("gpl command : {}", gpl_cmd_string);
+    invalidate_stages(opts, AtacStage::Gpl)?;
 
     let af_gpl_start = Instant::now();
     exec::run_checked(&mut af_gpl, "[atac::af_gpl]")?;
@@ -644,6 +1178,7 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
 
     af_process_info["time_info"]["gpl_time"] = json!(af_gpl_duration.as_secs_f64());
     af_process_info["cmd_info"]["gpl_cmd"] = json!(gpl_cmd_string);
+    record_stage(&mut af_process_info, opts, AtacStage::Gpl);
 
     // write the relevant info about
     // our run to file.
@@ -660,7 +1195,8 @@ fn af_gpl(af_home_path: &Path, opts: &ProcessOpts) -> anyhow::Result<GplStageOut
 mod tests {
     use std::fs;
 
-    use crate::atac::commands::{AtacChemistry, Macs3GenomeSize};
+    use crate::atac::commands::Macs3GenomeSize;
+    use crate::utils::chem_utils::CustomChemistry;
 
     use super::*;
 
@@ -671,14 +1207,23 @@ mod tests {
             reads2: None,
             reads: None,
             barcode_reads: vec![],
-            chemistry: AtacChemistry::TenxV2,
+            chemistry: String::from("10x-v2"),
             barcode_length: 16,
             output: PathBuf::from("/tmp/out"),
             threads: 1,
             call_peaks: false,
+            stages: None,
+            start_from: None,
+            stop_after: None,
             permit_barcode_ori: None,
             unfiltered_pl: None,
             min_reads: 10,
+            knee: false,
+            expect_cells: None,
+            forced_cells: None,
+            tss: None,
+            min_tss_enrichment: 4.0,
+            filter_fragments: false,
             compress: false,
             ignore_ambig_hits: false,
             no_poison: false,
@@ -698,6 +1243,193 @@ mod tests {
         }
     }
 
+    #[test]
+    fn selected_stages_honor_call_peaks_and_stage_range() {
+        let mut opts = base_process_opts();
+        assert_eq!(
+            selected_stages(&opts).expect("default stages"),
+            vec![AtacStage::Map, AtacStage::Gpl, AtacStage::Sort]
+        );
+
+        opts.call_peaks = true;
+        opts.start_from = Some(AtacStage::Peaks);
+        assert_eq!(
+            selected_stages(&opts).expect("peaks only"),
+            vec![AtacStage::Peaks]
+        );
+
+        opts.call_peaks = false;
+        let err = selected_stages(&opts).expect_err("peaks are not part of the run");
+        assert!(err.to_string().contains("--call-peaks"));
+
+        opts.start_from = Some(AtacStage::Sort);
+        opts.stop_after = Some(AtacStage::Gpl);
+        assert!(selected_stages(&opts).is_err());
+
+        opts.start_from = None;
+        opts.stop_after = None;
+        opts.stages = Some(vec![AtacStage::Sort, AtacStage::Gpl, AtacStage::Sort]);
+        assert_eq!(
+            selected_stages(&opts).expect("explicit stages"),
+            vec![AtacStage::Gpl, AtacStage::Sort]
+        );
+        opts.stages = Some(vec![AtacStage::Cells]);
+        assert!(selected_stages(&opts).is_err());
+    }
+
+    #[test]
+    fn stage_plan_requires_completed_dependencies() {
+        let pipeline = [
+            AtacStage::Map,
+            AtacStage::Gpl,
+            AtacStage::Sort,
+            AtacStage::Peaks,
+        ];
+        let done: BTreeSet<AtacStage> = [AtacStage::Map, AtacStage::Gpl, AtacStage::Sort].into();
+        check_stage_plan(&pipeline, &[AtacStage::Peaks], done.clone())
+            .expect("peaks can reuse the sorted BED");
+        check_stage_plan(&pipeline, &[AtacStage::Map], BTreeSet::new())
+            .expect("nothing to reuse when starting from scratch");
+
+        let err = check_stage_plan(&pipeline, &[AtacStage::Sort], [AtacStage::Map].into())
+            .expect_err("gpl never completed");
+        assert!(err.to_string().contains("`gpl`"));
+
+        // rerunning mapping invalidates the permit list of the previous run
+        assert!(check_stage_plan(&pipeline, &[AtacStage::Map, AtacStage::Sort], done).is_err());
+    }
+
+    #[test]
+    fn completed_stages_are_recorded_and_invalidated() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let mut opts = base_process_opts();
+        opts.output = td.path().to_path_buf();
+        let mut info = json!({ "completed_stages": ["map", "gpl", "sort", "peaks"] });
+        record_stage(&mut info, &opts, AtacStage::Gpl);
+        assert_eq!(info["completed_stages"], json!(["map", "gpl"]));
+
+        let mut info = json!({});
+        for stage in [AtacStage::Map, AtacStage::Gpl, AtacStage::Sort] {
+            record_stage(&mut info, &opts, stage);
+        }
+        io::write_json_pretty_atomic(&process_log_path(&opts), &info).expect("write log");
+        fs::create_dir_all(td.path().join("af_map")).expect("create af_map");
+        fs::write(td.path().join("af_map").join("map.rad"), b"").expect("write rad");
+        // only map left its outputs behind
+        assert_eq!(
+            reusable_stages(&opts).expect("reusable stages"),
+            [AtacStage::Map].into()
+        );
+
+        invalidate_stages(&opts, AtacStage::Sort).expect("invalidate");
+        let info = io::read_json_file(&process_log_path(&opts)).expect("read log");
+        assert_eq!(info["completed_stages"], json!(["map", "gpl"]));
+    }
+
+    #[test]
+    fn stages_run_with_other_options_are_not_reused() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let mut opts = base_process_opts();
+        opts.output = td.path().to_path_buf();
+        opts.knee = true;
+        let mut info = json!({});
+        for stage in pipeline_stages(&opts) {
+            record_stage(&mut info, &opts, stage);
+            for p in stage_outputs(&opts, stage) {
+                fs::create_dir_all(p.parent().expect("parent")).expect("create dir");
+                fs::write(p, b"").expect("write output");
+            }
+        }
+        io::write_json_pretty_atomic(&process_log_path(&opts), &info).expect("write log");
+        assert_eq!(
+            reusable_stages(&opts).expect("reusable stages"),
+            pipeline_stages(&opts).into_iter().collect()
+        );
+
+        // other cell calling options only invalidate the cells stage
+        let mut other_cells = base_process_opts();
+        other_cells.output = td.path().to_path_buf();
+        other_cells.expect_cells = Some(1000);
+        assert_eq!(
+            reusable_stages(&other_cells).expect("reusable stages"),
+            [AtacStage::Map, AtacStage::Gpl, AtacStage::Sort].into()
+        );
+
+        // another chemistry invalidates the mapping and everything after it
+        opts.chemistry = String::from("10x-multi");
+        assert!(reusable_stages(&opts).expect("reusable stages").is_empty());
+    }
+
+    #[test]
+    fn cell_filter_method_follows_requested_mode() {
+        let mut opts = base_process_opts();
+        assert!(cell_filter_method(&opts).is_none());
+        opts.expect_cells = Some(3000);
+        assert!(matches!(
+            cell_filter_method(&opts),
+            Some(af_utils::CellFilterMethod::ExpectCells(3000))
+        ));
+        opts.expect_cells = None;
+        opts.knee = true;
+        assert!(matches!(
+            cell_filter_method(&opts),
+            Some(af_utils::CellFilterMethod::KneeFinding)
+        ));
+    }
+
+    #[test]
+    fn barcode_length_comes_from_custom_geometry() {
+        let opts = base_process_opts();
+        let builtin = af_utils::Chemistry::Atac(crate::atac::commands::AtacChemistry::TenxV2);
+        assert_eq!(barcode_length(&builtin, &opts).expect("builtin"), 16);
+
+        let custom = |geo: &str| {
+            af_utils::Chemistry::Custom(Box::new(
+                CustomChemistry::simple_custom(geo).expect("valid geometry"),
+            ))
+        };
+        assert_eq!(
+            barcode_length(&custom("1{b[20]}2{r:}"), &opts).expect("custom"),
+            20
+        );
+        assert!(barcode_length(&custom("1{x[4]b[16]}2{r:}"), &opts).is_err());
+        assert!(barcode_length(&custom("1{b[16]u[12]}2{r:}"), &opts).is_err());
+    }
+
+    #[test]
+    fn map_cmd_carries_the_barcode_length() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let reads = td.path().join("reads.fastq");
+        let bc = td.path().join("bc.fastq");
+        fs::write(&reads, "").expect("failed to write reads");
+        fs::write(&bc, "").expect("failed to write bc");
+
+        let mut opts = base_process_opts();
+        opts.reads = Some(vec![reads]);
+        opts.barcode_reads = vec![bc];
+        let piscem = prog_utils::ProgInfo {
+            exe_path: PathBuf::from("piscem"),
+            version: String::from("0.18.0"),
+        };
+        let cmd = build_map_cmd(
+            &piscem,
+            Path::new("/tmp/index"),
+            &td.path().join("af_map"),
+            &opts,
+            20,
+        )
+        .expect("build map command");
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let pos = args
+            .iter()
+            .position(|a| a == "--bclen")
+            .expect("--bclen must be passed to piscem");
+        assert_eq!(args[pos + 1], "20");
+    }
+
     #[test]
     fn add_read_args_succeeds_for_paired_end_inputs() {
         let td = tempfile::tempdir().expect("failed to create tempdir");
@@ -1,9 +1,10 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use serde::Serialize;
 use serde_json::Value;
+use tracing::info;
 
 pub fn read_json_file(path: &Path) -> anyhow::Result<Value> {
     let json_file = std::fs::File::open(path)
@@ -22,24 +23,122 @@ pub fn write_json_pretty<T: Serialize>(path: &Path, value: &T) -> anyhow::Result
 pub fn write_json_pretty_atomic<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
     let payload = serde_json::to_string_pretty(value)
         .with_context(|| format!("Could not serialize JSON for {}.", path.display()))?;
-    let tmp_path = path.with_extension("tmp");
-    fs::write(&tmp_path, payload)
-        .with_context(|| format!("could not write temporary file {}", tmp_path.display()))?;
-    fs::rename(&tmp_path, path).with_context(|| {
+    let tmp = temp_file_for(path)?;
+    fs::write(tmp.path(), payload)
+        .with_context(|| format!("could not write temporary file {}", tmp.path().display()))?;
+    persist(tmp, path)
+}
+
+/// A temporary file next to `path`, named `.<file name>.<random>.part`, to be
+/// [`persist`]ed as `path` once it is complete. It is removed if dropped before.
+/// Being in the same directory, it can be renamed atomically, and being hidden, it is
+/// ignored by the commands that list the cache directories.
+pub fn temp_file_for(path: &Path) -> anyhow::Result<tempfile::NamedTempFile> {
+    let dir = parent_dir(path);
+    tempfile::Builder::new()
+        .prefix(&format!(".{}.", file_name(path)))
+        .suffix(".part")
+        .tempfile_in(dir)
+        .with_context(|| format!("could not create a temporary file in {}", dir.display()))
+}
+
+/// Atomically move the complete temporary file `tmp` to `path`, replacing any
+/// existing file.
+pub fn persist(tmp: tempfile::NamedTempFile, path: &Path) -> anyhow::Result<()> {
+    tmp.persist(path).map(|_| ()).with_context(|| {
         format!(
-            "could not atomically rename {} to {}",
-            tmp_path.display(),
+            "could not atomically rename the temporary file to {}",
             path.display()
         )
     })
 }
 
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The path of the lock file guarding `path`: the hidden `.<file name>.lock` next to it.
+pub fn lock_path_for(path: &Path) -> PathBuf {
+    parent_dir(path).join(format!(".{}.lock", file_name(path)))
+}
+
+/// An exclusive advisory lock on a lock file, held until it is dropped.
+///
+/// Processes sharing `ALEVIN_FRY_HOME` (e.g. the jobs of a job array) take it before
+/// creating or rewriting shared state, so that only one of them does, while the others
+/// wait and then reuse the result. The lock file itself is left in place, as removing
+/// it could let two processes hold locks on different files of the same name.
+#[derive(Debug)]
+pub struct FileLock {
+    file: fs::File,
+}
+
+impl FileLock {
+    /// Lock the lock file `lock_path`, creating it if needed, and waiting for the
+    /// process holding it, if any.
+    pub fn acquire(lock_path: &Path) -> anyhow::Result<FileLock> {
+        fs::create_dir_all(parent_dir(lock_path)).with_context(|| {
+            format!(
+                "could not create the directory of the lock file {}",
+                lock_path.display()
+            )
+        })?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)
+            .with_context(|| format!("could not open the lock file {}", lock_path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                info!(
+                    "Waiting for another process holding the lock {}",
+                    lock_path.display()
+                );
+                file.lock()
+                    .with_context(|| format!("could not lock {}", lock_path.display()))?;
+            }
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("could not lock {}", lock_path.display()));
+            }
+        }
+        Ok(FileLock { file })
+    }
+
+    /// Lock the file `path` itself, through the lock file [`lock_path_for`] it.
+    pub fn for_file(path: &Path) -> anyhow::Result<FileLock> {
+        FileLock::acquire(&lock_path_for(path))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
     use tempfile::tempdir;
 
-    use super::{read_json_file, write_json_pretty, write_json_pretty_atomic};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{
+        FileLock, lock_path_for, read_json_file, temp_file_for, write_json_pretty,
+        write_json_pretty_atomic,
+    };
 
     #[test]
     fn write_and_read_json_roundtrip() {
@@ -60,4 +159,42 @@ mod tests {
         let read_back = read_json_file(&path).expect("failed to read json");
         assert_eq!(read_back, v);
     }
+
+    #[test]
+    fn file_lock_waits_for_the_holder() {
+        let td = tempdir().expect("failed to create tempdir");
+        let path = td.path().join("c.json");
+        assert_eq!(lock_path_for(&path), td.path().join(".c.json.lock"));
+
+        let held = FileLock::for_file(&path).expect("failed to lock");
+        let acquired = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (path, acquired) = (path.clone(), Arc::clone(&acquired));
+            std::thread::spawn(move || {
+                let _lock = FileLock::for_file(&path).expect("failed to lock");
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(!acquired.load(Ordering::SeqCst));
+        drop(held);
+        waiter.join().expect("waiter panicked");
+        assert!(acquired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn temp_files_are_hidden_and_removed_unless_persisted() {
+        let td = tempdir().expect("failed to create tempdir");
+        let path = td.path().join("d.txt");
+        let tmp = temp_file_for(&path).expect("failed to create temp file");
+        let name = tmp
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        assert!(name.starts_with(".d.txt.") && name.ends_with(".part"));
+        drop(tmp);
+        assert_eq!(std::fs::read_dir(td.path()).unwrap().count(), 0);
+    }
 }
@@ -1,8 +1,9 @@
 use chemistry::{
-    add_chemistry, clean_chemistries, fetch_chemistries, lookup_chemistry, refresh_chemistries,
-    remove_chemistry,
+    add_chemistry, add_probe_set, alias_chemistry, clean_chemistries, export_chemistries,
+    fetch_chemistries, import_chemistries, lookup_chemistry, refresh_chemistries, remove_chemistry,
+    remove_probe_set, rollback_chemistries, set_sample_bc_list, set_translation_list, show_history,
+    validate_chemistries,
 };
-use tracing::info;
 use tracing_subscriber::{EnvFilter, filter::LevelFilter, fmt, prelude::*};
 
 use anyhow::bail;
@@ -84,12 +85,50 @@ fn main() -> anyhow::Result<()> {
         Commands::Chemistry(ChemistryCommand::Lookup(lookup_opts)) => {
             lookup_chemistry(af_home_path, lookup_opts)
         }
+        Commands::Chemistry(ChemistryCommand::Alias(alias_opts)) => {
+            alias_chemistry(af_home_path, alias_opts)
+        }
         Commands::Chemistry(ChemistryCommand::Refresh(refresh_opts)) => {
             refresh_chemistries(af_home_path, refresh_opts)
         }
         Commands::Chemistry(ChemistryCommand::Fetch(fetch_opts)) => {
             fetch_chemistries(af_home_path, fetch_opts)
         }
+        Commands::Chemistry(ChemistryCommand::ProbeSet(ProbeSetCommand::Add(add_opts))) => {
+            add_probe_set(af_home_path, add_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::ProbeSet(ProbeSetCommand::Remove(rem_opts))) => {
+            remove_probe_set(af_home_path, rem_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::SampleBc(SampleBcCommand::Set(set_opts))) => {
+            set_sample_bc_list(af_home_path, set_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::TranslationList(TranslationListCommand::Set(
+            set_opts,
+        ))) => set_translation_list(af_home_path, set_opts),
+        Commands::Chemistry(ChemistryCommand::Validate(validate_opts)) => {
+            validate_chemistries(af_home_path, validate_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::Export(export_opts)) => {
+            export_chemistries(af_home_path, export_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::Import(import_opts)) => {
+            import_chemistries(af_home_path, import_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::History(history_opts)) => {
+            show_history(af_home_path, history_opts)
+        }
+        Commands::Chemistry(ChemistryCommand::Rollback(rollback_opts)) => {
+            rollback_chemistries(af_home_path, rollback_opts)
+        }
+        // Inspect or prune the artifacts cached under ALEVIN_FRY_HOME
+        Commands::Cache(CacheCommand::List {}) => cache::list_cache(af_home_path),
+        Commands::Cache(CacheCommand::Verify {}) => cache::verify_cache(af_home_path),
+        Commands::Cache(CacheCommand::Clean(clean_opts)) => {
+            cache::clean_cache(af_home_path, clean_opts)
+        }
+        Commands::Cache(CacheCommand::Size {}) => cache::cache_size(af_home_path),
+
         // Inspect the status of simpleaf
         Commands::Inspect {} => inspect_simpleaf(crate_version!(), af_home_path),
         // re-refresh the versions information of all of the programs
@@ -106,6 +145,17 @@ fn main() -> anyhow::Result<()> {
             multiplex_quant::multiplex_map_and_quant(af_home_path.as_path(), mplx_opts)
         }
 
+        // if we are jointly processing a 10x Multiome sample
+        Commands::Multiome(multiome_opts) => {
+            multiome::multiome_process(af_home_path.as_path(), multiome_opts)
+        }
+
+        // if we are demultiplexing a cell-hashed sample
+        Commands::HtoDemux(hto_opts) => hto_demux::hto_demux(af_home_path.as_path(), hto_opts),
+
+        // aggregate a high-resolution spatial quantification into bins
+        Commands::Spatial(SpatialCommand::Bin(bin_opts)) => spatial::bin_spots(bin_opts),
+
         // indexing for ATAC-seq data
         Commands::Atac(AtacCommand::Index(index_opts)) => {
             atac::index::piscem_index(af_home_path.as_path(), &index_opts)
@@ -115,18 +165,19 @@ fn main() -> anyhow::Result<()> {
         Commands::Atac(AtacCommand::Process(process_opts)) => {
             // validate versions
             atac::process::check_progs(&af_home_path, &process_opts)?;
-            // first we map the reads
-            let map_stage = atac::process::map_reads(af_home_path.as_path(), &process_opts)?;
-            info!(
-                "ATAC map stage complete: output={} duration={:.2}s cmd={}",
-                map_stage.map_output.display(),
-                map_stage.map_duration_secs,
-                map_stage.map_cmd
-            );
-            // then we generate the permit list and sort the file
-            atac::process::gen_bed(af_home_path.as_path(), &process_opts)
+            // then map the reads, generate the permit list, sort the file,
+            // and call cells and peaks, as far as requested
+            atac::process::process(af_home_path.as_path(), &process_opts)
+        }
+
+        // gene activity scores from ATAC-seq fragments
+        Commands::Atac(AtacCommand::GeneActivity(ga_opts)) => {
+            atac::gene_activity::gene_activity(&ga_opts)
         }
 
+        // per-group coverage tracks from ATAC-seq fragments
+        Commands::Atac(AtacCommand::Coverage(cov_opts)) => atac::coverage::coverage(&cov_opts),
+
         Commands::Workflow(workflow_args) => {
             let workflow_cmd = workflow_args.command;
             match workflow_cmd {
@@ -6,6 +6,8 @@ pub use self::refresh::refresh_prog_info;
 
 pub mod chemistry;
 
+pub mod cache;
+
 pub mod paths;
 pub use self::paths::set_paths;
 
@@ -17,6 +19,12 @@ pub use self::quant::map_and_quant;
 
 pub mod multiplex_quant;
 
+pub mod multiome;
+
+pub mod hto_demux;
+
+pub mod spatial;
+
 pub mod workflow;
 pub use self::workflow::{
     get_workflow, list_workflows, patch_manifest_or_template, refresh_protocol_estuary,
@@ -66,6 +74,51 @@ fn ref_type_parser(s: &str) -> Result<ReferenceType, String> {
     }
 }
 
+/// The type of a library in a multi-library `quant` run.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LibraryType {
+    Gex,
+    Antibody,
+    Crispr,
+}
+
+impl LibraryType {
+    /// The 10x `feature_type` of the features quantified from this library.
+    pub fn feature_type(&self) -> &'static str {
+        match self {
+            LibraryType::Gex => "Gene Expression",
+            LibraryType::Antibody => "Antibody Capture",
+            LibraryType::Crispr => "CRISPR Guide Capture",
+        }
+    }
+}
+
+/// A library (and one pair of its read files) given to `quant --library`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibrarySpec {
+    pub library_type: LibraryType,
+    pub reads1: PathBuf,
+    pub reads2: PathBuf,
+}
+
+fn library_spec_parser(s: &str) -> Result<LibrarySpec, String> {
+    let (t, reads) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <TYPE>:<R1>,<R2>, found {}", s))?;
+    let library_type = LibraryType::from_str(t, true)?;
+    match reads.split(',').collect::<Vec<&str>>()[..] {
+        [r1, r2] if !r1.is_empty() && !r2.is_empty() => Ok(LibrarySpec {
+            library_type,
+            reads1: PathBuf::from(r1),
+            reads2: PathBuf::from(r2),
+        }),
+        _ => Err(format!(
+            "expected exactly one read 1 and one read 2 file after {}:, found {}",
+            t, reads
+        )),
+    }
+}
+
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true)]
 #[command(group(
@@ -92,15 +145,7 @@ pub struct MapQuantOpts {
     pub threads: u32,
 
     /// Path to a folder containing the index files
-    #[arg(
-        short = 'i',
-        long = "index",
-        help_heading = "Mapping Options",
-        requires_ifs([
-            (ArgPredicate::IsPresent, "reads1"),
-            (ArgPredicate::IsPresent, "reads2")
-        ])
-    )]
+    #[arg(short = 'i', long = "index", help_heading = "Mapping Options")]
     pub index: Option<PathBuf>,
 
     /// Comma-separated list of paths to read 1 files. The order must match the read 2 files.
@@ -110,6 +155,7 @@ pub struct MapQuantOpts {
         help_heading = "Mapping Options",
         value_delimiter = ',',
         requires = "index",
+        required_unless_present_any = ["map_dir", "libraries"],
         conflicts_with = "map_dir"
     )]
     pub reads1: Option<Vec<PathBuf>>,
@@ -121,10 +167,50 @@ pub struct MapQuantOpts {
         help_heading = "Mapping Options",
         value_delimiter = ',',
         requires = "index",
+        required_unless_present_any = ["map_dir", "libraries"],
         conflicts_with = "map_dir"
     )]
     pub reads2: Option<Vec<PathBuf>>,
 
+    /// A library to quantify jointly with the others, as `<TYPE>:<R1>,<R2>`, where TYPE is
+    /// one of `gex`, `antibody` or `crispr`. Repeat the option for every library (and for every
+    /// pair of read files of a library). The `gex` library is mapped against --index, and the
+    /// feature barcoding libraries against --feature-index or an index built from --feature-csv
+    #[arg(
+        long = "library",
+        value_name = "TYPE:R1,R2",
+        value_parser = library_spec_parser,
+        help_heading = "Multi-library Options",
+        requires = "index",
+        conflicts_with_all = ["reads1", "reads2", "map_dir"]
+    )]
+    pub libraries: Vec<LibrarySpec>,
+
+    /// 10x Feature Reference CSV of the feature barcoding libraries, from which their index
+    /// is built (unless --feature-index is given). If it has a `feature_type` column, each
+    /// library only contributes the features of its type to the combined matrix
+    #[arg(long, help_heading = "Multi-library Options", requires = "libraries")]
+    pub feature_csv: Option<PathBuf>,
+
+    /// Path to a prebuilt index of the feature barcodes (see `simpleaf index --feature-csv`)
+    #[arg(long, help_heading = "Multi-library Options", requires = "libraries")]
+    pub feature_index: Option<PathBuf>,
+
+    /// The chemistry of the feature barcoding libraries, if it differs from --chemistry
+    #[arg(long, help_heading = "Multi-library Options", requires = "libraries")]
+    pub feature_chemistry: Option<String>,
+
+    /// Two-column file translating the cell barcodes of the feature barcoding libraries
+    /// (first column) into those of the gex library (second column), as needed for e.g.
+    /// TotalSeq-B/C libraries of 10x 3' v3 and v4
+    #[arg(
+        long,
+        help_heading = "Multi-library Options",
+        requires = "libraries",
+        conflicts_with = "translate_barcodes"
+    )]
+    pub translation_list: Option<PathBuf>,
+
     /// Deprecated no-op retained for backward compatibility.
     #[arg(long = "use-piscem", requires = "index", hide = true)]
     pub use_piscem: bool,
@@ -243,6 +329,35 @@ pub struct MapQuantOpts {
     /// output.
     #[arg(long, help_heading = "Output Options")]
     pub anndata_out: bool,
+
+    /// Translate the cell barcodes of the count matrix with the translation list registered
+    /// with --chemistry (see `simpleaf chemistry translation-list`), merging the cells that
+    /// translate to the same barcode. With --library, the translation list registered with the
+    /// feature barcoding chemistry is used, even without this flag, to match their cells to
+    /// those of the gex library
+    #[arg(long, help_heading = "Output Options")]
+    pub translate_barcodes: bool,
+
+    /// Attach the array row and column of each spot, taken from the coordinates of the
+    /// chemistry's permit list (use with --unfiltered-pl and a Visium chemistry), and write
+    /// them to the `spatial` directory of the output
+    #[arg(
+        long,
+        help_heading = "Spatial Options",
+        conflicts_with_all = ["libraries", "translate_barcodes"]
+    )]
+    pub spatial: bool,
+
+    /// Space Ranger `tissue_positions.csv` (or `tissue_positions_list.csv`) giving the pixel
+    /// coordinates and in-tissue flag of each spot; the spots under tissue are also written
+    /// to the `af_quant_in_tissue` directory of the output
+    #[arg(long, help_heading = "Spatial Options", requires = "spatial")]
+    pub tissue_positions: Option<PathBuf>,
+
+    /// Space Ranger `scalefactors_json.json` to copy into the `spatial` directory of the
+    /// output and record in the anndata output
+    #[arg(long, help_heading = "Spatial Options", requires = "tissue_positions")]
+    pub scalefactors: Option<PathBuf>,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -456,6 +571,66 @@ pub struct ChemistryCleanOpts {
     pub dry_run: bool,
 }
 
+/// Check the chemistry registry for invalid entries and corrupted cached files
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = false)]
+pub struct ChemistryValidateOpts {
+    /// Refetch corrupted cached files and drop invalid entries from the registry
+    #[arg(long)]
+    pub fix: bool,
+}
+
+/// Package chemistries and their cached files into a bundle for offline use
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct ChemistryExportOpts {
+    /// A chemistry name or a regex pattern matching the names of chemistries in the registry to export
+    #[arg(short, long)]
+    pub name: String,
+    /// Path of the bundle (a .tar.gz file) to write
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+/// Import the chemistries of a bundle written by `chemistry export`
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct ChemistryImportOpts {
+    /// Path of the bundle (a .tar.gz file) to import
+    pub bundle: PathBuf,
+    /// overwrite existing chemistries even if the versions aren't newer
+    #[arg(short, long)]
+    pub force: bool,
+    /// print the chemistries that will be added or updated without
+    /// modifying the local registry.
+    #[arg(short, long)]
+    pub dry_run: bool,
+}
+
+/// Show the recorded changes of the chemistry registry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = false)]
+pub struct ChemistryHistoryOpts {
+    /// Only show the changes of the chemistries matching this name or regex pattern
+    #[arg(short, long)]
+    pub name: Option<String>,
+}
+
+/// Undo a recorded change of the chemistry registry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = false)]
+pub struct ChemistryRollbackOpts {
+    /// The id of the change to undo, as shown by `chemistry history` (defaults to the latest change)
+    #[arg(long)]
+    pub id: Option<u64>,
+    /// Undo the change even if the chemistries it changed have been changed again since
+    #[arg(short, long)]
+    pub force: bool,
+    /// Print the chemistries that would be restored without modifying the registry
+    #[arg(short, long)]
+    pub dry_run: bool,
+}
+
 /// Look up chemistries in the local registry and print the details
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true)]
@@ -465,6 +640,27 @@ pub struct ChemistryLookupOpts {
     pub name: String,
 }
 
+/// Add or remove aliases (alternative names) of a chemistry in the local registry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("change")
+    .required(true)
+    .multiple(true)
+    .args(["add", "remove"])
+))]
+pub struct ChemistryAliasOpts {
+    /// The name of the registered chemistry
+    #[arg(short, long)]
+    pub chemistry: String,
+    /// A comma-separated list of aliases to add
+    #[arg(short, long, value_delimiter = ',')]
+    pub add: Vec<String>,
+    /// A comma-separated list of aliases to remove
+    #[arg(short, long, value_delimiter = ',')]
+    pub remove: Vec<String>,
+}
+
 /// Add a new or update an existing chemistry in the local registry
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true, disable_version_flag = true)]
@@ -516,6 +712,125 @@ pub struct ChemistryRefreshOpts {
     /// modifying the local registry.
     #[arg(short, long)]
     pub dry_run: bool,
+    /// print the differences between the local and the upstream registry
+    /// as JSON instead of a table.
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Register a probe set for an organism with a registered chemistry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("source")
+    .required(true)
+    .multiple(true)
+    .args(["csv", "url"])
+))]
+pub struct ProbeSetAddOpts {
+    /// The name of the registered chemistry
+    #[arg(short, long)]
+    pub chemistry: String,
+    /// The organism the probe set targets
+    #[arg(short, long)]
+    pub organism: crate::utils::chem_utils::Organism,
+    /// The path to a local probe set CSV file that will be copied into the
+    /// ALEVIN_FRY_HOME directory
+    #[arg(long)]
+    pub csv: Option<PathBuf>,
+    /// The url of a remote probe set CSV file. If --csv is also given, the local
+    /// file is registered and the url is only recorded; otherwise, the file is
+    /// downloaded now
+    #[arg(long)]
+    pub url: Option<String>,
+    /// The name of the probe set [default: the file name, without its extension]
+    #[arg(short, long)]
+    pub name: Option<String>,
+}
+
+/// Remove the probe set of an organism from a registered chemistry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct ProbeSetRemoveOpts {
+    /// The name of the registered chemistry
+    #[arg(short, long)]
+    pub chemistry: String,
+    /// The organism whose probe set should be removed
+    #[arg(short, long)]
+    pub organism: crate::utils::chem_utils::Organism,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(arg_required_else_help = true)]
+pub enum ProbeSetCommand {
+    Add(ProbeSetAddOpts),
+    Remove(ProbeSetRemoveOpts),
+}
+
+/// Register the sample barcode list of a registered chemistry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("source")
+    .required(true)
+    .multiple(true)
+    .args(["tsv", "url"])
+))]
+pub struct SampleBcSetOpts {
+    /// The name of the registered chemistry
+    #[arg(short, long)]
+    pub chemistry: String,
+    /// The path to a local sample barcode list (a 3-column TSV of the observed
+    /// barcode, the canonical barcode and the sample name) that will be copied into
+    /// the ALEVIN_FRY_HOME directory
+    #[arg(long)]
+    pub tsv: Option<PathBuf>,
+    /// The url of a remote sample barcode list. If --tsv is also given, the local
+    /// file is registered and the url is only recorded; otherwise, the file is
+    /// downloaded now
+    #[arg(long)]
+    pub url: Option<String>,
+    /// The orientation of the listed barcodes relative to how they appear in the
+    /// reads [default: keep the registered orientation, or forward]
+    #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["forward", "reverse"]))]
+    pub sample_bc_ori: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(arg_required_else_help = true)]
+pub enum SampleBcCommand {
+    Set(SampleBcSetOpts),
+}
+
+/// Register the cell barcode translation list of a registered chemistry
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("source")
+    .required(true)
+    .multiple(true)
+    .args(["tsv", "url"])
+))]
+pub struct TranslationListSetOpts {
+    /// The name of the registered chemistry
+    #[arg(short, long)]
+    pub chemistry: String,
+    /// The path to a local translation list (a 2-column file of the barcode to
+    /// translate and the barcode it translates to, e.g. feature barcoding to gene
+    /// expression barcodes) that will be copied into the ALEVIN_FRY_HOME directory
+    #[arg(long)]
+    pub tsv: Option<PathBuf>,
+    /// The url of a remote translation list. If --tsv is also given, the local
+    /// file is registered and the url is only recorded; otherwise, the file is
+    /// downloaded now
+    #[arg(long)]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(arg_required_else_help = true)]
+pub enum TranslationListCommand {
+    Set(TranslationListSetOpts),
 }
 
 #[derive(Debug, Subcommand)]
@@ -526,7 +841,79 @@ pub enum ChemistryCommand {
     Remove(ChemistryRemoveOpts),
     Clean(ChemistryCleanOpts),
     Lookup(ChemistryLookupOpts),
+    Alias(ChemistryAliasOpts),
     Fetch(ChemistryFetchOpts),
+    Validate(ChemistryValidateOpts),
+    Export(ChemistryExportOpts),
+    Import(ChemistryImportOpts),
+    History(ChemistryHistoryOpts),
+    Rollback(ChemistryRollbackOpts),
+    /// Register or remove the probe sets of a chemistry
+    #[command(subcommand)]
+    ProbeSet(ProbeSetCommand),
+    /// Register the sample barcode list of a chemistry
+    #[command(subcommand)]
+    SampleBc(SampleBcCommand),
+    /// Register the cell barcode translation list of a chemistry
+    #[command(subcommand)]
+    TranslationList(TranslationListCommand),
+}
+
+/// Remove cached artifacts that are unused, stale or fail verification
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = false)]
+pub struct CacheCleanOpts {
+    /// Print the cached artifact(s) that would be removed without removing them
+    #[arg(short, long)]
+    pub dry_run: bool,
+    /// Also remove artifacts that have not been used in more than this many days,
+    /// even if a registered chemistry still refers to them
+    #[arg(long, value_name = "DAYS")]
+    pub older_than: Option<u64>,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(arg_required_else_help = true)]
+pub enum CacheCommand {
+    /// List every cached artifact with its size, owners and last use
+    List {},
+    /// Check the cached artifacts against their recorded hashes and expected contents
+    Verify {},
+    /// Remove cached artifacts that are unused, stale or fail verification
+    Clean(CacheCleanOpts),
+    /// Print the disk space used by each kind of cached artifact
+    Size {},
+}
+
+/// Aggregate the barcode × gene matrix of a high-resolution spatial quantification into
+/// square bins
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct SpatialBinOpts {
+    /// Path to the quantification directory (`af_quant`) holding the barcode × gene matrix
+    #[arg(short, long)]
+    pub quant_dir: PathBuf,
+    /// Tab- or comma-separated file giving the x and y coordinates of each barcode
+    /// (barcode, x, y), optionally compressed and with a header line
+    #[arg(long)]
+    pub coords: PathBuf,
+    /// Comma-separated list of the bin sizes, in µm
+    #[arg(short, long, value_delimiter = ',', required = true)]
+    pub bin_sizes: Vec<u32>,
+    /// The size in µm of one unit of the coordinates; the default suits the 2µm grid
+    /// indices of Visium HD, use 1 for coordinates in µm
+    #[arg(long, default_value_t = 2.0)]
+    pub coord_scale: f64,
+    /// Path to the output directory, which gets one quantification directory per bin size
+    #[arg(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(arg_required_else_help = true)]
+pub enum SpatialCommand {
+    /// Aggregate the barcodes of a quantification into square bins of one or more sizes
+    Bin(SpatialBinOpts),
 }
 
 #[derive(Args, Clone, Debug)]
@@ -550,6 +937,10 @@ pub struct SetPathOpts {
 /// hierarchical collation, and quantification with sample-prefixed output.
 #[derive(Args, Clone, Debug)]
 #[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("cell_calling")
+    .args(["knee", "expect_cells", "sample_sheet"])
+))]
 pub struct MultiplexQuantOpts {
     /// Chemistry name (e.g. 10x-flexv1-gex-3p). Provides defaults for geometry,
     /// cell BC whitelist, sample BC list, and probe set. All can be overridden
@@ -620,6 +1011,7 @@ pub struct MultiplexQuantOpts {
         short = '1',
         long,
         value_delimiter = ',',
+        required_unless_present = "map_dir",
         help_heading = "Mapping Options"
     )]
     pub reads1: Vec<PathBuf>,
@@ -629,10 +1021,17 @@ pub struct MultiplexQuantOpts {
         short = '2',
         long,
         value_delimiter = ',',
+        required_unless_present = "map_dir",
         help_heading = "Mapping Options"
     )]
     pub reads2: Vec<PathBuf>,
 
+    /// Path to a mapped output directory containing a RAD file (e.g. the `af_map`
+    /// directory of a previous run) to skip mapping; permit list generation,
+    /// collate and quant are run on it
+    #[arg(long, conflicts_with_all = ["reads1", "reads2"], help_heading = "Mapping Options")]
+    pub map_dir: Option<PathBuf>,
+
     /// UMI resolution mode
     #[arg(short, long, default_value = "cr-like",
         help_heading = "Quantification Options",
@@ -675,9 +1074,275 @@ pub struct MultiplexQuantOpts {
     #[arg(long, default_value_t = 10, help_heading = "Permit List Options")]
     pub min_reads: usize,
 
+    /// Call cells independently within each sample by finding the knee of the
+    /// sample's UMI count curve. The filtered matrix is written to `af_quant_filtered`
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub knee: bool,
+
+    /// Call cells independently within each sample, given the number of cells
+    /// expected in every sample. The filtered matrix is written to `af_quant_filtered`
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub expect_cells: Option<usize>,
+
+    /// A two-column TSV of `sample_name` and the number of cells expected in that
+    /// sample; cells are called independently within each listed sample, and samples
+    /// that are not listed are left out of the filtered matrix in `af_quant_filtered`
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub sample_sheet: Option<PathBuf>,
+
     /// Generate an anndata (h5ad format) count matrix from the standard (matrix-market format) output
     #[arg(long, help_heading = "Output Options")]
     pub anndata_out: bool,
+
+    /// Also write one quantification directory per sample (named by the `sample_name`
+    /// column of the sample BC list) under `af_quant_by_sample`, each with its own
+    /// count matrix, barcodes, features, summary and (with --anndata-out) h5ad file.
+    /// The combined matrix in `af_quant` is kept.
+    #[arg(long, help_heading = "Output Options")]
+    pub split_by_sample: bool,
+
+    /// Skip the stages (mapping, permit list generation, collate and quant) that a
+    /// previous run in the same output directory completed, as recorded in
+    /// `simpleaf_multiplex_quant_info.json`, if they would be run with the same
+    /// command and their outputs are still present
+    #[arg(long, help_heading = "Output Options")]
+    pub resume: bool,
+
+    /// Number of read pairs whose sample barcodes are checked against the sample BC
+    /// list for the sample-barcode QC report (`sample_bc_qc.json`/`.tsv`); with 0, or
+    /// with --map-dir, only the quantified cells and reads per sample are reported
+    #[arg(long, default_value_t = 1_000_000, help_heading = "Output Options")]
+    pub sample_bc_qc_reads: u64,
+}
+
+/// Options for the `multiome` subcommand — joint 10x Multiome (ARC) GEX + ATAC processing.
+///
+/// The GEX library is quantified with the RNA quant path and the ATAC library is
+/// processed with the ATAC process path. ATAC barcodes are then translated into GEX
+/// barcode space using the paired (row-wise) whitelists, and cells are called
+/// jointly from both modalities.
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+#[command(group(
+    ArgGroup::new("filter")
+    .required(true)
+    .args(["knee", "expect_cells", "forced_cells"])
+))]
+pub struct MultiomeOpts {
+    /// Path to output directory
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Number of threads to use
+    #[arg(short, long, default_value_t = 16)]
+    pub threads: u32,
+
+    /// Path to the piscem index of the (spliced+intronic) transcriptome
+    #[arg(long, help_heading = "GEX Options")]
+    pub gex_index: PathBuf,
+
+    /// Comma-separated list of GEX read 1 files
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "GEX Options"
+    )]
+    pub gex_reads1: Vec<PathBuf>,
+
+    /// Comma-separated list of GEX read 2 files
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "GEX Options"
+    )]
+    pub gex_reads2: Vec<PathBuf>,
+
+    /// The chemistry of the GEX library
+    #[arg(long, default_value = "10xv3", help_heading = "GEX Options")]
+    pub gex_chemistry: String,
+
+    /// GEX barcode whitelist, paired row-wise with the ATAC whitelist
+    /// (e.g. the GEX `737K-arc-v1.txt` list shipped with Cell Ranger ARC)
+    #[arg(long, help_heading = "GEX Options")]
+    pub gex_whitelist: PathBuf,
+
+    /// Path to a transcript to gene map file
+    #[arg(short = 'm', long, help_heading = "GEX Options")]
+    pub t2g_map: Option<PathBuf>,
+
+    /// UMI resolution mode
+    #[arg(short, long, default_value = "cr-like",
+        help_heading = "GEX Options",
+        value_parser = clap::builder::PossibleValuesParser::new([
+            "cr-like", "cr-like-em", "parsimony", "parsimony-em",
+            "parsimony-gene", "parsimony-gene-em"
+        ]))]
+    pub resolution: String,
+
+    /// Path to the piscem index of the genome
+    #[arg(long, help_heading = "ATAC Options")]
+    pub atac_index: PathBuf,
+
+    /// Comma-separated list of ATAC read 1 files
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "ATAC Options"
+    )]
+    pub atac_reads1: Vec<PathBuf>,
+
+    /// Comma-separated list of ATAC read 2 files
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "ATAC Options"
+    )]
+    pub atac_reads2: Vec<PathBuf>,
+
+    /// Comma-separated list of ATAC files containing the cell barcodes
+    #[arg(
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "ATAC Options"
+    )]
+    pub atac_barcode_reads: Vec<PathBuf>,
+
+    /// ATAC barcode whitelist, paired row-wise with the GEX whitelist. If not provided,
+    /// the permit list of the `10x-arc-atac-v1` registry chemistry is used
+    #[arg(long, help_heading = "ATAC Options")]
+    pub atac_whitelist: Option<PathBuf>,
+
+    /// GTF/GFF or BED file providing transcription start sites; if provided, called
+    /// cells must also pass --min-tss-enrichment
+    #[arg(long, help_heading = "ATAC Options")]
+    pub tss: Option<PathBuf>,
+
+    /// Minimum TSS enrichment score for a barcode to be called a cell; only used with --tss
+    #[arg(
+        long,
+        default_value_t = 4.0,
+        requires = "tss",
+        help_heading = "ATAC Options"
+    )]
+    pub min_tss_enrichment: f64,
+
+    /// Call cells by finding the knee in the UMI and unique fragment count curves
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub knee: bool,
+
+    /// Call cells given the expected number of cells
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub expect_cells: Option<usize>,
+
+    /// Call exactly this many cells (ranked jointly by UMIs and unique fragments)
+    #[arg(long, help_heading = "Cell Calling Options")]
+    pub forced_cells: Option<usize>,
+
+    /// Minimum read count threshold for a barcode to be retained/processed in either modality
+    #[arg(long, default_value_t = 10, help_heading = "Cell Calling Options")]
+    pub min_reads: usize,
+
+    /// Generate an anndata (h5ad format) count matrix for the called cells
+    #[arg(long, help_heading = "Output Options")]
+    pub anndata_out: bool,
+}
+
+/// How the per-HTO threshold separating tagged cells from the background is chosen.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HtoThresholdMethod {
+    /// Fit a two-component Gaussian mixture to the normalized counts of the HTO
+    Mixture,
+    /// Take a quantile of the normalized counts of the HTO in its background cluster, the
+    /// k-means cluster (out of one per HTO plus one) with the lowest mean for the HTO
+    Quantile,
+}
+
+/// Options for the `hto-demux` subcommand — cell hashing (HTO) demultiplexing.
+#[derive(Args, Clone, Debug)]
+#[command(arg_required_else_help = true)]
+pub struct HtoDemuxOpts {
+    /// Path to output directory
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Number of threads to use
+    #[arg(short, long, default_value_t = 16)]
+    pub threads: u32,
+
+    /// The quantification directory of the paired GEX run (the `af_quant` directory of a
+    /// `simpleaf quant` run, or its parent). Only the cells in its count matrix are demultiplexed
+    #[arg(long, help_heading = "GEX Options")]
+    pub gex_quant: PathBuf,
+
+    /// Path to the feature barcode index of the HTOs, built with `simpleaf index --feature-csv`
+    #[arg(short, long, help_heading = "HTO Options")]
+    pub index: PathBuf,
+
+    /// Comma-separated list of HTO read 1 files
+    #[arg(
+        short = '1',
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "HTO Options"
+    )]
+    pub reads1: Vec<PathBuf>,
+
+    /// Comma-separated list of HTO read 2 files
+    #[arg(
+        short = '2',
+        long,
+        value_delimiter = ',',
+        required = true,
+        help_heading = "HTO Options"
+    )]
+    pub reads2: Vec<PathBuf>,
+
+    /// The name of a registered chemistry or a quoted string representing a custom geometry
+    /// specification of the HTO library
+    #[arg(short, long, help_heading = "HTO Options")]
+    pub chemistry: String,
+
+    /// The expected direction/orientation of alignments in the HTO library
+    #[arg(short = 'd', long, help_heading = "HTO Options", value_parser = clap::builder::PossibleValuesParser::new(["fw", "rc", "both"]))]
+    pub expected_ori: Option<String>,
+
+    /// UMI resolution mode
+    #[arg(short, long, default_value = "cr-like",
+        help_heading = "HTO Options",
+        value_parser = clap::builder::PossibleValuesParser::new([
+            "cr-like", "cr-like-em", "parsimony", "parsimony-em",
+            "parsimony-gene", "parsimony-gene-em"
+        ]))]
+    pub resolution: String,
+
+    /// How the per-HTO threshold on the CLR-normalized counts is chosen
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = HtoThresholdMethod::Mixture,
+        help_heading = "Demultiplexing Options"
+    )]
+    pub threshold_method: HtoThresholdMethod,
+
+    /// The quantile of the background distribution used as the threshold with
+    /// `--threshold-method quantile`
+    #[arg(long, default_value_t = 0.99, help_heading = "Demultiplexing Options")]
+    pub quantile: f64,
+
+    /// Split the GEX count matrix into one directory per HTO, holding the cells
+    /// classified as singlets of that HTO
+    #[arg(long, help_heading = "Output Options")]
+    pub split_gex: bool,
+
+    /// Generate an anndata (h5ad format) count matrix for each split of the GEX matrix
+    #[arg(long, requires = "split_gex", help_heading = "Output Options")]
+    pub anndata_out: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -687,12 +1352,22 @@ pub enum Commands {
     /// operate on or inspect the chemistry registry
     #[command(subcommand)]
     Chemistry(ChemistryCommand),
+    /// inspect, verify and prune the artifacts cached under ALEVIN_FRY_HOME
+    #[command(subcommand)]
+    Cache(CacheCommand),
     /// inspect the current configuration
     Inspect {},
     /// quantify a sample
     Quant(MapQuantOpts),
     /// quantify a multiplexed sample (e.g. 10x Flex, or any custom multi-barcode protocol)
     MultiplexQuant(MultiplexQuantOpts),
+    /// jointly process the GEX and ATAC libraries of a 10x Multiome (ARC) sample
+    Multiome(MultiomeOpts),
+    /// demultiplex a cell-hashed (HTO) sample using the cells called in a paired GEX run
+    HtoDemux(HtoDemuxOpts),
+    /// process spatial quantifications
+    #[command(subcommand)]
+    Spatial(SpatialCommand),
     /// set paths to the programs that simpleaf will use
     SetPaths(SetPathOpts),
     /// refreshes version information associated with programs used by simpleaf
@@ -0,0 +1,734 @@
+//! Inspection and maintenance of the artifacts simpleaf caches under `ALEVIN_FRY_HOME`.
+//!
+//! The cache consists of
+//! * `plist/<hash>`: permit lists, sample barcode lists and probe set CSVs registered
+//!   for a chemistry, named by the Blake3 hash of their contents;
+//! * `probe_indices/<name>_<k>`: probe indices built by `multiplex-quant`, along with
+//!   the probe set CSVs they were built from (`probe_indices/downloads`);
+//! * `protocol-estuary`: the local copy of the workflow registry;
+//! * `plist_dryrun`: left over from an interrupted `chemistry refresh --dry-run`.
+//!
+//! Every artifact is attributed to the registered chemistries (and their probe sets)
+//! that refer to it, so that `clean` can tell which entries are no longer needed.
+
+use crate::simpleaf_commands::CacheCleanOpts;
+use crate::utils::chem_registry::ChemistryRegistry;
+use crate::utils::chem_utils::CustomChemistry;
+use crate::utils::constants::CHEMISTRIES_PATH;
+use crate::utils::prog_utils;
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tabled::{Table, Tabled, settings::Style};
+use tracing::{info, warn};
+
+const PLIST_DIR: &str = "plist";
+const PROBE_INDEX_DIR: &str = "probe_indices";
+const PROBE_DOWNLOAD_DIR: &str = "downloads";
+const ESTUARY_DIR: &str = "protocol-estuary";
+const PLIST_DRYRUN_DIR: &str = "plist_dryrun";
+
+/// The kind of a cached artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum CacheKind {
+    PermitList,
+    ProbeCsv,
+    ProbeIndex,
+    ProtocolEstuary,
+    Temporary,
+}
+
+impl std::fmt::Display for CacheKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            CacheKind::PermitList => "permit list",
+            CacheKind::ProbeCsv => "probe CSV",
+            CacheKind::ProbeIndex => "probe index",
+            CacheKind::ProtocolEstuary => "protocol-estuary",
+            CacheKind::Temporary => "temporary",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The outcome of verifying a cached artifact.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Verification {
+    Ok,
+    /// There is nothing to check the artifact against (e.g. it is not named by a hash).
+    Unchecked,
+    Failed(String),
+}
+
+/// A single artifact in the cache.
+#[derive(Debug, Clone)]
+pub(crate) struct CacheEntry {
+    pub kind: CacheKind,
+    pub path: PathBuf,
+    /// Total size in bytes (recursive for directories).
+    pub size: u64,
+    /// The most recent access or modification time of the artifact, or of
+    /// anything below it for directories.
+    pub last_used: SystemTime,
+    /// The chemistries (and the role the artifact plays for them) that refer to this artifact.
+    pub owners: Vec<String>,
+}
+
+impl CacheEntry {
+    fn from_path(kind: CacheKind, path: PathBuf, owners: Vec<String>) -> Result<CacheEntry> {
+        let (size, last_used) = disk_usage(&path)?;
+        Ok(CacheEntry {
+            kind,
+            path,
+            size,
+            last_used,
+            owners,
+        })
+    }
+
+    fn is_owned(&self) -> bool {
+        !self.owners.is_empty()
+    }
+
+    fn days_unused(&self, now: SystemTime) -> u64 {
+        now.duration_since(self.last_used)
+            .unwrap_or(Duration::ZERO)
+            .as_secs()
+            / 86_400
+    }
+
+    /// Check the artifact against what it is expected to contain.
+    pub fn verify(&self) -> Verification {
+        match self.kind {
+            CacheKind::PermitList | CacheKind::ProbeCsv => {
+                let Some(expected) = self
+                    .path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .filter(|s| is_blake3_hex(s))
+                else {
+                    return Verification::Unchecked;
+                };
+                let mut hasher = blake3::Hasher::new();
+                match hasher.update_mmap(&self.path) {
+                    Ok(_) => {
+                        let observed = hasher.finalize().to_string();
+                        if observed == expected {
+                            Verification::Ok
+                        } else {
+                            Verification::Failed(format!("content hash is {}", observed))
+                        }
+                    }
+                    Err(e) => Verification::Failed(format!("could not be read: {}", e)),
+                }
+            }
+            CacheKind::ProbeIndex => {
+                let index_base = self.path.join("probe_index").join("index");
+                match prog_utils::check_piscem_index_files(&index_base) {
+                    Ok(()) => Verification::Ok,
+                    Err(_) => Verification::Failed(String::from("incomplete piscem index")),
+                }
+            }
+            CacheKind::ProtocolEstuary => {
+                let main_dir = self.path.join("protocol-estuary-main");
+                if main_dir.join("protocols").is_dir() && main_dir.join("utils").is_dir() {
+                    Verification::Ok
+                } else {
+                    Verification::Failed(String::from("missing protocols or utils directory"))
+                }
+            }
+            CacheKind::Temporary => Verification::Unchecked,
+        }
+    }
+}
+
+/// Maps the names under which artifacts are cached to the chemistries that refer to them.
+#[derive(Debug, Default)]
+struct CacheOwners {
+    /// `plist/<name>` -> (kind, owner)
+    plists: HashMap<String, Vec<(CacheKind, String)>>,
+    /// probe set cache key (`plist_name`, falling back to the probe set name) -> owner
+    probe_sets: HashMap<String, Vec<String>>,
+}
+
+impl CacheOwners {
+    fn from_chemistries<'a>(chems: impl Iterator<Item = &'a CustomChemistry>) -> CacheOwners {
+        let mut owners = CacheOwners::default();
+        for chem in chems {
+            let name = chem.name();
+            if let Some(pl) = &chem.plist_name {
+                owners
+                    .plists
+                    .entry(pl.clone())
+                    .or_default()
+                    .push((CacheKind::PermitList, format!("{} (permit list)", name)));
+            }
+            if let Some(pl) = chem
+                .sample_bc_list
+                .as_ref()
+                .and_then(|s| s.plist_name.as_ref())
+            {
+                owners
+                    .plists
+                    .entry(pl.clone())
+                    .or_default()
+                    .push((CacheKind::PermitList, format!("{} (sample BC list)", name)));
+            }
+            if let Some(pl) = chem
+                .translation_list
+                .as_ref()
+                .and_then(|t| t.plist_name.as_ref())
+            {
+                owners.plists.entry(pl.clone()).or_default().push((
+                    CacheKind::PermitList,
+                    format!("{} (translation list)", name),
+                ));
+            }
+            for (organism, ps) in chem.probe_sets.iter().flatten() {
+                let owner = format!("{} (probe set {})", name, organism);
+                if let Some(pl) = &ps.plist_name {
+                    owners
+                        .plists
+                        .entry(pl.clone())
+                        .or_default()
+                        .push((CacheKind::ProbeCsv, owner.clone()));
+                }
+                let key = ps.plist_name.as_deref().unwrap_or(&ps.name);
+                owners
+                    .probe_sets
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(owner);
+            }
+        }
+        for v in owners.plists.values_mut() {
+            v.sort();
+        }
+        for v in owners.probe_sets.values_mut() {
+            v.sort();
+        }
+        owners
+    }
+}
+
+fn is_blake3_hex(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The total size of `path` and the latest access or modification time of anything
+/// at or below it.
+fn disk_usage(path: &Path) -> Result<(u64, SystemTime)> {
+    let md = fs::symlink_metadata(path)
+        .with_context(|| format!("could not read metadata of {}", path.display()))?;
+    let mut last_used = [md.accessed(), md.modified()]
+        .into_iter()
+        .filter_map(|t| t.ok())
+        .max()
+        .unwrap_or(UNIX_EPOCH);
+    let mut size = md.size();
+    if md.is_dir() {
+        for de in fs::read_dir(path)? {
+            let (s, t) = disk_usage(&de?.path())?;
+            size += s;
+            last_used = last_used.max(t);
+        }
+    }
+    Ok((size, last_used))
+}
+
+fn read_dir_sorted(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    // hidden entries are the locks and in-progress downloads or builds of simpleaf
+    // processes, not cached artifacts
+    let mut paths = fs::read_dir(dir)?
+        .filter(|de| {
+            !de.as_ref()
+                .is_ok_and(|e| e.file_name().to_string_lossy().starts_with('.'))
+        })
+        .map(|de| de.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Enumerate every cached artifact under `af_home`, attributed to the chemistries
+/// registered in the layered registries (`chemistries.json` and those listed in
+/// `SIMPLEAF_CHEMISTRY_PATH`).
+pub(crate) fn collect_cache_entries(af_home: &Path) -> Result<Vec<CacheEntry>> {
+    let registry = ChemistryRegistry::layered(&af_home.join(CHEMISTRIES_PATH));
+    collect_registry_cache_entries(af_home, &registry)
+}
+
+/// Enumerate every cached artifact under `af_home`, attributed to the chemistries
+/// of `registry`. Chemistries of the lower layers download their files into the
+/// user cache too, so they own cached artifacts just like those of the user registry.
+fn collect_registry_cache_entries(
+    af_home: &Path,
+    registry: &ChemistryRegistry,
+) -> Result<Vec<CacheEntry>> {
+    // don't trigger a download of the registry just to inspect the cache
+    let chems = if registry.exists() {
+        registry.entries()?
+    } else {
+        BTreeMap::new()
+    };
+    let owners = CacheOwners::from_chemistries(chems.values().map(|(chem, _)| chem));
+    let mut entries = vec![];
+
+    for path in read_dir_sorted(&af_home.join(PLIST_DIR))? {
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let roles = owners.plists.get(name.as_ref());
+        let kind = match roles {
+            Some(r) if r.iter().any(|(k, _)| *k == CacheKind::ProbeCsv) => CacheKind::ProbeCsv,
+            _ => CacheKind::PermitList,
+        };
+        let o = roles
+            .map(|r| r.iter().map(|(_, o)| o.clone()).collect())
+            .unwrap_or_default();
+        entries.push(CacheEntry::from_path(kind, path, o)?);
+    }
+
+    let probe_dir = af_home.join(PROBE_INDEX_DIR);
+    for path in read_dir_sorted(&probe_dir)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name == PROBE_DOWNLOAD_DIR || !path.is_dir() {
+            continue;
+        }
+        // indices are cached as `<probe set key>_<k>`
+        let key = name.rsplit_once('_').map_or(name.as_ref(), |(k, _)| k);
+        let o = owners.probe_sets.get(key).cloned().unwrap_or_default();
+        entries.push(CacheEntry::from_path(CacheKind::ProbeIndex, path, o)?);
+    }
+    for path in read_dir_sorted(&probe_dir.join(PROBE_DOWNLOAD_DIR))? {
+        if !path.is_file() {
+            continue;
+        }
+        let key = path.file_stem().unwrap_or_default().to_string_lossy();
+        let o = owners
+            .probe_sets
+            .get(key.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        entries.push(CacheEntry::from_path(CacheKind::ProbeCsv, path, o)?);
+    }
+
+    let estuary = af_home.join(ESTUARY_DIR);
+    if estuary.is_dir() {
+        // the workflow commands always use the estuary, so it has no chemistry owner
+        entries.push(CacheEntry::from_path(
+            CacheKind::ProtocolEstuary,
+            estuary,
+            vec![String::from("workflows")],
+        )?);
+    }
+
+    let dryrun = af_home.join(PLIST_DRYRUN_DIR);
+    if dryrun.exists() {
+        entries.push(CacheEntry::from_path(CacheKind::Temporary, dryrun, vec![])?);
+    }
+
+    Ok(entries)
+}
+
+/// Format a number of bytes for humans.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn display_path(af_home: &Path, path: &Path) -> String {
+    path.strip_prefix(af_home)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+#[derive(Tabled)]
+struct CacheRow {
+    kind: String,
+    path: String,
+    size: String,
+    last_used: String,
+    owners: String,
+}
+
+impl CacheRow {
+    fn new(af_home: &Path, e: &CacheEntry) -> CacheRow {
+        let last_used: DateTime<Local> = e.last_used.into();
+        CacheRow {
+            kind: e.kind.to_string(),
+            path: display_path(af_home, &e.path),
+            size: human_size(e.size),
+            last_used: last_used.format("%Y-%m-%d %H:%M").to_string(),
+            owners: if e.is_owned() {
+                e.owners.join("\n")
+            } else {
+                String::from("(unused)")
+            },
+        }
+    }
+}
+
+/// Print every cached artifact with its size, owners and last use.
+pub fn list_cache(af_home_path: PathBuf) -> Result<()> {
+    let entries = collect_cache_entries(&af_home_path)?;
+    if entries.is_empty() {
+        info!(
+            "The cache under {} is empty; Nothing to list.",
+            af_home_path.display()
+        );
+        return Ok(());
+    }
+    let rows = entries
+        .iter()
+        .map(|e| CacheRow::new(&af_home_path, e))
+        .collect::<Vec<CacheRow>>();
+    println!("{}", Table::new(rows).with(Style::rounded()));
+    Ok(())
+}
+
+/// Verify every cached artifact, failing if any of them is corrupt or incomplete.
+pub fn verify_cache(af_home_path: PathBuf) -> Result<()> {
+    let entries = collect_cache_entries(&af_home_path)?;
+    let mut num_ok = 0;
+    let mut num_unchecked = 0;
+    let mut failed = vec![];
+    for e in &entries {
+        match e.verify() {
+            Verification::Ok => num_ok += 1,
+            Verification::Unchecked => num_unchecked += 1,
+            Verification::Failed(reason) => {
+                warn!(
+                    "{} {} failed verification: {}",
+                    e.kind,
+                    e.path.display(),
+                    reason
+                );
+                failed.push(e);
+            }
+        }
+    }
+    info!(
+        "Verified {} cached artifact(s): {} ok, {} could not be checked, {} failed.",
+        entries.len(),
+        num_ok,
+        num_unchecked,
+        failed.len()
+    );
+    if !failed.is_empty() {
+        bail!(
+            "{} cached artifact(s) failed verification; `simpleaf cache clean` will remove them.",
+            failed.len()
+        );
+    }
+    Ok(())
+}
+
+/// Decide which entries `clean` removes, and why.
+fn removable_entries(
+    entries: &[CacheEntry],
+    older_than: Option<u64>,
+    now: SystemTime,
+) -> Vec<(&CacheEntry, String)> {
+    entries
+        .iter()
+        .filter_map(|e| {
+            let reason = if !e.is_owned() {
+                String::from("not used by any registered chemistry")
+            } else if let Verification::Failed(reason) = e.verify() {
+                format!("failed verification ({})", reason)
+            } else {
+                match older_than {
+                    Some(days) if e.days_unused(now) > days => {
+                        format!("not used in {} days", e.days_unused(now))
+                    }
+                    _ => return None,
+                }
+            };
+            Some((e, reason))
+        })
+        .collect()
+}
+
+/// Remove cached artifacts that are unused, fail verification, or (with
+/// `--older-than`) have not been used recently.
+pub fn clean_cache(af_home_path: PathBuf, clean_opts: CacheCleanOpts) -> Result<()> {
+    let entries = collect_cache_entries(&af_home_path)?;
+    let rem = removable_entries(&entries, clean_opts.older_than, SystemTime::now());
+    if rem.is_empty() {
+        info!("No cached artifacts are unused or stale; Nothing to clean.");
+        return Ok(());
+    }
+
+    let total: u64 = rem.iter().map(|(e, _)| e.size).sum();
+    for (e, reason) in &rem {
+        if clean_opts.dry_run {
+            info!(
+                "[dry_run] : would remove {} {} ({}): {}",
+                e.kind,
+                e.path.display(),
+                human_size(e.size),
+                reason
+            );
+        } else {
+            info!(
+                "removing {} {} ({}): {}",
+                e.kind,
+                e.path.display(),
+                human_size(e.size),
+                reason
+            );
+            if e.path.is_dir() {
+                fs::remove_dir_all(&e.path)
+            } else {
+                fs::remove_file(&e.path)
+            }
+            .with_context(|| format!("could not remove {}", e.path.display()))?;
+        }
+    }
+    info!(
+        "{}{} {} cached artifact(s), {}.",
+        if clean_opts.dry_run {
+            "[dry_run] : "
+        } else {
+            ""
+        },
+        if clean_opts.dry_run {
+            "Would remove"
+        } else {
+            "Removed"
+        },
+        rem.len(),
+        human_size(total)
+    );
+    Ok(())
+}
+
+/// Print the disk space used by each kind of cached artifact.
+pub fn cache_size(af_home_path: PathBuf) -> Result<()> {
+    let entries = collect_cache_entries(&af_home_path)?;
+    let mut by_kind: BTreeMap<CacheKind, (usize, u64, u64)> = BTreeMap::new();
+    for e in &entries {
+        let (n, size, unused) = by_kind.entry(e.kind).or_default();
+        *n += 1;
+        *size += e.size;
+        if !e.is_owned() {
+            *unused += e.size;
+        }
+    }
+
+    #[derive(Tabled)]
+    struct SizeRow {
+        kind: String,
+        entries: usize,
+        size: String,
+        unused: String,
+    }
+    let mut rows = by_kind
+        .iter()
+        .map(|(k, (n, size, unused))| SizeRow {
+            kind: k.to_string(),
+            entries: *n,
+            size: human_size(*size),
+            unused: human_size(*unused),
+        })
+        .collect::<Vec<SizeRow>>();
+    rows.push(SizeRow {
+        kind: String::from("total"),
+        entries: entries.len(),
+        size: human_size(by_kind.values().map(|v| v.1).sum()),
+        unused: human_size(by_kind.values().map(|v| v.2).sum()),
+    });
+    println!("{}", Table::new(rows).with(Style::rounded()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn write_hashed(dir: &Path, content: &[u8]) -> String {
+        let hash = blake3::hash(content).to_string();
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(&hash), content).unwrap();
+        hash
+    }
+
+    #[test]
+    fn entries_are_attributed_to_their_chemistries_and_verified() {
+        let tmp = tempfile::tempdir().unwrap();
+        let af_home = tmp.path();
+        let plist = af_home.join(PLIST_DIR);
+        let pl_hash = write_hashed(&plist, b"AAAA\nCCCC\n");
+        let probe_hash = write_hashed(&plist, b"gene_id,probe_seq,probe_id\n");
+        let orphan_hash = write_hashed(&plist, b"GGGG\n");
+        // a cached file whose contents no longer match its name
+        let corrupt = blake3::hash(b"original").to_string();
+        fs::write(plist.join(&corrupt), b"truncated").unwrap();
+
+        let index_dir = af_home
+            .join(PROBE_INDEX_DIR)
+            .join(format!("{}_23", probe_hash));
+        fs::create_dir_all(index_dir.join("probe_index")).unwrap();
+
+        let chems = json!({
+            "flex": {
+                "geometry": "1{b[16]u[12]x:}2{r:}",
+                "plist_name": pl_hash,
+                "probe_sets": {
+                    "human": { "name": "human_probes", "plist_name": probe_hash }
+                }
+            },
+            "other": {
+                "geometry": "1{b[16]u[12]x:}2{r:}",
+                "plist_name": corrupt
+            }
+        });
+        fs::write(
+            af_home.join(CHEMISTRIES_PATH),
+            serde_json::to_string(&chems).unwrap(),
+        )
+        .unwrap();
+
+        let entries = collect_cache_entries(af_home).unwrap();
+        assert_eq!(entries.len(), 5);
+        let find = |p: &Path| entries.iter().find(|e| e.path == p).unwrap();
+
+        let pl = find(&plist.join(&pl_hash));
+        assert_eq!(pl.kind, CacheKind::PermitList);
+        assert_eq!(pl.owners, vec!["flex (permit list)"]);
+        assert_eq!(pl.size, 10);
+        assert_eq!(pl.verify(), Verification::Ok);
+
+        let probe = find(&plist.join(&probe_hash));
+        assert_eq!(probe.kind, CacheKind::ProbeCsv);
+        assert_eq!(probe.owners, vec!["flex (probe set human)"]);
+
+        let index = find(&index_dir);
+        assert_eq!(index.kind, CacheKind::ProbeIndex);
+        assert_eq!(index.owners, vec!["flex (probe set human)"]);
+        assert!(matches!(index.verify(), Verification::Failed(_)));
+
+        assert!(!find(&plist.join(&orphan_hash)).is_owned());
+        assert!(matches!(
+            find(&plist.join(&corrupt)).verify(),
+            Verification::Failed(_)
+        ));
+
+        // unused, corrupt, and incomplete entries are removable; the rest are kept
+        let now = SystemTime::now();
+        let mut rem = removable_entries(&entries, None, now)
+            .into_iter()
+            .map(|(e, _)| e.path.clone())
+            .collect::<Vec<_>>();
+        rem.sort();
+        let mut expected = vec![
+            plist.join(&orphan_hash),
+            plist.join(&corrupt),
+            index_dir.clone(),
+        ];
+        expected.sort();
+        assert_eq!(rem, expected);
+
+        // everything is stale a year from now
+        let later = now + Duration::from_secs(365 * 86_400);
+        assert_eq!(removable_entries(&entries, Some(30), later).len(), 5);
+        assert_eq!(removable_entries(&entries, Some(400), later).len(), 3);
+
+        clean_cache(
+            af_home.to_path_buf(),
+            CacheCleanOpts {
+                dry_run: true,
+                older_than: None,
+            },
+        )
+        .unwrap();
+        assert!(plist.join(&orphan_hash).is_file());
+
+        clean_cache(
+            af_home.to_path_buf(),
+            CacheCleanOpts {
+                dry_run: false,
+                older_than: None,
+            },
+        )
+        .unwrap();
+        assert!(!plist.join(&orphan_hash).exists());
+        assert!(!plist.join(&corrupt).exists());
+        assert!(!index_dir.exists());
+        assert!(plist.join(&pl_hash).is_file());
+        assert!(plist.join(&probe_hash).is_file());
+    }
+
+    #[test]
+    fn entries_of_lower_layer_chemistries_are_owned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let af_home = tmp.path().join("af_home");
+        let team = tmp.path().join("team");
+        let plist = af_home.join(PLIST_DIR);
+        let team_pl = write_hashed(&plist, b"AAAA\nCCCC\n");
+        let orphan = write_hashed(&plist, b"GGGG\n");
+
+        fs::create_dir_all(&af_home).unwrap();
+        fs::write(
+            af_home.join(CHEMISTRIES_PATH),
+            json!({ "mine": { "geometry": "1{b[16]u[12]x:}2{r:}" } }).to_string(),
+        )
+        .unwrap();
+        fs::create_dir_all(&team).unwrap();
+        fs::write(
+            team.join(CHEMISTRIES_PATH),
+            json!({ "shared": { "geometry": "1{b[16]u[12]x:}2{r:}", "plist_name": team_pl } })
+                .to_string(),
+        )
+        .unwrap();
+
+        let chem_path = std::env::join_paths([team.clone()]).unwrap();
+        let registry = ChemistryRegistry::with_chemistry_path(
+            &af_home.join(CHEMISTRIES_PATH),
+            Some(chem_path.as_os_str()),
+        );
+        let entries = collect_registry_cache_entries(&af_home, &registry).unwrap();
+        let find = |p: &Path| entries.iter().find(|e| e.path == p).unwrap();
+        assert_eq!(
+            find(&plist.join(&team_pl)).owners,
+            vec!["shared (permit list)"]
+        );
+        assert!(!find(&plist.join(&orphan)).is_owned());
+
+        // the permit list downloaded for the team chemistry is not cleaned
+        let removable = removable_entries(&entries, None, SystemTime::now())
+            .into_iter()
+            .map(|(e, _)| e.path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(removable, vec![plist.join(&orphan)]);
+    }
+
+    #[test]
+    fn human_size_uses_binary_units() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
@@ -1,11 +1,18 @@
-use crate::core::io::write_json_pretty_atomic;
+use crate::core::io::{FileLock, persist, temp_file_for, write_json_pretty_atomic};
+use crate::simpleaf_commands::{
+    ProbeSetAddOpts, ProbeSetRemoveOpts, SampleBcSetOpts, TranslationListSetOpts,
+};
+use crate::utils::chem_registry::ChemistryRegistry;
 use crate::utils::chem_utils::{
-    CustomChemistry, CustomChemistryMap, ExpectedOri, LOCAL_PL_PATH_KEY, REMOTE_PL_URL_KEY,
-    custom_chem_hm_into_json, get_custom_chem_hm, get_single_custom_chem_from_file,
+    CustomChemistry, CustomChemistryMap, ExpectedOri, ProbeSetInfo, SampleBcListInfo,
+    TranslationListInfo, custom_chem_hm_into_json, get_custom_chem_hm,
+    get_single_custom_chem_from_file,
 };
 use crate::utils::constants::*;
+use crate::utils::probe_utils;
 use crate::utils::prog_utils::{self, download_to_file_compute_hash};
 use crate::utils::{self, af_utils::*};
+use crate::utils::{download, integrity};
 use regex::Regex;
 
 use anyhow::{Context, Result, bail};
@@ -14,12 +21,23 @@ use serde_json::json;
 use serde_json::{Map, Value};
 use std::collections::HashSet;
 use std::fs;
+use std::io::{BufRead, BufReader};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use tracing::{debug, info, warn};
 use utils::prog_utils::read_json_from_remote_url;
 use utils::remote::is_remote_url;
 
+mod bundle;
+mod diff;
+mod history;
+mod validate;
+pub use bundle::{export_chemistries, import_chemistries};
+use diff::{diff_registries, print_diff};
+use history::{lock_registry, record_registry_change, write_registry};
+pub use history::{rollback_chemistries, show_history};
+pub use validate::validate_chemistries;
+
 fn removable_permit_lists(
     used_pls: &HashSet<PathBuf>,
     present_pls: &HashSet<PathBuf>,
@@ -62,7 +80,8 @@ fn should_replace_registry_entry(existing: &Value, incoming: &Value, force: bool
 /// Merge incoming registry entries into an existing registry object.
 ///
 /// Missing keys are inserted. Existing keys are replaced only when
-/// `should_replace_registry_entry` permits it.
+/// `should_replace_registry_entry` permits it, keeping the aliases the user gave
+/// the existing entry.
 fn merge_registry_entries(
     existing: &mut Map<String, Value>,
     incoming: &Map<String, Value>,
@@ -77,7 +96,8 @@ fn merge_registry_entries(
             Some(curr) => {
                 if should_replace_registry_entry(curr, v, force)? {
                     info!("{}updating {}", dry_run_pref, k);
-                    existing.insert(k.clone(), v.clone());
+                    let merged = with_preserved_aliases(curr, v);
+                    existing.insert(k.clone(), merged);
                 }
             }
         }
@@ -85,6 +105,28 @@ fn merge_registry_entries(
     Ok(())
 }
 
+/// The `incoming` registry entry, with the aliases of the `existing` entry it
+/// replaces added to its own.
+fn with_preserved_aliases(existing: &Value, incoming: &Value) -> Value {
+    let mut merged = incoming.clone();
+    let Some(kept) = existing.get("aliases").and_then(Value::as_array) else {
+        return merged;
+    };
+    if let Value::Object(entry) = &mut merged {
+        let aliases = entry
+            .entry("aliases")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if let Value::Array(aliases) = aliases {
+            for alias in kept {
+                if !aliases.contains(alias) {
+                    aliases.push(alias.clone());
+                }
+            }
+        }
+    }
+    merged
+}
+
 /// Merge entries from deprecated `custom_chemistries.json` into the main registry.
 ///
 /// Only missing entries are inserted, and only when the deprecated value is a
@@ -232,6 +274,7 @@ pub fn add_chemistry(
     af_home_path: PathBuf,
     mut add_opts: crate::simpleaf_commands::ChemistryAddOpts,
 ) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
     let meta: Option<serde_json::Value>;
     let need_fetch_pl;
     let mut local_plist = None;
@@ -343,13 +386,15 @@ pub fn add_chemistry(
                         local_url.display(),
                         local_plist_path.display()
                     );
-                    std::fs::copy(&local_url, &local_plist_path).with_context(|| {
+                    let tmp = temp_file_for(&local_plist_path)?;
+                    std::fs::copy(&local_url, tmp.path()).with_context(|| {
                         format!(
                             "Failed to copy local permit list url {} to location {}",
                             local_url.display(),
                             local_plist_path.display()
                         )
                     })?;
+                    persist(tmp, &local_plist_path)?;
                 }
                 local_plist = Some(local_plist_name.display().to_string());
             } else {
@@ -398,7 +443,7 @@ pub fn add_chemistry(
     let ori = ExpectedOri::from_str(&ori_str)?;
 
     // init the custom chemistry struct
-    let custom_chem = CustomChemistry {
+    let mut custom_chem = CustomChemistry {
         name,
         geometry,
         expected_ori: ori,
@@ -408,6 +453,9 @@ pub fn add_chemistry(
         meta,
         sample_bc_list: None,
         probe_sets: None,
+        translation_list: None,
+        aliases: Vec::new(),
+        deprecated: None,
     };
 
     let mut chem_hm = get_custom_chem_hm(&chem_p)?;
@@ -424,6 +472,8 @@ pub fn add_chemistry(
             },
             cc.geometry()
         );
+        // the aliases of the chemistry outlive its redefinitions
+        custom_chem.aliases = cc.aliases.clone();
         chem_hm
             .entry(custom_chem.name().to_string())
             .and_modify(|e| *e = custom_chem);
@@ -438,7 +488,7 @@ pub fn add_chemistry(
 
     // convert the custom chemistry hashmap to json
     let v = custom_chem_hm_into_json(chem_hm)?;
-    write_json_pretty(&chem_p, &v)?;
+    write_registry(&chem_p, "add", &v)?;
 
     Ok(())
 }
@@ -453,6 +503,7 @@ pub fn refresh_chemistries(
     af_home: PathBuf,
     refresh_opts: crate::simpleaf_commands::ChemistryRefreshOpts,
 ) -> Result<()> {
+    let _lock = lock_registry(&af_home)?;
     let dry_run = refresh_opts.dry_run;
     let dry_run_pref = if dry_run { "[dry_run] : " } else { "" };
     let dry_run_dir = af_home.join("plist_dryrun");
@@ -473,6 +524,11 @@ pub fn refresh_chemistries(
     let chem_path = af_home.join(CHEMISTRIES_PATH);
     let fresh_download = if !chem_path.is_file() {
         prog_utils::download_to_file(CHEMISTRIES_URL, &chem_path)?;
+        if let Value::Object(upstream) = parse_resource_json_file(&chem_path, None)? {
+            let diff = diff_registries(&Map::new(), &upstream, refresh_opts.force)?;
+            print_diff(&diff, refresh_opts.json)?;
+            record_registry_change(&chem_path, "refresh", &Map::new(), &upstream)?;
+        }
         true
     } else {
         false
@@ -508,8 +564,10 @@ pub fn refresh_chemistries(
         prog_utils::download_to_file(CHEMISTRIES_URL, &tmp_chem_path)?;
         if let Some(existing_chem) = parse_resource_json_file(&chem_path, None)?.as_object_mut() {
             if let Some(new_chem) = parse_resource_json_file(&tmp_chem_path, None)?.as_object() {
+                let diff = diff_registries(existing_chem, new_chem, refresh_opts.force)?;
+                print_diff(&diff, refresh_opts.json)?;
                 merge_registry_entries(existing_chem, new_chem, refresh_opts.force, dry_run_pref)?;
-                write_json_pretty(&chem_path, &Value::Object(existing_chem.clone()))?;
+                write_registry(&chem_path, "refresh", &Value::Object(existing_chem.clone()))?;
 
                 // remove the temp file
                 std::fs::remove_file(tmp_chem_path)?;
@@ -533,7 +591,7 @@ pub fn refresh_chemistries(
                 parse_resource_json_file(&custom_chem_file, None)?.as_object()
             {
                 merge_deprecated_registry_entries(new_chem, old_custom_chem, dry_run_pref);
-                write_json_pretty(&chem_path, &Value::Object(new_chem.clone()))?;
+                write_registry(&chem_path, "refresh", &Value::Object(new_chem.clone()))?;
 
                 let backup = custom_chem_file.with_extension("json.bak");
                 std::fs::rename(custom_chem_file, backup)?;
@@ -567,17 +625,29 @@ pub fn refresh_chemistries(
 }
 
 /// Finds the set of files (A) listed in `ALEVIN_FRY_HOME/plist` (where permit list files live)
-/// and the set of files (B) listed in `chemistries.json` (all entries corresponding to a
-/// `plist_name` entry).  It then computes C = A - B, the set of currently unused permit list
-/// files, and removes them (or lists them if remove_opts has dry_run set).
+/// and the set of files (B) listed in the layered chemistry registries (all entries
+/// corresponding to a `plist_name` entry).  It then computes C = A - B, the set of currently
+/// unused permit list files, and removes them (or lists them if remove_opts has dry_run set).
 pub fn clean_chemistries(
     af_home_path: PathBuf,
     clean_opts: crate::simpleaf_commands::ChemistryCleanOpts,
 ) -> Result<()> {
-    let dry_run = clean_opts.dry_run;
-
-    // read in the custom chemistry file
+    let _lock = lock_registry(&af_home_path)?;
     let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    clean_registry_permit_lists(
+        &af_home_path,
+        &ChemistryRegistry::layered(&chem_p),
+        clean_opts.dry_run,
+    )
+}
+
+/// Remove (or, with `dry_run`, list) the files of `ALEVIN_FRY_HOME/plist` that no
+/// chemistry of `registry` refers to.
+fn clean_registry_permit_lists(
+    af_home_path: &Path,
+    registry: &ChemistryRegistry,
+    dry_run: bool,
+) -> Result<()> {
     let plist_path = af_home_path.join("plist");
     if !plist_path.is_dir() {
         info!(
@@ -587,18 +657,25 @@ pub fn clean_chemistries(
         return Ok(());
     }
 
-    let chem_hm = get_custom_chem_hm(&chem_p)?;
-
-    let used_pls = chem_hm
+    // the files of the chemistries of lower layers are downloaded here when their own
+    // layer doesn't cache them
+    let used_pls = registry
+        .entries()?
         .values()
-        .filter_map(|v| v.plist_name().as_ref().map(|s| plist_path.join(s)))
+        .flat_map(|(chem, _)| chem.plist_names())
+        .map(|s| plist_path.join(s))
         .collect::<HashSet<PathBuf>>();
 
+    // hidden files are the locks and in-progress downloads of other processes
     let present_pls = std::fs::read_dir(&plist_path)?
         .filter_map(|de| {
             if let Ok(entry) = de {
                 let path = entry.path();
-                if path.is_file() { Some(path) } else { None }
+                if path.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+                    Some(path)
+                } else {
+                    None
+                }
             } else {
                 None
             }
@@ -634,6 +711,7 @@ pub fn remove_chemistry(
     af_home_path: PathBuf,
     remove_opts: crate::simpleaf_commands::ChemistryRemoveOpts,
 ) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
     let name = remove_opts.name;
     // read in the custom chemistry file
     let chem_p = af_home_path.join(CHEMISTRIES_PATH);
@@ -675,26 +753,29 @@ pub fn remove_chemistry(
     } else if !remove_opts.dry_run {
         // convert the custom chemistry hashmap to json
         let v = custom_chem_hm_into_json(chem_hm)?;
-        write_json_pretty(&chem_p, &v)?;
+        write_registry(&chem_p, "remove", &v)?;
     }
 
     Ok(())
 }
 
 /// Lookup the chemistry, or the chemistries matching the provided regex in the
-/// chemistry registry.
+/// chemistry registries, showing the registry layer that supplied each of them.
 pub fn lookup_chemistry(
     af_home_path: PathBuf,
     lookup_opts: crate::simpleaf_commands::ChemistryLookupOpts,
 ) -> Result<()> {
     let name = lookup_opts.name;
-    // read in the custom chemistry file
+    // resolve through the user registry and the layers under it
     let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let registry = ChemistryRegistry::layered(&chem_p);
 
     // check if the chemistry already exists and log
-    if let Some(cc) = get_single_custom_chem_from_file(&chem_p, &name)? {
+    if let Some((cc, layer)) = registry.lookup(&name)? {
+        cc.log_resolution(&name);
         println!("=================");
         print!("{}", cc);
+        println!("registry\t: {}", layer.name);
         println!("=================");
     } else {
         info!("No chemistry with name {} was found in the registry!", name);
@@ -702,14 +783,15 @@ pub fn lookup_chemistry(
             "Treating {} as a regex and searching for matching chemistries",
             name
         );
-        let chem_hm = get_custom_chem_hm(&chem_p)?;
+        let chem_hm = registry.entries()?;
 
         match Regex::new(&name) {
             Ok(re) => {
                 println!("=================");
-                for (cname, cval) in chem_hm.iter() {
+                for (cname, (cval, layer)) in chem_hm.iter() {
                     if re.is_match(cname) {
                         print!("{}", cval);
+                        println!("registry\t: {}", layer.name);
                         println!("=================");
                     }
                 }
@@ -726,6 +808,65 @@ pub fn lookup_chemistry(
     Ok(())
 }
 
+/// Add or remove aliases of a chemistry of the user registry. Aliases are the user's
+/// own names for a chemistry, so its version is left as it is, and `chemistry refresh`
+/// keeps them when it updates the chemistry.
+pub fn alias_chemistry(
+    af_home_path: PathBuf,
+    alias_opts: crate::simpleaf_commands::ChemistryAliasOpts,
+) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let name = alias_opts.chemistry;
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let registry = ChemistryRegistry::layered(&chem_p);
+    for alias in &alias_opts.add {
+        if registry.lookup_value(alias)?.is_some() {
+            bail!(
+                "{} is the name of a registered chemistry; it cannot also be an alias.",
+                alias
+            );
+        }
+        if let Some(other) = registry.resolve_alias(alias)?
+            && other != name
+        {
+            bail!("{} is already an alias of the chemistry {}.", alias, other);
+        }
+    }
+
+    let mut chem_hm = get_custom_chem_hm(&chem_p)?;
+    let Some(chem) = chem_hm.get_mut(&name) else {
+        bail!(
+            "The chemistry {} is not registered in the user registry; please add it with `simpleaf chemistry add` first.",
+            name
+        );
+    };
+    let previous = chem.aliases.clone();
+    chem.aliases.retain(|a| !alias_opts.remove.contains(a));
+    for alias in alias_opts.add {
+        if !chem.aliases.contains(&alias) {
+            chem.aliases.push(alias);
+        }
+    }
+    if chem.aliases == previous {
+        info!(
+            "The aliases of chemistry {} are unchanged; nothing to update.",
+            name
+        );
+        return Ok(());
+    }
+    info!(
+        "The aliases of chemistry {} are now: {}",
+        name,
+        if chem.aliases.is_empty() {
+            String::from("none")
+        } else {
+            chem.aliases.join(", ")
+        }
+    );
+    let v = custom_chem_hm_into_json(chem_hm)?;
+    write_registry(&chem_p, "alias", &v)
+}
+
 struct FetchSet<'a> {
     pub m: HashSet<&'a String>,
     pub re: Option<Regex>,
@@ -757,97 +898,557 @@ impl<'a> FetchSet<'a> {
     }
 }
 
-/// Fetch the permit lists for the provided chemistry (or the chemistries matching the provided
-/// regex) in the registry.
+/// Download the file `pfile` (the `what` of chemistry `chem`) from `remote` into
+/// `plist_path`, unless it is already cached by one of the layers of `registry`.
+fn fetch_registry_file(
+    registry: &ChemistryRegistry,
+    plist_path: &Path,
+    chem: &str,
+    what: &str,
+    pfile: &str,
+    remote: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    let dry_run_str = if dry_run { "[dry_run] : " } else { "" };
+    // the file may be cached by any of the registry layers
+    let fpath = registry
+        .find_cached_file(pfile)
+        .unwrap_or_else(|| plist_path.join(pfile));
+
+    // if it doesn't exist
+    if !fpath.is_file() {
+        //check for a remote path
+        if let Some(rpath) = remote {
+            if dry_run {
+                info!(
+                    "[dry_run] : Fetch would fetch missing {} file {} for {} from {}",
+                    what, pfile, chem, rpath
+                );
+            } else {
+                let _lock = FileLock::for_file(&fpath)?;
+                if fpath.is_file() {
+                    info!(
+                        "The {} file for {} was fetched by another process ({}).",
+                        what,
+                        chem,
+                        fpath.display()
+                    );
+                    return Ok(());
+                }
+                download::download(rpath, &fpath, integrity::expected_hash(&fpath)).with_context(
+                    || format!("Could not fetch the {} file for chemistry {}", what, chem),
+                )?;
+                info!("Fetched {} file for {} to {}", what, chem, fpath.display());
+            }
+        } else {
+            warn!(
+                "{}Requested to obtain the {} of chemistry {}, but it has no remote URL!",
+                dry_run_str, what, chem
+            );
+        }
+    } else {
+        info!(
+            "{}The {} file for requested chemistry {} already exists ({}).",
+            dry_run_str,
+            what,
+            chem,
+            fpath.display()
+        );
+        if !dry_run {
+            // the existing file is downloaded again if it is corrupted
+            let af_home = plist_path.parent().unwrap_or(Path::new("."));
+            integrity::ensure_intact(af_home, &fpath, remote, what)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the permit lists and translation lists for the provided chemistry (or the chemistries
+/// matching the provided regex) in the layered chemistry registries.
 pub fn fetch_chemistries(
     af_home: PathBuf,
     fetch_opts: crate::simpleaf_commands::ChemistryFetchOpts,
 ) -> Result<()> {
-    let dry_run_str = if fetch_opts.dry_run {
-        "[dry_run] : "
-    } else {
-        ""
-    };
-
     // check if the chemistry file is absent altogether
     // if so, then download it
     let chem_path = af_home.join(CHEMISTRIES_PATH);
-    if !chem_path.is_file() {
+    let registry = ChemistryRegistry::layered(&chem_path);
+    if !registry.exists() {
         warn!(
             "The chemistry file is missing from {}; Nothing to download. To fetch the base chemistry registry itself, please issue the `refresh` command.",
             chem_path.display()
         );
+        return Ok(());
     }
 
     let plist_path = af_home.join("plist");
     create_dir_if_absent(&plist_path)?;
 
-    if let Some(chem_obj) = parse_resource_json_file(&chem_path, None)?.as_object() {
-        // if the user used the special `*`, then we lookup all chemistries
-        let fetch_chems: FetchSet = if fetch_opts.name.len() == 1 {
-            FetchSet::from_re(fetch_opts.name.first().expect("First entry is valid"))?
-        } else {
-            // otherwise, collect just the set they requested
-            let hs = HashSet::from_iter(fetch_opts.name.iter());
-            FetchSet::from_hash_set(hs)
+    // the requested names may be aliases of registered chemistries
+    let mut names = Vec::with_capacity(fetch_opts.name.len());
+    for name in &fetch_opts.name {
+        names.push(
+            registry
+                .lookup(name)?
+                .map_or_else(|| name.clone(), |(chem, _)| chem.name),
+        );
+    }
+    // if the user used the special `*`, then we lookup all chemistries
+    let fetch_chems: FetchSet = if names.len() == 1 {
+        FetchSet::from_re(names.first().expect("First entry is valid"))?
+    } else {
+        // otherwise, collect just the set they requested
+        let hs = HashSet::from_iter(names.iter());
+        FetchSet::from_hash_set(hs)
+    };
+
+    for (k, (chem, _)) in registry.entries()? {
+        // if we want to fetch this chem
+        if !fetch_chems.contains(&k) {
+            continue;
+        }
+        if let Some(pfile) = chem.plist_name.as_deref() {
+            fetch_registry_file(
+                &registry,
+                &plist_path,
+                &k,
+                "permit list",
+                pfile,
+                chem.remote_pl_url.as_deref(),
+                fetch_opts.dry_run,
+            )?;
+        }
+        if let Some(tl) = chem.translation_list.as_ref()
+            && let Some(pfile) = tl.plist_name.as_deref()
+        {
+            fetch_registry_file(
+                &registry,
+                &plist_path,
+                &k,
+                "translation list",
+                pfile,
+                tl.remote_url.as_deref(),
+                fetch_opts.dry_run,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that `path` is a probe set CSV that can be converted into a probe
+/// reference (see [`probe_utils::convert_probe_csv_to_reference_files`]), with at
+/// least one included probe.
+fn validate_probe_set_csv(path: &Path) -> Result<()> {
+    let conv_dir = tempfile::tempdir()?;
+    let converted = probe_utils::convert_probe_csv_to_reference_files(path, conv_dir.path())
+        .with_context(|| format!("{} is not a valid probe set CSV", path.display()))?;
+    if converted.metadata["num_included"].as_u64().unwrap_or(0) == 0 {
+        bail!(
+            "The probe set CSV {} has no included probes.",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check that `path` is a sample barcode list: whitespace-separated lines of the
+/// observed barcode, the canonical barcode and the sample name (an optional header
+/// line is allowed), with barcodes of a single length and no repeated observed
+/// barcode.
+fn validate_sample_bc_list(path: &Path) -> Result<()> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+    let mut observed = HashSet::new();
+    let mut bc_len = None;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [obs, canonical, _sample] = fields[..] else {
+            bail!(
+                "line {} of {} has {} column(s); a sample barcode list has the observed barcode, the canonical barcode and the sample name.",
+                i + 1,
+                path.display(),
+                fields.len()
+            );
         };
+        if observed.is_empty() && fields[2] == "sample_name" {
+            continue;
+        }
+        for bc in [obs, canonical] {
+            if bc.is_empty() || !bc.bytes().all(|c| b"ACGTNacgtn".contains(&c)) {
+                bail!(
+                    "line {} of {} has an invalid barcode {}.",
+                    i + 1,
+                    path.display(),
+                    bc
+                );
+            }
+            if *bc_len.get_or_insert(bc.len()) != bc.len() {
+                bail!(
+                    "the barcodes of {} do not all have the same length (line {}).",
+                    path.display(),
+                    i + 1
+                );
+            }
+        }
+        if !observed.insert(obs.to_ascii_uppercase()) {
+            bail!(
+                "the observed barcode {} is listed more than once in {}.",
+                obs,
+                path.display()
+            );
+        }
+    }
+    if observed.is_empty() {
+        bail!("{} does not list any sample barcodes.", path.display());
+    }
+    Ok(())
+}
 
-        for (k, v) in chem_obj.iter() {
-            // if we want to fetch this chem
-            if fetch_chems.contains(k)
-                && let Some(serde_json::Value::String(pfile)) = v.get(LOCAL_PL_PATH_KEY)
-            {
-                let fpath = plist_path.join(pfile);
-
-                // if it doesn't exist
-                if !fpath.is_file() {
-                    //check for a remote path
-                    if let Some(serde_json::Value::String(rpath)) = v.get(REMOTE_PL_URL_KEY) {
-                        if fetch_opts.dry_run {
-                            info!(
-                                "[dry_run] : Fetch would fetch missing file {} for {} from {}",
-                                pfile, k, rpath
-                            );
-                        } else {
-                            let hash = download_to_file_compute_hash(rpath, &fpath)?;
-                            let expected_hash = pfile.to_string();
-                            let observed_hash = hash.to_string();
-                            if expected_hash != observed_hash {
-                                warn!(
-                                    "Downloaded the file for chemistry {} from {}, but the observed hash {} was not equal to the expcted hash {}",
-                                    k, rpath, observed_hash, expected_hash
-                                );
-                            }
-                            info!("Fetched permit list file for {} to {}", k, fpath.display());
-                        }
-                    } else {
-                        warn!(
-                            "{}Requested to obtain chemistry {}, but it has no remote URL!",
-                            dry_run_str, k
-                        );
-                    }
-                } else {
-                    info!(
-                        "{}File for requested chemistry {} already exists ({}).",
-                        dry_run_str,
-                        k,
-                        fpath.display()
-                    );
-                }
+/// Check that `path` is a cell barcode translation list: whitespace-separated lines
+/// of the barcode to translate and the barcode it translates to, with no barcode
+/// translated more than once.
+fn validate_translation_list(path: &Path) -> Result<()> {
+    let (reader, _fmt) =
+        niffler::from_path(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut sources = HashSet::new();
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", path.display()))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [from, to] = fields[..] else {
+            bail!(
+                "line {} of {} has {} column(s); a translation list has the barcode to translate and the barcode it translates to.",
+                i + 1,
+                path.display(),
+                fields.len()
+            );
+        };
+        for bc in [from, to] {
+            if !bc.bytes().all(|c| b"ACGTNacgtn".contains(&c)) {
+                bail!(
+                    "line {} of {} has an invalid barcode {}.",
+                    i + 1,
+                    path.display(),
+                    bc
+                );
             }
         }
+        if !sources.insert(from.to_ascii_uppercase()) {
+            bail!(
+                "the barcode {} is translated more than once in {}.",
+                from,
+                path.display()
+            );
+        }
+    }
+    if sources.is_empty() {
+        bail!("{} does not list any barcodes.", path.display());
     }
+    Ok(())
+}
+
+/// Copy the local file `local`, or else download `remote`, into
+/// `ALEVIN_FRY_HOME/plist` under the name of its Blake3 content hash, as is done
+/// for permit lists, after checking it with `validate`. Returns the hash.
+fn cache_plist_file(
+    af_home_path: &Path,
+    local: Option<&Path>,
+    remote: Option<&str>,
+    validate: impl Fn(&Path) -> Result<()>,
+) -> Result<String> {
+    let pdir = af_home_path.join("plist");
+    create_dir_if_absent(&pdir)?;
+
+    if let Some(local) = local {
+        if !local.is_file() {
+            bail!(
+                "The provided local path does not point to a file: {}; cannot proceed.",
+                local.display()
+            );
+        }
+        validate(local)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_mmap(local)?;
+        let hash_str = hasher.finalize().to_string();
+        let plist_path = pdir.join(&hash_str);
+        if plist_path.is_file() {
+            info!("Found a content-equivalent file; will use the existing file.");
+        } else {
+            info!("Copying {} to {}", local.display(), plist_path.display());
+            let tmp = temp_file_for(&plist_path)?;
+            fs::copy(local, tmp.path()).with_context(|| {
+                format!(
+                    "Failed to copy {} to {}",
+                    local.display(),
+                    plist_path.display()
+                )
+            })?;
+            persist(tmp, &plist_path)?;
+        }
+        Ok(hash_str)
+    } else if let Some(remote) = remote {
+        let tmpfile = pdir.join(blake3::hash(remote.as_bytes()).to_string());
+        let hash_str = download_to_file_compute_hash(remote, &tmpfile)?.to_string();
+        if let Err(e) = validate(&tmpfile) {
+            fs::remove_file(&tmpfile)?;
+            return Err(e.context(format!("The file downloaded from {} is invalid", remote)));
+        }
+        let plist_path = pdir.join(&hash_str);
+        if plist_path.is_file() {
+            info!("Found a cached, content-equivalent file; will use the existing file.");
+            fs::remove_file(tmpfile)?;
+        } else {
+            info!("Copying {} to {}", remote, plist_path.display());
+            fs::rename(tmpfile, plist_path)?;
+        }
+        Ok(hash_str)
+    } else {
+        bail!("Either a local file or a remote url must be provided.");
+    }
+}
+
+/// Increment the patch version of a chemistry whose definition was modified
+/// locally, so that `chemistry refresh` does not revert the modification unless
+/// the upstream definition is newer.
+fn bump_chemistry_version(chem: &mut CustomChemistry) -> Result<()> {
+    let mut version = parse_chemistry_version(chem.version(), "bumping the chemistry version")?;
+    version.patch += 1;
+    version.pre = semver::Prerelease::EMPTY;
+    version.build = semver::BuildMetadata::EMPTY;
+    info!(
+        "Updating the version of chemistry {} from {} to {}",
+        chem.name(),
+        chem.version(),
+        version
+    );
+    chem.version = version.to_string();
+    Ok(())
+}
 
+/// Apply `update` to the registered chemistry `name` and, if it made a change,
+/// bump the chemistry's version and write the registry, recording the change as done
+/// by `operation`.
+fn update_registered_chemistry(
+    af_home_path: &Path,
+    operation: &str,
+    name: &str,
+    update: impl FnOnce(&mut CustomChemistry) -> Result<bool>,
+) -> Result<()> {
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let mut chem_hm = get_custom_chem_hm(&chem_p)?;
+    let Some(chem) = chem_hm.get_mut(name) else {
+        bail!(
+            "The chemistry {} is not registered; please add it with `simpleaf chemistry add` first.",
+            name
+        );
+    };
+    if update(chem)? {
+        bump_chemistry_version(chem)?;
+        let v = custom_chem_hm_into_json(chem_hm)?;
+        write_registry(&chem_p, operation, &v)?;
+    }
     Ok(())
 }
 
+/// Register a probe set CSV (local, remote, or both) for an organism with a
+/// registered chemistry. The file is validated and cached in
+/// `ALEVIN_FRY_HOME/plist` under its content hash.
+pub fn add_probe_set(af_home_path: PathBuf, add_opts: ProbeSetAddOpts) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let plist_name = cache_plist_file(
+        &af_home_path,
+        add_opts.csv.as_deref(),
+        add_opts.url.as_deref(),
+        validate_probe_set_csv,
+    )?;
+    let name = match add_opts.name {
+        Some(name) => name,
+        None => {
+            let source = add_opts
+                .csv
+                .as_ref()
+                .and_then(|p| p.file_stem())
+                .map(|s| s.to_string_lossy().into_owned())
+                .or_else(|| {
+                    add_opts
+                        .url
+                        .as_deref()
+                        .and_then(|u| u.trim_end_matches('/').rsplit('/').next())
+                        .map(|s| s.trim_end_matches(".csv").to_string())
+                });
+            source.unwrap_or_else(|| plist_name.clone())
+        }
+    };
+    let organism = add_opts.organism.to_string();
+    let probe_set = ProbeSetInfo {
+        name,
+        plist_name: Some(plist_name),
+        remote_url: add_opts.url,
+    };
+
+    update_registered_chemistry(
+        &af_home_path,
+        "probe-set add",
+        &add_opts.chemistry,
+        |chem| {
+            let probe_sets = chem.probe_sets.get_or_insert_with(Default::default);
+            if probe_sets.get(&organism) == Some(&probe_set) {
+                info!(
+                    "The probe set is already registered for {} with chemistry {}; nothing to update.",
+                    organism, add_opts.chemistry
+                );
+                return Ok(false);
+            }
+            info!(
+                "Registering probe set {} for {} with chemistry {}",
+                probe_set.name, organism, add_opts.chemistry
+            );
+            probe_sets.insert(organism.clone(), probe_set);
+            Ok(true)
+        },
+    )
+}
+
+/// Remove the probe set of an organism from a registered chemistry. The cached
+/// file is left in place for `chemistry clean`.
+pub fn remove_probe_set(af_home_path: PathBuf, remove_opts: ProbeSetRemoveOpts) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let organism = remove_opts.organism.to_string();
+    update_registered_chemistry(
+        &af_home_path,
+        "probe-set remove",
+        &remove_opts.chemistry,
+        |chem| {
+            let removed = chem
+                .probe_sets
+                .as_mut()
+                .and_then(|probe_sets| probe_sets.remove(&organism));
+            if chem.probe_sets.as_ref().is_some_and(|p| p.is_empty()) {
+                chem.probe_sets = None;
+            }
+            match removed {
+                Some(probe_set) => {
+                    info!(
+                        "Removed probe set {} for {} from chemistry {}",
+                        probe_set.name, organism, remove_opts.chemistry
+                    );
+                    Ok(true)
+                }
+                None => {
+                    info!(
+                        "Chemistry {} has no probe set for {}; nothing to remove.",
+                        remove_opts.chemistry, organism
+                    );
+                    Ok(false)
+                }
+            }
+        },
+    )
+}
+
+/// Register the sample barcode list (local, remote, or both) of a registered
+/// chemistry. The file is validated and cached in `ALEVIN_FRY_HOME/plist` under
+/// its content hash.
+pub fn set_sample_bc_list(af_home_path: PathBuf, set_opts: SampleBcSetOpts) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let plist_name = cache_plist_file(
+        &af_home_path,
+        set_opts.tsv.as_deref(),
+        set_opts.url.as_deref(),
+        validate_sample_bc_list,
+    )?;
+    update_registered_chemistry(
+        &af_home_path,
+        "sample-bc set",
+        &set_opts.chemistry,
+        |chem| {
+            let sample_bc_ori = set_opts.sample_bc_ori.or_else(|| {
+                chem.sample_bc_list
+                    .as_ref()
+                    .and_then(|s| s.sample_bc_ori.clone())
+            });
+            let sample_bc_list = SampleBcListInfo {
+                plist_name: Some(plist_name),
+                remote_url: set_opts.url,
+                sample_bc_ori,
+            };
+            if chem.sample_bc_list.as_ref() == Some(&sample_bc_list) {
+                info!(
+                    "The sample barcode list is already registered with chemistry {}; nothing to update.",
+                    set_opts.chemistry
+                );
+                return Ok(false);
+            }
+            info!(
+                "Registering the sample barcode list with chemistry {}",
+                set_opts.chemistry
+            );
+            chem.sample_bc_list = Some(sample_bc_list);
+            Ok(true)
+        },
+    )
+}
+
+/// Register the cell barcode translation list (local, remote, or both) of a
+/// registered chemistry. The file is validated and cached in
+/// `ALEVIN_FRY_HOME/plist` under its content hash.
+pub fn set_translation_list(af_home_path: PathBuf, set_opts: TranslationListSetOpts) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let plist_name = cache_plist_file(
+        &af_home_path,
+        set_opts.tsv.as_deref(),
+        set_opts.url.as_deref(),
+        validate_translation_list,
+    )?;
+    let translation_list = TranslationListInfo {
+        plist_name: Some(plist_name),
+        remote_url: set_opts.url,
+    };
+    update_registered_chemistry(
+        &af_home_path,
+        "translation-list set",
+        &set_opts.chemistry,
+        |chem| {
+            if chem.translation_list.as_ref() == Some(&translation_list) {
+                info!(
+                    "The translation list is already registered with chemistry {}; nothing to update.",
+                    set_opts.chemistry
+                );
+                return Ok(false);
+            }
+            info!(
+                "Registering the translation list with chemistry {}",
+                set_opts.chemistry
+            );
+            chem.translation_list = Some(translation_list);
+            Ok(true)
+        },
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        add_chemistry, clean_chemistries, merge_deprecated_registry_entries,
+        add_chemistry, add_probe_set, alias_chemistry, clean_chemistries,
+        clean_registry_permit_lists, fetch_chemistries, merge_deprecated_registry_entries,
         merge_registry_entries, parse_chemistry_version, removable_permit_lists, remove_chemistry,
+        remove_probe_set, set_sample_bc_list, set_translation_list,
+    };
+    use crate::simpleaf_commands::{
+        ChemistryAddOpts, ChemistryAliasOpts, ChemistryCleanOpts, ChemistryFetchOpts,
+        ChemistryRemoveOpts, ProbeSetAddOpts, ProbeSetRemoveOpts, SampleBcSetOpts,
+        TranslationListSetOpts,
     };
-    use crate::simpleaf_commands::{ChemistryAddOpts, ChemistryCleanOpts, ChemistryRemoveOpts};
+    use crate::utils::chem_registry::ChemistryRegistry;
+    use crate::utils::chem_utils::Organism;
     use crate::utils::constants::CHEMISTRIES_PATH;
     use serde_json::{Map, Value, json};
     use std::collections::HashSet;
@@ -907,6 +1508,74 @@ mod tests {
         assert_eq!(existing["chem_a"]["version"], json!("0.9.0"));
     }
 
+    #[test]
+    fn merge_registry_entries_preserves_user_aliases() {
+        let mut existing = Map::new();
+        existing.insert(
+            "chem_a".to_string(),
+            json!({"geometry":"1{b[16]u[12]x:}2{r:}","version":"1.0.0","aliases":["mine","shared"]}),
+        );
+        existing.insert(
+            "chem_b".to_string(),
+            json!({"geometry":"1{b[16]u[12]x:}2{r:}","version":"1.0.0","aliases":["b"]}),
+        );
+        let mut incoming = Map::new();
+        incoming.insert(
+            "chem_a".to_string(),
+            json!({
+                "geometry":"1{b[16]u[10]x:}2{r:}",
+                "version":"1.1.0",
+                "aliases":["shared","upstream"],
+                "deprecated":{"since":"1.1.0","replaced_by":"chem_c"}
+            }),
+        );
+        incoming.insert(
+            "chem_b".to_string(),
+            json!({"geometry":"1{b[16]u[10]x:}2{r:}","version":"1.1.0"}),
+        );
+
+        merge_registry_entries(&mut existing, &incoming, false, "").unwrap();
+        assert_eq!(existing["chem_a"]["version"], json!("1.1.0"));
+        assert_eq!(
+            existing["chem_a"]["aliases"],
+            json!(["shared", "upstream", "mine"])
+        );
+        assert_eq!(
+            existing["chem_a"]["deprecated"]["replaced_by"],
+            json!("chem_c")
+        );
+        assert_eq!(existing["chem_b"]["aliases"], json!(["b"]));
+    }
+
+    #[test]
+    fn aliases_are_added_and_removed_without_a_version_bump() {
+        let tmp = tempdir().unwrap();
+        write_registry(
+            tmp.path(),
+            &json!({
+                "chem_a": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" },
+                "chem_b": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0", "aliases": ["b"] }
+            }),
+        );
+        let alias_opts = |add: &[&str], remove: &[&str]| ChemistryAliasOpts {
+            chemistry: "chem_a".to_string(),
+            add: add.iter().map(|a| a.to_string()).collect(),
+            remove: remove.iter().map(|a| a.to_string()).collect(),
+        };
+
+        alias_chemistry(tmp.path().to_path_buf(), alias_opts(&["a", "first"], &[])).unwrap();
+        let registry = read_registry(tmp.path());
+        assert_eq!(registry["chem_a"]["aliases"], json!(["a", "first"]));
+        assert_eq!(registry["chem_a"]["version"], json!("0.1.0"));
+
+        alias_chemistry(tmp.path().to_path_buf(), alias_opts(&[], &["first"])).unwrap();
+        assert_eq!(read_registry(tmp.path())["chem_a"]["aliases"], json!(["a"]));
+
+        // names and aliases of other chemistries can't be taken
+        assert!(alias_chemistry(tmp.path().to_path_buf(), alias_opts(&["chem_b"], &[])).is_err());
+        assert!(alias_chemistry(tmp.path().to_path_buf(), alias_opts(&["b"], &[])).is_err());
+    }
+
     #[test]
     fn merge_deprecated_registry_entries_inserts_only_valid_missing_entries() {
         let mut existing = Map::new();
@@ -1066,6 +1735,38 @@ mod tests {
         assert!(!remove.exists());
     }
 
+    #[test]
+    fn clean_keeps_the_files_of_lower_layer_chemistries() {
+        let tmp = tempdir().unwrap();
+        let af_home = tmp.path().join("af_home");
+        let team = tmp.path().join("team");
+        fs::create_dir_all(af_home.join("plist")).unwrap();
+        fs::create_dir_all(&team).unwrap();
+        write_registry(
+            &af_home,
+            &json!({ "mine": { "geometry": "1{b[16]u[12]x:}2{r:}", "plist_name": "mine_pl" } }),
+        );
+        write_registry(
+            &team,
+            &json!({ "shared": { "geometry": "1{b[16]u[12]x:}2{r:}", "plist_name": "team_pl" } }),
+        );
+        // the permit list of the team chemistry was downloaded into the user cache
+        let plist_dir = af_home.join("plist");
+        for f in ["mine_pl", "team_pl", "orphan_pl"] {
+            fs::write(plist_dir.join(f), f).unwrap();
+        }
+
+        let chem_path = std::env::join_paths([team.clone()]).unwrap();
+        let registry = ChemistryRegistry::with_chemistry_path(
+            &af_home.join(CHEMISTRIES_PATH),
+            Some(chem_path.as_os_str()),
+        );
+        clean_registry_permit_lists(&af_home, &registry, false).unwrap();
+        assert!(plist_dir.join("mine_pl").exists());
+        assert!(plist_dir.join("team_pl").exists());
+        assert!(!plist_dir.join("orphan_pl").exists());
+    }
+
     #[test]
     fn parse_chemistry_version_rejects_invalid_versions() {
         let err = parse_chemistry_version("not-a-version", "unit test").unwrap_err();
@@ -1075,4 +1776,210 @@ mod tests {
             err
         );
     }
+
+    #[test]
+    fn probe_sets_and_sample_bc_lists_are_cached_and_registered_with_version_bumps() {
+        let tmp = tempdir().unwrap();
+        write_registry(
+            tmp.path(),
+            &json!({
+                "myflex": {
+                    "geometry": "1{b[16]u[12]x:}2{r[50]x[18]s[8]x:}",
+                    "expected_ori": "both",
+                    "version": "0.1.0"
+                }
+            }),
+        );
+        let csv = tmp.path().join("my_probes.csv");
+        fs::write(
+            &csv,
+            "#panel_name=test\ngene_id,probe_seq,probe_id,included\nG1,ACGTACGTAC,G1|p1,TRUE\nG2,TTGGCCAATT,G2|p2,TRUE\n",
+        )
+        .unwrap();
+        let sample_bcs = tmp.path().join("sample_bcs.tsv");
+        fs::write(
+            &sample_bcs,
+            "observed\tcanonical\tsample_name\nACGTACGT\tACGTACGT\tBC001\nTTGGCCAA\tACGTACGT\tBC001\n",
+        )
+        .unwrap();
+
+        let add_opts = ProbeSetAddOpts {
+            chemistry: "myflex".to_string(),
+            organism: Organism::Human,
+            csv: Some(csv.clone()),
+            url: None,
+            name: None,
+        };
+        add_probe_set(tmp.path().to_path_buf(), add_opts.clone()).unwrap();
+        let registry = read_registry(tmp.path());
+        let probe_set = &registry["myflex"]["probe_sets"]["human"];
+        assert_eq!(probe_set["name"], json!("my_probes"));
+        let hash = probe_set["plist_name"].as_str().unwrap().to_string();
+        assert_eq!(
+            fs::read(tmp.path().join("plist").join(&hash)).unwrap(),
+            fs::read(&csv).unwrap()
+        );
+        assert_eq!(registry["myflex"]["version"], json!("0.1.1"));
+
+        // registering the same probe set again changes nothing
+        add_probe_set(tmp.path().to_path_buf(), add_opts).unwrap();
+        assert_eq!(
+            read_registry(tmp.path())["myflex"]["version"],
+            json!("0.1.1")
+        );
+
+        set_sample_bc_list(
+            tmp.path().to_path_buf(),
+            SampleBcSetOpts {
+                chemistry: "myflex".to_string(),
+                tsv: Some(sample_bcs),
+                url: None,
+                sample_bc_ori: Some("reverse".to_string()),
+            },
+        )
+        .unwrap();
+        let registry = read_registry(tmp.path());
+        assert_eq!(
+            registry["myflex"]["sample_bc_list"]["sample_bc_ori"],
+            json!("reverse")
+        );
+        assert_eq!(registry["myflex"]["version"], json!("0.1.2"));
+
+        // files that are not valid are not registered
+        let bad = tmp.path().join("bad.tsv");
+        fs::write(&bad, "ACGT\tACGT\n").unwrap();
+        assert!(
+            set_sample_bc_list(
+                tmp.path().to_path_buf(),
+                SampleBcSetOpts {
+                    chemistry: "myflex".to_string(),
+                    tsv: Some(bad.clone()),
+                    url: None,
+                    sample_bc_ori: None,
+                },
+            )
+            .is_err()
+        );
+        assert!(
+            add_probe_set(
+                tmp.path().to_path_buf(),
+                ProbeSetAddOpts {
+                    chemistry: "myflex".to_string(),
+                    organism: Organism::Mouse,
+                    csv: Some(bad),
+                    url: None,
+                    name: None,
+                },
+            )
+            .is_err()
+        );
+        assert_eq!(
+            read_registry(tmp.path())["myflex"]["version"],
+            json!("0.1.2")
+        );
+
+        // registered files survive `clean`
+        clean_chemistries(
+            tmp.path().to_path_buf(),
+            ChemistryCleanOpts { dry_run: false },
+        )
+        .unwrap();
+        assert!(tmp.path().join("plist").join(&hash).is_file());
+
+        remove_probe_set(
+            tmp.path().to_path_buf(),
+            ProbeSetRemoveOpts {
+                chemistry: "myflex".to_string(),
+                organism: Organism::Human,
+            },
+        )
+        .unwrap();
+        let registry = read_registry(tmp.path());
+        assert!(registry["myflex"].get("probe_sets").is_none());
+        assert_eq!(registry["myflex"]["version"], json!("0.1.3"));
+
+        // a chemistry must be registered first
+        assert!(
+            remove_probe_set(
+                tmp.path().to_path_buf(),
+                ProbeSetRemoveOpts {
+                    chemistry: "unknown".to_string(),
+                    organism: Organism::Human,
+                },
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn translation_lists_are_cached_registered_and_kept_by_clean() {
+        let tmp = tempdir().unwrap();
+        write_registry(
+            tmp.path(),
+            &json!({
+                "my3p": {
+                    "geometry": "1{b[16]u[12]x:}2{r:}",
+                    "expected_ori": "fw",
+                    "version": "0.1.0"
+                }
+            }),
+        );
+        let list = tmp.path().join("translation.tsv");
+        fs::write(
+            &list,
+            "AAACCCAAGAAACACT\tAAACCCAAGAAACACT\nAAACCCAAGAAACCAT\tAAACCCAAGAAACCCA\n",
+        )
+        .unwrap();
+        let set_opts = TranslationListSetOpts {
+            chemistry: "my3p".to_string(),
+            tsv: Some(list.clone()),
+            url: None,
+        };
+        set_translation_list(tmp.path().to_path_buf(), set_opts.clone()).unwrap();
+        let registry = read_registry(tmp.path());
+        let hash = registry["my3p"]["translation_list"]["plist_name"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            fs::read(tmp.path().join("plist").join(&hash)).unwrap(),
+            fs::read(&list).unwrap()
+        );
+        assert_eq!(registry["my3p"]["version"], json!("0.1.1"));
+
+        // registering the same list again changes nothing
+        set_translation_list(tmp.path().to_path_buf(), set_opts).unwrap();
+        assert_eq!(read_registry(tmp.path())["my3p"]["version"], json!("0.1.1"));
+
+        // a barcode can only be translated once
+        let bad = tmp.path().join("bad.tsv");
+        fs::write(&bad, "AAAC\tAAAC\nAAAC\tAAAG\n").unwrap();
+        assert!(
+            set_translation_list(
+                tmp.path().to_path_buf(),
+                TranslationListSetOpts {
+                    chemistry: "my3p".to_string(),
+                    tsv: Some(bad),
+                    url: None,
+                },
+            )
+            .is_err()
+        );
+
+        // the registered list survives `clean` and needs no download
+        clean_chemistries(
+            tmp.path().to_path_buf(),
+            ChemistryCleanOpts { dry_run: false },
+        )
+        .unwrap();
+        assert!(tmp.path().join("plist").join(&hash).is_file());
+        fetch_chemistries(
+            tmp.path().to_path_buf(),
+            ChemistryFetchOpts {
+                name: vec!["my3p".to_string()],
+                dry_run: false,
+            },
+        )
+        .unwrap();
+    }
 }
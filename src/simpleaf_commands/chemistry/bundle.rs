@@ -0,0 +1,532 @@
+//! `chemistry export` / `chemistry import`: moving registry entries, together with
+//! their cached files, to machines without internet access.
+//!
+//! A bundle is a gzipped tarball holding
+//!
+//! - `chemistries.json`: the exported registry entries;
+//! - `bundle_info.json`: the [`BundleInfo`], with the Blake3 hash of every file;
+//! - `plist/<name>`: the permit lists, sample barcode lists, probe sets and translation
+//!   lists of the entries, under their names in `ALEVIN_FRY_HOME/plist`.
+
+use super::{fetch_registry_file, lock_registry, merge_registry_entries, write_registry};
+use crate::simpleaf_commands::{ChemistryExportOpts, ChemistryImportOpts};
+use crate::utils::af_utils::{create_dir_if_absent, parse_resource_json_file};
+use crate::utils::chem_registry::ChemistryRegistry;
+use crate::utils::chem_utils::CustomChemistry;
+use crate::utils::constants::CHEMISTRIES_PATH;
+
+use anyhow::{Context, Result, bail};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use tracing::{info, warn};
+
+const BUNDLE_INFO_NAME: &str = "bundle_info.json";
+const BUNDLE_PLIST_DIR: &str = "plist";
+
+/// The description of the contents of a bundle.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BundleInfo {
+    simpleaf_version: String,
+    chemistries: Vec<String>,
+    /// the Blake3 hash of every file of the bundle, keyed by its name in `plist/`.
+    files: BTreeMap<String, String>,
+}
+
+/// The cached files of `cc`, as (what, name, remote URL) triplets.
+fn chemistry_files(cc: &CustomChemistry) -> Vec<(&'static str, &str, Option<&str>)> {
+    let mut files = Vec::new();
+    if let Some(name) = cc.plist_name() {
+        files.push(("permit list", name.as_str(), cc.remote_pl_url().as_deref()));
+    }
+    if let Some(sbc) = &cc.sample_bc_list
+        && let Some(name) = &sbc.plist_name
+    {
+        files.push((
+            "sample barcode list",
+            name.as_str(),
+            sbc.remote_url.as_deref(),
+        ));
+    }
+    if let Some(probe_sets) = &cc.probe_sets {
+        for ps in probe_sets.values() {
+            if let Some(name) = &ps.plist_name {
+                files.push(("probe set", name.as_str(), ps.remote_url.as_deref()));
+            }
+        }
+    }
+    if let Some(tl) = &cc.translation_list
+        && let Some(name) = &tl.plist_name
+    {
+        files.push(("translation list", name.as_str(), tl.remote_url.as_deref()));
+    }
+    files
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_mmap(path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(hasher.finalize().to_string())
+}
+
+/// Whether `name` is a Blake3 hash, i.e. the name of a file cached under its content hash.
+fn is_hash_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Whether `name` is a plain file name, that cannot escape the directory it is joined to.
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    )
+}
+
+fn append_json<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    value: &Value,
+) -> Result<()> {
+    let content = serde_json::to_vec_pretty(value)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, name, content.as_slice())
+        .with_context(|| format!("Could not add {} to the bundle", name))
+}
+
+/// Package the registry entries matching the `--name` regex, with their cached files,
+/// into a gzipped tarball. Files that are not cached yet are downloaded first.
+pub fn export_chemistries(af_home_path: PathBuf, export_opts: ChemistryExportOpts) -> Result<()> {
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    if !chem_p.is_file() {
+        bail!(
+            "The chemistry registry {} does not exist; Nothing to export. Please issue the `refresh` command to obtain it.",
+            chem_p.display()
+        );
+    }
+    let registry = parse_resource_json_file(&chem_p, None)?;
+    let Some(registry) = registry.as_object() else {
+        bail!(
+            "Could not parse the chemistry registry {} as a JSON object.",
+            chem_p.display()
+        );
+    };
+
+    let Ok(name_re) = regex::Regex::new(&export_opts.name) else {
+        bail!(
+            "The provided chemistry name {} was neither a valid chemistry name nor a valid regex.",
+            export_opts.name
+        );
+    };
+    let mut selected: Vec<&String> = registry.keys().filter(|k| name_re.is_match(k)).collect();
+    selected.sort();
+    if selected.is_empty() {
+        bail!(
+            "No chemistry with name \"{}\" (or matching this as a regex) was found in the registry; Nothing to export.",
+            export_opts.name
+        );
+    }
+
+    let plist_path = af_home_path.join("plist");
+    create_dir_if_absent(&plist_path)?;
+    // only the files of the user layer are exported
+    let user_layer = ChemistryRegistry::with_chemistry_path(&chem_p, None);
+    let mut entries = Map::new();
+    let mut files = BTreeMap::new();
+    for k in &selected {
+        let v = &registry[k.as_str()];
+        let cc: CustomChemistry = serde_json::from_value(v.clone())
+            .with_context(|| format!("The registry entry of chemistry {} is invalid", k))?;
+        for (what, name, remote) in chemistry_files(&cc) {
+            if !is_plain_file_name(name) {
+                bail!(
+                    "The {} of chemistry {} has the invalid file name {}.",
+                    what,
+                    k,
+                    name
+                );
+            }
+            let path = plist_path.join(name);
+            if !path.is_file() {
+                fetch_registry_file(&user_layer, &plist_path, k, what, name, remote, false)?;
+            }
+            if !path.is_file() {
+                bail!(
+                    "The {} of chemistry {} is neither cached in {} nor could it be downloaded; Cannot export it.",
+                    what,
+                    k,
+                    plist_path.display()
+                );
+            }
+            let hash = hash_file(&path)?;
+            if is_hash_name(name) && hash != name {
+                bail!(
+                    "The cached {} of chemistry {} ({}) is corrupted; Please run `simpleaf chemistry validate --fix` before exporting.",
+                    what,
+                    k,
+                    path.display()
+                );
+            }
+            files.insert(name.to_string(), hash);
+        }
+        entries.insert(k.to_string(), v.clone());
+    }
+
+    let info = BundleInfo {
+        simpleaf_version: env!("CARGO_PKG_VERSION").to_string(),
+        chemistries: selected.iter().map(|k| k.to_string()).collect(),
+        files,
+    };
+
+    let out = fs::File::create(&export_opts.output)
+        .with_context(|| format!("Could not create {}", export_opts.output.display()))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    append_json(&mut builder, CHEMISTRIES_PATH, &Value::Object(entries))?;
+    append_json(
+        &mut builder,
+        BUNDLE_INFO_NAME,
+        &serde_json::to_value(&info)?,
+    )?;
+    for name in info.files.keys() {
+        builder
+            .append_path_with_name(
+                plist_path.join(name),
+                Path::new(BUNDLE_PLIST_DIR).join(name),
+            )
+            .with_context(|| format!("Could not add {} to the bundle", name))?;
+    }
+    builder.into_inner()?.finish()?;
+
+    info!(
+        "Exported {} chemistries and {} file(s) to {}.",
+        info.chemistries.len(),
+        info.files.len(),
+        export_opts.output.display()
+    );
+    Ok(())
+}
+
+/// Unpack the bundle at `bundle` into `dir`, accepting only the entries a bundle may hold.
+fn unpack_bundle(bundle: &Path, dir: &Path) -> Result<()> {
+    let f =
+        fs::File::open(bundle).with_context(|| format!("Could not open {}", bundle.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(f));
+    fs::create_dir_all(dir.join(BUNDLE_PLIST_DIR))?;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("Could not read the bundle {}", bundle.display()))?
+    {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let name = path.to_string_lossy().into_owned();
+        let dest = match name.split_once('/') {
+            None if name == CHEMISTRIES_PATH || name == BUNDLE_INFO_NAME => dir.join(&name),
+            Some((BUNDLE_PLIST_DIR, file))
+                if entry.header().entry_type().is_file() && is_plain_file_name(file) =>
+            {
+                dir.join(BUNDLE_PLIST_DIR).join(file)
+            }
+            _ => bail!(
+                "The bundle {} has the unexpected entry {}; Is it a chemistry bundle?",
+                bundle.display(),
+                name
+            ),
+        };
+        entry
+            .unpack(&dest)
+            .with_context(|| format!("Could not unpack {} from the bundle", name))?;
+    }
+    Ok(())
+}
+
+/// Merge the registry entries of a bundle written by `chemistry export` into the local
+/// registry, following the version rules of `refresh`, and place its files in
+/// `ALEVIN_FRY_HOME/plist` after verifying their hashes.
+pub fn import_chemistries(af_home_path: PathBuf, import_opts: ChemistryImportOpts) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let dry_run_pref = if import_opts.dry_run {
+        "[dry_run] : "
+    } else {
+        ""
+    };
+    create_dir_if_absent(&af_home_path)?;
+    // unpack next to the cache, so that the files can be moved into place
+    let unpack_dir = tempfile::tempdir_in(&af_home_path)?;
+    unpack_bundle(&import_opts.bundle, unpack_dir.path())?;
+
+    let info_p = unpack_dir.path().join(BUNDLE_INFO_NAME);
+    let bundled_chem_p = unpack_dir.path().join(CHEMISTRIES_PATH);
+    if !info_p.is_file() || !bundled_chem_p.is_file() {
+        bail!(
+            "The bundle {} lacks its {} or {}; Is it a chemistry bundle?",
+            import_opts.bundle.display(),
+            BUNDLE_INFO_NAME,
+            CHEMISTRIES_PATH
+        );
+    }
+    let info: BundleInfo = serde_json::from_value(parse_resource_json_file(&info_p, None)?)
+        .with_context(|| format!("Could not parse the {} of the bundle", BUNDLE_INFO_NAME))?;
+    let incoming = parse_resource_json_file(&bundled_chem_p, None)?;
+    let Some(incoming) = incoming.as_object() else {
+        bail!(
+            "Could not parse the {} of the bundle as a JSON object.",
+            CHEMISTRIES_PATH
+        );
+    };
+
+    // verify every file before touching the registry or the cache
+    let bundled_plist = unpack_dir.path().join(BUNDLE_PLIST_DIR);
+    for (name, expected) in &info.files {
+        if !is_plain_file_name(name) {
+            bail!("The bundle lists the invalid file name {}.", name);
+        }
+        let path = bundled_plist.join(name);
+        if !path.is_file() {
+            bail!("The bundle lacks the file {} it lists.", name);
+        }
+        let observed = hash_file(&path)?;
+        if &observed != expected {
+            bail!(
+                "The file {} of the bundle is corrupted: its Blake3 hash is {}, but {} was expected.",
+                name,
+                observed,
+                expected
+            );
+        }
+        // the registry refers to the file by its name, which must be its content hash
+        if is_hash_name(name) && &observed != name {
+            bail!(
+                "The file {} of the bundle is misnamed: cached files are named after their Blake3 hash, which is {}.",
+                name,
+                observed
+            );
+        }
+    }
+    let plist_path = af_home_path.join("plist");
+    for (k, v) in incoming {
+        let cc: CustomChemistry = serde_json::from_value(v.clone())
+            .with_context(|| format!("The bundled entry of chemistry {} is invalid", k))?;
+        for (what, name, _) in chemistry_files(&cc) {
+            if !info.files.contains_key(name) && !plist_path.join(name).is_file() {
+                warn!(
+                    "{}The {} of chemistry {} is neither in the bundle nor cached locally.",
+                    dry_run_pref, what, k
+                );
+            }
+        }
+    }
+
+    if import_opts.dry_run {
+        for name in info.files.keys() {
+            if !plist_path.join(name).is_file() {
+                info!(
+                    "{}Would place the file {} in the cache.",
+                    dry_run_pref, name
+                );
+            }
+        }
+    } else {
+        create_dir_if_absent(&plist_path)?;
+        for name in info.files.keys() {
+            let dest = plist_path.join(name);
+            if dest.is_file() && hash_file(&dest)? == info.files[name] {
+                continue;
+            }
+            fs::rename(bundled_plist.join(name), &dest)
+                .with_context(|| format!("Could not place {} in the cache", dest.display()))?;
+        }
+    }
+
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let mut registry = if chem_p.is_file() {
+        match parse_resource_json_file(&chem_p, None)? {
+            Value::Object(registry) => registry,
+            _ => bail!(
+                "Could not parse the chemistry registry {} as a JSON object.",
+                chem_p.display()
+            ),
+        }
+    } else {
+        Map::new()
+    };
+    for k in incoming.keys() {
+        if !registry.contains_key(k) {
+            info!("{}adding {}", dry_run_pref, k);
+        }
+    }
+    merge_registry_entries(&mut registry, incoming, import_opts.force, dry_run_pref)?;
+    if !import_opts.dry_run {
+        write_registry(&chem_p, "import", &Value::Object(registry))?;
+        info!(
+            "Imported {} chemistries and {} file(s) from {}.",
+            incoming.len(),
+            info.files.len(),
+            import_opts.bundle.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const GEO: &str = "1{b[16]u[12]x:}2{r:}";
+
+    fn write_cached(af_home: &Path, content: &str) -> String {
+        let plist = af_home.join("plist");
+        fs::create_dir_all(&plist).unwrap();
+        let hash = blake3::hash(content.as_bytes()).to_string();
+        fs::write(plist.join(&hash), content).unwrap();
+        hash
+    }
+
+    fn read_registry(af_home: &Path) -> Map<String, Value> {
+        serde_json::from_str(&fs::read_to_string(af_home.join(CHEMISTRIES_PATH)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn exported_chemistries_are_imported_with_their_files() {
+        let src = tempfile::tempdir().unwrap();
+        let pl = write_cached(src.path(), "AAAA\nCCCC\n");
+        let tl = write_cached(src.path(), "AAAA CCCC\n");
+        fs::write(
+            src.path().join(CHEMISTRIES_PATH),
+            json!({
+                "chem_a": {
+                    "geometry": GEO, "expected_ori": "fw", "version": "0.2.0",
+                    "plist_name": pl, "translation_list": { "plist_name": tl }
+                },
+                "chem_b": { "geometry": GEO, "expected_ori": "fw", "version": "0.1.0" },
+                "other": { "geometry": GEO, "expected_ori": "fw", "version": "0.1.0" }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let bundle = src.path().join("bundle.tar.gz");
+        export_chemistries(
+            src.path().to_path_buf(),
+            ChemistryExportOpts {
+                name: String::from("^chem_"),
+                output: bundle.clone(),
+            },
+        )
+        .unwrap();
+
+        // the destination already has an older chem_a and a newer chem_b
+        let dst = tempfile::tempdir().unwrap();
+        fs::write(
+            dst.path().join(CHEMISTRIES_PATH),
+            json!({
+                "chem_a": { "geometry": GEO, "expected_ori": "fw", "version": "0.1.0" },
+                "chem_b": { "geometry": GEO, "expected_ori": "rc", "version": "0.3.0" }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let import = |dry_run| {
+            import_chemistries(
+                dst.path().to_path_buf(),
+                ChemistryImportOpts {
+                    bundle: bundle.clone(),
+                    force: false,
+                    dry_run,
+                },
+            )
+        };
+        import(true).unwrap();
+        assert_eq!(read_registry(dst.path())["chem_a"]["version"], "0.1.0");
+        assert!(!dst.path().join("plist").join(&pl).exists());
+
+        import(false).unwrap();
+        let registry = read_registry(dst.path());
+        assert_eq!(
+            registry.keys().collect::<Vec<_>>(),
+            vec!["chem_a", "chem_b"]
+        );
+        assert_eq!(registry["chem_a"]["version"], "0.2.0");
+        assert_eq!(registry["chem_b"]["expected_ori"], "rc");
+        for name in [&pl, &tl] {
+            let cached = dst.path().join("plist").join(name);
+            assert_eq!(&hash_file(&cached).unwrap(), name);
+        }
+    }
+
+    /// Write a bundle of a chemistry whose permit list `name` has the JSON `content`,
+    /// listed in the bundle info with the hash `listed`.
+    fn write_bundle(bundle: &Path, name: &str, listed: String, content: &Value) {
+        let info = BundleInfo {
+            simpleaf_version: String::from("0.0.0"),
+            chemistries: vec![String::from("chem")],
+            files: BTreeMap::from([(name.to_string(), listed)]),
+        };
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            fs::File::create(bundle).unwrap(),
+            Compression::default(),
+        ));
+        let chems = json!({ "chem": { "geometry": GEO, "version": "0.1.0", "plist_name": name } });
+        append_json(&mut builder, CHEMISTRIES_PATH, &chems).unwrap();
+        append_json(
+            &mut builder,
+            BUNDLE_INFO_NAME,
+            &serde_json::to_value(&info).unwrap(),
+        )
+        .unwrap();
+        append_json(&mut builder, &format!("plist/{}", name), content).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn corrupted_bundles_are_rejected() {
+        let src = tempfile::tempdir().unwrap();
+        let bundle = src.path().join("bundle.tar.gz");
+        let import = |dst: &Path| {
+            import_chemistries(
+                dst.to_path_buf(),
+                ChemistryImportOpts {
+                    bundle: bundle.clone(),
+                    force: false,
+                    dry_run: false,
+                },
+            )
+        };
+
+        write_bundle(
+            &bundle,
+            "pl",
+            blake3::hash(b"AAAA\n").to_string(),
+            &json!("CCCC"),
+        );
+        let dst = tempfile::tempdir().unwrap();
+        let res = import(dst.path());
+        assert!(format!("{:#}", res.unwrap_err()).contains("corrupted"));
+        assert!(!dst.path().join(CHEMISTRIES_PATH).exists());
+        assert!(!dst.path().join("plist").join("pl").exists());
+
+        // a file listed with its own hash, but named after a different one
+        let content = json!("CCCC");
+        let observed = blake3::hash(&serde_json::to_vec_pretty(&content).unwrap()).to_string();
+        let misnamed = blake3::hash(b"AAAA\n").to_string();
+        write_bundle(&bundle, &misnamed, observed, &content);
+        let dst = tempfile::tempdir().unwrap();
+        let res = import(dst.path());
+        assert!(format!("{:#}", res.unwrap_err()).contains("misnamed"));
+        assert!(!dst.path().join(CHEMISTRIES_PATH).exists());
+        assert!(!dst.path().join("plist").join(&misnamed).exists());
+    }
+}
@@ -0,0 +1,245 @@
+//! The differences between the local chemistry registry and the upstream one, as shown
+//! by `chemistry refresh`.
+
+use super::should_replace_registry_entry;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fmt;
+use tabled::{Table, Tabled, settings::Style};
+use tracing::info;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum ChangeKind {
+    /// the chemistry is new upstream, and will be added.
+    Added,
+    /// the upstream definition has a higher version (or `--force` is set), and will
+    /// replace the local one.
+    Updated,
+    /// the upstream definition differs, but does not have a higher version, so the
+    /// local one is kept.
+    Kept,
+    /// the chemistry is only in the local registry: it was removed upstream, or was
+    /// only ever added locally. It stays in the local registry.
+    LocalOnly,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Updated => write!(f, "updated"),
+            ChangeKind::Kept => write!(f, "kept (not newer)"),
+            ChangeKind::LocalOnly => write!(f, "local only"),
+        }
+    }
+}
+
+/// A field of a registry entry that differs between the local and upstream registry.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(super) struct FieldChange {
+    pub field: String,
+    pub local: Option<Value>,
+    pub upstream: Option<Value>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(super) struct EntryDiff {
+    pub chemistry: String,
+    pub change: ChangeKind,
+    pub local_version: Option<String>,
+    pub upstream_version: Option<String>,
+    /// the differing fields; empty for added and local-only chemistries.
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Tabled)]
+struct DiffRow {
+    chemistry: String,
+    change: String,
+    version: String,
+    fields: String,
+}
+
+fn version_of(v: &Value) -> Option<String> {
+    v.get("version").and_then(Value::as_str).map(String::from)
+}
+
+/// A short rendering of a field value for the table.
+fn short(v: Option<&Value>) -> String {
+    match v {
+        None => String::from("-"),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Object(_)) => String::from("{...}"),
+        Some(Value::Array(_)) => String::from("[...]"),
+        Some(v) => v.to_string(),
+    }
+}
+
+fn field_changes(local: &Value, upstream: &Value) -> Vec<FieldChange> {
+    let empty = Map::new();
+    let local = local.as_object().unwrap_or(&empty);
+    let upstream = upstream.as_object().unwrap_or(&empty);
+    local
+        .keys()
+        .chain(upstream.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|k| local.get(*k) != upstream.get(*k))
+        .map(|k| FieldChange {
+            field: k.clone(),
+            local: local.get(k).cloned(),
+            upstream: upstream.get(k).cloned(),
+        })
+        .collect()
+}
+
+/// The differences between the `local` and `upstream` registries, in name order, given
+/// the rules of [`merge_registry_entries`](super::merge_registry_entries).
+/// Chemistries that are identical in both are left out.
+pub(super) fn diff_registries(
+    local: &Map<String, Value>,
+    upstream: &Map<String, Value>,
+    force: bool,
+) -> Result<Vec<EntryDiff>> {
+    let mut diff = Vec::new();
+    for k in local.keys().chain(upstream.keys()).collect::<BTreeSet<_>>() {
+        let entry = match (local.get(k), upstream.get(k)) {
+            (None, Some(up)) => EntryDiff {
+                chemistry: k.clone(),
+                change: ChangeKind::Added,
+                local_version: None,
+                upstream_version: version_of(up),
+                fields: Vec::new(),
+            },
+            (Some(loc), None) => EntryDiff {
+                chemistry: k.clone(),
+                change: ChangeKind::LocalOnly,
+                local_version: version_of(loc),
+                upstream_version: None,
+                fields: Vec::new(),
+            },
+            (Some(loc), Some(up)) if loc != up => EntryDiff {
+                chemistry: k.clone(),
+                change: if should_replace_registry_entry(loc, up, force)? {
+                    ChangeKind::Updated
+                } else {
+                    ChangeKind::Kept
+                },
+                local_version: version_of(loc),
+                upstream_version: version_of(up),
+                fields: field_changes(loc, up),
+            },
+            _ => continue,
+        };
+        diff.push(entry);
+    }
+    Ok(diff)
+}
+
+/// Print the `diff` as a table, or as JSON if `json` is set.
+pub(super) fn print_diff(diff: &[EntryDiff], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(diff)?);
+        return Ok(());
+    }
+    if diff.is_empty() {
+        info!("The local chemistry registry is identical to the upstream one.");
+        return Ok(());
+    }
+    let rows = diff
+        .iter()
+        .map(|d| DiffRow {
+            chemistry: d.chemistry.clone(),
+            change: d.change.to_string(),
+            version: match (&d.local_version, &d.upstream_version) {
+                (Some(l), Some(u)) if l != u => format!("{} -> {}", l, u),
+                (Some(v), _) | (None, Some(v)) => v.clone(),
+                (None, None) => String::from("-"),
+            },
+            fields: d
+                .fields
+                .iter()
+                .filter(|f| f.field != "version")
+                .map(|f| {
+                    format!(
+                        "{}: {} -> {}",
+                        f.field,
+                        short(f.local.as_ref()),
+                        short(f.upstream.as_ref())
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        })
+        .collect::<Vec<_>>();
+    println!("{}", Table::new(rows).with(Style::rounded()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn registries_are_diffed_per_entry_and_field() {
+        let local = json!({
+            "same": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" },
+            "bumped": { "geometry": "1{b[16]u[10]x:}2{r:}", "expected_ori": "fw", "version": "0.1.0" },
+            "not_newer": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.2.0" },
+            "custom": { "geometry": "1{b[12]u[8]x:}2{r:}", "version": "0.1.0" }
+        });
+        let upstream = json!({
+            "same": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" },
+            "bumped": { "geometry": "1{b[16]u[12]x:}2{r:}", "expected_ori": "fw", "version": "0.2.0", "plist_name": "abc" },
+            "not_newer": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" },
+            "new": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" }
+        });
+        let diff = diff_registries(
+            local.as_object().unwrap(),
+            upstream.as_object().unwrap(),
+            false,
+        )
+        .unwrap();
+        let summary = diff
+            .iter()
+            .map(|d| {
+                (
+                    d.chemistry.as_str(),
+                    d.change,
+                    d.fields
+                        .iter()
+                        .map(|f| f.field.as_str())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "bumped",
+                    ChangeKind::Updated,
+                    vec!["geometry", "plist_name", "version"]
+                ),
+                ("custom", ChangeKind::LocalOnly, vec![]),
+                ("new", ChangeKind::Added, vec![]),
+                ("not_newer", ChangeKind::Kept, vec!["version"]),
+            ]
+        );
+        assert_eq!(diff[0].fields[1].local, None);
+        assert_eq!(diff[0].fields[1].upstream, Some(json!("abc")));
+
+        let forced = diff_registries(
+            local.as_object().unwrap(),
+            upstream.as_object().unwrap(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(forced[3].change, ChangeKind::Updated);
+    }
+}
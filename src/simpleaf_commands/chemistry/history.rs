@@ -0,0 +1,327 @@
+//! The history of the changes of the chemistry registry, kept next to it in
+//! `chemistries.history.jsonl`, one JSON [`HistoryRecord`] per line. Every write of
+//! the registry goes through [`write_registry`], which records the entries it changed
+//! with their values before and after, so that `chemistry rollback` can undo it.
+
+use super::write_json_pretty;
+use crate::core::io::FileLock;
+use crate::simpleaf_commands::{ChemistryHistoryOpts, ChemistryRollbackOpts};
+use crate::utils::af_utils::parse_resource_json_file;
+use crate::utils::constants::{CHEMISTRIES_HISTORY_PATH, CHEMISTRIES_LOCK_PATH, CHEMISTRIES_PATH};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tabled::{Table, Tabled, settings::Style};
+use tracing::{info, warn};
+
+/// The change of a single registry entry; `None` means the entry is absent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct EntryChange {
+    chemistry: String,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl EntryChange {
+    /// `+`, `-` or `~` followed by the chemistry name, for an added, removed or
+    /// modified entry.
+    fn summary(&self) -> String {
+        let sign = match (&self.before, &self.after) {
+            (None, _) => '+',
+            (_, None) => '-',
+            _ => '~',
+        };
+        format!("{}{}", sign, self.chemistry)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct HistoryRecord {
+    id: u64,
+    timestamp: String,
+    /// the command that changed the registry, e.g. `add` or `refresh`.
+    operation: String,
+    changes: Vec<EntryChange>,
+}
+
+#[derive(Tabled)]
+struct HistoryRow {
+    id: u64,
+    time: String,
+    operation: String,
+    changes: String,
+}
+
+fn history_path(chem_p: &Path) -> PathBuf {
+    chem_p.with_file_name(CHEMISTRIES_HISTORY_PATH)
+}
+
+fn read_history(chem_p: &Path) -> Result<Vec<HistoryRecord>> {
+    let hist_p = history_path(chem_p);
+    if !hist_p.is_file() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&hist_p)
+        .with_context(|| format!("Could not read {}", hist_p.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| {
+            serde_json::from_str(l)
+                .with_context(|| format!("Could not parse line {} of {}", i + 1, hist_p.display()))
+        })
+        .collect()
+}
+
+/// The entries that differ between `before` and `after`, in name order.
+fn entry_changes(before: &Map<String, Value>, after: &Map<String, Value>) -> Vec<EntryChange> {
+    before
+        .keys()
+        .chain(after.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|k| before.get(*k) != after.get(*k))
+        .map(|k| EntryChange {
+            chemistry: k.clone(),
+            before: before.get(k).cloned(),
+            after: after.get(k).cloned(),
+        })
+        .collect()
+}
+
+/// Append the changes from `before` to `after` made by `operation` to the history of
+/// the registry `chem_p`, if there are any.
+pub(super) fn record_registry_change(
+    chem_p: &Path,
+    operation: &str,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) -> Result<()> {
+    let changes = entry_changes(before, after);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let id = read_history(chem_p)?.last().map_or(1, |r| r.id + 1);
+    let record = HistoryRecord {
+        id,
+        timestamp: chrono::Local::now().to_rfc3339(),
+        operation: operation.to_string(),
+        changes,
+    };
+    let hist_p = history_path(chem_p);
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&hist_p)
+        .with_context(|| format!("Could not open {}", hist_p.display()))?;
+    writeln!(f, "{}", serde_json::to_string(&record)?)
+        .with_context(|| format!("Could not write {}", hist_p.display()))?;
+    Ok(())
+}
+
+fn read_registry_map(chem_p: &Path) -> Option<Map<String, Value>> {
+    if !chem_p.is_file() {
+        return None;
+    }
+    match parse_resource_json_file(chem_p, None) {
+        Ok(Value::Object(registry)) => Some(registry),
+        _ => None,
+    }
+}
+
+/// Lock the registry of `af_home_path` for the duration of a command changing it, so
+/// that concurrent commands neither lose each other's changes nor remove the files
+/// the others are about to register. Commands take it once, as it is not reentrant.
+pub(super) fn lock_registry(af_home_path: &Path) -> Result<FileLock> {
+    FileLock::acquire(&af_home_path.join(CHEMISTRIES_LOCK_PATH))
+}
+
+/// Write `registry` to `chem_p`, recording the entries it changes in the history as
+/// done by `operation`.
+pub(super) fn write_registry(chem_p: &Path, operation: &str, registry: &Value) -> Result<()> {
+    let before = read_registry_map(chem_p).unwrap_or_default();
+    write_json_pretty(chem_p, registry)?;
+    if let Value::Object(after) = registry {
+        record_registry_change(chem_p, operation, &before, after)?;
+    }
+    Ok(())
+}
+
+/// Print the history of the registry, optionally restricted to the records changing a
+/// chemistry matching the `--name` regex.
+pub fn show_history(af_home_path: PathBuf, history_opts: ChemistryHistoryOpts) -> Result<()> {
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let name_re = match &history_opts.name {
+        Some(name) => match regex::Regex::new(name) {
+            Ok(re) => Some(re),
+            Err(_) => bail!(
+                "The provided chemistry name {} was neither a valid chemistry name nor a valid regex.",
+                name
+            ),
+        },
+        None => None,
+    };
+    let rows = read_history(&chem_p)?
+        .into_iter()
+        .filter_map(|r| {
+            let changes = r
+                .changes
+                .iter()
+                .filter(|c| name_re.as_ref().is_none_or(|re| re.is_match(&c.chemistry)))
+                .map(EntryChange::summary)
+                .collect::<Vec<_>>();
+            (!changes.is_empty()).then(|| HistoryRow {
+                id: r.id,
+                time: r.timestamp,
+                operation: r.operation,
+                changes: changes.join(", "),
+            })
+        })
+        .collect::<Vec<_>>();
+    if rows.is_empty() {
+        info!("No changes of the chemistry registry were recorded.");
+    } else {
+        println!("{}", Table::new(rows).with(Style::rounded()));
+    }
+    Ok(())
+}
+
+/// Undo the changes of a history record (the latest one by default), restoring the
+/// entries it changed to their values before it.
+pub fn rollback_chemistries(
+    af_home_path: PathBuf,
+    rollback_opts: ChemistryRollbackOpts,
+) -> Result<()> {
+    let _lock = lock_registry(&af_home_path)?;
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let history = read_history(&chem_p)?;
+    let record = match rollback_opts.id {
+        Some(id) => history.iter().find(|r| r.id == id),
+        None => history.last(),
+    };
+    let Some(record) = record else {
+        match rollback_opts.id {
+            Some(id) => bail!("There is no record {} in the registry history.", id),
+            None => {
+                bail!("No changes of the chemistry registry were recorded; Nothing to roll back.")
+            }
+        }
+    };
+
+    let mut registry = read_registry_map(&chem_p).unwrap_or_default();
+    let conflicts = record
+        .changes
+        .iter()
+        .filter(|c| registry.get(&c.chemistry) != c.after.as_ref())
+        .map(|c| c.chemistry.as_str())
+        .collect::<Vec<_>>();
+    if !conflicts.is_empty() {
+        if !rollback_opts.force {
+            bail!(
+                "The chemistries {} were changed after record {}; Pass --force to roll them back anyway.",
+                conflicts.join(", "),
+                record.id
+            );
+        }
+        warn!(
+            "The chemistries {} were changed after record {}; Rolling them back anyway.",
+            conflicts.join(", "),
+            record.id
+        );
+    }
+
+    let dry_run_pref = if rollback_opts.dry_run {
+        "[dry_run] : "
+    } else {
+        ""
+    };
+    for c in &record.changes {
+        match &c.before {
+            Some(v) => {
+                info!(
+                    "{}restoring {} as before the {} of record {}",
+                    dry_run_pref, c.chemistry, record.operation, record.id
+                );
+                registry.insert(c.chemistry.clone(), v.clone());
+            }
+            None => {
+                info!(
+                    "{}removing {}, added by the {} of record {}",
+                    dry_run_pref, c.chemistry, record.operation, record.id
+                );
+                registry.remove(&c.chemistry);
+            }
+        }
+    }
+    if !rollback_opts.dry_run {
+        write_registry(
+            &chem_p,
+            &format!("rollback {}", record.id),
+            &Value::Object(registry),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rollback(af_home: &Path, id: Option<u64>, force: bool) -> Result<()> {
+        rollback_chemistries(
+            af_home.to_path_buf(),
+            ChemistryRollbackOpts {
+                id,
+                force,
+                dry_run: false,
+            },
+        )
+    }
+
+    #[test]
+    fn registry_writes_are_recorded_and_rolled_back() {
+        let td = tempfile::tempdir().unwrap();
+        let chem_p = td.path().join(CHEMISTRIES_PATH);
+        let a1 = json!({ "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" });
+        let a2 = json!({ "geometry": "1{b[16]u[10]x:}2{r:}", "version": "0.2.0" });
+        let b = json!({ "geometry": "1{b[12]u[8]x:}2{r:}", "version": "0.1.0" });
+
+        write_registry(&chem_p, "add", &json!({ "a": a1 })).unwrap();
+        write_registry(&chem_p, "refresh", &json!({ "a": a2, "b": b })).unwrap();
+        // writing an unchanged registry records nothing
+        write_registry(&chem_p, "refresh", &json!({ "a": a2, "b": b })).unwrap();
+
+        let history = read_history(&chem_p).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].id, 2);
+        assert_eq!(
+            history[1]
+                .changes
+                .iter()
+                .map(EntryChange::summary)
+                .collect::<Vec<_>>(),
+            vec!["~a", "+b"]
+        );
+
+        rollback(td.path(), None, false).unwrap();
+        assert_eq!(
+            read_registry_map(&chem_p).unwrap(),
+            *json!({ "a": a1 }).as_object().unwrap()
+        );
+        let history = read_history(&chem_p).unwrap();
+        assert_eq!(history[2].operation, "rollback 2");
+
+        // `a` has been changed since record 1 added it
+        write_registry(&chem_p, "add", &json!({ "a": a2 })).unwrap();
+        assert!(rollback(td.path(), Some(1), false).is_err());
+        rollback(td.path(), Some(1), true).unwrap();
+        assert!(read_registry_map(&chem_p).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,730 @@
+//! `chemistry validate`: integrity checks of every entry of the chemistry registry.
+//!
+//! Unlike [`get_custom_chem_hm`](crate::utils::chem_utils::get_custom_chem_hm), which
+//! fails on the first invalid geometry, the registry is checked as raw JSON so that
+//! every problem of every entry is reported, along with how `--fix` would resolve it.
+
+use super::{
+    fetch_registry_file, lock_registry, parse_chemistry_version, validate_probe_set_csv,
+    validate_sample_bc_list, validate_translation_list, write_registry,
+};
+use crate::simpleaf_commands::ChemistryValidateOpts;
+use crate::utils::af_utils::validate_geometry;
+use crate::utils::chem_utils::{
+    DeprecationInfo, ExpectedOri, LOCAL_PL_PATH_KEY, ProbeSetInfo, ProtocolType, REMOTE_PL_URL_KEY,
+    SampleBcListInfo, TRANSLATION_LIST_KEY, TranslationListInfo,
+};
+use crate::utils::constants::CHEMISTRIES_PATH;
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tabled::{Table, Tabled, settings::Style};
+use tracing::{info, warn};
+
+/// the URL schemes registry files may be downloaded from.
+const ALLOWED_URL_SCHEMES: &[&str] = &["https", "http"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// How `--fix` resolves a problem.
+#[derive(Debug, Clone, PartialEq)]
+enum Fix {
+    None,
+    /// download the cached file `plist/<file>` (the `what` of the chemistry) again.
+    Refetch {
+        what: String,
+        file: String,
+        url: String,
+    },
+    /// remove the chemistry from the registry.
+    DropEntry,
+}
+
+impl fmt::Display for Fix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fix::None => write!(f, "-"),
+            Fix::Refetch { .. } => write!(f, "refetch"),
+            Fix::DropEntry => write!(f, "drop entry"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Problem {
+    chemistry: String,
+    field: String,
+    severity: Severity,
+    message: String,
+    fix: Fix,
+}
+
+#[derive(Tabled)]
+struct ProblemRow {
+    chemistry: String,
+    field: String,
+    severity: String,
+    problem: String,
+    fix: String,
+}
+
+impl From<&Problem> for ProblemRow {
+    fn from(p: &Problem) -> ProblemRow {
+        ProblemRow {
+            chemistry: p.chemistry.clone(),
+            field: p.field.clone(),
+            severity: p.severity.to_string(),
+            problem: p.message.clone(),
+            fix: p.fix.to_string(),
+        }
+    }
+}
+
+/// Collects the problems of a single registry entry.
+struct EntryChecker<'a> {
+    chemistry: &'a str,
+    plist_dir: &'a Path,
+    problems: Vec<Problem>,
+}
+
+impl EntryChecker<'_> {
+    fn report(&mut self, field: &str, severity: Severity, message: String, fix: Fix) {
+        self.problems.push(Problem {
+            chemistry: self.chemistry.to_string(),
+            field: field.to_string(),
+            severity,
+            message,
+            fix,
+        });
+    }
+
+    /// An invalid value that makes the whole entry unusable.
+    fn invalid(&mut self, field: &str, message: String) {
+        self.report(field, Severity::Error, message, Fix::DropEntry);
+    }
+
+    /// Check a file registered under `field` (the `what` of the chemistry): its remote URL
+    /// and, if it is cached, its hash and contents.
+    fn check_file(
+        &mut self,
+        field: &str,
+        what: &str,
+        plist_name: Option<&str>,
+        remote_url: Option<&str>,
+        validate: Option<fn(&Path) -> Result<()>>,
+    ) {
+        if let Some(url) = remote_url {
+            match url.split_once("://") {
+                Some((scheme, _)) if ALLOWED_URL_SCHEMES.contains(&scheme) => {
+                    if scheme == "http" {
+                        self.report(
+                            field,
+                            Severity::Warning,
+                            format!("the {} is downloaded over plain http", what),
+                            Fix::None,
+                        );
+                    }
+                }
+                _ => {
+                    self.invalid(
+                        field,
+                        format!(
+                            "the remote URL {} of the {} does not use an allowed scheme ({})",
+                            url,
+                            what,
+                            ALLOWED_URL_SCHEMES.join(", ")
+                        ),
+                    );
+                    return;
+                }
+            }
+        }
+
+        let Some(name) = plist_name else {
+            return;
+        };
+        let path = self.plist_dir.join(name);
+        let refetch = || match remote_url {
+            Some(url) => Fix::Refetch {
+                what: what.to_string(),
+                file: name.to_string(),
+                url: url.to_string(),
+            },
+            None => Fix::None,
+        };
+        if !path.is_file() {
+            if remote_url.is_none() {
+                self.report(
+                    field,
+                    Severity::Warning,
+                    format!("the {} is not cached and has no remote URL", what),
+                    Fix::None,
+                );
+            }
+            return;
+        }
+
+        // cached files are named after their blake3 content hash; other names (e.g. of
+        // hand-edited registries) can't be checked for corruption.
+        if blake3::Hash::from_hex(name).is_err() {
+            if let Some(validate) = validate
+                && let Err(e) = validate(&path)
+            {
+                self.report(
+                    field,
+                    Severity::Error,
+                    format!("the cached {} is invalid: {:#}", what, e),
+                    Fix::None,
+                );
+            }
+            return;
+        }
+        let mut hasher = blake3::Hasher::new();
+        let observed = match hasher.update_mmap(&path) {
+            Ok(_) => hasher.finalize().to_string(),
+            Err(e) => {
+                self.report(
+                    field,
+                    Severity::Error,
+                    format!("the cached {} could not be read: {}", what, e),
+                    refetch(),
+                );
+                return;
+            }
+        };
+        if observed != name {
+            self.report(
+                field,
+                Severity::Error,
+                format!(
+                    "the cached {} is corrupted (its blake3 hash is {})",
+                    what, observed
+                ),
+                refetch(),
+            );
+            return;
+        }
+        if let Some(validate) = validate
+            && let Err(e) = validate(&path)
+        {
+            self.report(
+                field,
+                Severity::Error,
+                format!("the cached {} is invalid: {:#}", what, e),
+                refetch(),
+            );
+        }
+    }
+}
+
+/// Deserialize the optional `field` of `entry` as a `T`.
+fn typed_field<T: serde::de::DeserializeOwned>(
+    entry: &Map<String, Value>,
+    field: &str,
+) -> Result<Option<T>> {
+    entry
+        .get(field)
+        .map(|v| serde_json::from_value(v.clone()))
+        .transpose()
+        .with_context(|| format!("invalid {}", field))
+}
+
+/// Check every field of the registry entry `v` of `chemistry`.
+fn check_entry(chemistry: &str, v: &Value, plist_dir: &Path) -> Vec<Problem> {
+    let mut checker = EntryChecker {
+        chemistry,
+        plist_dir,
+        problems: Vec::new(),
+    };
+    let Some(entry) = v.as_object() else {
+        checker.invalid("-", String::from("the entry is not a JSON object"));
+        return checker.problems;
+    };
+
+    match entry.get("geometry").map(Value::as_str) {
+        Some(Some(geo)) => {
+            if let Err(e) = validate_geometry(geo) {
+                checker.invalid("geometry", format!("{:#}", e));
+            }
+        }
+        Some(None) => checker.invalid("geometry", String::from("the geometry is not a string")),
+        None => checker.invalid("geometry", String::from("the geometry is missing")),
+    }
+
+    if let Some(ori) = entry.get("expected_ori")
+        && ori
+            .as_str()
+            .is_none_or(|o| ExpectedOri::from_str(o).is_err())
+    {
+        checker.invalid(
+            "expected_ori",
+            format!(
+                "{} is not one of {}",
+                ori,
+                ExpectedOri::all_to_str().join(", ")
+            ),
+        );
+    }
+
+    match entry.get("version").map(Value::as_str) {
+        Some(Some(version)) => {
+            if let Err(e) = parse_chemistry_version(version, "validating the registry") {
+                checker.invalid("version", format!("{:#}", e));
+            }
+        }
+        Some(None) => checker.invalid("version", String::from("the version is not a string")),
+        None => checker.report(
+            "version",
+            Severity::Warning,
+            String::from("the version is missing (0.0.0 is assumed)"),
+            Fix::None,
+        ),
+    }
+
+    let mut protocol_type = ProtocolType::default();
+    if let Some(pt) = entry.get("meta").and_then(|m| m.get("protocol_type")) {
+        match serde_json::from_value::<ProtocolType>(pt.clone()) {
+            Ok(pt) => protocol_type = pt,
+            Err(_) => checker.invalid(
+                "meta.protocol_type",
+                format!(
+                    "{} is not a known protocol type (standard_rna, flex_gex or atac)",
+                    pt
+                ),
+            ),
+        }
+    }
+
+    checker.check_file(
+        LOCAL_PL_PATH_KEY,
+        "permit list",
+        entry.get(LOCAL_PL_PATH_KEY).and_then(Value::as_str),
+        entry.get(REMOTE_PL_URL_KEY).and_then(Value::as_str),
+        None,
+    );
+
+    match typed_field::<SampleBcListInfo>(entry, "sample_bc_list") {
+        Ok(Some(sbc)) => {
+            if let Some(ori) = sbc.sample_bc_ori.as_deref()
+                && ori != "forward"
+                && ori != "reverse"
+            {
+                checker.invalid(
+                    "sample_bc_list",
+                    format!("the sample_bc_ori {} is neither forward nor reverse", ori),
+                );
+            }
+            if sbc.plist_name.is_none() && sbc.remote_url.is_none() {
+                checker.invalid(
+                    "sample_bc_list",
+                    String::from(
+                        "the sample barcode list has neither a plist_name nor a remote_url",
+                    ),
+                );
+            }
+            checker.check_file(
+                "sample_bc_list",
+                "sample barcode list",
+                sbc.plist_name.as_deref(),
+                sbc.remote_url.as_deref(),
+                Some(validate_sample_bc_list),
+            );
+        }
+        Ok(None) => {}
+        Err(e) => checker.invalid("sample_bc_list", format!("{:#}", e)),
+    }
+
+    match typed_field::<HashMap<String, ProbeSetInfo>>(entry, "probe_sets") {
+        Ok(Some(probe_sets)) => {
+            let mut organisms: Vec<&String> = probe_sets.keys().collect();
+            organisms.sort();
+            for organism in organisms {
+                let ps = &probe_sets[organism];
+                let field = format!("probe_sets.{}", organism);
+                if ps.name.trim().is_empty() {
+                    checker.invalid(&field, String::from("the probe set has no name"));
+                }
+                if ps.plist_name.is_none() && ps.remote_url.is_none() {
+                    checker.invalid(
+                        &field,
+                        String::from("the probe set has neither a plist_name nor a remote_url"),
+                    );
+                }
+                checker.check_file(
+                    &field,
+                    "probe set",
+                    ps.plist_name.as_deref(),
+                    ps.remote_url.as_deref(),
+                    Some(validate_probe_set_csv),
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(e) => checker.invalid("probe_sets", format!("{:#}", e)),
+    }
+
+    match typed_field::<TranslationListInfo>(entry, TRANSLATION_LIST_KEY) {
+        Ok(Some(tl)) => checker.check_file(
+            TRANSLATION_LIST_KEY,
+            "translation list",
+            tl.plist_name.as_deref(),
+            tl.remote_url.as_deref(),
+            Some(validate_translation_list),
+        ),
+        Ok(None) => {}
+        Err(e) => checker.invalid(TRANSLATION_LIST_KEY, format!("{:#}", e)),
+    }
+
+    if let Err(e) = typed_field::<Vec<String>>(entry, "aliases") {
+        checker.invalid("aliases", format!("{:#}", e));
+    }
+    if let Err(e) = typed_field::<DeprecationInfo>(entry, "deprecated") {
+        checker.invalid("deprecated", format!("{:#}", e));
+    }
+
+    // the sample barcode list and probe sets only make sense for (and are needed by) Flex
+    let has_flex_files = entry.contains_key("sample_bc_list") || entry.contains_key("probe_sets");
+    if protocol_type == ProtocolType::FlexGex {
+        for field in ["sample_bc_list", "probe_sets"] {
+            if !entry.contains_key(field) {
+                checker.report(
+                    field,
+                    Severity::Warning,
+                    format!("a flex_gex chemistry should register its {}", field),
+                    Fix::None,
+                );
+            }
+        }
+    } else if has_flex_files {
+        checker.report(
+            "meta.protocol_type",
+            Severity::Warning,
+            String::from("the chemistry registers a sample barcode list or probe sets, but is not a flex_gex chemistry"),
+            Fix::None,
+        );
+    }
+
+    checker.problems
+}
+
+/// Check every entry of the registry, in name order.
+fn check_registry(registry: &Map<String, Value>, plist_dir: &Path) -> Vec<Problem> {
+    let mut names: Vec<&String> = registry.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .flat_map(|name| check_entry(name, &registry[name], plist_dir))
+        .collect()
+}
+
+fn read_registry(chem_p: &Path) -> Result<Map<String, Value>> {
+    if !chem_p.is_file() {
+        bail!(
+            "The chemistry registry {} does not exist; Nothing to validate. Please issue the `refresh` command to obtain it.",
+            chem_p.display()
+        );
+    }
+    let content = std::fs::read_to_string(chem_p)
+        .with_context(|| format!("Could not read {}", chem_p.display()))?;
+    match serde_json::from_str::<Value>(&content)
+        .with_context(|| format!("Could not parse {} as JSON", chem_p.display()))?
+    {
+        Value::Object(registry) => Ok(registry),
+        _ => bail!(
+            "The chemistry registry {} is not a JSON object.",
+            chem_p.display()
+        ),
+    }
+}
+
+/// Apply the fixes of `problems`, returning the number of fixes that were applied.
+fn apply_fixes(
+    chem_p: &Path,
+    plist_dir: &Path,
+    registry: &mut Map<String, Value>,
+    problems: &[Problem],
+) -> Result<usize> {
+    let mut num_fixed = 0;
+    let mut refetched: Vec<PathBuf> = Vec::new();
+    let mut dropped = false;
+    for p in problems {
+        match &p.fix {
+            Fix::None => {}
+            Fix::Refetch { what, file, url } => {
+                let path = plist_dir.join(file);
+                if refetched.contains(&path) {
+                    continue;
+                }
+                if path.is_file() {
+                    std::fs::remove_file(&path)
+                        .with_context(|| format!("Could not remove {}", path.display()))?;
+                }
+                match fetch_registry_file(plist_dir, &p.chemistry, what, file, Some(url), false) {
+                    Ok(()) => num_fixed += 1,
+                    Err(e) => warn!(
+                        "Could not refetch the {} of chemistry {}: {:#}",
+                        what, p.chemistry, e
+                    ),
+                }
+                refetched.push(path);
+            }
+            Fix::DropEntry => {
+                if registry.remove(&p.chemistry).is_some() {
+                    info!("Dropping chemistry {} from the registry", p.chemistry);
+                    num_fixed += 1;
+                    dropped = true;
+                }
+            }
+        }
+    }
+    if dropped {
+        write_registry(chem_p, "validate --fix", &Value::Object(registry.clone()))?;
+    }
+    Ok(num_fixed)
+}
+
+fn print_problems(problems: &[Problem]) {
+    let rows = problems.iter().map(ProblemRow::from).collect::<Vec<_>>();
+    println!("{}", Table::new(rows).with(Style::rounded()));
+}
+
+/// Check every entry of the chemistry registry, reporting the problems found in a table,
+/// and (with `--fix`) refetch the corrupted cached files and drop the invalid entries.
+/// Fails if errors remain.
+pub fn validate_chemistries(af_home_path: PathBuf, opts: ChemistryValidateOpts) -> Result<()> {
+    let _lock = if opts.fix {
+        Some(lock_registry(&af_home_path)?)
+    } else {
+        None
+    };
+    let chem_p = af_home_path.join(CHEMISTRIES_PATH);
+    let plist_dir = af_home_path.join("plist");
+    let mut registry = read_registry(&chem_p)?;
+    let mut problems = check_registry(&registry, &plist_dir);
+
+    if problems.is_empty() {
+        info!(
+            "All {} chemistries of the registry are valid.",
+            registry.len()
+        );
+        return Ok(());
+    }
+    print_problems(&problems);
+
+    if opts.fix {
+        let num_fixed = apply_fixes(&chem_p, &plist_dir, &mut registry, &problems)?;
+        info!("Applied {} fix(es).", num_fixed);
+        problems = check_registry(&registry, &plist_dir);
+        if !problems.is_empty() {
+            info!("Problems remaining after the fixes:");
+            print_problems(&problems);
+        }
+    }
+
+    let num_errors = problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    let num_warnings = problems.len() - num_errors;
+    info!(
+        "Validated {} chemistries: {} error(s), {} warning(s).",
+        registry.len(),
+        num_errors,
+        num_warnings
+    );
+    if num_errors > 0 {
+        if opts.fix {
+            bail!("{} error(s) could not be fixed.", num_errors);
+        }
+        bail!(
+            "{} error(s) found in the chemistry registry; `simpleaf chemistry validate --fix` refetches corrupted files and drops invalid entries.",
+            num_errors
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    const GEO: &str = "1{b[16]u[12]x:}2{r:}";
+
+    fn write_cached(plist_dir: &Path, content: &str) -> String {
+        let hash = blake3::hash(content.as_bytes()).to_string();
+        fs::write(plist_dir.join(&hash), content).unwrap();
+        hash
+    }
+
+    fn problem_summary(problems: &[Problem]) -> Vec<(String, String, Severity, Fix)> {
+        problems
+            .iter()
+            .map(|p| {
+                (
+                    p.chemistry.clone(),
+                    p.field.clone(),
+                    p.severity,
+                    p.fix.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn registry_problems_are_reported_with_their_fix() {
+        let td = tempfile::tempdir().unwrap();
+        let plist_dir = td.path().join("plist");
+        fs::create_dir_all(&plist_dir).unwrap();
+        let good_pl = write_cached(&plist_dir, "AAAA\nCCCC\n");
+        let corrupted = blake3::hash(b"the original").to_string();
+        fs::write(plist_dir.join(&corrupted), "something else").unwrap();
+        let bad_tl = write_cached(&plist_dir, "AAAA\n");
+
+        let registry = json!({
+            "good": {
+                "geometry": GEO, "expected_ori": "fw", "version": "0.1.0",
+                "plist_name": good_pl, "remote_url": "https://example.org/pl.txt"
+            },
+            "bad_geometry": { "geometry": "1{b[16]", "version": "0.1.0" },
+            "bad_ori_version": { "geometry": GEO, "expected_ori": "up", "version": "one" },
+            "bad_protocol": { "geometry": GEO, "version": "0.1.0", "meta": { "protocol_type": "smart" } },
+            "bad_aliases": { "geometry": GEO, "version": "0.1.0", "aliases": "v3", "deprecated": { "since": "0.2.0" } },
+            "ftp": {
+                "geometry": GEO, "version": "0.1.0",
+                "plist_name": "abc", "remote_url": "ftp://example.org/pl.txt"
+            },
+            "corrupted": {
+                "geometry": GEO, "version": "0.1.0",
+                "plist_name": corrupted, "remote_url": "https://example.org/pl.txt"
+            },
+            "bad_tl": {
+                "geometry": GEO, "version": "0.1.0",
+                "translation_list": { "plist_name": bad_tl }
+            },
+            "flex_files": {
+                "geometry": GEO, "version": "0.1.0",
+                "sample_bc_list": { "plist_name": "sbc", "sample_bc_ori": "sideways" }
+            }
+        });
+        let problems = check_registry(registry.as_object().unwrap(), &plist_dir);
+        let s = |x: &str| x.to_string();
+        assert_eq!(
+            problem_summary(&problems),
+            vec![
+                (
+                    s("bad_aliases"),
+                    s("aliases"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("bad_geometry"),
+                    s("geometry"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("bad_ori_version"),
+                    s("expected_ori"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("bad_ori_version"),
+                    s("version"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("bad_protocol"),
+                    s("meta.protocol_type"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("bad_tl"),
+                    s("translation_list"),
+                    Severity::Error,
+                    Fix::None
+                ),
+                (
+                    s("corrupted"),
+                    s("plist_name"),
+                    Severity::Error,
+                    Fix::Refetch {
+                        what: s("permit list"),
+                        file: corrupted.clone(),
+                        url: s("https://example.org/pl.txt")
+                    }
+                ),
+                (
+                    s("flex_files"),
+                    s("sample_bc_list"),
+                    Severity::Error,
+                    Fix::DropEntry
+                ),
+                (
+                    s("flex_files"),
+                    s("sample_bc_list"),
+                    Severity::Warning,
+                    Fix::None
+                ),
+                (
+                    s("flex_files"),
+                    s("meta.protocol_type"),
+                    Severity::Warning,
+                    Fix::None
+                ),
+                (s("ftp"), s("plist_name"), Severity::Error, Fix::DropEntry),
+            ]
+        );
+    }
+
+    #[test]
+    fn fix_drops_invalid_entries() {
+        let td = tempfile::tempdir().unwrap();
+        fs::write(
+            td.path().join(CHEMISTRIES_PATH),
+            json!({
+                "good": { "geometry": GEO, "expected_ori": "fw", "version": "0.1.0" },
+                "bad": { "geometry": GEO, "expected_ori": "up", "version": "0.1.0" }
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        assert!(
+            validate_chemistries(
+                td.path().to_path_buf(),
+                ChemistryValidateOpts { fix: false }
+            )
+            .is_err()
+        );
+        validate_chemistries(td.path().to_path_buf(), ChemistryValidateOpts { fix: true }).unwrap();
+        let registry = read_registry(&td.path().join(CHEMISTRIES_PATH)).unwrap();
+        assert_eq!(registry.keys().collect::<Vec<_>>(), vec!["good"]);
+        validate_chemistries(
+            td.path().to_path_buf(),
+            ChemistryValidateOpts { fix: false },
+        )
+        .unwrap();
+    }
+}
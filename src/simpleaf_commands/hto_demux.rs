@@ -0,0 +1,819 @@
+//! Cell hashing (HTO) demultiplexing.
+//!
+//! The HTO library is quantified against a feature barcode index (built with
+//! `simpleaf index --feature-csv`), using the cells called in the paired GEX run as an
+//! explicit permit list. Each cell is then classified in the manner of Seurat's
+//! `HTODemux`:
+//!
+//! 1. the HTO counts are CLR-normalized across cells, separately for every HTO;
+//! 2. a per-HTO threshold separating the tagged cells from the background is chosen,
+//!    either by fitting a two-component Gaussian mixture to the normalized counts of
+//!    the HTO, or as a quantile of its normalized counts in its background cluster
+//!    (the k-means cluster, out of one per HTO plus one, with its lowest mean);
+//! 3. cells above the threshold of exactly one HTO are singlets of that HTO, cells
+//!    above the thresholds of several HTOs are doublets, and the rest are negatives.
+
+use crate::core::io;
+use crate::defaults::{DefaultMappingParams, DefaultParams};
+use crate::simpleaf_commands::multiplex_quant::sample_dir_name;
+use crate::simpleaf_commands::{HtoDemuxOpts, HtoThresholdMethod, MapQuantOpts, PiscemDict, quant};
+use crate::utils::mtx_utils;
+
+use anyhow::{Context, bail};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+const HTO_DEMUX_INFO_FILE: &str = "simpleaf_hto_demux_info.json";
+
+/// maximum number of EM iterations when fitting the per-HTO mixture.
+const MAX_EM_ITER: usize = 500;
+/// variance floor of the mixture components, to keep them from collapsing onto
+/// a single value (e.g. the many cells with no counts for an HTO).
+const MIN_MIXTURE_VAR: f64 = 1e-4;
+/// maximum number of k-means iterations when finding the background of every HTO.
+const MAX_KMEANS_ITER: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+enum HtoClass {
+    Singlet,
+    Doublet,
+    Negative,
+}
+
+impl std::fmt::Display for HtoClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            HtoClass::Singlet => "Singlet",
+            HtoClass::Doublet => "Doublet",
+            HtoClass::Negative => "Negative",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The classification of a single cell.
+#[derive(Debug, Clone, PartialEq)]
+struct CellCall {
+    class: HtoClass,
+    /// the HTOs above their threshold, strongest (by normalized count) first.
+    positive: Vec<usize>,
+    /// the strongest and second strongest HTO, by normalized count.
+    max_hto: usize,
+    second_hto: Option<usize>,
+    /// difference between the normalized counts of the two strongest HTOs.
+    margin: f64,
+}
+
+impl CellCall {
+    fn assignment(&self, htos: &[String]) -> String {
+        match self.class {
+            HtoClass::Singlet => htos[self.positive[0]].clone(),
+            HtoClass::Doublet => format!("{}_{}", htos[self.positive[0]], htos[self.positive[1]]),
+            HtoClass::Negative => String::from("Negative"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HtoSummary {
+    hto: String,
+    threshold: Option<f64>,
+    num_positive: usize,
+    num_singlets: usize,
+}
+
+/// The quantification directory of the GEX run: either `dir` itself or its `af_quant`.
+fn resolve_gex_quant_dir(dir: &Path) -> anyhow::Result<PathBuf> {
+    for cand in [dir.join("af_quant"), dir.to_path_buf()] {
+        let (mtx, rows, _) = mtx_utils::quant_mat_paths(&cand);
+        if mtx.is_file() && rows.is_file() {
+            return Ok(cand);
+        }
+    }
+    bail!(
+        "{} does not contain an alevin-fry count matrix (alevin/quants_mat.mtx), nor does its af_quant subdirectory.",
+        dir.display()
+    )
+}
+
+fn hto_quant_opts(opts: &HtoDemuxOpts, cells_file: PathBuf, output: PathBuf) -> MapQuantOpts {
+    MapQuantOpts {
+        chemistry: opts.chemistry.clone(),
+        output,
+        threads: opts.threads,
+        index: Some(opts.index.clone()),
+        reads1: Some(opts.reads1.clone()),
+        reads2: Some(opts.reads2.clone()),
+        libraries: vec![],
+        feature_csv: None,
+        feature_index: None,
+        feature_chemistry: None,
+        translation_list: None,
+        use_piscem: false,
+        struct_constraints: false,
+        ignore_ambig_hits: false,
+        no_poison: false,
+        skipping_strategy: DefaultParams::SKIPPING_STRATEGY.to_string(),
+        max_ec_card: DefaultParams::MAX_EC_CARD,
+        max_hit_occ: DefaultParams::MAX_HIT_OCC,
+        max_hit_occ_recover: DefaultParams::MAX_HIT_OCC_RECOVER,
+        max_read_occ: DefaultParams::MAX_READ_OCC,
+        dict: PiscemDict::Auto,
+        map_dir: None,
+        knee: false,
+        unfiltered_pl: None,
+        forced_cells: None,
+        explicit_pl: Some(cells_file),
+        expect_cells: None,
+        expected_ori: opts.expected_ori.clone(),
+        min_reads: 10,
+        t2g_map: None,
+        resolution: opts.resolution.clone(),
+        anndata_out: false,
+        translate_barcodes: false,
+        spatial: false,
+        tissue_positions: None,
+        scalefactors: None,
+    }
+}
+
+/// Read the HTO counts of `cells` from the HTO quantification directory, one row per
+/// cell. Cells without any HTO reads get a row of zeros. Returns the HTO names, the
+/// counts, and the number of cells without HTO reads.
+fn hto_counts_for_cells(
+    hto_quant: &Path,
+    cells: &[String],
+) -> anyhow::Result<(Vec<String>, Vec<Vec<f64>>, usize)> {
+    let (mtx, rows, cols) = mtx_utils::quant_mat_paths(hto_quant);
+    let htos = std::fs::read_to_string(&cols)
+        .with_context(|| format!("could not read {}", cols.display()))?
+        .lines()
+        .map(|l| l.to_string())
+        .collect::<Vec<String>>();
+    let barcodes = mtx_utils::read_row_barcodes(&rows)?;
+    let mat = mtx_utils::read_mtx_dense(&mtx)?;
+    if mat.len() != barcodes.len() {
+        bail!(
+            "{} lists {} barcodes but the count matrix has {} rows",
+            rows.display(),
+            barcodes.len(),
+            mat.len()
+        );
+    }
+    if htos.len() < 2 {
+        bail!(
+            "the HTO count matrix has {} feature(s); demultiplexing requires at least two HTOs.",
+            htos.len()
+        );
+    }
+
+    let by_barcode: HashMap<&str, &Vec<f64>> = barcodes
+        .iter()
+        .map(|b| b.as_str())
+        .zip(mat.iter())
+        .collect();
+    let mut missing = 0;
+    let counts = cells
+        .iter()
+        .map(|c| match by_barcode.get(c.as_str()) {
+            Some(row) => (*row).clone(),
+            None => {
+                missing += 1;
+                vec![0.0; htos.len()]
+            }
+        })
+        .collect();
+    Ok((htos, counts, missing))
+}
+
+/// CLR-normalize the counts of every HTO (column) across cells, as Seurat does:
+/// `ln(1 + x / g)`, where `g` is the geometric mean of `1 + x` over the cells.
+fn clr_normalize(counts: &[Vec<f64>]) -> Vec<Vec<f64>> {
+    let nhto = counts.first().map_or(0, |r| r.len());
+    let n = counts.len().max(1) as f64;
+    let geo_means: Vec<f64> = (0..nhto)
+        .map(|j| (counts.iter().map(|r| r[j].ln_1p()).sum::<f64>() / n).exp())
+        .collect();
+    counts
+        .iter()
+        .map(|r| {
+            r.iter()
+                .zip(&geo_means)
+                .map(|(x, g)| (x / g).ln_1p())
+                .collect()
+        })
+        .collect()
+}
+
+/// The `q`-quantile of `values` (linear interpolation between order statistics).
+fn quantile(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut v = values.to_vec();
+    v.sort_by(f64::total_cmp);
+    let pos = q.clamp(0.0, 1.0) * (v.len() - 1) as f64;
+    let lo = pos.floor() as usize;
+    let hi = pos.ceil() as usize;
+    v[lo] + (v[hi] - v[lo]) * (pos - lo as f64)
+}
+
+fn normal_log_density(x: f64, mu: f64, var: f64) -> f64 {
+    -0.5 * ((x - mu).powi(2) / var + (2.0 * std::f64::consts::PI * var).ln())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Component {
+    weight: f64,
+    mean: f64,
+    var: f64,
+}
+
+impl Component {
+    fn fit(values: &[f64], resp: impl Fn(usize) -> f64) -> Option<Component> {
+        let n: f64 = (0..values.len()).map(&resp).sum();
+        if n < 1e-9 {
+            return None;
+        }
+        let mean = values
+            .iter()
+            .enumerate()
+            .map(|(i, x)| resp(i) * x)
+            .sum::<f64>()
+            / n;
+        let var = values
+            .iter()
+            .enumerate()
+            .map(|(i, x)| resp(i) * (x - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        Some(Component {
+            weight: n / values.len() as f64,
+            mean,
+            var: var.max(MIN_MIXTURE_VAR),
+        })
+    }
+
+    fn log_joint(&self, x: f64) -> f64 {
+        self.weight.ln() + normal_log_density(x, self.mean, self.var)
+    }
+}
+
+/// Fit a two-component Gaussian mixture to `values` and return the point between the
+/// component means at which a value becomes more likely to belong to the upper one.
+/// Returns `None` if the values do not separate into two components.
+fn mixture_threshold(values: &[f64]) -> Option<f64> {
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    let upper = |i: usize| if values[i] > mean { 1.0 } else { 0.0 };
+    let mut lo = Component::fit(values, |i| 1.0 - upper(i))?;
+    let mut hi = Component::fit(values, upper)?;
+
+    let mut prev_ll = f64::NEG_INFINITY;
+    for _ in 0..MAX_EM_ITER {
+        // E-step: the responsibility of the upper component for every value
+        let mut ll = 0.0;
+        let resp: Vec<f64> = values
+            .iter()
+            .map(|&x| {
+                let (a, b) = (lo.log_joint(x), hi.log_joint(x));
+                let m = a.max(b);
+                ll += m + ((a - m).exp() + (b - m).exp()).ln();
+                1.0 / (1.0 + (a - b).exp())
+            })
+            .collect();
+        // M-step
+        lo = Component::fit(values, |i| 1.0 - resp[i])?;
+        hi = Component::fit(values, |i| resp[i])?;
+        if (ll - prev_ll).abs() < 1e-8 * values.len() as f64 {
+            break;
+        }
+        prev_ll = ll;
+    }
+    if lo.mean > hi.mean {
+        std::mem::swap(&mut lo, &mut hi);
+    }
+    if hi.mean - lo.mean < 1e-6 {
+        return None;
+    }
+
+    // bisect for the point where the posterior of the upper component reaches 1/2
+    let odds = |x: f64| hi.log_joint(x) - lo.log_joint(x);
+    let (mut a, mut b) = (lo.mean, hi.mean);
+    if odds(a) >= 0.0 || odds(b) <= 0.0 {
+        return Some((a + b) / 2.0);
+    }
+    for _ in 0..100 {
+        let m = (a + b) / 2.0;
+        if odds(m) < 0.0 {
+            a = m;
+        } else {
+            b = m;
+        }
+    }
+    Some((a + b) / 2.0)
+}
+
+/// Cluster the CLR-normalized counts with k-means into one cluster per HTO plus one
+/// for the negatives. The clusters are seeded with the per-HTO minimum (the negatives)
+/// and the centroids of the cells whose strongest HTO is each HTO, so that the result
+/// is deterministic. Returns the cluster of every cell.
+fn kmeans_clusters(clr: &[Vec<f64>], nhto: usize) -> Vec<usize> {
+    let mut centers = vec![vec![f64::INFINITY; nhto]; nhto + 1];
+    for r in clr {
+        for (c, x) in centers[0].iter_mut().zip(r) {
+            *c = c.min(*x);
+        }
+    }
+    let strongest: Vec<usize> = clr.iter().map(|r| argmax(r)).collect();
+    let centroid = |members: &[usize]| -> Option<Vec<f64>> {
+        if members.is_empty() {
+            return None;
+        }
+        let n = members.len() as f64;
+        Some(
+            (0..nhto)
+                .map(|j| members.iter().map(|i| clr[*i][j]).sum::<f64>() / n)
+                .collect(),
+        )
+    };
+    for (j, center) in centers.iter_mut().enumerate().skip(1) {
+        let members: Vec<usize> = (0..clr.len()).filter(|i| strongest[*i] == j - 1).collect();
+        *center = centroid(&members).unwrap_or_else(|| {
+            let mut c = vec![0.0; nhto];
+            c[j - 1] = f64::MAX.sqrt();
+            c
+        });
+    }
+
+    let mut assignment = vec![usize::MAX; clr.len()];
+    for _ in 0..MAX_KMEANS_ITER {
+        let next: Vec<usize> = clr
+            .iter()
+            .map(|r| {
+                let dist = |c: &Vec<f64>| r.iter().zip(c).map(|(x, y)| (x - y).powi(2)).sum();
+                let d: Vec<f64> = centers.iter().map(dist).collect();
+                d.iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map_or(0, |(k, _)| k)
+            })
+            .collect();
+        if next == assignment {
+            break;
+        }
+        assignment = next;
+        for (k, center) in centers.iter_mut().enumerate() {
+            let members: Vec<usize> = (0..clr.len()).filter(|i| assignment[*i] == k).collect();
+            // an emptied cluster keeps its previous center
+            if let Some(c) = centroid(&members) {
+                *center = c;
+            }
+        }
+    }
+    assignment
+}
+
+/// Choose the threshold of every HTO (column) of the CLR-normalized counts. An HTO
+/// without a usable threshold gets `None`, and no cell is positive for it.
+fn hto_thresholds(
+    clr: &[Vec<f64>],
+    nhto: usize,
+    method: HtoThresholdMethod,
+    q: f64,
+) -> Vec<Option<f64>> {
+    let clusters = match method {
+        HtoThresholdMethod::Mixture => vec![],
+        HtoThresholdMethod::Quantile => kmeans_clusters(clr, nhto),
+    };
+    (0..nhto)
+        .map(|j| {
+            let values: Vec<f64> = clr.iter().map(|r| r[j]).collect();
+            match method {
+                HtoThresholdMethod::Mixture => mixture_threshold(&values),
+                HtoThresholdMethod::Quantile => {
+                    // the background of the HTO is the cluster with its lowest mean
+                    let mut sums = vec![(0.0, 0usize); nhto + 1];
+                    for (v, k) in values.iter().zip(&clusters) {
+                        sums[*k].0 += v;
+                        sums[*k].1 += 1;
+                    }
+                    let background = sums
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (_, n))| *n > 0)
+                        .min_by(|(_, a), (_, b)| (a.0 / a.1 as f64).total_cmp(&(b.0 / b.1 as f64)))
+                        .map(|(k, _)| k)?;
+                    let bg_values: Vec<f64> = values
+                        .iter()
+                        .zip(&clusters)
+                        .filter(|(_, k)| **k == background)
+                        .map(|(v, _)| *v)
+                        .collect();
+                    Some(quantile(&bg_values, q))
+                }
+            }
+        })
+        .collect()
+}
+
+fn argmax(row: &[f64]) -> usize {
+    row.iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Classify every cell given the CLR-normalized counts and the per-HTO thresholds.
+fn classify_cells(clr: &[Vec<f64>], thresholds: &[Option<f64>]) -> Vec<CellCall> {
+    clr.iter()
+        .map(|r| {
+            let mut order: Vec<usize> = (0..r.len()).collect();
+            order.sort_by(|a, b| r[*b].total_cmp(&r[*a]));
+            let positive: Vec<usize> = order
+                .iter()
+                .copied()
+                .filter(|j| thresholds[*j].is_some_and(|t| r[*j] > t))
+                .collect();
+            let class = match positive.len() {
+                0 => HtoClass::Negative,
+                1 => HtoClass::Singlet,
+                _ => HtoClass::Doublet,
+            };
+            let second_hto = order.get(1).copied();
+            CellCall {
+                class,
+                positive,
+                max_hto: order[0],
+                second_hto,
+                margin: second_hto.map_or(r[order[0]], |s| r[order[0]] - r[s]),
+            }
+        })
+        .collect()
+}
+
+fn write_assignments(
+    path: &Path,
+    cells: &[String],
+    htos: &[String],
+    counts: &[Vec<f64>],
+    calls: &[CellCall],
+) -> anyhow::Result<()> {
+    let mut w = BufWriter::new(
+        std::fs::File::create(path)
+            .with_context(|| format!("could not create {}", path.display()))?,
+    );
+    write!(
+        w,
+        "barcode\tclassification\tassignment\thto_umis\tmax_hto\tsecond_hto\tmargin"
+    )?;
+    for h in htos {
+        write!(w, "\t{}", h)?;
+    }
+    writeln!(w)?;
+    for ((bc, row), call) in cells.iter().zip(counts).zip(calls) {
+        write!(
+            w,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}",
+            bc,
+            call.class,
+            call.assignment(htos),
+            row.iter().sum::<f64>(),
+            htos[call.max_hto],
+            call.second_hto.map_or("NA", |s| htos[s].as_str()),
+            call.margin
+        )?;
+        for x in row {
+            write!(w, "\t{}", x)?;
+        }
+        writeln!(w)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write one copy of the GEX quantification directory per HTO, holding its singlets.
+fn split_gex_by_hto(
+    gex_quant: &Path,
+    split_root: &Path,
+    htos: &[String],
+    calls: &[CellCall],
+    anndata_out: bool,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut summaries = Vec::with_capacity(htos.len());
+    for (j, hto) in htos.iter().enumerate() {
+        let keep: Vec<usize> = calls
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.class == HtoClass::Singlet && c.positive[0] == j)
+            .map(|(i, _)| i)
+            .collect();
+        if keep.is_empty() {
+            warn!("no cells were assigned to HTO {}; skipping it.", hto);
+            summaries.push(json!({ "hto": hto, "num_cells": 0, "output_dir": null }));
+            continue;
+        }
+        let hto_dir = split_root.join(sample_dir_name(hto));
+        mtx_utils::subset_quant_dir(gex_quant, &hto_dir, &keep, None)?;
+        if anndata_out {
+            let opath = hto_dir.join("alevin").join("quants.h5ad");
+            af_anndata::convert_csr_to_anndata(&hto_dir, &opath)?;
+        }
+        summaries.push(json!({
+            "hto": hto,
+            "num_cells": keep.len(),
+            "output_dir": hto_dir.display().to_string(),
+        }));
+    }
+    Ok(summaries)
+}
+
+/// Classify the GEX cells from the HTO quantification and write the assignment table
+/// (and optionally the per-HTO splits of the GEX matrix) to `out_dir`.
+fn demultiplex(
+    gex_quant: &Path,
+    hto_quant: &Path,
+    out_dir: &Path,
+    opts: &HtoDemuxOpts,
+) -> anyhow::Result<serde_json::Value> {
+    let (_, gex_rows, _) = mtx_utils::quant_mat_paths(gex_quant);
+    let cells = mtx_utils::read_row_barcodes(&gex_rows)?;
+    let (htos, counts, num_no_hto) = hto_counts_for_cells(hto_quant, &cells)?;
+    if num_no_hto > 0 {
+        warn!(
+            "{} of {} GEX cells have no HTO reads; they will be classified as negatives.",
+            num_no_hto,
+            cells.len()
+        );
+    }
+
+    let clr = clr_normalize(&counts);
+    let thresholds = hto_thresholds(&clr, htos.len(), opts.threshold_method, opts.quantile);
+    for (h, t) in htos.iter().zip(&thresholds) {
+        if t.is_none() {
+            warn!(
+                "could not separate the cells tagged with HTO {} from the background; no cell will be assigned to it.",
+                h
+            );
+        }
+    }
+    let calls = classify_cells(&clr, &thresholds);
+
+    let table = out_dir.join("hto_assignments.tsv");
+    write_assignments(&table, &cells, &htos, &counts, &calls)?;
+
+    let count_class = |c: HtoClass| calls.iter().filter(|x| x.class == c).count();
+    let hto_summaries: Vec<HtoSummary> = htos
+        .iter()
+        .enumerate()
+        .map(|(j, h)| HtoSummary {
+            hto: h.clone(),
+            threshold: thresholds[j],
+            num_positive: calls.iter().filter(|c| c.positive.contains(&j)).count(),
+            num_singlets: calls
+                .iter()
+                .filter(|c| c.class == HtoClass::Singlet && c.positive[0] == j)
+                .count(),
+        })
+        .collect();
+    info!(
+        "{} cells: {} singlets, {} doublets, {} negatives.",
+        cells.len(),
+        count_class(HtoClass::Singlet),
+        count_class(HtoClass::Doublet),
+        count_class(HtoClass::Negative)
+    );
+
+    let split = if opts.split_gex {
+        let split_root = out_dir.join("split");
+        Some(split_gex_by_hto(
+            gex_quant,
+            &split_root,
+            &htos,
+            &calls,
+            opts.anndata_out,
+        )?)
+    } else {
+        None
+    };
+
+    Ok(json!({
+        "num_cells": cells.len(),
+        "num_cells_without_hto_reads": num_no_hto,
+        "threshold_method": format!("{:?}", opts.threshold_method).to_lowercase(),
+        "quantile": (opts.threshold_method == HtoThresholdMethod::Quantile).then_some(opts.quantile),
+        "num_singlets": count_class(HtoClass::Singlet),
+        "num_doublets": count_class(HtoClass::Doublet),
+        "num_negatives": count_class(HtoClass::Negative),
+        "htos": hto_summaries,
+        "assignments": table,
+        "split_gex": split,
+    }))
+}
+
+pub fn hto_demux(af_home_path: &Path, opts: HtoDemuxOpts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    if opts.quantile <= 0.0 || opts.quantile >= 1.0 {
+        bail!(
+            "--quantile must lie strictly between 0 and 1, but {} was given.",
+            opts.quantile
+        );
+    }
+    let gex_quant = resolve_gex_quant_dir(&opts.gex_quant)?;
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+
+    // 1. the cells called in the GEX run become the explicit permit list of the HTO run
+    let (_, gex_rows, _) = mtx_utils::quant_mat_paths(&gex_quant);
+    let cells = mtx_utils::read_row_barcodes(&gex_rows)?;
+    if cells.is_empty() {
+        bail!(
+            "the GEX count matrix in {} has no cells.",
+            gex_quant.display()
+        );
+    }
+    let cells_file = opts.output.join("gex_cells.txt");
+    let mut w = BufWriter::new(
+        std::fs::File::create(&cells_file)
+            .with_context(|| format!("could not create {}", cells_file.display()))?,
+    );
+    for c in &cells {
+        writeln!(w, "{}", c)?;
+    }
+    w.flush()?;
+    info!(
+        "demultiplexing the {} cells of {}",
+        cells.len(),
+        gex_quant.display()
+    );
+
+    // 2. HTO quantification
+    info!("quantifying the HTO library");
+    let hto_start = Instant::now();
+    let hto_out = opts.output.join("hto");
+    quant::map_and_quant(
+        af_home_path,
+        hto_quant_opts(&opts, cells_file.clone(), hto_out.clone()),
+    )?;
+    let hto_duration = hto_start.elapsed();
+
+    // 3. classification
+    let demux_start = Instant::now();
+    let mut info_json = demultiplex(&gex_quant, &hto_out.join("af_quant"), &opts.output, &opts)?;
+    info_json["gex_quant"] = json!(gex_quant);
+    info_json["hto_output"] = json!(hto_out);
+    info_json["time_info"] = json!({
+        "hto_quant_time": hto_duration.as_secs_f64(),
+        "demux_time": demux_start.elapsed().as_secs_f64(),
+        "total_time": start.elapsed().as_secs_f64(),
+    });
+    io::write_json_pretty_atomic(&opts.output.join(HTO_DEMUX_INFO_FILE), &info_json)?;
+    info!(
+        "HTO demultiplexing complete; assignments written to {}",
+        opts.output.join("hto_assignments.tsv").display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn test_opts(output: PathBuf, method: HtoThresholdMethod, split_gex: bool) -> HtoDemuxOpts {
+        HtoDemuxOpts {
+            output,
+            threads: 1,
+            gex_quant: PathBuf::new(),
+            index: PathBuf::new(),
+            reads1: vec![],
+            reads2: vec![],
+            chemistry: String::from("10xv3"),
+            expected_ori: None,
+            resolution: String::from("cr-like"),
+            threshold_method: method,
+            quantile: 0.99,
+            split_gex,
+            anndata_out: false,
+        }
+    }
+
+    fn write_quant_dir(dir: &Path, rows: &[String], cols: &[&str], mat: &[Vec<f64>]) {
+        let entries: Vec<(usize, usize, f64)> = mat
+            .iter()
+            .enumerate()
+            .flat_map(|(i, r)| {
+                r.iter()
+                    .enumerate()
+                    .filter(|(_, v)| **v > 0.0)
+                    .map(move |(j, v)| (i + 1, j + 1, *v))
+            })
+            .collect();
+        mtx_utils::write_test_quant_mat(dir, rows, cols, &entries);
+    }
+
+    /// 90 GEX cells: 30 tagged with each of HTO A and B, 20 with both and 10 with
+    /// neither. Every cell has a little background for both HTOs.
+    fn simulated() -> (Vec<String>, Vec<Vec<f64>>) {
+        let mut cells = vec![];
+        let mut counts = vec![];
+        for i in 0..90 {
+            let bg = (i % 5) as f64;
+            let row = match i {
+                0..30 => vec![200.0 + bg * 10.0, bg],
+                30..60 => vec![bg, 150.0 + bg * 10.0],
+                60..80 => vec![120.0 + bg, 130.0 + bg],
+                _ => vec![bg, bg],
+            };
+            cells.push(format!("CELL{:03}", i));
+            counts.push(row);
+        }
+        (cells, counts)
+    }
+
+    #[test]
+    fn clr_normalizes_each_hto_across_cells() {
+        let clr = clr_normalize(&[vec![0.0, 3.0], vec![3.0, 3.0]]);
+        let g0 = (4f64.ln() / 2.0).exp();
+        assert!((clr[0][0] - 0.0).abs() < 1e-12);
+        assert!((clr[1][0] - (3.0 / g0).ln_1p()).abs() < 1e-12);
+        // an HTO with equal counts in all cells normalizes to the same value
+        assert_eq!(clr[0][1], clr[1][1]);
+        assert!((quantile(&[1.0, 2.0, 3.0, 4.0], 0.5) - 2.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn mixture_threshold_separates_two_modes() {
+        let mut values: Vec<f64> = (0..50).map(|i| 0.1 * (i % 5) as f64).collect();
+        values.extend((0..20).map(|i| 4.0 + 0.1 * (i % 5) as f64));
+        let t = mixture_threshold(&values).unwrap();
+        assert!(t > 0.4 && t < 4.0, "threshold {}", t);
+        assert_eq!(mixture_threshold(&[1.0; 10]), None);
+    }
+
+    #[test]
+    fn cells_are_classified_as_singlets_doublets_and_negatives() {
+        let (_, counts) = simulated();
+        let clr = clr_normalize(&counts);
+        for method in [HtoThresholdMethod::Mixture, HtoThresholdMethod::Quantile] {
+            let thresholds = hto_thresholds(&clr, 2, method, 0.99);
+            let calls = classify_cells(&clr, &thresholds);
+            let classes: Vec<HtoClass> = calls.iter().map(|c| c.class).collect();
+            assert!(classes[..60].iter().all(|c| *c == HtoClass::Singlet));
+            assert!(calls[..30].iter().all(|c| c.positive == vec![0]));
+            assert!(calls[30..60].iter().all(|c| c.positive == vec![1]));
+            assert!(
+                classes[60..80].iter().all(|c| *c == HtoClass::Doublet),
+                "{:?}",
+                method
+            );
+            assert!(classes[80..].iter().all(|c| *c == HtoClass::Negative));
+            let htos = vec![String::from("A"), String::from("B")];
+            assert_eq!(calls[70].assignment(&htos), "B_A");
+        }
+    }
+
+    #[test]
+    fn demultiplex_writes_assignments_and_splits_gex() {
+        let td = tempfile::tempdir().unwrap();
+        let (cells, counts) = simulated();
+        let gex = td.path().join("gex");
+        let gex_counts: Vec<Vec<f64>> = (0..cells.len()).map(|i| vec![i as f64 + 1.0]).collect();
+        write_quant_dir(&gex, &cells, &["g1"], &gex_counts);
+        // the last GEX cell has no HTO reads at all
+        let hto = td.path().join("hto");
+        write_quant_dir(&hto, &cells[..89], &["A", "B"], &counts[..89]);
+
+        let out = td.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+        let opts = test_opts(out.clone(), HtoThresholdMethod::Mixture, true);
+        let info = demultiplex(&gex, &hto, &out, &opts).unwrap();
+        assert_eq!(info["num_cells"], 90);
+        assert_eq!(info["num_cells_without_hto_reads"], 1);
+        assert_eq!(info["num_singlets"], 60);
+        assert_eq!(info["num_doublets"], 20);
+        assert_eq!(info["num_negatives"], 10);
+
+        let table = fs::read_to_string(out.join("hto_assignments.tsv")).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "barcode\tclassification\tassignment\thto_umis\tmax_hto\tsecond_hto\tmargin\tA\tB"
+        );
+        assert!(lines[1].starts_with("CELL000\tSinglet\tA\t200\tA\tB\t"));
+        assert!(lines[90].starts_with("CELL089\tNegative\tNegative\t0\t"));
+
+        let (a_mtx, a_rows, _) = mtx_utils::quant_mat_paths(&out.join("split").join("A"));
+        assert_eq!(mtx_utils::read_row_barcodes(&a_rows).unwrap(), cells[..30]);
+        assert_eq!(mtx_utils::mtx_row_sums(&a_mtx).unwrap()[29], 30.0);
+        let (_, b_rows, _) = mtx_utils::quant_mat_paths(&out.join("split").join("B"));
+        assert_eq!(
+            mtx_utils::read_row_barcodes(&b_rows).unwrap(),
+            cells[30..60]
+        );
+    }
+}
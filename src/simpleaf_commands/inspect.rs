@@ -2,6 +2,7 @@ use crate::atac::commands::AtacChemistry;
 use crate::utils::constants::CHEMISTRIES_PATH;
 use crate::utils::{
     af_utils::RnaChemistry,
+    chem_registry::ChemistryRegistry,
     chem_utils::{custom_chem_hm_into_json, get_custom_chem_hm},
     prog_utils::*,
 };
@@ -44,10 +45,18 @@ pub fn inspect_simpleaf(version: &str, af_home_path: PathBuf) -> Result<()> {
         .map(|x| format!("{:?}", x))
         .collect::<Vec<String>>();
 
+    // the registries chemistries are looked up in, by decreasing precedence
+    let registry_layers = ChemistryRegistry::layered(&custom_chem_p)
+        .layers()
+        .iter()
+        .map(|l| l.registry.display().to_string())
+        .collect::<Vec<String>>();
+
     let inspect_v = json!({
         "simpleaf_version" : version,
         "simpleaf_info" : simpleaf_info,
         "custom_chem_info" : chem_info_value,
+        "chemistry_registry_layers" : registry_layers,
         "builtin_chemistries" : {
             "rna" : rna_chem_list,
             "atac" : atac_chem_list,
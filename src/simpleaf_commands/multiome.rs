@@ -0,0 +1,562 @@
+//! Joint processing of 10x Multiome (ARC) GEX + ATAC libraries.
+//!
+//! 1. Quantify the GEX library with the RNA quant path (unfiltered GEX whitelist)
+//! 2. Map the ATAC library and generate the sorted BED with the ATAC process path
+//! 3. Translate ATAC barcodes into GEX barcode space via the paired whitelists
+//! 4. Call cells jointly from UMI and unique fragment counts (and TSS enrichment)
+//! 5. Write the GEX matrix and ATAC fragments of the called cells keyed by GEX barcode
+
+use crate::atac::cell_calling::{self, BarcodeMetrics};
+use crate::atac::commands::{AtacChemistry, Macs3GenomeSize, ProcessOpts};
+use crate::atac::defaults::DefaultAtacParams;
+use crate::atac::process as atac_process;
+use crate::core::io;
+use crate::defaults::{DefaultMappingParams, DefaultParams};
+use crate::simpleaf_commands::quant::barcode_translation::BarcodeTranslation;
+use crate::simpleaf_commands::{MapQuantOpts, MultiomeOpts, PiscemDict, quant};
+use crate::utils::af_utils::{self, CellFilterMethod, Chemistry, PermitListResult};
+use crate::utils::mtx_utils;
+
+use anyhow::{Context, bail};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// The name of the run metadata file written to the output directory.
+const MULTIOME_INFO_FILE: &str = "simpleaf_multiome_info.json";
+
+/// The chemistry of the ATAC library of a 10x Multiome run.
+const ATAC_CHEMISTRY: AtacChemistry = AtacChemistry::TenxMulti;
+
+/// The evidence for a single barcode (in GEX space) across both modalities.
+#[derive(Debug, Default, Clone)]
+struct JointRecord {
+    gex_row: Option<usize>,
+    umis: f64,
+    atac_barcode: Option<String>,
+    atac: BarcodeMetrics,
+}
+
+impl JointRecord {
+    fn in_both(&self) -> bool {
+        self.gex_row.is_some() && self.atac_barcode.is_some()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JointCallSummary {
+    method: String,
+    gex_umi_threshold: Option<f64>,
+    atac_fragment_threshold: Option<u64>,
+    min_tss_enrichment: Option<f64>,
+    num_cells: usize,
+    median_cell_umis: f64,
+    median_cell_fragments: u64,
+}
+
+/// The count at the `n`-th position of the descending counts, i.e. the minimum
+/// count a barcode must reach to be among the first `n`.
+fn threshold_at(sorted_desc: &[u64], n: usize) -> u64 {
+    if n == 0 {
+        u64::MAX
+    } else {
+        sorted_desc[n.min(sorted_desc.len()) - 1]
+    }
+}
+
+/// Call cells jointly. For the knee and expect-cells methods, the method is applied
+/// independently to the GEX UMI and ATAC unique fragment count curves, and a cell
+/// must pass both thresholds. For forced cells, barcodes are ranked by the product
+/// of their (pseudo-counted) UMI and fragment counts. If `min_tss_enrichment` is
+/// given, cells must also reach that TSS enrichment.
+fn call_joint_cells(
+    records: &HashMap<String, JointRecord>,
+    method: &CellFilterMethod,
+    min_tss_enrichment: Option<f64>,
+) -> anyhow::Result<(Vec<String>, JointCallSummary)> {
+    let mut candidates: Vec<(&String, &JointRecord)> =
+        records.iter().filter(|(_, r)| r.in_both()).collect();
+    candidates.sort_by(|a, b| a.0.cmp(b.0));
+
+    let (mut cells, method_str, gex_thr, atac_thr) = match method {
+        CellFilterMethod::ForceCells(n) => {
+            let score =
+                |r: &JointRecord| (1.0 + r.umis).ln() + (1.0 + r.atac.unique_fragments as f64).ln();
+            candidates.sort_by(|a, b| score(b.1).total_cmp(&score(a.1)).then_with(|| a.0.cmp(b.0)));
+            let cells: Vec<(&String, &JointRecord)> = candidates.iter().take(*n).copied().collect();
+            (cells, format!("forced_cells({})", n), None, None)
+        }
+        m => {
+            let mut umis: Vec<u64> = records
+                .values()
+                .filter(|r| r.gex_row.is_some())
+                .map(|r| r.umis.round() as u64)
+                .collect();
+            umis.sort_unstable_by(|a, b| b.cmp(a));
+            let mut frags: Vec<u64> = records
+                .values()
+                .filter(|r| r.atac_barcode.is_some())
+                .map(|r| r.atac.unique_fragments)
+                .collect();
+            frags.sort_unstable_by(|a, b| b.cmp(a));
+
+            let (n_gex, desc) = cell_calling::num_barcodes_passing(&umis, m)?;
+            let (n_atac, _) = cell_calling::num_barcodes_passing(&frags, m)?;
+            let gex_thr = threshold_at(&umis, n_gex);
+            let atac_thr = threshold_at(&frags, n_atac);
+            info!(
+                "joint cell calling thresholds: {} GEX UMIs, {} ATAC unique fragments.",
+                gex_thr, atac_thr
+            );
+            let cells = candidates
+                .iter()
+                .filter(|(_, r)| {
+                    r.umis.round() as u64 >= gex_thr && r.atac.unique_fragments >= atac_thr
+                })
+                .copied()
+                .collect();
+            (cells, desc, Some(gex_thr as f64), Some(atac_thr))
+        }
+    };
+
+    if let Some(min_e) = min_tss_enrichment {
+        cells.retain(|(_, r)| r.atac.tss_enrichment() >= min_e);
+    }
+    if cells.is_empty() {
+        warn!("joint cell calling did not retain any barcodes.");
+    }
+
+    let mut cell_umis: Vec<f64> = cells.iter().map(|(_, r)| r.umis).collect();
+    cell_umis.sort_by(|a, b| a.total_cmp(b));
+    let mut cell_frags: Vec<u64> = cells.iter().map(|(_, r)| r.atac.unique_fragments).collect();
+    cell_frags.sort_unstable();
+
+    let summary = JointCallSummary {
+        method: method_str,
+        gex_umi_threshold: gex_thr,
+        atac_fragment_threshold: atac_thr,
+        min_tss_enrichment,
+        num_cells: cells.len(),
+        median_cell_umis: cell_umis.get(cell_umis.len() / 2).copied().unwrap_or(0.0),
+        median_cell_fragments: cell_frags.get(cell_frags.len() / 2).copied().unwrap_or(0),
+    };
+    let mut cell_bcs: Vec<String> = cells.into_iter().map(|(bc, _)| bc.clone()).collect();
+    cell_bcs.sort();
+    Ok((cell_bcs, summary))
+}
+
+fn filter_method(opts: &MultiomeOpts) -> anyhow::Result<CellFilterMethod> {
+    if opts.knee {
+        Ok(CellFilterMethod::KneeFinding)
+    } else if let Some(n) = opts.expect_cells {
+        Ok(CellFilterMethod::ExpectCells(n))
+    } else if let Some(n) = opts.forced_cells {
+        Ok(CellFilterMethod::ForceCells(n))
+    } else {
+        bail!("one of --knee, --expect-cells or --forced-cells must be provided.")
+    }
+}
+
+/// Use the provided ATAC whitelist, or fetch the permit list registered for `10x-arc-atac-v1`.
+fn resolve_atac_whitelist(af_home_path: &Path, opts: &MultiomeOpts) -> anyhow::Result<PathBuf> {
+    if let Some(p) = &opts.atac_whitelist {
+        if !p.is_file() {
+            bail!(
+                "The provided ATAC whitelist {} does not exist.",
+                p.display()
+            );
+        }
+        return Ok(p.clone());
+    }
+    match af_utils::get_permit_if_absent(af_home_path, &Chemistry::Atac(ATAC_CHEMISTRY))? {
+        PermitListResult::DownloadSuccessful(p) | PermitListResult::AlreadyPresent(p) => Ok(p),
+        PermitListResult::UnregisteredChemistry => bail!(
+            "Cannot automatically obtain the ATAC permit list for {}; please provide --atac-whitelist.",
+            ATAC_CHEMISTRY.as_str()
+        ),
+    }
+}
+
+fn gex_quant_opts(opts: &MultiomeOpts, output: PathBuf) -> MapQuantOpts {
+    MapQuantOpts {
+        chemistry: opts.gex_chemistry.clone(),
+        output,
+        threads: opts.threads,
+        index: Some(opts.gex_index.clone()),
+        reads1: Some(opts.gex_reads1.clone()),
+        reads2: Some(opts.gex_reads2.clone()),
+        libraries: vec![],
+        feature_csv: None,
+        feature_index: None,
+        feature_chemistry: None,
+        translation_list: None,
+        use_piscem: false,
+        struct_constraints: false,
+        ignore_ambig_hits: false,
+        no_poison: false,
+        skipping_strategy: DefaultParams::SKIPPING_STRATEGY.to_string(),
+        max_ec_card: DefaultParams::MAX_EC_CARD,
+        max_hit_occ: DefaultParams::MAX_HIT_OCC,
+        max_hit_occ_recover: DefaultParams::MAX_HIT_OCC_RECOVER,
+        max_read_occ: DefaultParams::MAX_READ_OCC,
+        dict: PiscemDict::Auto,
+        map_dir: None,
+        knee: false,
+        unfiltered_pl: Some(Some(opts.gex_whitelist.clone())),
+        forced_cells: None,
+        explicit_pl: None,
+        expect_cells: None,
+        expected_ori: None,
+        min_reads: opts.min_reads,
+        t2g_map: opts.t2g_map.clone(),
+        resolution: opts.resolution.clone(),
+        anndata_out: false,
+        translate_barcodes: false,
+        spatial: false,
+        tissue_positions: None,
+        scalefactors: None,
+    }
+}
+
+fn atac_process_opts(opts: &MultiomeOpts, atac_whitelist: PathBuf, output: PathBuf) -> ProcessOpts {
+    ProcessOpts {
+        index: opts.atac_index.clone(),
+        reads1: Some(opts.atac_reads1.clone()),
+        reads2: Some(opts.atac_reads2.clone()),
+        reads: None,
+        barcode_reads: opts.atac_barcode_reads.clone(),
+        chemistry: String::from(ATAC_CHEMISTRY.as_str()),
+        barcode_length: ATAC_CHEMISTRY.barcode_length(),
+        output,
+        threads: opts.threads,
+        call_peaks: false,
+        stages: None,
+        start_from: None,
+        stop_after: None,
+        permit_barcode_ori: None,
+        unfiltered_pl: Some(atac_whitelist),
+        min_reads: opts.min_reads,
+        knee: false,
+        expect_cells: None,
+        forced_cells: None,
+        tss: None,
+        min_tss_enrichment: opts.min_tss_enrichment,
+        filter_fragments: false,
+        compress: false,
+        ignore_ambig_hits: false,
+        no_poison: false,
+        use_chr: false,
+        thr: DefaultParams::KMER_FRACTION,
+        bin_size: DefaultParams::BIN_SIZE,
+        bin_overlap: DefaultParams::BIN_OVERLAP,
+        no_tn5_shift: false,
+        check_kmer_orphan: false,
+        max_ec_card: DefaultParams::MAX_EC_CARD,
+        max_hit_occ: DefaultParams::MAX_HIT_OCC,
+        max_hit_occ_recover: DefaultParams::MAX_HIT_OCC_RECOVER,
+        max_read_occ: DefaultParams::MAX_READ_OCC,
+        gsize: Macs3GenomeSize::KnownOpt("hs"),
+        qvalue: 0.1,
+        extsize: 50,
+    }
+}
+
+/// Build the joint per-barcode table (keyed by GEX barcode). Returns the table and
+/// the number of ATAC barcodes that could not be translated.
+fn build_joint_records(
+    gex_barcodes: &[String],
+    gex_umis: &[f64],
+    atac_metrics: HashMap<String, BarcodeMetrics>,
+    translation: &BarcodeTranslation,
+) -> (HashMap<String, JointRecord>, usize) {
+    let mut records: HashMap<String, JointRecord> = HashMap::new();
+    for (i, (bc, umis)) in gex_barcodes.iter().zip(gex_umis).enumerate() {
+        let r = records.entry(bc.clone()).or_default();
+        r.gex_row = Some(i);
+        r.umis = *umis;
+    }
+    let mut untranslated = 0usize;
+    for (atac_bc, m) in atac_metrics {
+        match translation.translate(&atac_bc) {
+            Some(gex_bc) => {
+                let r = records.entry(gex_bc.to_string()).or_default();
+                r.atac_barcode = Some(atac_bc);
+                r.atac = m;
+            }
+            None => untranslated += 1,
+        }
+    }
+    (records, untranslated)
+}
+
+fn write_joint_metrics(
+    records: &HashMap<String, JointRecord>,
+    cells: &HashSet<&str>,
+    with_tss: bool,
+    out: &Path,
+) -> anyhow::Result<()> {
+    let mut w = BufWriter::new(
+        std::fs::File::create(out)
+            .with_context(|| format!("could not create {}", out.display()))?,
+    );
+    let mut rows: Vec<(&String, &JointRecord)> = records.iter().collect();
+    rows.sort_by(|a, b| a.0.cmp(b.0));
+    write!(
+        w,
+        "barcode\tatac_barcode\tgex_umis\tatac_unique_fragments\tatac_read_pairs"
+    )?;
+    if with_tss {
+        write!(w, "\ttss_enrichment")?;
+    }
+    writeln!(w, "\tis_cell")?;
+    for (bc, r) in rows {
+        write!(
+            w,
+            "{}\t{}\t{}\t{}\t{}",
+            bc,
+            r.atac_barcode.as_deref().unwrap_or("NA"),
+            r.umis,
+            r.atac.unique_fragments,
+            r.atac.read_pairs
+        )?;
+        if with_tss {
+            write!(w, "\t{:.4}", r.atac.tss_enrichment())?;
+        }
+        writeln!(w, "\t{}", cells.contains(bc.as_str()))?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Write the fragments of the called cells with their barcodes translated to GEX space.
+fn write_translated_fragments(
+    bed: &Path,
+    atac_to_gex_cells: &HashMap<&str, &str>,
+    out: &Path,
+) -> anyhow::Result<u64> {
+    let (reader, _fmt) = niffler::from_path(bed)
+        .with_context(|| format!("could not open fragment file {}", bed.display()))?;
+    let mut w = BufWriter::new(
+        std::fs::File::create(out)
+            .with_context(|| format!("could not create {}", out.display()))?,
+    );
+    let mut kept = 0u64;
+    for line in BufReader::new(reader).lines() {
+        let line = line.with_context(|| format!("could not read {}", bed.display()))?;
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() < 4 {
+            continue;
+        }
+        if let Some(gex_bc) = atac_to_gex_cells.get(toks[3]) {
+            let mut rec = toks.clone();
+            rec[3] = gex_bc;
+            writeln!(w, "{}", rec.join("\t"))?;
+            kept += 1;
+        }
+    }
+    w.flush()?;
+    Ok(kept)
+}
+
+pub fn multiome_process(af_home_path: &Path, opts: MultiomeOpts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let method = filter_method(&opts)?;
+    if !opts.gex_whitelist.is_file() {
+        bail!(
+            "The provided GEX whitelist {} does not exist.",
+            opts.gex_whitelist.display()
+        );
+    }
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+
+    let gex_out = opts.output.join("gex");
+    let atac_out = opts.output.join("atac");
+    let joint_out = opts.output.join("multiome");
+
+    let atac_whitelist = resolve_atac_whitelist(af_home_path, &opts)?;
+    let atac_opts = atac_process_opts(&opts, atac_whitelist.clone(), atac_out.clone());
+    atac_process::check_progs(af_home_path, &atac_opts)?;
+
+    // 1. GEX quantification over the unfiltered GEX whitelist
+    info!("quantifying the GEX library");
+    let gex_start = Instant::now();
+    quant::map_and_quant(af_home_path, gex_quant_opts(&opts, gex_out.clone()))?;
+    let gex_duration = gex_start.elapsed();
+
+    // 2. ATAC mapping and BED generation over the unfiltered ATAC whitelist
+    info!("processing the ATAC library");
+    let atac_start = Instant::now();
+    atac_process::map_reads(af_home_path, &atac_opts)?;
+    atac_process::gen_sorted_bed(af_home_path, &atac_opts)?;
+    let atac_duration = atac_start.elapsed();
+
+    // 3. translate and join
+    let joint_start = Instant::now();
+    let atac_chem = atac_process::resolve_chemistry(af_home_path, &atac_opts)?;
+    let atac_bc_ori = atac_process::permit_barcode_ori(af_home_path, &atac_opts, &atac_chem)?;
+    let translation = BarcodeTranslation::from_paired_whitelists(
+        &atac_whitelist,
+        &opts.gex_whitelist,
+        atac_bc_ori == "rc",
+    )?;
+    let gex_quant = gex_out.join("af_quant");
+    let (gex_mtx, gex_rows, _) = mtx_utils::quant_mat_paths(&gex_quant);
+    let gex_barcodes = mtx_utils::read_row_barcodes(&gex_rows)?;
+    let gex_umis = mtx_utils::mtx_row_sums(&gex_mtx)?;
+    if gex_barcodes.len() != gex_umis.len() {
+        bail!(
+            "{} lists {} barcodes but the count matrix has {} rows",
+            gex_rows.display(),
+            gex_barcodes.len(),
+            gex_umis.len()
+        );
+    }
+
+    let tss = match &opts.tss {
+        Some(p) => Some(cell_calling::read_tss_file(p)?),
+        None => None,
+    };
+    let atac_bed = atac_process::sorted_bed_path(&atac_opts);
+    let atac_metrics = cell_calling::collect_barcode_metrics(&atac_bed, tss.as_ref())?;
+    let num_atac_barcodes = atac_metrics.len();
+    let (records, untranslated) =
+        build_joint_records(&gex_barcodes, &gex_umis, atac_metrics, &translation);
+    if untranslated > 0 {
+        warn!(
+            "{} of {} ATAC barcodes could not be translated to GEX barcodes and were ignored.",
+            untranslated, num_atac_barcodes
+        );
+    }
+    let num_shared = records.values().filter(|r| r.in_both()).count();
+    info!(
+        "{} GEX barcodes, {} ATAC barcodes, {} barcodes observed in both modalities.",
+        gex_barcodes.len(),
+        num_atac_barcodes,
+        num_shared
+    );
+
+    // 4. joint cell calling
+    let min_tsse = tss.as_ref().map(|_| opts.min_tss_enrichment);
+    let (cells, summary) = call_joint_cells(&records, &method, min_tsse)?;
+    info!("jointly called {} cells.", summary.num_cells);
+
+    // 5. outputs keyed by the GEX barcode
+    std::fs::create_dir_all(&joint_out)
+        .with_context(|| format!("could not create {}", joint_out.display()))?;
+    let cells_file = joint_out.join("cells.txt");
+    cell_calling::write_cell_barcodes(&cells, &cells_file)?;
+    let cell_set: HashSet<&str> = cells.iter().map(|c| c.as_str()).collect();
+    let metrics_file = joint_out.join("barcode_metrics.tsv");
+    write_joint_metrics(&records, &cell_set, tss.is_some(), &metrics_file)?;
+
+    let keep_rows: Vec<usize> = cells.iter().filter_map(|bc| records[bc].gex_row).collect();
+    let joint_gex = joint_out.join("gex");
+    mtx_utils::subset_quant_dir(&gex_quant, &joint_gex, &keep_rows, Some(&cells))?;
+
+    let atac_to_gex_cells: HashMap<&str, &str> = cells
+        .iter()
+        .filter_map(|bc| {
+            records[bc]
+                .atac_barcode
+                .as_deref()
+                .map(|abc| (abc, bc.as_str()))
+        })
+        .collect();
+    let fragments_file = joint_out.join("atac_fragments.bed");
+    let num_fragments = write_translated_fragments(&atac_bed, &atac_to_gex_cells, &fragments_file)?;
+    info!(
+        "wrote {} fragments of the called cells to {}",
+        num_fragments,
+        fragments_file.display()
+    );
+    let joint_duration = joint_start.elapsed();
+
+    let mut convert_duration = None;
+    if opts.anndata_out {
+        let convert_start = Instant::now();
+        let opath = joint_gex.join("alevin").join("quants.h5ad");
+        af_anndata::convert_csr_to_anndata(&joint_gex, &opath)?;
+        convert_duration = Some(convert_start.elapsed().as_secs_f64());
+    }
+
+    let info_json = json!({
+        "gex_output": gex_out,
+        "atac_output": atac_out,
+        "gex_whitelist": opts.gex_whitelist,
+        "atac_whitelist": atac_whitelist,
+        "tss_file": opts.tss,
+        "num_gex_barcodes": gex_barcodes.len(),
+        "num_atac_barcodes": num_atac_barcodes,
+        "num_untranslated_atac_barcodes": untranslated,
+        "num_shared_barcodes": num_shared,
+        "cell_calling": summary,
+        "cells_file": cells_file,
+        "metrics_file": metrics_file,
+        "gex_matrix_dir": joint_gex,
+        "atac_fragments": fragments_file,
+        "num_cell_fragments": num_fragments,
+        "time_info": {
+            "gex_time": gex_duration.as_secs_f64(),
+            "atac_time": atac_duration.as_secs_f64(),
+            "joint_time": joint_duration.as_secs_f64(),
+            "conversion_time": convert_duration,
+            "total_time": start.elapsed().as_secs_f64(),
+        }
+    });
+    io::write_json_pretty_atomic(&opts.output.join(MULTIOME_INFO_FILE), &info_json)?;
+    info!(
+        "multiome processing complete; outputs written to {}",
+        joint_out.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(gex_row: Option<usize>, umis: f64, atac: Option<(&str, u64)>) -> JointRecord {
+        JointRecord {
+            gex_row,
+            umis,
+            atac_barcode: atac.map(|(bc, _)| bc.to_string()),
+            atac: BarcodeMetrics {
+                unique_fragments: atac.map(|(_, f)| f).unwrap_or(0),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn joint_calling_requires_both_modalities() {
+        let mut records = HashMap::new();
+        records.insert(
+            "C1".to_string(),
+            record(Some(0), 5000.0, Some(("A1", 8000))),
+        );
+        records.insert("C2".to_string(), record(Some(1), 4000.0, Some(("A2", 1))));
+        records.insert("C3".to_string(), record(Some(2), 10.0, Some(("A3", 9000))));
+        records.insert("C4".to_string(), record(Some(3), 4500.0, None));
+        records.insert("C5".to_string(), record(None, 0.0, Some(("A5", 7000))));
+        let (cells, summary) =
+            call_joint_cells(&records, &CellFilterMethod::ExpectCells(2), None).expect("call");
+        assert_eq!(cells, vec!["C1".to_string()]);
+        assert_eq!(summary.num_cells, 1);
+    }
+
+    #[test]
+    fn forced_joint_calling_ranks_by_both_counts() {
+        let mut records = HashMap::new();
+        records.insert("C1".to_string(), record(Some(0), 100.0, Some(("A1", 100))));
+        records.insert("C2".to_string(), record(Some(1), 1000.0, Some(("A2", 5))));
+        records.insert("C3".to_string(), record(Some(2), 500.0, Some(("A3", 500))));
+        let (cells, _) =
+            call_joint_cells(&records, &CellFilterMethod::ForceCells(2), None).expect("call");
+        assert_eq!(cells, vec!["C1".to_string(), "C3".to_string()]);
+    }
+}
@@ -6,18 +6,28 @@
 //! 3. Generate-permit-list (multi-barcode aware)
 //! 4. Collate (hierarchical, multi-barcode)
 //! 5. Quant with sample-prefixed output
+//! 6. Sample-barcode QC (see [`sample_bc_qc`])
+//! 7. Optionally, cell calling within each sample and splitting the sample-prefixed
+//!    output into one directory per sample
 
-use crate::core::{context, exec, index_meta};
+mod sample_bc_qc;
+
+use crate::atac::cell_calling;
+use crate::core::{context, exec, index_meta, io};
 use crate::simpleaf_commands::MultiplexQuantOpts;
-use crate::utils::af_utils::IndexType;
-use crate::utils::chem_utils::{CustomChemistry, CustomChemistryMap};
+use crate::utils::af_utils::{CellFilterMethod, IndexType};
+use crate::utils::chem_registry::ChemistryRegistry;
+use crate::utils::chem_utils::CustomChemistry;
 use crate::utils::constants::CHEMISTRIES_PATH;
+use crate::utils::integrity;
+use crate::utils::mtx_utils;
 use crate::utils::probe_utils;
 use crate::utils::prog_parsing_utils;
 use crate::utils::prog_utils;
 
 use anyhow::{Context, bail};
 use serde_json::json;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 use tracing::{info, warn};
@@ -223,6 +233,122 @@ fn resolve_user_supplied_index(
     );
 }
 
+/// Runs the external stages of the pipeline (mapping, permit list generation,
+/// collate and quant), recording each completed stage in
+/// `simpleaf_multiplex_quant_info.json` as soon as it finishes. With `--resume`,
+/// a stage is skipped if the previous run recorded there completed it with the
+/// same options (see [`stage_options`]), its outputs still exist, and no earlier
+/// stage had to be rerun.
+struct StageTracker {
+    info_path: PathBuf,
+    previous: Option<serde_json::Value>,
+    progress: serde_json::Value,
+    completed: Vec<String>,
+    resumed: Vec<String>,
+    rerun: bool,
+}
+
+impl StageTracker {
+    fn new(output_dir: &Path, resume: bool) -> anyhow::Result<Self> {
+        let info_path = output_dir.join("simpleaf_multiplex_quant_info.json");
+        let previous = if resume && info_path.is_file() {
+            Some(io::read_json_file(&info_path)?)
+        } else {
+            if resume {
+                info!(
+                    "No previous run was found in {}; running every stage.",
+                    output_dir.display()
+                );
+            }
+            None
+        };
+        Ok(Self {
+            info_path,
+            previous,
+            progress: json!({}),
+            completed: Vec::new(),
+            resumed: Vec::new(),
+            rerun: false,
+        })
+    }
+
+    /// Whether the previous run completed `stage` with `options`, leaving `outputs`.
+    fn completed_previously(&self, stage: &str, options: &str, outputs: &[PathBuf]) -> bool {
+        let Some(prev) = &self.previous else {
+            return false;
+        };
+        let completed = prev["completed_stages"]
+            .as_array()
+            .is_some_and(|stages| stages.iter().any(|s| s.as_str() == Some(stage)));
+        completed
+            && prev[format!("{}_options", stage)].as_str() == Some(options)
+            && outputs.iter().all(|p| p.is_file())
+    }
+
+    /// Run (or, when resuming, skip) `stage`, returning its command line.
+    fn run(
+        &mut self,
+        stage: &str,
+        cmd: &mut std::process::Command,
+        outputs: &[PathBuf],
+        label: &str,
+    ) -> anyhow::Result<String> {
+        let cmd_str = prog_utils::get_cmd_line_string(cmd);
+        let options = stage_options(cmd);
+        info!("{} cmd: {}", stage, cmd_str);
+        let duration_secs = if !self.rerun && self.completed_previously(stage, &options, outputs) {
+            info!(
+                "The {} stage already completed in the previous run; skipping it.",
+                stage
+            );
+            self.resumed.push(stage.to_string());
+            self.previous
+                .as_ref()
+                .and_then(|prev| prev[format!("{}_duration_secs", stage)].as_f64())
+                .unwrap_or(0.0)
+        } else {
+            // every later stage depends on the outputs of this one
+            self.rerun = true;
+            let stage_start = Instant::now();
+            exec::run_checked(cmd, label)?;
+            let duration = stage_start.elapsed();
+            info!(
+                "The {} stage completed in {:.1}s",
+                stage,
+                duration.as_secs_f64()
+            );
+            duration.as_secs_f64()
+        };
+
+        self.completed.push(stage.to_string());
+        self.progress[format!("{}_cmd", stage)] = json!(cmd_str);
+        self.progress[format!("{}_options", stage)] = json!(options);
+        self.progress[format!("{}_duration_secs", stage)] = json!(duration_secs);
+        self.progress["completed_stages"] = json!(self.completed);
+        io::write_json_pretty_atomic(&self.info_path, &self.progress)?;
+        Ok(cmd_str)
+    }
+
+    fn duration_secs(&self, stage: &str) -> Option<f64> {
+        self.progress[format!("{}_duration_secs", stage)].as_f64()
+    }
+}
+
+/// The command line of `cmd` without its thread count (`-t <threads>`), which
+/// doesn't change the outputs of a stage.
+fn stage_options(cmd: &std::process::Command) -> String {
+    let mut options = vec![cmd.get_program().to_string_lossy().to_string()];
+    let mut args = cmd.get_args();
+    while let Some(arg) = args.next() {
+        if arg == "-t" {
+            args.next();
+        } else {
+            options.push(arg.to_string_lossy().to_string());
+        }
+    }
+    options.join(" ")
+}
+
 /// Main entry point for the multiplex-quant pipeline.
 pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyhow::Result<()> {
     let start = Instant::now();
@@ -246,25 +372,26 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
     // Load chemistry from registry (optional)
     let chem: Option<CustomChemistry> = if let Some(ref chem_name) = opts.chemistry {
         let chem_path = af_home.join(CHEMISTRIES_PATH);
-        if !chem_path.exists() {
+        let registry = ChemistryRegistry::layered(&chem_path);
+        if !registry.exists() {
             bail!(
                 "Chemistry registry not found at {}. Run `simpleaf chemistry refresh` first.",
                 chem_path.display(),
             );
         }
-        let chem_file = std::fs::File::open(&chem_path)?;
-        let chem_map: CustomChemistryMap = serde_json::from_reader(chem_file)
-            .with_context(|| format!("couldn't parse {}", chem_path.display()))?;
-
-        let c = chem_map
-            .get(chem_name)
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Chemistry '{}' not found in registry. Run `simpleaf chemistry refresh`.",
-                    chem_name,
-                )
-            })?
-            .clone();
+
+        let (c, layer) = registry.lookup(chem_name)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Chemistry '{}' not found in registry. Run `simpleaf chemistry refresh`.",
+                chem_name,
+            )
+        })?;
+        c.log_resolution(chem_name);
+        info!(
+            "Using chemistry '{}' from the {} registry",
+            c.name(),
+            layer.name
+        );
 
         Some(c)
     } else {
@@ -297,10 +424,23 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
     // Create output directory structure
     let output_dir = &opts.output;
     std::fs::create_dir_all(output_dir)?;
-    let map_output = output_dir.join("af_map");
+    let map_output = if let Some(map_dir) = &opts.map_dir {
+        if !map_dir.join("map.rad").is_file() {
+            bail!(
+                "The mapping directory {} does not contain a RAD file (map.rad).",
+                map_dir.display()
+            );
+        }
+        map_dir.clone()
+    } else {
+        output_dir.join("af_map")
+    };
     let quant_output = output_dir.join("af_quant");
-    std::fs::create_dir_all(&map_output)?;
+    if opts.map_dir.is_none() {
+        std::fs::create_dir_all(&map_output)?;
+    }
     std::fs::create_dir_all(&quant_output)?;
+    let mut stages = StageTracker::new(output_dir, opts.resume)?;
 
     // === Step 1: Resolve index and t2g ===
     let (index_path, probe_t2g_path_opt, gene_id_to_name_opt) = resolve_probe_index(
@@ -335,54 +475,70 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
     // === Step 3: Resolve probe barcode (sample BC) file ===
     let sample_bc_path = resolve_sample_bc_list(af_home, chem.as_ref(), &opts)?;
 
-    // === Step 4: Map reads with piscem ===
-    info!("Mapping reads with piscem...");
-    let mut piscem_cmd = std::process::Command::new(&piscem_info.exe_path);
-    piscem_cmd
-        .arg("map-sc")
-        .arg("-i")
-        .arg(&index_path)
-        .arg("-g")
-        .arg(&geometry)
-        .arg("-o")
-        .arg(&map_output)
-        .arg("-t")
-        .arg(format!("{}", opts.threads));
-
-    if opts.struct_constraints {
-        piscem_cmd.arg("--struct-constraints");
-    }
-    piscem_cmd
-        .arg("--skipping-strategy")
-        .arg(&opts.skipping_strategy)
-        .arg("--max-ec-card")
-        .arg(format!("{}", opts.max_ec_card))
-        .arg("--dict")
-        .arg(opts.dict.as_cli());
-
-    let r1_str: Vec<String> = opts
-        .reads1
-        .iter()
-        .map(|p| p.display().to_string())
-        .collect();
-    let r2_str: Vec<String> = opts
-        .reads2
-        .iter()
-        .map(|p| p.display().to_string())
-        .collect();
-    piscem_cmd.arg("-1").arg(r1_str.join(","));
-    piscem_cmd.arg("-2").arg(r2_str.join(","));
-
-    let map_cmd_str = prog_utils::get_cmd_line_string(&piscem_cmd);
-    info!("piscem map-scrna cmd: {}", map_cmd_str);
-    let map_start = Instant::now();
-    exec::run_checked(&mut piscem_cmd, "[piscem map-sc]")?;
-    let map_duration = map_start.elapsed();
-    info!("Mapping complete in {:.1}s", map_duration.as_secs_f64());
-    let mapping_log = prog_parsing_utils::construct_json_from_piscem_log(map_output.join("map_info.json"))?;
-    let map_info_path = quant_output.join("simpleaf_map_info.json");
-    let map_info_file = std::fs::File::create(&map_info_path)?;
-    serde_json::to_writer(map_info_file, &mapping_log)?;
+    // === Step 4: Map reads with piscem (unless an existing mapping was given) ===
+    let map_cmd_str = if let Some(map_dir) = &opts.map_dir {
+        info!("Using the existing mapping in {}", map_dir.display());
+        None
+    } else {
+        let mut piscem_cmd = std::process::Command::new(&piscem_info.exe_path);
+        piscem_cmd
+            .arg("map-sc")
+            .arg("-i")
+            .arg(&index_path)
+            .arg("-g")
+            .arg(&geometry)
+            .arg("-o")
+            .arg(&map_output)
+            .arg("-t")
+            .arg(format!("{}", opts.threads));
+
+        if opts.struct_constraints {
+            piscem_cmd.arg("--struct-constraints");
+        }
+        piscem_cmd
+            .arg("--skipping-strategy")
+            .arg(&opts.skipping_strategy)
+            .arg("--max-ec-card")
+            .arg(format!("{}", opts.max_ec_card))
+            .arg("--dict")
+            .arg(opts.dict.as_cli());
+
+        let r1_str: Vec<String> = opts
+            .reads1
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        let r2_str: Vec<String> = opts
+            .reads2
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        piscem_cmd.arg("-1").arg(r1_str.join(","));
+        piscem_cmd.arg("-2").arg(r2_str.join(","));
+
+        info!("Mapping reads with piscem...");
+        let map_cmd_str = stages.run(
+            "map",
+            &mut piscem_cmd,
+            &[map_output.join("map.rad")],
+            "[piscem map-sc]",
+        )?;
+        Some(map_cmd_str)
+    };
+    let map_log_path = map_output.join("map_info.json");
+    let map_info_path = if map_log_path.is_file() {
+        let mapping_log = prog_parsing_utils::construct_json_from_piscem_log(&map_log_path)?;
+        let map_info_path = quant_output.join("simpleaf_map_info.json");
+        let map_info_file = std::fs::File::create(&map_info_path)?;
+        serde_json::to_writer(map_info_file, &mapping_log)?;
+        Some(map_info_path)
+    } else {
+        warn!(
+            "No mapping log was found at {}; the mapping information will not be recorded.",
+            map_log_path.display()
+        );
+        None
+    };
 
     // === Step 5: Generate permit list (multi-barcode) ===
     info!("Generating permit list...");
@@ -416,11 +572,12 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
         }
     }
 
-    let gpl_cmd_str = prog_utils::get_cmd_line_string(&gpl_cmd);
-    info!("generate-permit-list cmd: {}", gpl_cmd_str);
-    let gpl_start = Instant::now();
-    exec::run_checked(&mut gpl_cmd, "[generate permit list]")?;
-    let gpl_duration = gpl_start.elapsed();
+    let gpl_cmd_str = stages.run(
+        "gpl",
+        &mut gpl_cmd,
+        &[quant_output.join("generate_permit_list.json")],
+        "[generate permit list]",
+    )?;
 
     // === Step 6: Collate ===
     info!("Collating...");
@@ -434,11 +591,12 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
         .arg("-t")
         .arg(format!("{}", opts.threads));
 
-    let collate_cmd_str = prog_utils::get_cmd_line_string(&collate_cmd);
-    info!("collate cmd: {}", collate_cmd_str);
-    let collate_start = Instant::now();
-    exec::run_checked(&mut collate_cmd, "[collate]")?;
-    let collate_duration = collate_start.elapsed();
+    let collate_cmd_str = stages.run(
+        "collate",
+        &mut collate_cmd,
+        &[quant_output.join("collate.json")],
+        "[collate]",
+    )?;
 
     // === Step 7: Quantify ===
     info!("Quantifying...");
@@ -457,11 +615,13 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
         .arg(&opts.resolution)
         .arg("--use-mtx");
 
-    let quant_cmd_str = prog_utils::get_cmd_line_string(&quant_cmd);
-    info!("quant cmd: {}", quant_cmd_str);
-    let quant_start = Instant::now();
-    exec::run_checked(&mut quant_cmd, "[quant]")?;
-    let quant_duration = quant_start.elapsed();
+    let (quant_mtx, _, _) = mtx_utils::quant_mat_paths(&quant_output);
+    let quant_cmd_str = stages.run(
+        "quant",
+        &mut quant_cmd,
+        &[quant_output.join("quant.json"), quant_mtx],
+        "[quant]",
+    )?;
 
     if let Some(gene_id_to_name_path) = &gene_id_to_name_opt {
         let target_path = quant_output.join("gene_id_to_name.tsv");
@@ -494,15 +654,18 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
         "gene_id_to_name_path": gene_id_to_name_opt
             .as_ref()
             .map(|p| p.display().to_string()),
+        "map_dir": map_output.display().to_string(),
         "map_cmd": map_cmd_str,
         "gpl_cmd": gpl_cmd_str,
         "collate_cmd": collate_cmd_str,
         "quant_cmd": quant_cmd_str,
-        "map_info_path": map_info_path.display().to_string(),
-        "map_duration_secs": map_duration.as_secs_f64(),
-        "gpl_duration_secs": gpl_duration.as_secs_f64(),
-        "collate_duration_secs": collate_duration.as_secs_f64(),
-        "quant_duration_secs": quant_duration.as_secs_f64(),
+        "map_info_path": map_info_path.as_ref().map(|p| p.display().to_string()),
+        "map_duration_secs": stages.duration_secs("map"),
+        "gpl_duration_secs": stages.duration_secs("gpl"),
+        "collate_duration_secs": stages.duration_secs("collate"),
+        "quant_duration_secs": stages.duration_secs("quant"),
+        "completed_stages": stages.completed,
+        "resumed_stages": stages.resumed,
         "anndata_path": anndata_path.as_ref().map(|p| p.display().to_string()),
         "conversion_duration_secs": convert_duration_secs,
         "total_duration_secs": start.elapsed().as_secs_f64(),
@@ -519,6 +682,95 @@ pub fn multiplex_map_and_quant(af_home: &Path, opts: MultiplexQuantOpts) -> anyh
         convert_duration_secs = Some(convert_start.elapsed().as_secs_f64());
     }
 
+    // === Sample-barcode QC ===
+    let qc_start = Instant::now();
+    let sample_bc_ori = chem
+        .as_ref()
+        .and_then(|c| c.sample_bc_list.as_ref())
+        .and_then(|s| s.sample_bc_ori.as_deref())
+        .unwrap_or("forward");
+    let sample_barcodes =
+        sample_bc_qc::SampleBarcodes::from_file(&sample_bc_path, sample_bc_ori == "reverse")?;
+    let sampled = if opts.sample_bc_qc_reads > 0 && opts.map_dir.is_none() {
+        info!(
+            "Sampling the sample barcodes of up to {} reads...",
+            opts.sample_bc_qc_reads
+        );
+        Some(sample_bc_qc::sample_read_barcodes(
+            &geometry,
+            &opts.reads1,
+            &opts.reads2,
+            opts.sample_bc_qc_reads,
+        )?)
+    } else {
+        None
+    };
+    let qc = sample_bc_qc::sample_bc_qc(&sample_barcodes, sampled.as_ref(), &quant_output)?;
+    sample_bc_qc::write_sample_bc_qc(&qc, output_dir, sample_bc_ori)?;
+    meta["sample_bc_qc"] = json!({
+        "path": output_dir.join("sample_bc_qc.json").display().to_string(),
+        "orientation_flipped": qc.orientation_flipped(),
+        "reads": qc.reads,
+    });
+    meta["sample_bc_qc_duration_secs"] = json!(qc_start.elapsed().as_secs_f64());
+
+    let cell_filters = sample_cell_filters(&opts, &sample_bc_path)?;
+    let samples = if opts.split_by_sample {
+        read_sample_names(&sample_bc_path)?
+    } else {
+        Vec::new()
+    };
+
+    if opts.split_by_sample {
+        info!("Splitting the quantification by sample...");
+        let split_start = Instant::now();
+        let split_root = output_dir.join("af_quant_by_sample");
+        let summaries =
+            split_quant_dir_by_sample(&quant_output, &split_root, &samples, opts.anndata_out)?;
+        meta["split_by_sample_dir"] = json!(split_root.display().to_string());
+        meta["samples"] = json!(summaries);
+        meta["split_duration_secs"] = json!(split_start.elapsed().as_secs_f64());
+    }
+
+    // === Optional: call cells within each sample ===
+    if let Some(filters) = cell_filters {
+        info!("Calling cells within each sample...");
+        let call_start = Instant::now();
+        let (cells, summaries) = call_cells_by_sample(&quant_output, &filters)?;
+        let filtered_output = output_dir.join("af_quant_filtered");
+        mtx_utils::subset_quant_dir(&quant_output, &filtered_output, &cells, None)?;
+        io::write_json_pretty(
+            &filtered_output.join("simpleaf_cell_calling.json"),
+            &summaries,
+        )?;
+        info!(
+            "Called {} cells; the filtered matrix was written to {}",
+            cells.len(),
+            filtered_output.display()
+        );
+        if opts.anndata_out {
+            let opath = filtered_output.join("alevin").join("quants.h5ad");
+            af_anndata::convert_csr_to_anndata(&filtered_output, &opath)?;
+        }
+        meta["cell_calling"] = json!({
+            "filtered_dir": filtered_output.display().to_string(),
+            "num_cells": cells.len(),
+            "samples": summaries,
+        });
+        if opts.split_by_sample {
+            let split_root = output_dir.join("af_quant_filtered_by_sample");
+            let split_summaries = split_quant_dir_by_sample(
+                &filtered_output,
+                &split_root,
+                &samples,
+                opts.anndata_out,
+            )?;
+            meta["cell_calling"]["split_by_sample_dir"] = json!(split_root.display().to_string());
+            meta["cell_calling"]["split_samples"] = json!(split_summaries);
+        }
+        meta["cell_calling_duration_secs"] = json!(call_start.elapsed().as_secs_f64());
+    }
+
     // === Rewrite pipeline metadata with final timing information ===
     meta["conversion_duration_secs"] = json!(convert_duration_secs);
     meta["total_duration_secs"] = json!(start.elapsed().as_secs_f64());
@@ -599,40 +851,90 @@ fn resolve_probe_index(
     let cache_dir = af_home.join("probe_indices");
     std::fs::create_dir_all(&cache_dir)?;
     let cache_key = probe_info.plist_name.as_deref().unwrap_or("unknown");
-    let cached_index = cache_dir.join(format!("{}_{}", cache_key, opts.kmer_length));
-        let cached_probe_index_dir = cached_index.join("probe_index");
-        let cached_probe_index = cached_probe_index_dir.join("index");
-        if probe_index_base_exists(&cached_probe_index) {
-            let candidates = multiplex_t2g_candidates_for(None, Some(&cached_probe_index_dir), mode);
-            let t2g =
-                resolve_t2g_from_candidates(&candidates, &opts.output.join("resolved_t2g"), mode)?;
-            let gene_id_to_name = gene_id_to_name_for_dir(Some(&cached_probe_index_dir));
-            info!("Using cached probe index: {}", cached_probe_index.display());
-            return Ok((cached_probe_index, t2g, gene_id_to_name));
-        }
+    let cached_name = format!("{}_{}", cache_key, opts.kmer_length);
+    let cached_index = cache_dir.join(&cached_name);
+    // only one of the processes sharing ALEVIN_FRY_HOME builds the index; the others
+    // wait for it, and then use it
+    let _lock = io::FileLock::for_file(&cached_index)?;
+    let cached_probe_index_dir = cached_index.join("probe_index");
+    let cached_probe_index = cached_probe_index_dir.join("index");
+    if probe_index_base_exists(&cached_probe_index) {
+        let candidates = multiplex_t2g_candidates_for(None, Some(&cached_probe_index_dir), mode);
+        let t2g =
+            resolve_t2g_from_candidates(&candidates, &opts.output.join("resolved_t2g"), mode)?;
+        let gene_id_to_name = gene_id_to_name_for_dir(Some(&cached_probe_index_dir));
+        info!("Using cached probe index: {}", cached_probe_index.display());
+        return Ok((cached_probe_index, t2g, gene_id_to_name));
+    }
 
-    // Download and build
-    if let Some(ref url) = probe_info.remote_url {
-        info!("Downloading probe set '{}'...", probe_info.name);
-        let download_dir = cache_dir.join("downloads");
-        std::fs::create_dir_all(&download_dir)?;
-        let csv_path = download_dir.join(format!("{}.csv", probe_info.name));
-        if !csv_path.exists() {
-            prog_utils::download_to_file(url, &csv_path)?;
-            info!("Downloaded probe set to {}", csv_path.display());
+    // Use the registered copy of the probe set, or download it, and build
+    let download_dir = cache_dir.join("downloads");
+    std::fs::create_dir_all(&download_dir)?;
+    let csv_name = probe_info.plist_name.as_deref().unwrap_or(&probe_info.name);
+    let csv_path = download_dir.join(format!("{}.csv", csv_name));
+    if !csv_path.exists() {
+        let registered = probe_info.plist_name.as_ref().and_then(|hash| {
+            ChemistryRegistry::layered(&af_home.join(CHEMISTRIES_PATH)).find_cached_file(hash)
+        });
+        if let Some(registered) = registered {
+            info!("Using registered probe set '{}'", probe_info.name);
+            let registered = integrity::ensure_intact(
+                af_home,
+                &registered,
+                probe_info.remote_url.as_deref(),
+                "probe set",
+            )?;
+            let tmp = io::temp_file_for(&csv_path)?;
+            std::fs::copy(&registered, tmp.path())?;
+            io::persist(tmp, &csv_path)?;
+        } else if let Some(ref url) = probe_info.remote_url {
+            info!("Downloading probe set '{}'...", probe_info.name);
+            // indices of other k-mer lengths may be built from the same probe set
+            if prog_utils::download_if_absent(url, &csv_path)? {
+                info!("Downloaded probe set to {}", csv_path.display());
+            }
+        } else {
+            bail!(
+                "No remote URL for probe set '{}'. Provide --probe-set.",
+                probe_info.name,
+            );
         }
+    }
+    let csv_path = integrity::ensure_intact(
+        af_home,
+        &csv_path,
+        probe_info.remote_url.as_deref(),
+        "probe set",
+    )?;
 
-        // Build index in the cache directory
-        let mut build_opts = opts.clone();
-        build_opts.output = cached_index.clone();
-        let result = build_index_from_probe_set(&csv_path, &build_opts, piscem_path, mode)?;
-        Ok((result.0, Some(result.1), result.2))
-    } else {
-        bail!(
-            "No remote URL for probe set '{}'. Provide --probe-set.",
-            probe_info.name,
-        );
+    // Build the index in a hidden directory, moved into the cache once complete, so
+    // that an interrupted build never leaves a partial index behind
+    let build_dir = cache_dir.join(format!(".{}.build", cached_name));
+    for stale in [&build_dir, &cached_index] {
+        if stale.exists() {
+            std::fs::remove_dir_all(stale)
+                .with_context(|| format!("could not remove {}", stale.display()))?;
+        }
     }
+    let mut build_opts = opts.clone();
+    build_opts.output = build_dir.clone();
+    let result = build_index_from_probe_set(&csv_path, &build_opts, piscem_path, mode)?;
+    std::fs::rename(&build_dir, &cached_index).with_context(|| {
+        format!(
+            "could not move the probe index from {} to {}",
+            build_dir.display(),
+            cached_index.display()
+        )
+    })?;
+    let in_cache = |p: PathBuf| match p.strip_prefix(&build_dir) {
+        Ok(rel) => cached_index.join(rel),
+        Err(_) => p,
+    };
+    Ok((
+        in_cache(result.0),
+        Some(in_cache(result.1)),
+        result.2.map(in_cache),
+    ))
 }
 
 /// Build a probe index from a CSV or FASTA file.
@@ -680,24 +982,33 @@ fn resolve_cell_bc_whitelist(af_home: &Path, chem: &CustomChemistry) -> anyhow::
     let plist_dir = af_home.join("plist");
     std::fs::create_dir_all(&plist_dir)?;
 
-    if let Some(ref hash) = chem.plist_name {
-        let cached = plist_dir.join(hash);
-        if cached.exists() {
-            info!("Cell barcode whitelist cached: {}", cached.display());
-            return Ok(cached);
-        }
+    if let Some(ref hash) = chem.plist_name
+        && let Some(cached) =
+            ChemistryRegistry::layered(&af_home.join(CHEMISTRIES_PATH)).find_cached_file(hash)
+    {
+        info!("Cell barcode whitelist cached: {}", cached.display());
+        return integrity::ensure_intact(
+            af_home,
+            &cached,
+            chem.remote_pl_url.as_deref(),
+            "cell barcode whitelist",
+        );
     }
 
     if let Some(ref url) = chem.remote_pl_url {
+        info!("Downloading cell barcode whitelist...");
         let dest = if let Some(ref hash) = chem.plist_name {
-            plist_dir.join(hash)
+            // named by its content, so a copy downloaded concurrently can be reused
+            let dest = plist_dir.join(hash);
+            prog_utils::download_if_absent(url, &dest)?;
+            dest
         } else {
-            plist_dir.join("cell_bc_whitelist.txt")
+            let dest = plist_dir.join("cell_bc_whitelist.txt");
+            prog_utils::download_to_file(url, &dest)?;
+            dest
         };
-        info!("Downloading cell barcode whitelist...");
-        prog_utils::download_to_file(url, &dest)?;
         info!("Downloaded to {}", dest.display());
-        Ok(dest)
+        integrity::ensure_intact(af_home, &dest, Some(url), "cell barcode whitelist")
     } else {
         bail!(
             "Chemistry '{}' has no cell barcode whitelist URL.",
@@ -730,33 +1041,348 @@ fn resolve_sample_bc_list(
     let plist_dir = af_home.join("plist");
     std::fs::create_dir_all(&plist_dir)?;
 
-    if let Some(ref hash) = sbc_info.plist_name {
-        let cached = plist_dir.join(hash);
-        if cached.exists() {
-            info!("Sample barcode list cached: {}", cached.display());
-            return Ok(cached);
-        }
+    if let Some(ref hash) = sbc_info.plist_name
+        && let Some(cached) =
+            ChemistryRegistry::layered(&af_home.join(CHEMISTRIES_PATH)).find_cached_file(hash)
+    {
+        info!("Sample barcode list cached: {}", cached.display());
+        return integrity::ensure_intact(
+            af_home,
+            &cached,
+            sbc_info.remote_url.as_deref(),
+            "sample barcode list",
+        );
     }
 
     if let Some(ref url) = sbc_info.remote_url {
+        info!("Downloading sample barcode list...");
         let dest = if let Some(ref hash) = sbc_info.plist_name {
-            plist_dir.join(hash)
+            // named by its content, so a copy downloaded concurrently can be reused
+            let dest = plist_dir.join(hash);
+            prog_utils::download_if_absent(url, &dest)?;
+            dest
         } else {
-            plist_dir.join("sample_bc_list.txt")
+            let dest = plist_dir.join("sample_bc_list.txt");
+            prog_utils::download_to_file(url, &dest)?;
+            dest
         };
-        info!("Downloading sample barcode list...");
-        prog_utils::download_to_file(url, &dest)?;
         info!("Downloaded to {}", dest.display());
-        Ok(dest)
+        integrity::ensure_intact(af_home, &dest, Some(url), "sample barcode list")
     } else {
         bail!("Chemistry has no sample barcode list URL. Provide --sample-bc-list.");
     }
 }
 
+/// Read the sample names (the third, `sample_name` column) of a sample BC list,
+/// in the order in which they first appear.
+fn read_sample_names(sample_bc_path: &Path) -> anyhow::Result<Vec<String>> {
+    let content = std::fs::read_to_string(sample_bc_path)
+        .with_context(|| format!("could not read {}", sample_bc_path.display()))?;
+    let mut names: Vec<String> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(name) = fields.get(2) else {
+            bail!(
+                "line {} of {} has {} column(s), but the observed, canonical and sample_name columns are needed to split the output by sample.",
+                i + 1,
+                sample_bc_path.display(),
+                fields.len()
+            );
+        };
+        // an optional header line
+        if names.is_empty() && *name == "sample_name" {
+            continue;
+        }
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    if names.is_empty() {
+        bail!("{} does not list any samples.", sample_bc_path.display());
+    }
+    Ok(names)
+}
+
+/// Turn a sample name into a safe directory name.
+pub(crate) fn sample_dir_name(sample: &str) -> String {
+    sample
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn median(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mut v = values.to_vec();
+    v.sort_by(f64::total_cmp);
+    let mid = v.len() / 2;
+    if v.len().is_multiple_of(2) {
+        (v[mid - 1] + v[mid]) / 2.0
+    } else {
+        v[mid]
+    }
+}
+
+/// Group the (0-based) rows of a sample-prefixed (`<sample_name>_<cell barcode>`)
+/// matrix by their sample. Also returns the number of rows that belong to none
+/// of `samples`.
+fn group_rows_by_sample<'a>(
+    rows: &'a [String],
+    samples: &[String],
+) -> (HashMap<&'a str, Vec<usize>>, usize) {
+    let mut by_sample: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut unassigned = 0usize;
+    for (i, row) in rows.iter().enumerate() {
+        match row.rsplit_once('_') {
+            Some((sample, _)) if samples.iter().any(|s| s == sample) => {
+                by_sample.entry(sample).or_default().push(i);
+            }
+            _ => unassigned += 1,
+        }
+    }
+    (by_sample, unassigned)
+}
+
+/// Read a sample sheet of `sample_name` and expected number of cells (an
+/// optional header line is skipped).
+fn read_sample_sheet(sheet: &Path) -> anyhow::Result<Vec<(String, usize)>> {
+    let content = std::fs::read_to_string(sheet)
+        .with_context(|| format!("could not read {}", sheet.display()))?;
+    let mut entries: Vec<(String, usize)> = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [name, expected] = fields[..] else {
+            bail!(
+                "line {} of {} should have two columns (sample_name and expected cells) but has {}.",
+                i + 1,
+                sheet.display(),
+                fields.len()
+            );
+        };
+        let Ok(expected) = expected.parse::<usize>() else {
+            if entries.is_empty() {
+                // a header line
+                continue;
+            }
+            bail!(
+                "invalid expected number of cells for sample {} in {}: {}",
+                name,
+                sheet.display(),
+                expected
+            );
+        };
+        if entries.iter().any(|(n, _)| n == name) {
+            bail!(
+                "sample {} is listed more than once in {}.",
+                name,
+                sheet.display()
+            );
+        }
+        entries.push((name.to_string(), expected));
+    }
+    if entries.is_empty() {
+        bail!("{} does not list any samples.", sheet.display());
+    }
+    Ok(entries)
+}
+
+/// The cell calling method to apply within each sample, if cell calling was
+/// requested.
+fn sample_cell_filters(
+    opts: &MultiplexQuantOpts,
+    sample_bc_path: &Path,
+) -> anyhow::Result<Option<Vec<(String, CellFilterMethod)>>> {
+    let method = if opts.knee {
+        CellFilterMethod::KneeFinding
+    } else if let Some(n) = opts.expect_cells {
+        CellFilterMethod::ExpectCells(n)
+    } else if let Some(sheet) = &opts.sample_sheet {
+        let samples = read_sample_names(sample_bc_path)?;
+        let entries = read_sample_sheet(sheet)?;
+        if let Some((name, _)) = entries.iter().find(|(n, _)| !samples.contains(n)) {
+            bail!(
+                "sample {} of the sample sheet {} is not in the sample BC list {}.",
+                name,
+                sheet.display(),
+                sample_bc_path.display()
+            );
+        }
+        return Ok(Some(
+            entries
+                .into_iter()
+                .map(|(n, e)| (n, CellFilterMethod::ExpectCells(e)))
+                .collect(),
+        ));
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(
+        read_sample_names(sample_bc_path)?
+            .into_iter()
+            .map(|n| (n, method.clone()))
+            .collect(),
+    ))
+}
+
+/// Call cells independently within each sample of the combined quantification,
+/// ranking the sample's barcodes by their number of UMIs. Returns the (sorted)
+/// rows of the combined matrix that are cells and a summary of every sample.
+fn call_cells_by_sample(
+    quant_output: &Path,
+    filters: &[(String, CellFilterMethod)],
+) -> anyhow::Result<(Vec<usize>, Vec<serde_json::Value>)> {
+    let (mtx_path, rows_path, _) = mtx_utils::quant_mat_paths(quant_output);
+    let rows = mtx_utils::read_row_barcodes(&rows_path)?;
+    let umis = mtx_utils::mtx_row_sums(&mtx_path)?;
+    if rows.len() != umis.len() {
+        bail!(
+            "{} lists {} barcodes but the count matrix has {} rows",
+            rows_path.display(),
+            rows.len(),
+            umis.len()
+        );
+    }
+    let samples: Vec<String> = filters.iter().map(|(n, _)| n.clone()).collect();
+    let (by_sample, unassigned) = group_rows_by_sample(&rows, &samples);
+    if unassigned > 0 {
+        info!(
+            "{} of {} barcodes belong to no sample selected for cell calling and are left out of the filtered matrix.",
+            unassigned,
+            rows.len()
+        );
+    }
+
+    let mut cells = Vec::new();
+    let mut summaries = Vec::with_capacity(filters.len());
+    for (sample, method) in filters {
+        let mut ranked = by_sample.get(sample.as_str()).cloned().unwrap_or_default();
+        ranked.sort_by(|a, b| umis[*b].total_cmp(&umis[*a]).then(a.cmp(b)));
+        let counts: Vec<u64> = ranked.iter().map(|r| umis[*r].round() as u64).collect();
+        let (n, desc) = cell_calling::num_barcodes_passing(&counts, method)?;
+        let cell_umis: Vec<f64> = counts[..n].iter().map(|c| *c as f64).collect();
+        info!(
+            "sample {}: called {} cells out of {} barcodes ({})",
+            sample,
+            n,
+            ranked.len(),
+            desc
+        );
+        summaries.push(json!({
+            "sample_name": sample,
+            "method": desc,
+            "num_barcodes": ranked.len(),
+            "num_cells": n,
+            "min_cell_umis": counts[..n].last(),
+            "median_cell_umis": median(&cell_umis),
+        }));
+        cells.extend_from_slice(&ranked[..n]);
+    }
+    cells.sort_unstable();
+    Ok((cells, summaries))
+}
+
+/// Split the rows (`<sample_name>_<cell barcode>`) of the combined quantification
+/// directory into one quantification directory per sample under `split_root`,
+/// returning a summary of every sample. Row names are kept sample-qualified so
+/// that they match the combined matrix.
+fn split_quant_dir_by_sample(
+    quant_output: &Path,
+    split_root: &Path,
+    samples: &[String],
+    anndata_out: bool,
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let (_, rows_path, _) = mtx_utils::quant_mat_paths(quant_output);
+    let rows = mtx_utils::read_row_barcodes(&rows_path)?;
+    let (by_sample, unassigned) = group_rows_by_sample(&rows, samples);
+    if unassigned > 0 {
+        warn!(
+            "{} of {} cells are not prefixed by a sample name from the sample BC list and were not written to any sample.",
+            unassigned,
+            rows.len()
+        );
+    }
+
+    let corrected_reads = mtx_utils::read_feature_dump_column(quant_output, "CorrectedReads")?;
+    let mapped_reads = mtx_utils::read_feature_dump_column(quant_output, "MappedReads")?;
+    let total_reads = |col: &Option<Vec<f64>>, keep: &[usize]| {
+        col.as_ref()
+            .map(|v| keep.iter().filter_map(|r| v.get(*r)).sum::<f64>() as u64)
+    };
+
+    let mut dir_names = HashSet::new();
+    let mut summaries = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let dir_name = sample_dir_name(sample);
+        if !dir_names.insert(dir_name.clone()) {
+            bail!(
+                "more than one sample name maps to the output directory {}; please rename the samples in the sample BC list.",
+                dir_name
+            );
+        }
+        let Some(keep) = by_sample.get(sample.as_str()) else {
+            warn!("no cells were assigned to sample {}; skipping it.", sample);
+            summaries.push(json!({
+                "sample_name": sample,
+                "num_cells": 0,
+                "output_dir": null,
+            }));
+            continue;
+        };
+
+        let sample_dir = split_root.join(&dir_name);
+        mtx_utils::subset_quant_dir(quant_output, &sample_dir, keep, None)?;
+        let (sample_mtx, _, _) = mtx_utils::quant_mat_paths(&sample_dir);
+        let umis = mtx_utils::mtx_row_sums(&sample_mtx)?;
+        let summary = json!({
+            "sample_name": sample,
+            "num_cells": keep.len(),
+            "corrected_reads": total_reads(&corrected_reads, keep),
+            "mapped_reads": total_reads(&mapped_reads, keep),
+            "median_umis_per_cell": median(&umis),
+            "output_dir": sample_dir.display().to_string(),
+        });
+        io::write_json_pretty(&sample_dir.join("simpleaf_sample_summary.json"), &summary)?;
+        info!(
+            "sample {}: {} cells, median {} UMIs per cell",
+            sample,
+            keep.len(),
+            median(&umis)
+        );
+
+        if anndata_out {
+            let opath = sample_dir.join("alevin").join("quants.h5ad");
+            af_anndata::convert_csr_to_anndata(&sample_dir, &opath)?;
+        }
+        summaries.push(summary);
+    }
+    Ok(summaries)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{resolve_user_supplied_index, t2g_mode};
+    use super::{
+        StageTracker, call_cells_by_sample, read_sample_names, read_sample_sheet,
+        resolve_user_supplied_index, split_quant_dir_by_sample, t2g_mode,
+    };
     use crate::simpleaf_commands::MultiplexQuantOpts;
+    use crate::utils::af_utils::CellFilterMethod;
+    use crate::utils::mtx_utils;
     use crate::utils::probe_utils::ProbeT2gMode;
     use serde_json::json;
     use std::fs;
@@ -858,6 +1484,13 @@ mod tests {
             dict: crate::simpleaf_commands::PiscemDict::Auto,
             min_reads: 10,
             anndata_out: false,
+            split_by_sample: false,
+            map_dir: None,
+            resume: false,
+            sample_bc_qc_reads: 0,
+            knee: false,
+            expect_cells: None,
+            sample_sheet: None,
         };
 
         assert_eq!(t2g_mode(&opts), ProbeT2gMode::Usa);
@@ -880,4 +1513,211 @@ mod tests {
             "unexpected error: {msg}",
         );
     }
+
+    #[test]
+    fn sample_names_come_from_the_third_column() {
+        let td = tempdir().expect("failed to create tempdir");
+        let p = td.path().join("sample_bc.tsv");
+        fs::write(
+            &p,
+            "observed\tcanonical\tsample_name\nAAAA\tAAAA\tBC001\nCCCC\tAAAA\tBC001\nGGGG\tGGGG\tBC002\n",
+        )
+        .expect("failed to write sample bc list");
+        assert_eq!(
+            read_sample_names(&p).expect("sample names"),
+            vec!["BC001", "BC002"]
+        );
+
+        fs::write(&p, "AAAA\tBC001\n").expect("failed to write sample bc list");
+        assert!(read_sample_names(&p).is_err());
+    }
+
+    #[test]
+    fn split_by_sample_writes_one_directory_per_sample() {
+        let td = tempdir().expect("failed to create tempdir");
+        let quant = td.path().join("af_quant");
+        mtx_utils::write_test_quant_mat(
+            &quant,
+            &["BC001_AAAA", "BC002_CCCC", "BC001_GGGG", "other_TTTT"],
+            &["g1", "g2"],
+            &[
+                (1, 1, 2.0),
+                (1, 2, 1.0),
+                (2, 2, 5.0),
+                (3, 1, 4.0),
+                (4, 1, 7.0),
+            ],
+        );
+        fs::write(quant.join("collate.json"), "{}").expect("failed to write collate.json");
+        fs::write(
+            quant.join("featureDump.txt"),
+            "CB\tCorrectedReads\tMappedReads\nAAAA\t10\t8\nCCCC\t20\t15\nGGGG\t6\t4\nTTTT\t1\t1\n",
+        )
+        .expect("failed to write featureDump.txt");
+
+        let split_root = td.path().join("af_quant_by_sample");
+        let samples = vec![
+            "BC001".to_string(),
+            "BC002".to_string(),
+            "BC003".to_string(),
+        ];
+        let summaries =
+            split_quant_dir_by_sample(&quant, &split_root, &samples, false).expect("split");
+
+        assert_eq!(summaries[0]["num_cells"], json!(2));
+        assert_eq!(summaries[0]["mapped_reads"], json!(12));
+        assert_eq!(summaries[0]["median_umis_per_cell"], json!(3.5));
+        assert_eq!(summaries[1]["num_cells"], json!(1));
+        assert_eq!(summaries[2]["num_cells"], json!(0));
+        assert!(!split_root.join("BC003").exists());
+
+        let bc001 = split_root.join("BC001");
+        assert_eq!(
+            fs::read_to_string(bc001.join("alevin").join("quants_mat_rows.txt"))
+                .expect("failed to read rows"),
+            "BC001_AAAA\nBC001_GGGG\n"
+        );
+        assert!(bc001.join("collate.json").is_file());
+        assert!(bc001.join("simpleaf_sample_summary.json").is_file());
+        // the combined matrix is left in place
+        assert!(mtx_utils::quant_mat_paths(&quant).0.is_file());
+    }
+
+    #[test]
+    fn sample_sheet_lists_expected_cells() {
+        let td = tempdir().expect("failed to create tempdir");
+        let p = td.path().join("sheet.tsv");
+        fs::write(&p, "sample_name\texpected_cells\nBC001\t3000\nBC002\t500\n")
+            .expect("failed to write sample sheet");
+        assert_eq!(
+            read_sample_sheet(&p).expect("sample sheet"),
+            vec![("BC001".to_string(), 3000), ("BC002".to_string(), 500)]
+        );
+
+        fs::write(&p, "BC001\t3000\nBC002\tmany\n").expect("failed to write sample sheet");
+        assert!(read_sample_sheet(&p).is_err());
+        fs::write(&p, "BC001\t3000\nBC001\t10\n").expect("failed to write sample sheet");
+        assert!(read_sample_sheet(&p).is_err());
+    }
+
+    #[test]
+    fn cells_are_called_within_each_sample() {
+        let td = tempdir().expect("failed to create tempdir");
+        let quant = td.path().join("af_quant");
+        // BC001 is sequenced ten times deeper than BC002
+        let umis = [1000.0, 900.0, 800.0, 5.0, 100.0, 90.0, 70.0, 1.0];
+        let entries: Vec<(usize, usize, f64)> = umis
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (i + 1, 1, *u))
+            .collect();
+        mtx_utils::write_test_quant_mat(
+            &quant,
+            &[
+                "BC001_AAAA",
+                "BC001_CCCC",
+                "BC001_GGGG",
+                "BC001_TTTT",
+                "BC002_AAAA",
+                "BC002_CCCC",
+                "BC002_GGGG",
+                "BC002_TTTT",
+            ],
+            &["g1"],
+            &entries,
+        );
+
+        let filters = vec![
+            ("BC001".to_string(), CellFilterMethod::ExpectCells(2)),
+            ("BC002".to_string(), CellFilterMethod::ExpectCells(2)),
+        ];
+        let (cells, summaries) = call_cells_by_sample(&quant, &filters).expect("call cells");
+        // the threshold of BC001 (80 UMIs) would drop a cell of BC002
+        assert_eq!(cells, vec![0, 1, 2, 4, 5, 6]);
+        assert_eq!(summaries[1]["num_cells"], json!(3));
+        assert_eq!(summaries[1]["min_cell_umis"], json!(70));
+
+        let filters = vec![("BC002".to_string(), CellFilterMethod::ExpectCells(2))];
+        let (cells, _) = call_cells_by_sample(&quant, &filters).expect("call cells");
+        assert_eq!(cells, vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn resume_skips_completed_stages_until_one_must_rerun() {
+        let td = tempdir().expect("failed to create tempdir");
+        let out = td.path();
+        let runs = out.join("runs.txt");
+        // each stage appends its name to `runs.txt` and creates its output
+        let stage_cmd = |stage: &str, extra: &str, threads: &str| {
+            let mut cmd = std::process::Command::new("sh");
+            cmd.arg("-c")
+                .arg(format!(
+                    "echo {stage} >> {runs}; touch {out}/{stage}.done{extra}",
+                    runs = runs.display(),
+                    out = out.display()
+                ))
+                .arg("-t")
+                .arg(threads);
+            cmd
+        };
+        let run_all = |tracker: &mut StageTracker, collate_extra: &str, threads: &str| {
+            for (stage, extra) in [
+                ("map", ""),
+                ("gpl", ""),
+                ("collate", collate_extra),
+                ("quant", ""),
+            ] {
+                tracker
+                    .run(
+                        stage,
+                        &mut stage_cmd(stage, extra, threads),
+                        &[out.join(format!("{}.done", stage))],
+                        stage,
+                    )
+                    .expect("stage failed");
+            }
+        };
+
+        let mut first = StageTracker::new(out, false).expect("failed to create tracker");
+        run_all(&mut first, "", "4");
+        let info: serde_json::Value =
+            crate::core::io::read_json_file(&out.join("simpleaf_multiplex_quant_info.json"))
+                .expect("failed to read progress");
+        assert_eq!(
+            info["completed_stages"],
+            json!(["map", "gpl", "collate", "quant"])
+        );
+
+        // an identical rerun skips everything
+        let mut second = StageTracker::new(out, true).expect("failed to create tracker");
+        run_all(&mut second, "", "4");
+        assert_eq!(second.resumed, vec!["map", "gpl", "collate", "quant"]);
+        assert_eq!(
+            fs::read_to_string(&runs).unwrap().lines().count(),
+            4,
+            "no stage should have run again"
+        );
+
+        // a changed collate command reruns collate and everything after it
+        let mut third = StageTracker::new(out, true).expect("failed to create tracker");
+        run_all(&mut third, " ", "4");
+        assert_eq!(third.resumed, vec!["map", "gpl"]);
+        let log = fs::read_to_string(&runs).unwrap();
+        assert_eq!(
+            log.lines().skip(4).collect::<Vec<_>>(),
+            vec!["collate", "quant"]
+        );
+
+        // a missing output also forces its stage to rerun
+        fs::remove_file(out.join("gpl.done")).unwrap();
+        let mut fourth = StageTracker::new(out, true).expect("failed to create tracker");
+        run_all(&mut fourth, " ", "4");
+        assert_eq!(fourth.resumed, vec!["map"]);
+        assert!(out.join("gpl.done").is_file());
+
+        // the thread count doesn't affect the outputs, so no stage reruns
+        let mut fifth = StageTracker::new(out, true).expect("failed to create tracker");
+        run_all(&mut fifth, " ", "16");
+        assert_eq!(fifth.resumed, vec!["map", "gpl", "collate", "quant"]);
+    }
 }
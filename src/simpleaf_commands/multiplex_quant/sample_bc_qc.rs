@@ -0,0 +1,527 @@
+//! Sample-barcode QC for multiplexed runs.
+//!
+//! Rotated or reverse-complemented sample barcodes (e.g. a wrong `sample_bc_ori`)
+//! do not make the pipeline fail; reads and cells just silently end up missing
+//! from some samples. This module samples the sample barcodes of the input reads,
+//! classifies them against the sample BC list, and combines that with the cells
+//! and reads that were quantified for each sample.
+
+use super::group_rows_by_sample;
+use crate::utils::af_utils::reverse_complement;
+use crate::utils::mtx_utils;
+
+use anyhow::{Context, bail};
+use seq_geom_parser::CompiledGeom;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// The number of most frequent unexpected sample barcodes that are reported.
+const NUM_TOP_UNEXPECTED: usize = 20;
+
+/// The sample BC list, oriented as the sample barcodes are expected to appear
+/// in the reads.
+pub(super) struct SampleBarcodes {
+    /// in-read sequence -> sample name
+    by_seq: HashMap<Vec<u8>, String>,
+    /// (sample name, its barcodes as listed), in the order of the list
+    samples: Vec<(String, Vec<String>)>,
+}
+
+/// How a sample barcode found in a read relates to the sample BC list.
+#[derive(Debug, PartialEq)]
+enum Assignment<'a> {
+    Exact(&'a str),
+    Corrected(&'a str),
+    /// within one mismatch of the barcodes of more than one sample
+    Ambiguous,
+    Unexpected,
+}
+
+impl SampleBarcodes {
+    /// Read a sample BC list of observed, canonical and `sample_name` columns
+    /// (without a `sample_name` column, the canonical or else the observed barcode
+    /// names the sample). If `reverse`, the reads carry the reverse complement
+    /// of the listed barcodes.
+    pub(super) fn from_file(path: &Path, reverse: bool) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read {}", path.display()))?;
+        let mut by_seq = HashMap::new();
+        let mut samples: Vec<(String, Vec<String>)> = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let name = fields.get(2).or(fields.get(1)).unwrap_or(&fields[0]);
+            // an optional header line
+            if by_seq.is_empty() && *name == "sample_name" {
+                continue;
+            }
+            let listed = fields[0].to_ascii_uppercase();
+            let in_read = if reverse {
+                reverse_complement(&listed).into_bytes()
+            } else {
+                listed.as_bytes().to_vec()
+            };
+            by_seq.insert(in_read, name.to_string());
+            match samples.iter_mut().find(|(s, _)| s == name) {
+                Some((_, barcodes)) => barcodes.push(listed),
+                None => samples.push((name.to_string(), vec![listed])),
+            }
+        }
+        if by_seq.is_empty() {
+            bail!("{} does not list any sample barcodes.", path.display());
+        }
+        Ok(Self { by_seq, samples })
+    }
+
+    fn assign(&self, seq: &[u8]) -> Assignment<'_> {
+        if let Some(sample) = self.by_seq.get(seq) {
+            return Assignment::Exact(sample);
+        }
+        let mut nearest: Option<&str> = None;
+        for (bc, sample) in &self.by_seq {
+            let within_one =
+                bc.len() == seq.len() && bc.iter().zip(seq).filter(|(a, b)| a != b).count() <= 1;
+            if !within_one {
+                continue;
+            }
+            match nearest {
+                Some(s) if s != sample => return Assignment::Ambiguous,
+                _ => nearest = Some(sample),
+            }
+        }
+        nearest.map_or(Assignment::Unexpected, Assignment::Corrected)
+    }
+}
+
+/// The sample barcodes found in (a prefix of) the input reads.
+#[derive(Debug, Default)]
+pub(super) struct SampledBarcodes {
+    reads: u64,
+    /// reads in which no sample barcode could be located
+    missing: u64,
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+/// Read the sequence of the next FASTQ record into `seq`. Returns `false` at
+/// the end of the file.
+fn read_fastq_seq(
+    reader: &mut dyn BufRead,
+    seq: &mut Vec<u8>,
+    scratch: &mut Vec<u8>,
+    path: &Path,
+) -> anyhow::Result<bool> {
+    scratch.clear();
+    if reader.read_until(b'\n', scratch)? == 0 {
+        return Ok(false);
+    }
+    if scratch[0] != b'@' {
+        bail!("{} does not look like a FASTQ file.", path.display());
+    }
+    seq.clear();
+    reader.read_until(b'\n', seq)?;
+    while seq.last().is_some_and(|c| c.is_ascii_whitespace()) {
+        seq.pop();
+    }
+    for _ in 0..2 {
+        scratch.clear();
+        reader.read_until(b'\n', scratch)?;
+    }
+    Ok(true)
+}
+
+/// Extract the sample barcodes of the first `max_reads` read pairs of
+/// `reads1`/`reads2` according to `geometry`.
+pub(super) fn sample_read_barcodes(
+    geometry: &str,
+    reads1: &[PathBuf],
+    reads2: &[PathBuf],
+    max_reads: u64,
+) -> anyhow::Result<SampledBarcodes> {
+    let fg = seq_geom_parser::parse_geometry(geometry)
+        .map_err(|errors| anyhow::anyhow!(seq_geom_parser::format_errors(geometry, &errors)))?;
+    let geom = CompiledGeom::from_fragment_geom(&fg)
+        .map_err(|e| anyhow::anyhow!("could not compile the geometry {}: {}", geometry, e))?;
+
+    let mut sampled = SampledBarcodes::default();
+    let (mut s1, mut s2, mut scratch) = (Vec::new(), Vec::new(), Vec::new());
+    for (p1, p2) in reads1.iter().zip(reads2) {
+        let open = |p: &Path| -> anyhow::Result<Box<dyn BufRead>> {
+            let (reader, _fmt) =
+                niffler::from_path(p).with_context(|| format!("could not open {}", p.display()))?;
+            Ok(Box::new(BufReader::new(reader)))
+        };
+        let (mut r1, mut r2) = (open(p1)?, open(p2)?);
+        while sampled.reads < max_reads {
+            let has1 = read_fastq_seq(&mut r1, &mut s1, &mut scratch, p1)?;
+            let has2 = read_fastq_seq(&mut r2, &mut s2, &mut scratch, p2)?;
+            if !(has1 && has2) {
+                break;
+            }
+            sampled.reads += 1;
+            match geom.extract(&s1, &s2).barcodes.first().copied().flatten() {
+                Some(bc) => *sampled.counts.entry(bc.to_ascii_uppercase()).or_default() += 1,
+                None => sampled.missing += 1,
+            }
+        }
+        if sampled.reads >= max_reads {
+            break;
+        }
+    }
+    Ok(sampled)
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct SampleQc {
+    sample_name: String,
+    sample_barcodes: Vec<String>,
+    /// sampled reads assigned to the sample, exactly or after correction
+    sampled_reads: Option<u64>,
+    sampled_read_fraction: Option<f64>,
+    cells: usize,
+    corrected_reads: Option<u64>,
+    mapped_reads: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct UnexpectedBarcode {
+    sequence: String,
+    reads: u64,
+    fraction: f64,
+    /// the sample whose barcode this is the reverse complement of
+    reverse_complement_of: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct ReadQc {
+    sampled_reads: u64,
+    reads_without_sample_bc: u64,
+    exact_reads: u64,
+    corrected_reads: u64,
+    ambiguous_reads: u64,
+    unexpected_reads: u64,
+    /// reads without a sample barcode, or with one that could not be assigned
+    unassigned_fraction: f64,
+    /// of the reads with a sample barcode, those matching the list exactly, in
+    /// the expected orientation and in reverse complement
+    forward_match_rate: f64,
+    reverse_complement_match_rate: f64,
+    top_unexpected: Vec<UnexpectedBarcode>,
+}
+
+#[derive(Debug, Serialize)]
+pub(super) struct SampleBcQc {
+    pub(super) reads: Option<ReadQc>,
+    samples: Vec<SampleQc>,
+    /// quantified cells that belong to none of the listed samples
+    unassigned_cells: usize,
+}
+
+impl SampleBcQc {
+    /// Whether more of the sampled reads match the sample BC list in reverse
+    /// complement than in the expected orientation.
+    pub(super) fn orientation_flipped(&self) -> bool {
+        self.reads
+            .as_ref()
+            .is_some_and(|r| r.reverse_complement_match_rate > r.forward_match_rate)
+    }
+}
+
+fn fraction(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+/// Summarize the sampled read barcodes (if any) and the cells and reads that
+/// were quantified for each sample in `quant_output`.
+pub(super) fn sample_bc_qc(
+    barcodes: &SampleBarcodes,
+    sampled: Option<&SampledBarcodes>,
+    quant_output: &Path,
+) -> anyhow::Result<SampleBcQc> {
+    let mut sample_reads: HashMap<&str, u64> = HashMap::new();
+    let reads = sampled.map(|sampled| {
+        let (mut exact, mut corrected, mut ambiguous, mut unexpected, mut rc) = (0, 0, 0, 0, 0);
+        let mut unexpected_seqs = Vec::new();
+        for (seq, &n) in &sampled.counts {
+            if barcodes
+                .by_seq
+                .contains_key(reverse_complement(seq).as_bytes())
+            {
+                rc += n;
+            }
+            match barcodes.assign(seq) {
+                Assignment::Exact(s) => {
+                    exact += n;
+                    *sample_reads.entry(s).or_default() += n;
+                }
+                Assignment::Corrected(s) => {
+                    corrected += n;
+                    *sample_reads.entry(s).or_default() += n;
+                }
+                Assignment::Ambiguous => {
+                    ambiguous += n;
+                    unexpected_seqs.push((seq, n));
+                }
+                Assignment::Unexpected => {
+                    unexpected += n;
+                    unexpected_seqs.push((seq, n));
+                }
+            }
+        }
+        unexpected_seqs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let top_unexpected = unexpected_seqs
+            .into_iter()
+            .take(NUM_TOP_UNEXPECTED)
+            .map(|(seq, n)| UnexpectedBarcode {
+                sequence: String::from_utf8_lossy(seq).into_owned(),
+                reads: n,
+                fraction: fraction(n, sampled.reads),
+                reverse_complement_of: barcodes
+                    .by_seq
+                    .get(reverse_complement(seq).as_bytes())
+                    .cloned(),
+            })
+            .collect();
+        let with_bc = sampled.reads - sampled.missing;
+        ReadQc {
+            sampled_reads: sampled.reads,
+            reads_without_sample_bc: sampled.missing,
+            exact_reads: exact,
+            corrected_reads: corrected,
+            ambiguous_reads: ambiguous,
+            unexpected_reads: unexpected,
+            unassigned_fraction: fraction(sampled.missing + ambiguous + unexpected, sampled.reads),
+            forward_match_rate: fraction(exact, with_bc),
+            reverse_complement_match_rate: fraction(rc, with_bc),
+            top_unexpected,
+        }
+    });
+
+    let sample_names: Vec<String> = barcodes.samples.iter().map(|(s, _)| s.clone()).collect();
+    let (_, rows_path, _) = mtx_utils::quant_mat_paths(quant_output);
+    let rows = mtx_utils::read_row_barcodes(&rows_path)?;
+    let (by_sample, unassigned_cells) = group_rows_by_sample(&rows, &sample_names);
+    let corrected_reads = mtx_utils::read_feature_dump_column(quant_output, "CorrectedReads")?;
+    let mapped_reads = mtx_utils::read_feature_dump_column(quant_output, "MappedReads")?;
+    let total_reads = |col: &Option<Vec<f64>>, keep: &[usize]| {
+        col.as_ref()
+            .map(|v| keep.iter().filter_map(|r| v.get(*r)).sum::<f64>() as u64)
+    };
+
+    let samples = barcodes
+        .samples
+        .iter()
+        .map(|(name, listed)| {
+            let cells = by_sample
+                .get(name.as_str())
+                .map_or(&[][..], |v| v.as_slice());
+            let n_sampled = sampled.map(|_| sample_reads.get(name.as_str()).copied().unwrap_or(0));
+            SampleQc {
+                sample_name: name.clone(),
+                sample_barcodes: listed.clone(),
+                sampled_reads: n_sampled,
+                sampled_read_fraction: sampled.zip(n_sampled).map(|(s, n)| fraction(n, s.reads)),
+                cells: cells.len(),
+                corrected_reads: total_reads(&corrected_reads, cells),
+                mapped_reads: total_reads(&mapped_reads, cells),
+            }
+        })
+        .collect();
+
+    Ok(SampleBcQc {
+        reads,
+        samples,
+        unassigned_cells,
+    })
+}
+
+/// Write the QC report as `sample_bc_qc.json`, plus one row per sample in
+/// `sample_bc_qc.tsv`, into `out_dir`, and log a summary.
+pub(super) fn write_sample_bc_qc(
+    qc: &SampleBcQc,
+    out_dir: &Path,
+    sample_bc_ori: &str,
+) -> anyhow::Result<()> {
+    crate::core::io::write_json_pretty(&out_dir.join("sample_bc_qc.json"), qc)?;
+
+    let tsv_path = out_dir.join("sample_bc_qc.tsv");
+    let mut tsv = std::io::BufWriter::new(
+        std::fs::File::create(&tsv_path)
+            .with_context(|| format!("could not create {}", tsv_path.display()))?,
+    );
+    writeln!(
+        tsv,
+        "sample_name\tsample_barcodes\tsampled_reads\tsampled_read_fraction\tcells\tcorrected_reads\tmapped_reads"
+    )?;
+    let na = |v: Option<String>| v.unwrap_or_else(|| "NA".to_string());
+    for s in &qc.samples {
+        writeln!(
+            tsv,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            s.sample_name,
+            s.sample_barcodes.join(","),
+            na(s.sampled_reads.map(|n| n.to_string())),
+            na(s.sampled_read_fraction.map(|f| format!("{:.4}", f))),
+            s.cells,
+            na(s.corrected_reads.map(|n| n.to_string())),
+            na(s.mapped_reads.map(|n| n.to_string())),
+        )?;
+    }
+    tsv.flush()?;
+
+    if let Some(r) = &qc.reads {
+        info!(
+            "Sample BC QC: of {} sampled reads, {:.1}% could not be assigned to a sample; {:.1}% match the sample BC list as expected and {:.1}% in reverse complement.",
+            r.sampled_reads,
+            100.0 * r.unassigned_fraction,
+            100.0 * r.forward_match_rate,
+            100.0 * r.reverse_complement_match_rate
+        );
+    }
+    if qc.orientation_flipped() {
+        let r = qc
+            .reads
+            .as_ref()
+            .expect("flipped orientation implies sampled reads");
+        warn!(
+            "!!! The sample barcodes of {:.1}% of the sampled reads match the sample BC list in REVERSE COMPLEMENT, but only {:.1}% match it in the expected orientation (sample_bc_ori = {}). The sample BC orientation or the sample BC list is likely wrong, and most reads were probably not assigned to their samples. See {} for details. !!!",
+            100.0 * r.reverse_complement_match_rate,
+            100.0 * r.forward_match_rate,
+            sample_bc_ori,
+            out_dir.join("sample_bc_qc.json").display()
+        );
+    }
+    for s in qc.samples.iter().filter(|s| s.cells == 0) {
+        warn!(
+            "No cells were quantified for sample {} (sample barcodes {}).",
+            s.sample_name,
+            s.sample_barcodes.join(",")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Assignment, SampleBarcodes, sample_bc_qc, sample_read_barcodes};
+    use crate::utils::mtx_utils;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn write_fastq(path: &Path, seqs: &[&str]) {
+        let content: String = seqs
+            .iter()
+            .enumerate()
+            .map(|(i, s)| format!("@r{}\n{}\n+\n{}\n", i, s, "I".repeat(s.len())))
+            .collect();
+        fs::write(path, content).expect("failed to write FASTQ");
+    }
+
+    fn write_quant_dir(quant: &Path, rows: &[&str]) {
+        mtx_utils::write_test_quant_mat(quant, rows, &["g1"], &[]);
+        let mut dump = String::from("CB\tCorrectedReads\tMappedReads\n");
+        for (i, r) in rows.iter().enumerate() {
+            dump.push_str(&format!("{}\t{}\t{}\n", r, 10 * (i + 1), 5 * (i + 1)));
+        }
+        fs::write(quant.join("featureDump.txt"), dump).expect("failed to write featureDump");
+    }
+
+    #[test]
+    fn assigns_exact_corrected_and_ambiguous_barcodes() {
+        let td = tempdir().expect("failed to create tempdir");
+        let list = td.path().join("sample_bc.tsv");
+        fs::write(
+            &list,
+            "observed\tcanonical\tsample_name\nAAAA\tAAAA\tS1\nAAAC\tAAAA\tS1\nCCGG\tCCGG\tS2\nCCGT\tCCGT\tS3\n",
+        )
+        .unwrap();
+        let bcs = SampleBarcodes::from_file(&list, false).expect("failed to read list");
+        assert_eq!(bcs.assign(b"AAAC"), Assignment::Exact("S1"));
+        // within one mismatch of both S1 barcodes, which is still unambiguous
+        assert_eq!(bcs.assign(b"AAAG"), Assignment::Corrected("S1"));
+        assert_eq!(bcs.assign(b"CCGA"), Assignment::Ambiguous);
+        assert_eq!(bcs.assign(b"TTTT"), Assignment::Unexpected);
+
+        let reversed = SampleBarcodes::from_file(&list, true).expect("failed to read list");
+        assert_eq!(reversed.assign(b"CCGG"), Assignment::Exact("S2"));
+        assert_eq!(reversed.assign(b"TTTT"), Assignment::Exact("S1"));
+    }
+
+    #[test]
+    fn reports_reverse_complemented_sample_barcodes() {
+        let td = tempdir().expect("failed to create tempdir");
+        let list = td.path().join("sample_bc.tsv");
+        fs::write(&list, "AACC\tAACC\tS1\nCAGG\tCAGG\tS2\n").unwrap();
+        let bcs = SampleBarcodes::from_file(&list, false).expect("failed to read list");
+
+        // the sample barcode is the last 4 bases of read 2; four reads carry
+        // the reverse complement of S1's barcode, and one read is too short
+        let r1 = td.path().join("r1.fq");
+        let r2 = td.path().join("r2.fq");
+        write_fastq(&r1, &["ACGTACGT"; 8]);
+        write_fastq(
+            &r2,
+            &[
+                "TTTTAACC", "TTTTGGTT", "TTTTGGTT", "TTTTGGTT", "TTTTGGTT", "TTTTCCCC", "TTT",
+                "TTTTCAGG",
+            ],
+        );
+        let geometry = "1{b[4]u[4]}2{r[4]s[4]}";
+        let sampled = sample_read_barcodes(
+            geometry,
+            std::slice::from_ref(&r1),
+            std::slice::from_ref(&r2),
+            100,
+        )
+        .expect("failed to sample reads");
+        assert_eq!(sampled.reads, 8);
+        assert_eq!(sampled.missing, 1);
+
+        let quant = td.path().join("af_quant");
+        write_quant_dir(&quant, &["S1_AAAA", "S1_CCCC", "S2_GGGG", "X_TTTT"]);
+        let qc = sample_bc_qc(&bcs, Some(&sampled), &quant).expect("failed to run QC");
+        let reads = qc.reads.as_ref().unwrap();
+        assert_eq!(reads.exact_reads, 2);
+        assert_eq!(reads.unexpected_reads, 5);
+        assert!((reads.unassigned_fraction - 6.0 / 8.0).abs() < 1e-9);
+        assert!((reads.reverse_complement_match_rate - 4.0 / 7.0).abs() < 1e-9);
+        assert!(qc.orientation_flipped());
+        assert_eq!(reads.top_unexpected[0].sequence, "GGTT");
+        assert_eq!(reads.top_unexpected[0].reads, 4);
+        assert_eq!(
+            reads.top_unexpected[0].reverse_complement_of.as_deref(),
+            Some("S1")
+        );
+        assert_eq!(qc.samples[0].cells, 2);
+        assert_eq!(qc.samples[0].corrected_reads, Some(30));
+        assert_eq!(qc.samples[1].sampled_reads, Some(1));
+        assert_eq!(qc.unassigned_cells, 1);
+
+        // only the first read pairs are sampled
+        let sampled = sample_read_barcodes(geometry, &[r1], &[r2], 3).expect("failed to sample");
+        assert_eq!(sampled.reads, 3);
+
+        // the registered Flex geometries locate their sample barcodes
+        for geometry in [
+            "1{b[16]u[12]x:}2{r[50]x[18]s[8]x:}",
+            "1{b[16]u[12]x[0-3]f[TTGCTAGGACCG]s[10]x:}2{r:}",
+        ] {
+            sample_read_barcodes(geometry, &[], &[], 1).expect("failed to compile geometry");
+        }
+
+        // without sampled reads, only the quantified cells are summarized
+        let qc = sample_bc_qc(&bcs, None, &quant).expect("failed to run QC");
+        assert!(qc.reads.is_none() && !qc.orientation_flipped());
+        assert_eq!(qc.samples[1].sampled_reads, None);
+    }
+}
@@ -17,6 +17,10 @@ use super::MapQuantOpts;
 use crate::utils::chem_utils::ExpectedOri;
 use crate::utils::constants::{CHEMISTRIES_PATH, NUM_SAMPLE_LINES};
 
+pub(crate) mod barcode_translation;
+mod multi_library;
+mod spatial;
+
 /// Open a permit-list file with transparent compression handling and return a
 /// buffered reader over the resulting stream.
 fn get_generic_buf_reader(ipath: &Path) -> anyhow::Result<BufReader<Box<dyn Read>>> {
@@ -230,7 +234,13 @@ fn push_advanced_piscem_options(
     Ok(())
 }
 
-fn validate_map_and_quant_opts(_opts: &MapQuantOpts) -> anyhow::Result<()> {
+fn validate_map_and_quant_opts(opts: &MapQuantOpts) -> anyhow::Result<()> {
+    // the spot coordinates are carried by the (unfiltered) permit list of the chemistry
+    if opts.spatial && opts.unfiltered_pl.is_none() {
+        bail!(
+            "--spatial requires the permit list of a Visium chemistry to be passed with --unfiltered-pl."
+        );
+    }
     Ok(())
 }
 
@@ -622,6 +632,8 @@ fn write_quant_log(
     mapping: &MappingStageOutput,
     quant_stage: &QuantStageOutput,
     convert_duration: Option<Duration>,
+    translation: Option<&barcode_translation::TranslationSummary>,
+    spatial: Option<&spatial::SpatialSummary>,
 ) -> anyhow::Result<()> {
     let af_quant_info_file = opts.output.join("simpleaf_quant_log.json");
     let mut af_quant_info = json!({
@@ -647,32 +659,75 @@ fn write_quant_log(
     if let Some(ctime) = convert_duration {
         af_quant_info["time_info"]["conversion_time"] = json!(ctime);
     }
+    if let Some(translation) = translation {
+        af_quant_info["barcode_translation"] = json!(translation);
+    }
+    if let Some(spatial) = spatial {
+        af_quant_info["spatial"] = json!(spatial);
+    }
 
     io::write_json_pretty_atomic(&af_quant_info_file, &af_quant_info)?;
     Ok(())
 }
 
 pub fn map_and_quant(af_home_path: &Path, opts: MapQuantOpts) -> anyhow::Result<()> {
+    if !opts.libraries.is_empty() {
+        return multi_library::multi_library_quant(af_home_path, opts);
+    }
     validate_map_and_quant_opts(&opts)?;
     let (setup, mut pl_info) = resolve_quant_setup(af_home_path, &opts)?;
     let mapping = run_mapping_stage(&opts, &setup)?;
     let quant_stage = run_quant_stage(&opts, &setup, &mapping, &mut pl_info)?;
 
+    // rewrite the barcodes into the target space of the chemistry's translation list
+    let translation = if opts.translate_barcodes {
+        Some(barcode_translation::translate_with_registered_list(
+            af_home_path,
+            &opts.chemistry,
+            &quant_stage.gpl_output,
+        )?)
+    } else {
+        None
+    };
+
+    // attach the array (and, with tissue positions, pixel) coordinates to each spot
+    let spatial = if opts.spatial {
+        Some(spatial::SpatialOutput::write(
+            &quant_stage.gpl_output,
+            &opts.output.join("af_quant_in_tissue"),
+            opts.tissue_positions.as_deref(),
+            opts.scalefactors.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
     let mut convert_duration = None;
     if opts.anndata_out {
         let convert_start = Instant::now();
         let opath = quant_stage.gpl_output.join("alevin").join("quants.h5ad");
         af_anndata::convert_csr_to_anndata(&quant_stage.gpl_output, &opath)?;
+        if let Some(spatial) = &spatial {
+            spatial.write_anndata(&opath)?;
+        }
         convert_duration = Some(convert_start.elapsed());
     }
 
-    write_quant_log(&opts, &mapping, &quant_stage, convert_duration)
+    write_quant_log(
+        &opts,
+        &mapping,
+        &quant_stage,
+        convert_duration,
+        translation.as_ref(),
+        spatial.as_ref().map(|s| &s.summary),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use clap::Parser;
 
+    use crate::simpleaf_commands::{LibrarySpec, LibraryType};
     use crate::utils::af_utils::RnaChemistry;
     use crate::{Cli, Commands};
 
@@ -749,4 +804,91 @@ mod tests {
             err
         );
     }
+
+    #[test]
+    fn library_options_parse_into_specs() {
+        let opts = parse_quant_opts(&[
+            "quant",
+            "-c",
+            "10xv3",
+            "-o",
+            "/tmp/out",
+            "-r",
+            "cr-like",
+            "--knee",
+            "-i",
+            "/tmp/index",
+            "--library",
+            "gex:g_R1.fq.gz,g_R2.fq.gz",
+            "--library",
+            "Antibody:a_R1.fq.gz,a_R2.fq.gz",
+            "--feature-csv",
+            "/tmp/features.csv",
+        ]);
+        assert_eq!(
+            opts.libraries,
+            vec![
+                LibrarySpec {
+                    library_type: LibraryType::Gex,
+                    reads1: PathBuf::from("g_R1.fq.gz"),
+                    reads2: PathBuf::from("g_R2.fq.gz"),
+                },
+                LibrarySpec {
+                    library_type: LibraryType::Antibody,
+                    reads1: PathBuf::from("a_R1.fq.gz"),
+                    reads2: PathBuf::from("a_R2.fq.gz"),
+                },
+            ]
+        );
+        assert!(opts.reads1.is_none());
+
+        let mut cli_args = vec!["simpleaf", "quant", "-c", "10xv3", "-o", "/tmp/out"];
+        cli_args.extend(["-r", "cr-like", "--knee", "-i", "/tmp/index"]);
+        cli_args.extend(["--library", "atac:a_R1.fq.gz,a_R2.fq.gz"]);
+        assert!(Cli::try_parse_from(cli_args).is_err());
+    }
+
+    #[test]
+    fn spatial_options_require_the_coordinate_permit_list() {
+        let base = [
+            "simpleaf",
+            "quant",
+            "-c",
+            "visiumv4",
+            "-o",
+            "/tmp/out",
+            "-r",
+            "cr-like",
+            "-i",
+            "/tmp/index",
+            "-1",
+            "r1.fq",
+            "-2",
+            "r2.fq",
+        ];
+        let with = |extra: &[&'static str]| [&base[..], extra].concat();
+
+        let opts = parse_quant_opts(
+            &with(&[
+                "-u",
+                "--spatial",
+                "--tissue-positions",
+                "/tmp/tissue_positions.csv",
+                "--scalefactors",
+                "/tmp/scalefactors_json.json",
+            ])[1..],
+        );
+        assert!(opts.spatial);
+        assert_eq!(opts.unfiltered_pl, Some(None));
+        assert_eq!(
+            opts.tissue_positions,
+            Some(PathBuf::from("/tmp/tissue_positions.csv"))
+        );
+
+        assert!(validate_map_and_quant_opts(&opts).is_ok());
+        let opts = parse_quant_opts(&with(&["--knee", "--spatial"])[1..]);
+        assert!(validate_map_and_quant_opts(&opts).is_err());
+        assert!(Cli::try_parse_from(with(&["-u", "--tissue-positions", "/tmp/tp.csv"])).is_err());
+        assert!(Cli::try_parse_from(with(&["-u", "--spatial", "--translate-barcodes"])).is_err());
+    }
 }
@@ -0,0 +1,396 @@
+//! Translation of cell barcodes between the barcode sets of a chemistry, e.g. from the
+//! feature barcoding to the gene expression barcodes of 10x 3' v3, using a two-column
+//! translation list (the barcode to translate, and the barcode it translates to) that
+//! is either given directly or registered with the chemistry (`translation_list`), or
+//! from the ATAC to the GEX barcodes of 10x Multiome, using whitelists paired row-wise.
+
+use crate::core::io::FileLock;
+use crate::utils::af_utils;
+use crate::utils::chem_registry::ChemistryRegistry;
+use crate::utils::constants::CHEMISTRIES_PATH;
+use crate::utils::integrity;
+use crate::utils::mtx_utils;
+use crate::utils::prog_utils::download_to_file;
+
+use anyhow::{Context, bail};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// A one-to-one translation between two cell barcode sets.
+pub(crate) struct BarcodeTranslation {
+    forward: HashMap<String, String>,
+    backward: HashMap<String, String>,
+}
+
+/// Read the first column of each line of a (possibly compressed) whitelist.
+fn read_whitelist(p: &Path) -> anyhow::Result<Vec<String>> {
+    let (reader, _fmt) = niffler::from_path(p)
+        .with_context(|| format!("could not open whitelist {}", p.display()))?;
+    let mut bcs = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        let line = line.with_context(|| format!("could not read {}", p.display()))?;
+        if let Some(bc) = line.split_whitespace().next() {
+            bcs.push(bc.to_string());
+        }
+    }
+    Ok(bcs)
+}
+
+impl BarcodeTranslation {
+    /// Pair the whitelists `from` and `to` row-wise, as the ATAC and GEX whitelists
+    /// of 10x Multiome are. The barcodes of `from` are keyed as they appear in the
+    /// reads, i.e. reverse complemented if `reverse_complement` is set (the
+    /// chemistry's permit list orientation is `rc`). Each barcode of either list may
+    /// only appear once, so that no two barcodes are translated to the same one.
+    pub(crate) fn from_paired_whitelists(
+        from: &Path,
+        to: &Path,
+        reverse_complement: bool,
+    ) -> anyhow::Result<Self> {
+        let from_bcs = read_whitelist(from)?;
+        let to_bcs = read_whitelist(to)?;
+        if from_bcs.len() != to_bcs.len() {
+            bail!(
+                "the whitelist {} has {} barcodes but the whitelist {} has {}; they must be paired row-wise.",
+                from.display(),
+                from_bcs.len(),
+                to.display(),
+                to_bcs.len()
+            );
+        }
+        let mut forward = HashMap::with_capacity(from_bcs.len());
+        let mut backward = HashMap::with_capacity(to_bcs.len());
+        let mut seen_to = HashSet::with_capacity(to_bcs.len());
+        for (from_bc, to_bc) in from_bcs.into_iter().zip(to_bcs) {
+            if !seen_to.insert(to_bc.clone()) {
+                bail!(
+                    "the barcode {} appears more than once in {}; the paired whitelists must translate barcodes one-to-one.",
+                    to_bc,
+                    to.display()
+                );
+            }
+            let key = if reverse_complement {
+                af_utils::reverse_complement(&from_bc)
+            } else {
+                from_bc
+            };
+            if let Some(prev) = forward.insert(key.clone(), to_bc.clone()) {
+                bail!(
+                    "the barcode paired with {} appears more than once in {}; the paired whitelists must translate barcodes one-to-one.",
+                    prev,
+                    from.display()
+                );
+            }
+            backward.insert(to_bc, key);
+        }
+        Ok(Self { forward, backward })
+    }
+
+    /// Read a (possibly compressed) translation list.
+    pub(super) fn from_file(p: &Path) -> anyhow::Result<Self> {
+        let (reader, _fmt) = niffler::from_path(p)
+            .with_context(|| format!("could not open translation list {}", p.display()))?;
+        let mut forward = HashMap::new();
+        let mut backward = HashMap::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line.with_context(|| format!("could not read {}", p.display()))?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut toks = line.split_whitespace();
+            let (Some(from), Some(to)) = (toks.next(), toks.next()) else {
+                bail!(
+                    "the translation list {} should have two columns (the barcode to translate and the barcode it translates to), found: {}",
+                    p.display(),
+                    line
+                );
+            };
+            forward.insert(from.to_string(), to.to_string());
+            backward
+                .entry(to.to_string())
+                .or_insert_with(|| from.to_string());
+        }
+        Ok(Self { forward, backward })
+    }
+
+    /// The barcode that `bc` translates to.
+    pub(crate) fn translate(&self, bc: &str) -> Option<&str> {
+        self.forward.get(bc).map(String::as_str)
+    }
+
+    /// The barcode that translates to `bc`.
+    pub(super) fn untranslate(&self, bc: &str) -> Option<&str> {
+        self.backward.get(bc).map(String::as_str)
+    }
+}
+
+/// The path to the translation list registered with `chemistry`, downloading it
+/// into `ALEVIN_FRY_HOME/plist` if needed. Returns `None` if `chemistry` is not
+/// registered or has no translation list.
+pub(super) fn registered_translation_list(
+    af_home_path: &Path,
+    chemistry: &str,
+) -> anyhow::Result<Option<PathBuf>> {
+    let registry = ChemistryRegistry::layered(&af_home_path.join(CHEMISTRIES_PATH));
+    if !registry.exists() {
+        return Ok(None);
+    }
+    let Some(tl) = registry
+        .lookup(chemistry)?
+        .and_then(|(chem, _)| chem.translation_list)
+    else {
+        return Ok(None);
+    };
+
+    let plist_dir = af_home_path.join("plist");
+    if let Some(hash) = &tl.plist_name
+        && let Some(cached) = registry.find_cached_file(hash)
+    {
+        info!("Translation list cached: {}", cached.display());
+        let verified = integrity::ensure_intact(
+            af_home_path,
+            &cached,
+            tl.remote_url.as_deref(),
+            "translation list",
+        )?;
+        return Ok(Some(verified));
+    }
+    let Some(url) = &tl.remote_url else {
+        bail!(
+            "The translation list of chemistry {} is not cached and has no remote URL.",
+            chemistry
+        );
+    };
+    std::fs::create_dir_all(&plist_dir)
+        .with_context(|| format!("could not create {}", plist_dir.display()))?;
+    let dest = match &tl.plist_name {
+        Some(hash) => plist_dir.join(hash),
+        None => plist_dir.join(blake3::hash(url.as_bytes()).to_string()),
+    };
+    let lock = FileLock::for_file(&dest)?;
+    if dest.is_file() {
+        info!(
+            "The translation list of chemistry {} was downloaded by another process: {}",
+            chemistry,
+            dest.display()
+        );
+    } else {
+        info!(
+            "Downloading the translation list of chemistry {}",
+            chemistry
+        );
+        download_to_file(url, &dest)?;
+    }
+    drop(lock);
+    if tl.plist_name.is_none() {
+        // named after its url, so there is no hash to verify it against
+        return Ok(Some(dest));
+    }
+    let verified = integrity::ensure_intact(af_home_path, &dest, Some(url), "translation list")?;
+    Ok(Some(verified))
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(super) struct TranslationSummary {
+    pub(super) translation_list: PathBuf,
+    pub(super) num_rows: usize,
+    /// rows whose barcode is absent from the translation list, and was kept as is.
+    pub(super) num_untranslated: usize,
+    /// rows merged into another row translated to the same barcode.
+    pub(super) num_merged: usize,
+}
+
+/// Rewrite the rows of the quantification directory `quant_dir` into the target barcode
+/// space of the translation list `list`, merging the rows that translate to the same
+/// barcode.
+pub(super) fn translate_quant_rows(
+    quant_dir: &Path,
+    list: &Path,
+) -> anyhow::Result<TranslationSummary> {
+    let translation = BarcodeTranslation::from_file(list)?;
+    let (_mtx, rows, _cols) = mtx_utils::quant_mat_paths(quant_dir);
+    let barcodes = mtx_utils::read_row_barcodes(&rows)?;
+    let mut num_untranslated = 0;
+    let names: Vec<String> = barcodes
+        .iter()
+        .map(|bc| match translation.translate(bc) {
+            Some(t) => t.to_string(),
+            None => {
+                num_untranslated += 1;
+                bc.clone()
+            }
+        })
+        .collect();
+    let num_merged = mtx_utils::rename_quant_rows(quant_dir, &names)?;
+
+    if num_untranslated > 0 {
+        warn!(
+            "{} of {} barcodes are missing from the translation list {} and were not translated.",
+            num_untranslated,
+            barcodes.len(),
+            list.display()
+        );
+    }
+    info!(
+        "translated the barcodes of {} with {} ({} rows merged)",
+        quant_dir.display(),
+        list.display(),
+        num_merged
+    );
+    Ok(TranslationSummary {
+        translation_list: list.to_path_buf(),
+        num_rows: barcodes.len(),
+        num_untranslated,
+        num_merged,
+    })
+}
+
+/// Apply the translation list registered with `chemistry` to the quantification
+/// directory `quant_dir`.
+pub(super) fn translate_with_registered_list(
+    af_home_path: &Path,
+    chemistry: &str,
+    quant_dir: &Path,
+) -> anyhow::Result<TranslationSummary> {
+    let Some(list) = registered_translation_list(af_home_path, chemistry)? else {
+        bail!(
+            "The chemistry {} has no registered translation list; register one with `simpleaf chemistry translation-list set`.",
+            chemistry
+        );
+    };
+    translate_quant_rows(quant_dir, &list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn translation_list_maps_both_ways() {
+        let td = tempfile::tempdir().unwrap();
+        let p = td.path().join("translation.txt");
+        fs::write(&p, "# from\tto\nAAAT\tAAAA\nCCCT\tCCCC\n\n").unwrap();
+        let t = BarcodeTranslation::from_file(&p).unwrap();
+        assert_eq!(t.translate("CCCT"), Some("CCCC"));
+        assert_eq!(t.untranslate("AAAA"), Some("AAAT"));
+        assert_eq!(t.translate("AAAA"), None);
+        fs::write(&p, "AAAT\n").unwrap();
+        assert!(BarcodeTranslation::from_file(&p).is_err());
+    }
+
+    #[test]
+    fn translation_is_row_wise_in_the_chemistry_orientation() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let atac = td.path().join("atac.txt");
+        let gex = td.path().join("gex.txt");
+        fs::write(&atac, "AAAC\nGGTT\n").expect("failed to write atac whitelist");
+        fs::write(&gex, "TTTT\nCCCC\n").expect("failed to write gex whitelist");
+        let fw = BarcodeTranslation::from_paired_whitelists(&atac, &gex, false).expect("fw");
+        assert_eq!(fw.translate("AAAC"), Some("TTTT"));
+        // only the chosen orientation is looked up
+        assert!(fw.translate("AACC").is_none());
+        assert!(fw.translate("ACGT").is_none());
+
+        let rc = BarcodeTranslation::from_paired_whitelists(&atac, &gex, true).expect("rc");
+        assert_eq!(rc.translate("AACC"), Some("CCCC"));
+        assert!(rc.translate("GGTT").is_none());
+    }
+
+    #[test]
+    fn translation_rejects_unpaired_whitelists() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let atac = td.path().join("atac.txt");
+        let gex = td.path().join("gex.txt");
+        fs::write(&atac, "AAAC\nGGTT\n").expect("failed to write atac whitelist");
+        fs::write(&gex, "TTTT\n").expect("failed to write gex whitelist");
+        assert!(BarcodeTranslation::from_paired_whitelists(&atac, &gex, false).is_err());
+    }
+
+    #[test]
+    fn translation_rejects_colliding_barcodes() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let atac = td.path().join("atac.txt");
+        let gex = td.path().join("gex.txt");
+        fs::write(&atac, "AAAC\nGGTT\n").expect("failed to write atac whitelist");
+        fs::write(&gex, "TTTT\nTTTT\n").expect("failed to write gex whitelist");
+        let Err(err) = BarcodeTranslation::from_paired_whitelists(&atac, &gex, false) else {
+            panic!("duplicate barcodes must be rejected");
+        };
+        assert!(err.to_string().contains("TTTT"));
+
+        fs::write(&atac, "AAAC\nAAAC\n").expect("failed to write atac whitelist");
+        fs::write(&gex, "TTTT\nCCCC\n").expect("failed to write gex whitelist");
+        assert!(BarcodeTranslation::from_paired_whitelists(&atac, &gex, true).is_err());
+    }
+
+    #[test]
+    fn rows_are_translated_and_collisions_merged() {
+        let td = tempfile::tempdir().unwrap();
+        let quant = td.path().join("af_quant");
+        mtx_utils::write_test_quant_mat(
+            &quant,
+            &["AAAT", "GGGG", "AAAC"],
+            &["g1"],
+            &[(1, 1, 1.0), (2, 1, 2.0), (3, 1, 4.0)],
+        );
+        let (mtx, rows, _) = mtx_utils::quant_mat_paths(&quant);
+        let list = td.path().join("translation.txt");
+        fs::write(&list, "AAAT\tAAAA\nAAAC\tAAAA\n").unwrap();
+
+        let summary = translate_quant_rows(&quant, &list).unwrap();
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            json!({
+                "translation_list": list,
+                "num_rows": 3,
+                "num_untranslated": 1,
+                "num_merged": 1,
+            })
+        );
+        assert_eq!(fs::read_to_string(&rows).unwrap(), "AAAA\nGGGG\n");
+        assert_eq!(
+            mtx_utils::read_mtx_dense(&mtx).unwrap(),
+            vec![vec![5.0], vec![2.0]]
+        );
+    }
+
+    #[test]
+    fn registered_translation_list_is_resolved_from_the_cache() {
+        let td = tempfile::tempdir().unwrap();
+        fs::create_dir_all(td.path().join("plist")).unwrap();
+        fs::write(td.path().join("plist").join("tl_hash"), "AAAT\tAAAA\n").unwrap();
+        fs::write(
+            td.path().join(CHEMISTRIES_PATH),
+            json!({
+                "with_tl": {
+                    "geometry": "1{b[16]u[12]x:}2{r:}",
+                    "expected_ori": "fw",
+                    "translation_list": { "plist_name": "tl_hash" }
+                },
+                "without_tl": {
+                    "geometry": "1{b[16]u[12]x:}2{r:}",
+                    "expected_ori": "fw"
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            registered_translation_list(td.path(), "with_tl").unwrap(),
+            Some(td.path().join("plist").join("tl_hash"))
+        );
+        assert_eq!(
+            registered_translation_list(td.path(), "without_tl").unwrap(),
+            None
+        );
+        assert_eq!(
+            registered_translation_list(td.path(), "1{b[16]u[12]x:}2{r:}").unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,659 @@
+//! Joint quantification of a gene expression (gex) library and its feature barcoding
+//! (antibody / CRISPR) libraries, with `simpleaf quant --library`.
+//!
+//! The gex library is quantified as usual. Its cells then form the explicit permit list
+//! of every feature barcoding library (translated into the barcode space of the feature
+//! libraries if a translation list is given), so that all libraries are quantified over
+//! the same cells. Finally, the matrices are combined into a single one, keyed by the
+//! gex barcodes, whose features are annotated with their 10x `feature_type`.
+
+use super::barcode_translation::{self, BarcodeTranslation};
+use crate::core::io;
+use crate::simpleaf_commands::indexing;
+use crate::simpleaf_commands::{IndexOpts, LibrarySpec, LibraryType, MapQuantOpts, ReferenceType};
+use crate::utils::af_utils::RnaChemistry;
+use crate::utils::mtx_utils;
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+const MULTI_LIBRARY_INFO_FILE: &str = "simpleaf_multi_library_info.json";
+/// the id, name and 10x `feature_type` of every column of the combined matrix.
+const COMBINED_FEATURES_FILE: &str = "features.tsv";
+
+#[derive(Debug, Deserialize)]
+struct FeatureTypeRow {
+    name: String,
+    feature_type: Option<String>,
+}
+
+/// Read the `feature_type` of every feature (keyed by name, as the features are named in
+/// the quantification) of a 10x Feature Reference CSV.
+fn read_feature_types(csv: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .comment(Some(b'#'))
+        .from_path(csv)
+        .with_context(|| format!("could not open {}", csv.display()))?;
+    let mut types = HashMap::new();
+    for row in rdr.deserialize() {
+        let row: FeatureTypeRow =
+            row.with_context(|| format!("could not parse {}", csv.display()))?;
+        if let Some(t) = row.feature_type.filter(|t| !t.is_empty()) {
+            types.insert(row.name, t);
+        }
+    }
+    Ok(types)
+}
+
+/// The read1 and read2 files of a library type.
+type LibraryReads = (Vec<PathBuf>, Vec<PathBuf>);
+
+/// Group the read files of the given libraries by library type.
+fn group_libraries(
+    libraries: &[LibrarySpec],
+) -> anyhow::Result<BTreeMap<LibraryType, LibraryReads>> {
+    let mut grouped: BTreeMap<LibraryType, LibraryReads> = BTreeMap::new();
+    for lib in libraries {
+        let (r1, r2) = grouped.entry(lib.library_type).or_default();
+        r1.push(lib.reads1.clone());
+        r2.push(lib.reads2.clone());
+    }
+    if !grouped.contains_key(&LibraryType::Gex) {
+        bail!("a multi-library run needs a gex library (--library gex:R1,R2).");
+    }
+    if grouped.len() < 2 {
+        bail!(
+            "a multi-library run needs at least one feature barcoding library (--library antibody:R1,R2 or --library crispr:R1,R2)."
+        );
+    }
+    Ok(grouped)
+}
+
+fn build_feature_index(
+    af_home_path: &Path,
+    feature_csv: &Path,
+    opts: &MapQuantOpts,
+) -> anyhow::Result<PathBuf> {
+    let index_dir = opts.output.join("feature_index");
+    info!(
+        "building the feature barcode index from {}",
+        feature_csv.display()
+    );
+    indexing::build_ref_and_index(
+        af_home_path,
+        IndexOpts {
+            ref_type: ReferenceType::SplicedIntronic,
+            fasta: None,
+            gtf: None,
+            gff3_format: false,
+            rlen: 91,
+            use_piscem: false,
+            dedup: false,
+            ref_seq: None,
+            spliced: None,
+            unspliced: None,
+            minimizer_length: 19,
+            decoy_paths: None,
+            hash_seed: 1,
+            work_dir: index_dir.join("workdir.noindex"),
+            output: index_dir.clone(),
+            overwrite: true,
+            dict: opts.dict,
+            threads: opts.threads,
+            kmer_length: 31,
+            keep_duplicates: false,
+            probe_csv: None,
+            feature_csv: Some(feature_csv.to_path_buf()),
+        },
+    )?;
+    Ok(index_dir.join("index"))
+}
+
+/// The options of the single-library run quantifying the reads of `library_type`.
+fn library_quant_opts(
+    opts: &MapQuantOpts,
+    library_type: LibraryType,
+    reads: &LibraryReads,
+    feature_setup: Option<(&Path, &Path)>,
+) -> MapQuantOpts {
+    let mut lib_opts = opts.clone();
+    lib_opts.output = opts
+        .output
+        .join(format!("{:?}", library_type).to_lowercase());
+    lib_opts.reads1 = Some(reads.0.clone());
+    lib_opts.reads2 = Some(reads.1.clone());
+    lib_opts.libraries = vec![];
+    lib_opts.feature_csv = None;
+    lib_opts.feature_index = None;
+    lib_opts.feature_chemistry = None;
+    lib_opts.translation_list = None;
+    lib_opts.translate_barcodes = false;
+    lib_opts.anndata_out = false;
+    // the feature barcoding libraries are quantified over the gex cells
+    if let Some((index, permit_list)) = feature_setup {
+        lib_opts.index = Some(index.to_path_buf());
+        lib_opts.chemistry = opts
+            .feature_chemistry
+            .clone()
+            .unwrap_or_else(|| opts.chemistry.clone());
+        lib_opts.knee = false;
+        lib_opts.unfiltered_pl = None;
+        lib_opts.forced_cells = None;
+        lib_opts.expect_cells = None;
+        lib_opts.explicit_pl = Some(permit_list.to_path_buf());
+        lib_opts.expected_ori = None;
+        lib_opts.t2g_map = None;
+    }
+    lib_opts
+}
+
+fn read_lines(p: &Path) -> anyhow::Result<Vec<String>> {
+    Ok(std::fs::read_to_string(p)
+        .with_context(|| format!("could not read {}", p.display()))?
+        .lines()
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// A feature barcoding library to add to the combined matrix.
+struct FeatureLibrary {
+    library_type: LibraryType,
+    quant_dir: PathBuf,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CombineSummary {
+    num_genes: usize,
+    /// number of features contributed by each feature barcoding library.
+    num_features: Vec<(LibraryType, usize)>,
+    /// number of feature library barcodes that are not gex cells.
+    num_unmatched_barcodes: usize,
+}
+
+/// Combine the gex quantification with those of the feature barcoding libraries into
+/// a single quantification directory `out`, with the gex cells as rows. A gex run in USA
+/// mode contributes the total (spliced + unspliced + ambiguous) count of every gene.
+fn combine_libraries(
+    gex_quant: &Path,
+    features: &[FeatureLibrary],
+    feature_to_gex: impl Fn(&str) -> Option<String>,
+    feature_types: Option<&HashMap<String, String>>,
+    out: &Path,
+) -> anyhow::Result<CombineSummary> {
+    let (gex_mtx, gex_rows, gex_cols) = mtx_utils::quant_mat_paths(gex_quant);
+    let (out_mtx, out_rows, out_cols) = mtx_utils::quant_mat_paths(out);
+    let out_alevin = out.join("alevin");
+    std::fs::create_dir_all(&out_alevin)
+        .with_context(|| format!("could not create {}", out_alevin.display()))?;
+
+    let mut quant_json: serde_json::Value = serde_json::from_reader(
+        std::fs::File::open(gex_quant.join("quant.json"))
+            .with_context(|| format!("could not open quant.json in {}", gex_quant.display()))?,
+    )?;
+    let usa_mode = quant_json["usa_mode"].as_bool().unwrap_or(false);
+    let gex_col_names = read_lines(&gex_cols)?;
+    let num_genes = if usa_mode {
+        gex_col_names.len() / 3
+    } else {
+        gex_col_names.len()
+    };
+    let gex_names: HashMap<String, String> = match gex_quant.join("gene_id_to_name.tsv") {
+        p if p.is_file() => read_lines(&p)?
+            .into_iter()
+            .filter_map(|l| {
+                l.split_once('\t')
+                    .map(|(id, name)| (id.to_string(), name.to_string()))
+            })
+            .collect(),
+        _ => HashMap::new(),
+    };
+    let barcodes = mtx_utils::read_row_barcodes(&gex_rows)?;
+    let gex_row: HashMap<&str, usize> = barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, b)| (b.as_str(), i))
+        .collect();
+
+    // (id, name, feature type) of every column of the combined matrix
+    let mut columns: Vec<(String, String, &str)> = gex_col_names[..num_genes]
+        .iter()
+        .map(|id| {
+            let name = gex_names.get(id).unwrap_or(id).clone();
+            (id.clone(), name, LibraryType::Gex.feature_type())
+        })
+        .collect();
+
+    // the entries are written to a temporary file, since the size line that precedes
+    // them needs their number
+    let body_path = out_alevin.join("quants_mat.mtx.body");
+    let mut body = BufWriter::new(
+        std::fs::File::create(&body_path)
+            .with_context(|| format!("could not create {}", body_path.display()))?,
+    );
+    let mut nnz = 0usize;
+
+    // gex entries, summing the USA layers of each gene; alevin-fry writes the matrix
+    // one cell at a time, so the entries of a row can be gathered before writing them
+    let mut cur_row = None;
+    let mut row_entries: BTreeMap<usize, f64> = BTreeMap::new();
+    let mut flush = |row: usize, entries: &mut BTreeMap<usize, f64>, nnz: &mut usize| {
+        for (c, v) in std::mem::take(entries) {
+            writeln!(body, "{}\t{}\t{}", row + 1, c + 1, v)?;
+            *nnz += 1;
+        }
+        anyhow::Ok(())
+    };
+    mtx_utils::for_each_mtx_entry(&gex_mtx, |r, c, v| {
+        match cur_row {
+            Some(cr) if cr == r => {}
+            Some(cr) if cr > r => bail!(
+                "the entries of {} are not ordered by row; cannot combine the libraries.",
+                gex_mtx.display()
+            ),
+            Some(cr) => {
+                flush(cr, &mut row_entries, &mut nnz)?;
+                cur_row = Some(r);
+            }
+            None => cur_row = Some(r),
+        }
+        *row_entries.entry(c % num_genes).or_default() += v;
+        Ok(())
+    })?;
+    if let Some(cr) = cur_row {
+        flush(cr, &mut row_entries, &mut nnz)?;
+    }
+
+    // feature barcoding entries, re-keyed by gex row
+    let mut summary = CombineSummary {
+        num_genes,
+        ..Default::default()
+    };
+    for lib in features {
+        let (mtx, rows, cols) = mtx_utils::quant_mat_paths(&lib.quant_dir);
+        let lib_rows: Vec<Option<usize>> = mtx_utils::read_row_barcodes(&rows)?
+            .iter()
+            .map(|b| feature_to_gex(b).and_then(|g| gex_row.get(g.as_str()).copied()))
+            .collect();
+        summary.num_unmatched_barcodes += lib_rows.iter().filter(|r| r.is_none()).count();
+        let feature_type = lib.library_type.feature_type();
+        // features of another type (according to the feature reference) are left out
+        let mut new_col = vec![None; 0];
+        let mut num_features = 0;
+        for name in read_lines(&cols)? {
+            let keep = feature_types
+                .and_then(|ft| ft.get(&name))
+                .is_none_or(|t| t == feature_type);
+            if keep {
+                new_col.push(Some(columns.len()));
+                columns.push((name.clone(), name, feature_type));
+                num_features += 1;
+            } else {
+                new_col.push(None);
+            }
+        }
+        summary.num_features.push((lib.library_type, num_features));
+        mtx_utils::for_each_mtx_entry(&mtx, |r, c, v| {
+            if let (Some(Some(gr)), Some(Some(gc))) = (lib_rows.get(r), new_col.get(c)) {
+                writeln!(body, "{}\t{}\t{}", gr + 1, gc + 1, v)?;
+                nnz += 1;
+            }
+            Ok(())
+        })?;
+    }
+    body.flush()?;
+    drop(body);
+
+    let mut mtx_w = BufWriter::new(
+        std::fs::File::create(&out_mtx)
+            .with_context(|| format!("could not create {}", out_mtx.display()))?,
+    );
+    writeln!(mtx_w, "%%MatrixMarket matrix coordinate real general")?;
+    writeln!(mtx_w, "{}\t{}\t{}", barcodes.len(), columns.len(), nnz)?;
+    std::io::copy(&mut std::fs::File::open(&body_path)?, &mut mtx_w)?;
+    mtx_w.flush()?;
+    std::fs::remove_file(&body_path)?;
+
+    std::fs::copy(&gex_rows, &out_rows)
+        .with_context(|| format!("could not copy {}", gex_rows.display()))?;
+    let mut cols_w = BufWriter::new(std::fs::File::create(&out_cols)?);
+    let mut names_w = BufWriter::new(std::fs::File::create(out.join("gene_id_to_name.tsv"))?);
+    let mut features_w = BufWriter::new(std::fs::File::create(out.join(COMBINED_FEATURES_FILE))?);
+    for (id, name, t) in &columns {
+        writeln!(cols_w, "{}", id)?;
+        writeln!(names_w, "{}\t{}", id, name)?;
+        writeln!(features_w, "{}\t{}\t{}", id, name, t)?;
+    }
+    cols_w.flush()?;
+    names_w.flush()?;
+    features_w.flush()?;
+
+    // metadata of the gex run, so that the directory can be converted like any other
+    for fname in [
+        "generate_permit_list.json",
+        "collate.json",
+        "simpleaf_map_info.json",
+        "featureDump.txt",
+    ] {
+        let p = gex_quant.join(fname);
+        if p.is_file() {
+            std::fs::copy(&p, out.join(fname))
+                .with_context(|| format!("could not copy {}", p.display()))?;
+        }
+    }
+    quant_json["usa_mode"] = json!(false);
+    io::write_json_pretty(&out.join("quant.json"), &quant_json)?;
+    Ok(summary)
+}
+
+/// Quantify a gex library together with its feature barcoding libraries, and combine
+/// the results into a single matrix.
+pub(super) fn multi_library_quant(af_home_path: &Path, opts: MapQuantOpts) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let libraries = group_libraries(&opts.libraries)?;
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+
+    // 1. the feature barcode index
+    let feature_index = match (&opts.feature_index, &opts.feature_csv) {
+        (Some(index), _) => index.clone(),
+        (None, Some(csv)) => build_feature_index(af_home_path, csv, &opts)?,
+        (None, None) => bail!(
+            "the feature barcoding libraries need an index; please provide --feature-index or --feature-csv."
+        ),
+    };
+    let feature_types = opts
+        .feature_csv
+        .as_deref()
+        .map(read_feature_types)
+        .transpose()?;
+
+    // 2. the gex library
+    info!("quantifying the gex library");
+    let gex_start = Instant::now();
+    let gex_opts = library_quant_opts(&opts, LibraryType::Gex, &libraries[&LibraryType::Gex], None);
+    let gex_quant = gex_opts.output.join("af_quant");
+    super::map_and_quant(af_home_path, gex_opts)?;
+    let gex_duration = gex_start.elapsed();
+    let (_, gex_rows, _) = mtx_utils::quant_mat_paths(&gex_quant);
+    let cells = mtx_utils::read_row_barcodes(&gex_rows)?;
+
+    // 3. the permit list of the feature barcoding libraries, in their barcode space
+    let feature_chem = opts.feature_chemistry.as_ref().unwrap_or(&opts.chemistry);
+    // a translation list registered with the chemistry is needed to match the cells
+    let translation_list = match &opts.translation_list {
+        Some(p) => Some(p.clone()),
+        None => match barcode_translation::registered_translation_list(af_home_path, feature_chem)?
+        {
+            Some(p) => {
+                info!(
+                    "using the translation list registered with {}: {}",
+                    feature_chem,
+                    p.display()
+                );
+                Some(p)
+            }
+            None if opts.translate_barcodes => bail!(
+                "The chemistry {} has no registered translation list; please provide --translation-list.",
+                feature_chem
+            ),
+            None => None,
+        },
+    };
+    let translation = translation_list
+        .as_deref()
+        .map(BarcodeTranslation::from_file)
+        .transpose()?;
+    if translation.is_none()
+        && [
+            RnaChemistry::TenxV3.as_str(),
+            RnaChemistry::TenxV43P.as_str(),
+        ]
+        .contains(&feature_chem.as_str())
+    {
+        warn!(
+            "the feature barcoding libraries of {} use a different cell barcode set than the gex library when captured with a capture sequence (e.g. TotalSeq-B/C); if so, please provide --translation-list or register one with `simpleaf chemistry translation-list set`.",
+            feature_chem
+        );
+    }
+    let permit_list = opts.output.join("feature_permit_list.txt");
+    let mut num_untranslated = 0;
+    {
+        let mut w = BufWriter::new(
+            std::fs::File::create(&permit_list)
+                .with_context(|| format!("could not create {}", permit_list.display()))?,
+        );
+        for c in &cells {
+            match &translation {
+                Some(t) => match t.untranslate(c) {
+                    Some(f) => writeln!(w, "{}", f)?,
+                    None => num_untranslated += 1,
+                },
+                None => writeln!(w, "{}", c)?,
+            }
+        }
+        w.flush()?;
+    }
+    if num_untranslated > 0 {
+        warn!(
+            "{} of {} gex cells are missing from the translation list and will have no feature barcoding counts.",
+            num_untranslated,
+            cells.len()
+        );
+    }
+
+    // 4. the feature barcoding libraries
+    let mut feature_libs = vec![];
+    let mut feature_durations = serde_json::Map::new();
+    for (library_type, reads) in libraries.iter().filter(|(t, _)| **t != LibraryType::Gex) {
+        info!("quantifying the {:?} library", library_type);
+        let lib_start = Instant::now();
+        let lib_opts = library_quant_opts(
+            &opts,
+            *library_type,
+            reads,
+            Some((&feature_index, &permit_list)),
+        );
+        let quant_dir = lib_opts.output.join("af_quant");
+        super::map_and_quant(af_home_path, lib_opts)?;
+        feature_libs.push(FeatureLibrary {
+            library_type: *library_type,
+            quant_dir,
+        });
+        feature_durations.insert(
+            format!("{:?}_time", library_type).to_lowercase(),
+            json!(lib_start.elapsed().as_secs_f64()),
+        );
+    }
+
+    // 5. the combined matrix
+    let combine_start = Instant::now();
+    let combined = opts.output.join("combined");
+    let summary = combine_libraries(
+        &gex_quant,
+        &feature_libs,
+        |bc| match &translation {
+            Some(t) => t.translate(bc).map(str::to_string),
+            None => Some(bc.to_string()),
+        },
+        feature_types.as_ref(),
+        &combined,
+    )?;
+    if summary.num_unmatched_barcodes > 0 {
+        warn!(
+            "{} feature barcoding barcodes could not be matched to a gex cell and were left out of the combined matrix.",
+            summary.num_unmatched_barcodes
+        );
+    }
+    info!(
+        "combined matrix of {} cells, {} genes and {} features written to {}",
+        cells.len(),
+        summary.num_genes,
+        summary.num_features.iter().map(|(_, n)| n).sum::<usize>(),
+        combined.display()
+    );
+    let mut convert_duration = None;
+    if opts.anndata_out {
+        let convert_start = Instant::now();
+        let opath = combined.join("alevin").join("quants.h5ad");
+        af_anndata::convert_csr_to_anndata(&combined, &opath)?;
+        convert_duration = Some(convert_start.elapsed().as_secs_f64());
+    }
+
+    let mut time_info = json!({
+        "gex_time": gex_duration.as_secs_f64(),
+        "combine_time": combine_start.elapsed().as_secs_f64(),
+        "conversion_time": convert_duration,
+        "total_time": start.elapsed().as_secs_f64(),
+    });
+    time_info
+        .as_object_mut()
+        .expect("time_info is an object")
+        .extend(feature_durations);
+    let info_json = json!({
+        "libraries": libraries
+            .iter()
+            .map(|(t, (r1, r2))| json!({
+                "library_type": format!("{:?}", t).to_lowercase(),
+                "feature_type": t.feature_type(),
+                "reads1": r1,
+                "reads2": r2,
+            }))
+            .collect::<Vec<_>>(),
+        "feature_index": feature_index,
+        "feature_csv": opts.feature_csv,
+        "translation_list": translation_list,
+        "num_cells": cells.len(),
+        "num_untranslated_cells": num_untranslated,
+        "num_genes": summary.num_genes,
+        "num_features": summary
+            .num_features
+            .iter()
+            .map(|(t, n)| (format!("{:?}", t).to_lowercase(), json!(n)))
+            .collect::<serde_json::Map<_, _>>(),
+        "num_unmatched_feature_barcodes": summary.num_unmatched_barcodes,
+        "combined_output": combined,
+        "time_info": time_info,
+    });
+    io::write_json_pretty_atomic(&opts.output.join(MULTI_LIBRARY_INFO_FILE), &info_json)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_quant_dir(
+        dir: &Path,
+        rows: &[&str],
+        cols: &[&str],
+        entries: &[(usize, usize, f64)],
+        usa: bool,
+    ) {
+        mtx_utils::write_test_quant_mat(dir, rows, cols, entries);
+        fs::write(
+            dir.join("quant.json"),
+            json!({ "usa_mode": usa }).to_string(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn library_specs_are_grouped_by_type() {
+        let spec = |t, r1: &str, r2: &str| LibrarySpec {
+            library_type: t,
+            reads1: PathBuf::from(r1),
+            reads2: PathBuf::from(r2),
+        };
+        let grouped = group_libraries(&[
+            spec(LibraryType::Gex, "g1_R1", "g1_R2"),
+            spec(LibraryType::Antibody, "a_R1", "a_R2"),
+            spec(LibraryType::Gex, "g2_R1", "g2_R2"),
+        ])
+        .unwrap();
+        assert_eq!(
+            grouped[&LibraryType::Gex].0,
+            vec![PathBuf::from("g1_R1"), PathBuf::from("g2_R1")]
+        );
+        assert_eq!(
+            grouped[&LibraryType::Antibody].1,
+            vec![PathBuf::from("a_R2")]
+        );
+        assert!(group_libraries(&[spec(LibraryType::Gex, "g", "g")]).is_err());
+        assert!(group_libraries(&[spec(LibraryType::Crispr, "c", "c")]).is_err());
+    }
+
+    #[test]
+    fn libraries_are_combined_in_gex_barcode_space() {
+        let td = tempfile::tempdir().unwrap();
+        // a USA-mode gex run over 2 genes: columns are g1,g2 (S), g1,g2 (U), g1,g2 (A)
+        let gex = td.path().join("gex");
+        write_quant_dir(
+            &gex,
+            &["AAAA", "CCCC"],
+            &["g1", "g2", "g1", "g2", "g1", "g2"],
+            &[(1, 1, 2.0), (1, 3, 1.0), (1, 6, 4.0), (2, 2, 3.0)],
+            true,
+        );
+        fs::write(gex.join("gene_id_to_name.tsv"), "g1\tGENE1\ng2\tGENE2\n").unwrap();
+        // the antibody library is in its own barcode space (lowercase here), and
+        // also contains a CRISPR feature that it should not contribute
+        let ab = td.path().join("antibody");
+        write_quant_dir(
+            &ab,
+            &["cccc", "aaaa", "gggg"],
+            &["CD3", "CD4", "guide1"],
+            &[(1, 2, 7.0), (2, 1, 5.0), (2, 3, 9.0), (3, 1, 1.0)],
+            false,
+        );
+        let types = HashMap::from([
+            (String::from("CD3"), String::from("Antibody Capture")),
+            (String::from("CD4"), String::from("Antibody Capture")),
+            (String::from("guide1"), String::from("CRISPR Guide Capture")),
+        ]);
+
+        let out = td.path().join("combined");
+        let summary = combine_libraries(
+            &gex,
+            &[FeatureLibrary {
+                library_type: LibraryType::Antibody,
+                quant_dir: ab,
+            }],
+            |bc| Some(bc.to_uppercase()),
+            Some(&types),
+            &out,
+        )
+        .unwrap();
+        assert_eq!(
+            summary,
+            CombineSummary {
+                num_genes: 2,
+                num_features: vec![(LibraryType::Antibody, 2)],
+                num_unmatched_barcodes: 1,
+            }
+        );
+
+        let (mtx, rows, cols) = mtx_utils::quant_mat_paths(&out);
+        assert_eq!(
+            mtx_utils::read_mtx_dense(&mtx).unwrap(),
+            vec![vec![3.0, 4.0, 5.0, 0.0], vec![0.0, 3.0, 0.0, 7.0]]
+        );
+        assert_eq!(fs::read_to_string(rows).unwrap(), "AAAA\nCCCC\n");
+        assert_eq!(fs::read_to_string(cols).unwrap(), "g1\ng2\nCD3\nCD4\n");
+        assert_eq!(
+            fs::read_to_string(out.join(COMBINED_FEATURES_FILE)).unwrap(),
+            "g1\tGENE1\tGene Expression\ng2\tGENE2\tGene Expression\n\
+             CD3\tCD3\tAntibody Capture\nCD4\tCD4\tAntibody Capture\n"
+        );
+        let quant_json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(out.join("quant.json")).unwrap()).unwrap();
+        assert_eq!(quant_json["usa_mode"], false);
+    }
+}
@@ -0,0 +1,485 @@
+//! Spatial output for the Visium chemistries, whose registered permit lists are the Space
+//! Ranger coordinate files (the barcode, then the 1-based column and row of the spot on the
+//! array). After quantification, `quants_mat_rows.txt` keeps these columns (see
+//! `CBListInfo`), from which the array position of each spot is recovered. A Space Ranger
+//! `tissue_positions.csv` adds the pixel coordinates and in-tissue flag of each spot.
+
+use crate::utils::mtx_utils;
+
+use anndata::data::Mapping;
+use anndata::{AnnData, AnnDataOp, AxisArraysOp, Backend, Data, ElemCollectionOp};
+use anndata_hdf5::H5;
+use anyhow::{Context, bail};
+use ndarray::Array2;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// the directory (within the quantification directory) holding the spot annotations.
+const SPATIAL_DIR: &str = "spatial";
+const SPOT_COORDINATES_FILE: &str = "spot_coordinates.tsv";
+const SCALEFACTORS_FILE: &str = "scalefactors_json.json";
+
+/// The position of a spot in the full resolution image, from `tissue_positions.csv`.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct TissuePosition {
+    pub(super) in_tissue: bool,
+    pub(super) pxl_row: f64,
+    pub(super) pxl_col: f64,
+}
+
+/// A quantified spot, with its (0-based) position on the array.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Spot {
+    pub(super) barcode: String,
+    pub(super) array_row: u32,
+    pub(super) array_col: u32,
+    pub(super) position: Option<TissuePosition>,
+}
+
+/// Space Ranger suffixes the barcodes with the GEM well (e.g. `AAACAACGAATAGTTC-1`).
+fn strip_gem_well(barcode: &str) -> &str {
+    barcode.split_once('-').map_or(barcode, |(bc, _)| bc)
+}
+
+/// Read a Space Ranger `tissue_positions.csv` (with a header, Space Ranger >= 2.0) or
+/// `tissue_positions_list.csv` (without), keyed by barcode.
+pub(super) fn read_tissue_positions(p: &Path) -> anyhow::Result<HashMap<String, TissuePosition>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_path(p)
+        .with_context(|| format!("could not open the tissue positions file {}", p.display()))?;
+    let mut positions = HashMap::new();
+    for (i, rec) in rdr.records().enumerate() {
+        let rec = rec.with_context(|| format!("could not read {}", p.display()))?;
+        if i == 0 && rec.get(0) == Some("barcode") {
+            continue;
+        }
+        if rec.len() != 6 {
+            bail!(
+                "line {} of {} has {} columns; expected barcode, in_tissue, array_row, array_col, pxl_row_in_fullres and pxl_col_in_fullres",
+                i + 1,
+                p.display(),
+                rec.len()
+            );
+        }
+        let pxl = |j: usize| -> anyhow::Result<f64> {
+            rec[j].parse::<f64>().with_context(|| {
+                format!(
+                    "could not parse the pixel coordinate {} on line {} of {}",
+                    &rec[j],
+                    i + 1,
+                    p.display()
+                )
+            })
+        };
+        let in_tissue = match &rec[1] {
+            "1" => true,
+            "0" => false,
+            v => bail!(
+                "invalid in_tissue flag {} on line {} of {}; expected 0 or 1",
+                v,
+                i + 1,
+                p.display()
+            ),
+        };
+        positions.insert(
+            strip_gem_well(&rec[0]).to_string(),
+            TissuePosition {
+                in_tissue,
+                pxl_row: pxl(4)?,
+                pxl_col: pxl(5)?,
+            },
+        );
+    }
+    Ok(positions)
+}
+
+/// Read the spots of `quants_mat_rows.txt`, whose rows must carry the coordinates of the
+/// permit list (the barcode, then the 1-based array column and row).
+pub(super) fn read_spots(rows_path: &Path) -> anyhow::Result<Vec<Spot>> {
+    let content = std::fs::read_to_string(rows_path)
+        .with_context(|| format!("could not read {}", rows_path.display()))?;
+    let mut spots = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let toks: Vec<&str> = line.split('\t').collect();
+        if toks.len() != 3 {
+            bail!(
+                "line {} of {} has no spot coordinates; the spatial output requires the permit list of a Visium chemistry, i.e. a barcode followed by the array column and row, passed with --unfiltered-pl",
+                i + 1,
+                rows_path.display()
+            );
+        }
+        let coord = |j: usize| -> anyhow::Result<u32> {
+            match toks[j].parse::<u32>() {
+                Ok(c) if c > 0 => Ok(c - 1),
+                _ => bail!(
+                    "invalid spot coordinate {} on line {} of {}",
+                    toks[j],
+                    i + 1,
+                    rows_path.display()
+                ),
+            }
+        };
+        spots.push(Spot {
+            barcode: toks[0].to_string(),
+            array_col: coord(1)?,
+            array_row: coord(2)?,
+            position: None,
+        });
+    }
+    Ok(spots)
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(super) struct SpatialSummary {
+    pub(super) num_spots: usize,
+    pub(super) tissue_positions: Option<PathBuf>,
+    /// spots absent from the tissue positions, which have no pixel coordinates.
+    pub(super) num_unpositioned: usize,
+    pub(super) num_in_tissue: Option<usize>,
+    pub(super) in_tissue_quant: Option<PathBuf>,
+    pub(super) scalefactors: Option<PathBuf>,
+}
+
+/// The spots of a spatial quantification, and the in-tissue subset of it.
+pub(super) struct SpatialOutput {
+    spots: Vec<Spot>,
+    /// the in-tissue quantification directory, and the indices of the spots it retains.
+    in_tissue: Option<(PathBuf, Vec<usize>)>,
+    scalefactors: Option<serde_json::Map<String, serde_json::Value>>,
+    library_id: String,
+    pub(super) summary: SpatialSummary,
+}
+
+impl SpatialOutput {
+    /// Annotate the spots of the quantification directory `quant_dir`, writing them to its
+    /// `spatial` directory. With `tissue_positions`, the spots under tissue are also written
+    /// to the quantification directory `in_tissue_dir`.
+    pub(super) fn write(
+        quant_dir: &Path,
+        in_tissue_dir: &Path,
+        tissue_positions: Option<&Path>,
+        scalefactors: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let (_mtx, rows, _cols) = mtx_utils::quant_mat_paths(quant_dir);
+        let mut spots = read_spots(&rows)?;
+
+        let mut num_unpositioned = 0;
+        if let Some(p) = tissue_positions {
+            let positions = read_tissue_positions(p)?;
+            for spot in spots.iter_mut() {
+                spot.position = positions.get(&spot.barcode).cloned();
+                if spot.position.is_none() {
+                    num_unpositioned += 1;
+                }
+            }
+            if num_unpositioned > 0 {
+                warn!(
+                    "{} of {} spots are missing from the tissue positions {}; they have no pixel coordinates and are considered outside the tissue.",
+                    num_unpositioned,
+                    spots.len(),
+                    p.display()
+                );
+            }
+        }
+
+        let spatial_dir = quant_dir.join(SPATIAL_DIR);
+        std::fs::create_dir_all(&spatial_dir)
+            .with_context(|| format!("could not create {}", spatial_dir.display()))?;
+        write_spot_coordinates(
+            &spatial_dir.join(SPOT_COORDINATES_FILE),
+            &spots,
+            tissue_positions.is_some(),
+        )?;
+
+        let scalefactors = match scalefactors {
+            Some(p) => {
+                let content = std::fs::read_to_string(p)
+                    .with_context(|| format!("could not read {}", p.display()))?;
+                let sf: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&content)
+                    .with_context(|| format!("could not parse {} as a JSON object", p.display()))?;
+                std::fs::copy(p, spatial_dir.join(SCALEFACTORS_FILE))
+                    .with_context(|| format!("could not copy {}", p.display()))?;
+                Some(sf)
+            }
+            None => None,
+        };
+
+        let in_tissue = if tissue_positions.is_some() {
+            let keep: Vec<usize> = spots
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| s.position.as_ref().is_some_and(|p| p.in_tissue))
+                .map(|(i, _)| i)
+                .collect();
+            mtx_utils::subset_quant_dir(quant_dir, in_tissue_dir, &keep, None)?;
+            std::fs::create_dir_all(in_tissue_dir.join(SPATIAL_DIR))?;
+            write_spot_coordinates(
+                &in_tissue_dir.join(SPATIAL_DIR).join(SPOT_COORDINATES_FILE),
+                &keep.iter().map(|i| spots[*i].clone()).collect::<Vec<_>>(),
+                true,
+            )?;
+            if scalefactors.is_some() {
+                std::fs::copy(
+                    spatial_dir.join(SCALEFACTORS_FILE),
+                    in_tissue_dir.join(SPATIAL_DIR).join(SCALEFACTORS_FILE),
+                )?;
+            }
+            info!(
+                "wrote the {} of {} spots under tissue to {}",
+                keep.len(),
+                spots.len(),
+                in_tissue_dir.display()
+            );
+            Some((in_tissue_dir.to_path_buf(), keep))
+        } else {
+            None
+        };
+
+        let library_id = quant_dir.parent().and_then(Path::file_name).map_or_else(
+            || "simpleaf".to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        let summary = SpatialSummary {
+            num_spots: spots.len(),
+            tissue_positions: tissue_positions.map(Path::to_path_buf),
+            num_unpositioned,
+            num_in_tissue: in_tissue.as_ref().map(|(_, keep)| keep.len()),
+            in_tissue_quant: in_tissue.as_ref().map(|(p, _)| p.clone()),
+            scalefactors: scalefactors
+                .as_ref()
+                .map(|_| spatial_dir.join(SCALEFACTORS_FILE)),
+        };
+        Ok(Self {
+            spots,
+            in_tissue,
+            scalefactors,
+            library_id,
+            summary,
+        })
+    }
+
+    /// Add the spot coordinates to the h5ad file converted from the quantification
+    /// directory, and convert the in-tissue quantification directory (if any) alongside.
+    pub(super) fn write_anndata(&self, h5ad: &Path) -> anyhow::Result<()> {
+        self.annotate_h5ad(h5ad, &self.spots)?;
+        if let Some((dir, keep)) = &self.in_tissue {
+            let in_tissue_h5ad = dir.join("alevin").join("quants.h5ad");
+            af_anndata::convert_csr_to_anndata(dir, &in_tissue_h5ad)?;
+            let spots: Vec<Spot> = keep.iter().map(|i| self.spots[*i].clone()).collect();
+            self.annotate_h5ad(&in_tissue_h5ad, &spots)?;
+        }
+        Ok(())
+    }
+
+    /// Write `obsm["spatial"]` (the pixel column and row of each spot, or its array column
+    /// and row without tissue positions) and, with scale factors, `uns["spatial"]` in the
+    /// layout expected by scanpy and squidpy.
+    fn annotate_h5ad(&self, h5ad: &Path, spots: &[Spot]) -> anyhow::Result<()> {
+        let adata = AnnData::<H5>::open(H5::open_rw(h5ad)?)
+            .with_context(|| format!("could not open {}", h5ad.display()))?;
+        if adata.n_obs() != spots.len() {
+            bail!(
+                "{} has {} observations, but {} spots were quantified",
+                h5ad.display(),
+                adata.n_obs(),
+                spots.len()
+            );
+        }
+        let with_positions = self.summary.tissue_positions.is_some();
+        let coords: Vec<f64> = spots
+            .iter()
+            .flat_map(|s| match (&s.position, with_positions) {
+                (Some(p), _) => [p.pxl_col, p.pxl_row],
+                (None, true) => [f64::NAN, f64::NAN],
+                (None, false) => [f64::from(s.array_col), f64::from(s.array_row)],
+            })
+            .collect();
+        adata
+            .obsm()
+            .add("spatial", Array2::from_shape_vec((spots.len(), 2), coords)?)?;
+
+        if let Some(sf) = &self.scalefactors {
+            let scalefactors: HashMap<String, Data> = sf
+                .iter()
+                .filter_map(|(k, v)| v.as_f64().map(|x| (k.clone(), Data::from(x))))
+                .collect();
+            let library = HashMap::from([(
+                "scalefactors".to_string(),
+                Data::from(Mapping::from(scalefactors)),
+            )]);
+            let spatial =
+                HashMap::from([(self.library_id.clone(), Data::from(Mapping::from(library)))]);
+            adata.uns().add("spatial", Mapping::from(spatial))?;
+        }
+        adata.close()?;
+        Ok(())
+    }
+}
+
+fn write_spot_coordinates(p: &Path, spots: &[Spot], with_positions: bool) -> anyhow::Result<()> {
+    let mut w = BufWriter::new(
+        std::fs::File::create(p).with_context(|| format!("could not create {}", p.display()))?,
+    );
+    write!(w, "barcode\tarray_row\tarray_col")?;
+    if with_positions {
+        write!(w, "\tin_tissue\tpxl_row_in_fullres\tpxl_col_in_fullres")?;
+    }
+    writeln!(w)?;
+    for s in spots {
+        write!(w, "{}\t{}\t{}", s.barcode, s.array_row, s.array_col)?;
+        match (&s.position, with_positions) {
+            (Some(p), true) => write!(
+                w,
+                "\t{}\t{}\t{}",
+                u8::from(p.in_tissue),
+                p.pxl_row,
+                p.pxl_col
+            )?,
+            (None, true) => write!(w, "\t0\tNA\tNA")?,
+            (_, false) => {}
+        }
+        writeln!(w)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_quant_dir(quant: &Path) {
+        mtx_utils::write_test_quant_mat(
+            quant,
+            &["AAAA\t17\t1", "CCCC\t2\t3", "GGGG\t128\t78"],
+            &["g1", "g2"],
+            &[(1, 1, 1.0), (2, 2, 2.0), (3, 1, 3.0), (3, 2, 4.0)],
+        );
+    }
+
+    #[test]
+    fn tissue_positions_parse_with_or_without_header() {
+        let td = tempfile::tempdir().unwrap();
+        let with_header = td.path().join("tissue_positions.csv");
+        fs::write(
+            &with_header,
+            "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres\nAAAA-1,1,0,16,100.5,200\nCCCC-1,0,2,1,300,400\n",
+        )
+        .unwrap();
+        let without_header = td.path().join("tissue_positions_list.csv");
+        fs::write(
+            &without_header,
+            "AAAA-1,1,0,16,100.5,200\nCCCC-1,0,2,1,300,400\n",
+        )
+        .unwrap();
+        for p in [&with_header, &without_header] {
+            let positions = read_tissue_positions(p).unwrap();
+            assert_eq!(positions.len(), 2);
+            assert_eq!(
+                positions["AAAA"],
+                TissuePosition {
+                    in_tissue: true,
+                    pxl_row: 100.5,
+                    pxl_col: 200.0
+                }
+            );
+            assert!(!positions["CCCC"].in_tissue);
+        }
+        fs::write(&without_header, "AAAA-1,yes,0,16,100,200\n").unwrap();
+        assert!(read_tissue_positions(&without_header).is_err());
+    }
+
+    #[test]
+    fn spots_require_permit_list_coordinates() {
+        let td = tempfile::tempdir().unwrap();
+        let rows = td.path().join("quants_mat_rows.txt");
+        fs::write(&rows, "AAAA\t17\t1\n").unwrap();
+        assert_eq!(
+            read_spots(&rows).unwrap(),
+            vec![Spot {
+                barcode: "AAAA".to_string(),
+                array_row: 0,
+                array_col: 16,
+                position: None
+            }]
+        );
+        fs::write(&rows, "AAAA\n").unwrap();
+        assert!(read_spots(&rows).is_err());
+    }
+
+    #[test]
+    fn spots_are_annotated_and_filtered_to_the_tissue() {
+        let td = tempfile::tempdir().unwrap();
+        let quant = td.path().join("out").join("af_quant");
+        write_quant_dir(&quant);
+        let positions = td.path().join("tissue_positions.csv");
+        fs::write(
+            &positions,
+            "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres\nAAAA-1,1,0,16,10,20\nCCCC-1,0,2,1,30,40\n",
+        )
+        .unwrap();
+        let scalefactors = td.path().join("scalefactors_json.json");
+        fs::write(
+            &scalefactors,
+            r#"{"tissue_hires_scalef": 0.17, "spot_diameter_fullres": 89.4}"#,
+        )
+        .unwrap();
+        let in_tissue = td.path().join("out").join("af_quant_in_tissue");
+
+        let out = SpatialOutput::write(&quant, &in_tissue, Some(&positions), Some(&scalefactors))
+            .unwrap();
+        assert_eq!(
+            out.summary,
+            SpatialSummary {
+                num_spots: 3,
+                tissue_positions: Some(positions.clone()),
+                num_unpositioned: 1,
+                num_in_tissue: Some(1),
+                in_tissue_quant: Some(in_tissue.clone()),
+                scalefactors: Some(quant.join(SPATIAL_DIR).join(SCALEFACTORS_FILE)),
+            }
+        );
+        assert_eq!(out.library_id, "out");
+        assert_eq!(
+            fs::read_to_string(quant.join(SPATIAL_DIR).join(SPOT_COORDINATES_FILE)).unwrap(),
+            "barcode\tarray_row\tarray_col\tin_tissue\tpxl_row_in_fullres\tpxl_col_in_fullres\n\
+             AAAA\t0\t16\t1\t10\t20\n\
+             CCCC\t2\t1\t0\t30\t40\n\
+             GGGG\t77\t127\t0\tNA\tNA\n"
+        );
+
+        let (mtx, rows, _cols) = mtx_utils::quant_mat_paths(&in_tissue);
+        assert_eq!(fs::read_to_string(rows).unwrap(), "AAAA\t17\t1\n");
+        assert_eq!(
+            mtx_utils::read_mtx_dense(&mtx).unwrap(),
+            vec![vec![1.0, 0.0]]
+        );
+        assert!(
+            in_tissue
+                .join(SPATIAL_DIR)
+                .join(SCALEFACTORS_FILE)
+                .is_file()
+        );
+    }
+
+    #[test]
+    fn spots_without_tissue_positions_keep_array_coordinates() {
+        let td = tempfile::tempdir().unwrap();
+        let quant = td.path().join("af_quant");
+        write_quant_dir(&quant);
+        let in_tissue = td.path().join("af_quant_in_tissue");
+        let out = SpatialOutput::write(&quant, &in_tissue, None, None).unwrap();
+        assert_eq!(out.summary.num_in_tissue, None);
+        assert!(!in_tissue.exists());
+        assert_eq!(
+            fs::read_to_string(quant.join(SPATIAL_DIR).join(SPOT_COORDINATES_FILE)).unwrap(),
+            "barcode\tarray_row\tarray_col\nAAAA\t0\t16\nCCCC\t2\t1\nGGGG\t77\t127\n"
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Aggregation of high-resolution spatial quantifications (e.g. the 2µm barcodes of
+//! Visium HD) into square bins of larger sizes.
+//!
+//! The barcode × gene matrix of an alevin-fry quantification directory is streamed once;
+//! every entry is added to the bin its barcode falls in, for each bin size, so that only
+//! the (sparse) binned matrices are held in memory, never the input matrix.
+
+use crate::core::io;
+use crate::simpleaf_commands::SpatialBinOpts;
+use crate::utils::mtx_utils;
+
+use anyhow::{Context, bail};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// marks a barcode without coordinates.
+const UNPLACED: u32 = u32::MAX;
+
+/// The counts of one bin, as (column, count) pairs. Entries are appended as they are
+/// streamed, and periodically sorted and merged so that a bin never holds more than
+/// about twice as many entries as it has distinct columns.
+#[derive(Default)]
+struct SparseBin {
+    entries: Vec<(u32, f32)>,
+    merged_len: usize,
+}
+
+impl SparseBin {
+    fn add(&mut self, col: u32, v: f32) {
+        self.entries.push((col, v));
+        if self.entries.len() >= 2 * self.merged_len + 64 {
+            self.merge();
+        }
+    }
+
+    fn merge(&mut self) {
+        self.entries.sort_unstable_by_key(|(c, _)| *c);
+        self.entries.dedup_by(|next, kept| {
+            if next.0 == kept.0 {
+                kept.1 += next.1;
+                true
+            } else {
+                false
+            }
+        });
+        self.merged_len = self.entries.len();
+    }
+}
+
+/// The bins of one bin size.
+struct Binning {
+    /// the bin size, in µm.
+    size: u32,
+    /// the bin of each barcode (row of the input matrix), or `UNPLACED`.
+    bin_of_row: Vec<u32>,
+    /// the (row, column) of each bin on the grid of this size.
+    grid: Vec<(i64, i64)>,
+    grid_index: HashMap<(i64, i64), u32>,
+    num_barcodes: Vec<u32>,
+    bins: Vec<SparseBin>,
+}
+
+impl Binning {
+    fn new(size: u32, nrows: usize) -> Self {
+        Binning {
+            size,
+            bin_of_row: vec![UNPLACED; nrows],
+            grid: Vec::new(),
+            grid_index: HashMap::new(),
+            num_barcodes: Vec::new(),
+            bins: Vec::new(),
+        }
+    }
+
+    /// Place the barcode of row `row` at (`x_um`, `y_um`).
+    fn place(&mut self, row: usize, x_um: f64, y_um: f64) {
+        let size = f64::from(self.size);
+        let cell = ((y_um / size).floor() as i64, (x_um / size).floor() as i64);
+        let next = self.grid.len() as u32;
+        let bin = *self.grid_index.entry(cell).or_insert(next);
+        if bin == next {
+            self.grid.push(cell);
+            self.num_barcodes.push(0);
+            self.bins.push(SparseBin::default());
+        }
+        if self.bin_of_row[row] == UNPLACED {
+            self.num_barcodes[bin as usize] += 1;
+        } else {
+            self.num_barcodes[self.bin_of_row[row] as usize] -= 1;
+            self.num_barcodes[bin as usize] += 1;
+        }
+        self.bin_of_row[row] = bin;
+    }
+
+    fn add(&mut self, row: usize, col: usize, v: f64) {
+        let bin = self.bin_of_row[row];
+        if bin != UNPLACED {
+            self.bins[bin as usize].add(col as u32, v as f32);
+        }
+    }
+
+    /// The name of a bin, following the Visium HD convention (e.g. `s_008um_00012_00034`).
+    fn bin_name(&self, cell: (i64, i64)) -> String {
+        format!("s_{:03}um_{:05}_{:05}", self.size, cell.0, cell.1)
+    }
+
+    /// Write the binned matrix, its rows and columns, and the bin coordinates to `dir`,
+    /// with the bins ordered by grid row and column.
+    fn write(mut self, dir: &Path, cols_path: &Path, ncols: usize) -> anyhow::Result<BinSummary> {
+        let alevin = dir.join("alevin");
+        std::fs::create_dir_all(&alevin)
+            .with_context(|| format!("could not create {}", alevin.display()))?;
+        let (mtx, rows, cols) = mtx_utils::quant_mat_paths(dir);
+        std::fs::copy(cols_path, &cols)
+            .with_context(|| format!("could not copy {}", cols_path.display()))?;
+
+        let mut order: Vec<usize> = (0..self.grid.len())
+            .filter(|b| self.num_barcodes[*b] > 0)
+            .collect();
+        order.sort_unstable_by_key(|b| self.grid[*b]);
+        for bin in self.bins.iter_mut() {
+            bin.merge();
+        }
+        let nnz: usize = order.iter().map(|b| self.bins[*b].entries.len()).sum();
+
+        let mut mtx_w = BufWriter::new(
+            std::fs::File::create(&mtx)
+                .with_context(|| format!("could not create {}", mtx.display()))?,
+        );
+        let mut rows_w = BufWriter::new(
+            std::fs::File::create(&rows)
+                .with_context(|| format!("could not create {}", rows.display()))?,
+        );
+        let coords = dir.join("bin_coords.tsv");
+        let mut coords_w = BufWriter::new(
+            std::fs::File::create(&coords)
+                .with_context(|| format!("could not create {}", coords.display()))?,
+        );
+        writeln!(mtx_w, "%%MatrixMarket matrix coordinate real general")?;
+        writeln!(mtx_w, "{}\t{}\t{}", order.len(), ncols, nnz)?;
+        writeln!(coords_w, "bin\tbin_row\tbin_col\tx_um\ty_um\tnum_barcodes")?;
+        let size = f64::from(self.size);
+        for (i, b) in order.iter().enumerate() {
+            let cell = self.grid[*b];
+            let name = self.bin_name(cell);
+            writeln!(rows_w, "{}", name)?;
+            writeln!(
+                coords_w,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                name,
+                cell.0,
+                cell.1,
+                (cell.1 as f64 + 0.5) * size,
+                (cell.0 as f64 + 0.5) * size,
+                self.num_barcodes[*b]
+            )?;
+            for (c, v) in &self.bins[*b].entries {
+                writeln!(mtx_w, "{}\t{}\t{}", i + 1, c + 1, v)?;
+            }
+        }
+        mtx_w.flush()?;
+        rows_w.flush()?;
+        coords_w.flush()?;
+        Ok(BinSummary {
+            bin_size: self.size,
+            num_bins: order.len(),
+            nnz,
+            output: dir.to_path_buf(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct BinSummary {
+    bin_size: u32,
+    num_bins: usize,
+    nnz: usize,
+    output: PathBuf,
+}
+
+/// Space Ranger suffixes the barcodes with the GEM well (e.g. `s_002um_00000_00000-1`).
+fn strip_gem_well(barcode: &str) -> &str {
+    barcode.rsplit_once('-').map_or(barcode, |(bc, _)| bc)
+}
+
+/// Stream the coordinates file, placing each quantified barcode in its bin of every size.
+/// Returns the number of quantified barcodes that were placed.
+fn place_barcodes(
+    coords: &Path,
+    coord_scale: f64,
+    row_of: &HashMap<Box<str>, u32>,
+    binnings: &mut [Binning],
+) -> anyhow::Result<usize> {
+    let (reader, _fmt) = niffler::from_path(coords)
+        .with_context(|| format!("could not open the coordinates file {}", coords.display()))?;
+    let mut placed = vec![false; row_of.len()];
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.with_context(|| format!("could not read {}", coords.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let toks: Vec<&str> = line.split(['\t', ',']).map(str::trim).collect();
+        let [bc, x, y, ..] = toks.as_slice() else {
+            bail!(
+                "line {} of {} should hold a barcode and its x and y coordinates",
+                i + 1,
+                coords.display()
+            );
+        };
+        let (x, y) = match (x.parse::<f64>(), y.parse::<f64>()) {
+            (Ok(x), Ok(y)) => (x, y),
+            // a header line
+            _ if i == 0 => continue,
+            _ => bail!(
+                "could not parse the coordinates on line {} of {}",
+                i + 1,
+                coords.display()
+            ),
+        };
+        let Some(row) = row_of.get(*bc).or_else(|| row_of.get(strip_gem_well(bc))) else {
+            continue;
+        };
+        let row = *row as usize;
+        placed[row] = true;
+        for binning in binnings.iter_mut() {
+            binning.place(row, x * coord_scale, y * coord_scale);
+        }
+    }
+    Ok(placed.into_iter().filter(|p| *p).count())
+}
+
+/// Aggregate the barcode × gene matrix of `opts.quant_dir` into square bins of each of
+/// `opts.bin_sizes`, writing one quantification directory per bin size.
+pub fn bin_spots(opts: SpatialBinOpts) -> anyhow::Result<()> {
+    let mut bin_sizes = opts.bin_sizes.clone();
+    bin_sizes.sort_unstable();
+    bin_sizes.dedup();
+    if bin_sizes.contains(&0) {
+        bail!("the bin sizes must be positive.");
+    }
+    if opts.coord_scale <= 0.0 {
+        bail!("--coord-scale must be positive.");
+    }
+
+    let (mtx, rows, cols) = mtx_utils::quant_mat_paths(&opts.quant_dir);
+    let barcodes = mtx_utils::read_row_barcodes(&rows)?;
+    let mut row_of: HashMap<Box<str>, u32> = HashMap::with_capacity(barcodes.len());
+    for (i, bc) in barcodes.into_iter().enumerate() {
+        if row_of
+            .insert(bc.clone().into_boxed_str(), i as u32)
+            .is_some()
+        {
+            bail!(
+                "the barcode {} appears more than once in {}",
+                bc,
+                rows.display()
+            );
+        }
+    }
+    let nrows = row_of.len();
+    info!(
+        "binning the {} barcodes of {} into bins of {} µm",
+        nrows,
+        opts.quant_dir.display(),
+        bin_sizes
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut binnings: Vec<Binning> = bin_sizes.iter().map(|s| Binning::new(*s, nrows)).collect();
+    let num_placed = place_barcodes(&opts.coords, opts.coord_scale, &row_of, &mut binnings)?;
+    drop(row_of);
+    if num_placed == 0 {
+        bail!(
+            "none of the barcodes of {} were found in {}",
+            rows.display(),
+            opts.coords.display()
+        );
+    }
+    if num_placed < nrows {
+        warn!(
+            "{} of {} barcodes have no coordinates in {} and were left out of the bins.",
+            nrows - num_placed,
+            nrows,
+            opts.coords.display()
+        );
+    }
+
+    let (mtx_rows, ncols) = mtx_utils::for_each_mtx_entry(&mtx, |r, c, v| {
+        for binning in binnings.iter_mut() {
+            binning.add(r, c, v);
+        }
+        Ok(())
+    })?;
+    if mtx_rows != nrows {
+        bail!(
+            "{} has {} rows, but {} lists {} barcodes",
+            mtx.display(),
+            mtx_rows,
+            rows.display(),
+            nrows
+        );
+    }
+
+    std::fs::create_dir_all(&opts.output)
+        .with_context(|| format!("could not create {}", opts.output.display()))?;
+    let mut summaries = Vec::with_capacity(binnings.len());
+    for binning in binnings {
+        let dir = opts.output.join(format!("bin_{:03}um", binning.size));
+        let summary = binning.write(&dir, &cols, ncols)?;
+        info!(
+            "wrote {} bins of {} µm to {}",
+            summary.num_bins,
+            summary.bin_size,
+            dir.display()
+        );
+        summaries.push(summary);
+    }
+
+    io::write_json_pretty_atomic(
+        &opts.output.join("simpleaf_spatial_bin_info.json"),
+        &json!({
+            "quant_dir": opts.quant_dir,
+            "coords": opts.coords,
+            "coord_scale": opts.coord_scale,
+            "num_barcodes": nrows,
+            "num_placed": num_placed,
+            "bins": summaries,
+        }),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sparse_bins_merge_repeated_columns() {
+        let mut bin = SparseBin::default();
+        for i in 0..200 {
+            bin.add(i % 3, 1.0);
+        }
+        assert!(bin.entries.len() < 70);
+        bin.merge();
+        assert_eq!(bin.entries, vec![(0, 67.0), (1, 67.0), (2, 66.0)]);
+    }
+
+    #[test]
+    fn barcodes_are_binned_at_each_size() {
+        let td = tempfile::tempdir().unwrap();
+        let quant = td.path().join("af_quant");
+        // 5 barcodes x 2 genes; the last barcode has no coordinates
+        mtx_utils::write_test_quant_mat(
+            &quant,
+            &["A", "B", "C", "D", "E"],
+            &["g1", "g2"],
+            &[
+                (1, 1, 1.0),
+                (2, 1, 2.0),
+                (2, 2, 1.0),
+                (3, 2, 4.0),
+                (4, 1, 3.0),
+                (5, 1, 9.0),
+            ],
+        );
+        // coordinates on the 2µm grid
+        let coords = td.path().join("barcode_coords.tsv");
+        fs::write(
+            &coords,
+            "barcode\tx\ty\nA-1\t0\t0\nB-1\t1\t0\nC-1\t2\t1\nD-1\t0\t3\nZ-1\t9\t9\n",
+        )
+        .unwrap();
+        let out = td.path().join("binned");
+
+        bin_spots(SpatialBinOpts {
+            quant_dir: quant.clone(),
+            coords: coords.clone(),
+            bin_sizes: vec![8, 4],
+            coord_scale: 2.0,
+            output: out.clone(),
+        })
+        .unwrap();
+
+        // 4µm bins: A and B share bin (0, 0), C is in (0, 1) and D in (1, 0)
+        let bin4 = out.join("bin_004um");
+        let (mtx4, rows4, _cols4) = mtx_utils::quant_mat_paths(&bin4);
+        assert_eq!(
+            fs::read_to_string(rows4).unwrap(),
+            "s_004um_00000_00000\ns_004um_00000_00001\ns_004um_00001_00000\n"
+        );
+        assert_eq!(
+            mtx_utils::read_mtx_dense(&mtx4).unwrap(),
+            vec![vec![3.0, 1.0], vec![0.0, 4.0], vec![3.0, 0.0]]
+        );
+        assert_eq!(
+            fs::read_to_string(bin4.join("bin_coords.tsv")).unwrap(),
+            "bin\tbin_row\tbin_col\tx_um\ty_um\tnum_barcodes\n\
+             s_004um_00000_00000\t0\t0\t2\t2\t2\n\
+             s_004um_00000_00001\t0\t1\t6\t2\t1\n\
+             s_004um_00001_00000\t1\t0\t2\t6\t1\n"
+        );
+
+        // 8µm bins: every placed barcode is in (0, 0)
+        let (mtx8, rows8, cols8) = mtx_utils::quant_mat_paths(&out.join("bin_008um"));
+        assert_eq!(fs::read_to_string(rows8).unwrap(), "s_008um_00000_00000\n");
+        assert_eq!(fs::read_to_string(cols8).unwrap(), "g1\ng2\n");
+        assert_eq!(
+            mtx_utils::read_mtx_dense(&mtx8).unwrap(),
+            vec![vec![6.0, 5.0]]
+        );
+
+        let info: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(out.join("simpleaf_spatial_bin_info.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(info["num_barcodes"], json!(5));
+        assert_eq!(info["num_placed"], json!(4));
+        assert_eq!(info["bins"][0]["bin_size"], json!(4));
+        assert_eq!(info["bins"][0]["nnz"], json!(4));
+
+        let err = bin_spots(SpatialBinOpts {
+            quant_dir: quant,
+            coords,
+            bin_sizes: vec![0],
+            coord_scale: 2.0,
+            output: out,
+        });
+        assert!(err.is_err());
+    }
+}
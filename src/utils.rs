@@ -1,7 +1,12 @@
 pub mod af_utils;
+pub mod bigwig;
+pub mod chem_registry;
 pub mod chem_utils;
 pub mod constants;
+pub mod download;
+pub mod integrity;
 pub mod jrsonnet_main;
+pub mod mtx_utils;
 pub mod probe_utils;
 pub mod prog_parsing_utils;
 pub mod prog_utils;
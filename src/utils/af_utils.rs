@@ -9,11 +9,13 @@ use std::io::BufReader;
 use std::path::{Path, PathBuf};
 
 use strum_macros::EnumIter;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info};
 
 use crate::atac::commands::AtacChemistry;
-use crate::utils::chem_utils::{CustomChemistry, ExpectedOri, get_single_custom_chem_from_file};
-use crate::utils::{self, prog_utils};
+use crate::core::io::FileLock;
+use crate::utils::chem_registry::ChemistryRegistry;
+use crate::utils::chem_utils::{CustomChemistry, ExpectedOri, ProtocolType};
+use crate::utils::{self, download, integrity, prog_utils};
 
 use super::chem_utils::{LOCAL_PL_PATH_KEY, QueryInRegistry, REMOTE_PL_URL_KEY};
 
@@ -191,6 +193,43 @@ impl Chemistry {
         }
     }
 
+    /// Resolve the chemistry passed to `simpleaf atac process`. This is either one of
+    /// the builtin 10x ATAC chemistries, or a registered chemistry (looked up through
+    /// the registry layers of `custom_chem_p`) whose `meta.protocol_type` is `atac`.
+    pub fn from_atac_str(custom_chem_p: &Path, chem_str: &str) -> Result<Chemistry> {
+        if let Ok(ac) = chem_str.parse::<AtacChemistry>() {
+            return Ok(Chemistry::Atac(ac));
+        }
+        let registry = ChemistryRegistry::layered(custom_chem_p);
+        let chem = registry.lookup(chem_str)?.map(|(chem, _)| chem);
+        if let Some(chem) = &chem {
+            if chem.name() != chem_str && chem.name().parse::<AtacChemistry>().is_ok() {
+                info!("{} is an alias of the chemistry {}", chem_str, chem.name());
+                return Chemistry::from_atac_str(custom_chem_p, chem.name());
+            }
+            chem.log_resolution(chem_str);
+        }
+        match chem {
+            Some(chem) if chem.protocol_type() == ProtocolType::Atac => {
+                info!(
+                    "custom ATAC chemistry {} maps to geometry {}",
+                    chem_str,
+                    chem.geometry()
+                );
+                Ok(Chemistry::Custom(Box::new(chem)))
+            }
+            Some(chem) => bail!(
+                "The chemistry {} is registered, but its protocol type is {:?} rather than atac. Only chemistries with \"protocol_type\": \"atac\" in their meta field can be used with `simpleaf atac process`.",
+                chem_str,
+                chem.protocol_type()
+            ),
+            None => bail!(
+                "The chemistry {} is neither a builtin ATAC chemistry (10x-v1, 10x-v2, 10x-multi) nor a registered chemistry. Please add it to the registry with the \"chemistry add\" command, setting \"protocol_type\": \"atac\" in its meta field.",
+                chem_str
+            ),
+        }
+    }
+
     pub fn from_str(
         index_type: &IndexType,
         custom_chem_p: &Path,
@@ -230,12 +269,26 @@ impl Chemistry {
                 // Third, we check if its a custom geometry string
                 if index_type.is_known_chem(s) {
                     Chemistry::Rna(RnaChemistry::Other(s.to_string()))
-                } else if let Some(chem) =
-                    get_single_custom_chem_from_file(custom_chem_p, chem_str)?
+                } else if let Some((chem, layer)) =
+                    ChemistryRegistry::layered(custom_chem_p).lookup(chem_str)?
                 {
+                    // an alias may name a builtin chemistry, so resolve the name it
+                    // aliases from scratch
+                    if chem.name() != s {
+                        info!("{} is an alias of the chemistry {}", s, chem.name());
+                        return Chemistry::from_str(index_type, custom_chem_p, chem.name());
+                    }
+                    chem.log_resolution(s);
+                    if chem.protocol_type() == ProtocolType::Atac {
+                        bail!(
+                            "The chemistry {} is registered as an ATAC chemistry; please process it with `simpleaf atac process`.",
+                            s
+                        );
+                    }
                     info!(
-                        "custom chemistry {} maps to geometry {}",
+                        "custom chemistry {} (from the {} registry) maps to geometry {}",
                         s,
+                        layer.name,
                         chem.geometry()
                     );
                     Chemistry::Custom(Box::new(chem))
@@ -425,16 +478,17 @@ pub fn add_chemistry_to_args_piscem(chem_str: &str, cmd: &mut std::process::Comm
 ///           like manually delete a file). In that case, they are responsible for fixing
 ///           it --- we should suggest they remove and re-add the chemistry
 pub fn get_permit_if_absent(af_home: &Path, chem: &Chemistry) -> Result<PermitListResult> {
-    // consult the chemistry file to see what the permit list for this should be
+    // consult the chemistry registries to see what the permit list for this should be
     let chem_registry_path = af_home.join(utils::constants::CHEMISTRIES_PATH);
-    let chem_registry =
-        parse_resource_json_file(&chem_registry_path, Some(utils::constants::CHEMISTRIES_URL))
-            .with_context(|| {
-                format!(
-                    "couldn't obtain and/or parse {}",
-                    chem_registry_path.display()
-                )
-            })?;
+    let chem_registry = ChemistryRegistry::layered(&chem_registry_path);
+    let reg_entry = chem_registry
+        .lookup_value(chem.registry_key())
+        .with_context(|| {
+            format!(
+                "couldn't obtain and/or parse {}",
+                chem_registry_path.display()
+            )
+        })?;
 
     // make sure the output directory exists
     let pdir = af_home.join("plist");
@@ -443,7 +497,7 @@ pub fn get_permit_if_absent(af_home: &Path, chem: &Chemistry) -> Result<PermitLi
     let expected_file_path;
     let expected_file_name;
     // we try to get the registry entry for this chemistry
-    if let Some(reg_val) = chem_registry.get(chem.registry_key()) {
+    if let Some((reg_layer, reg_val)) = reg_entry {
         if let Some(chem_obj) = reg_val.as_object() {
             // check if the resource has a local url
             match chem_obj.get(LOCAL_PL_PATH_KEY) {
@@ -460,8 +514,12 @@ pub fn get_permit_if_absent(af_home: &Path, chem: &Chemistry) -> Result<PermitLi
                 Some(Value::String(lpath)) => {
                     expected_file_name = PathBuf::from(lpath);
                     expected_file_path = pdir.join(&expected_file_name);
-                    if expected_file_path.is_file() {
-                        return Ok(PermitListResult::AlreadyPresent(expected_file_path));
+                    // the file may be cached by any of the registry layers
+                    if let Some(cached) = chem_registry.find_cached_file(lpath) {
+                        let remote = chem_obj.get(REMOTE_PL_URL_KEY).and_then(Value::as_str);
+                        let verified =
+                            integrity::ensure_intact(af_home, &cached, remote, "permit list")?;
+                        return Ok(PermitListResult::AlreadyPresent(verified));
                     } else {
                         info!(
                             "Expected {} but didn't find it, will try to download it using a remote url.",
@@ -491,28 +549,50 @@ permit list was added using a local-url and later (manually) removed. However,
 the chemistry registry should only be modified using the `chemistry` command.
 Please consider removing and re-adding this chemistry with a valid permit list.",
                         chem.as_str(),
-                        chem_registry_path.display(),
+                        reg_layer.registry.display(),
                         expected_file_path.display()
                     );
                     bail!("Expected permit list was absent, and no remote source was provided.");
                 }
                 Some(Value::String(rpath)) => {
-                    // download the file
-                    let hash =
-                        prog_utils::download_to_file_compute_hash(rpath, &expected_file_path)?;
-                    let expected_hash = expected_file_name
-                        .to_str()
-                        .ok_or(anyhow!("cannot convert expected filename to proper string"))?;
-                    if hash.to_string() != expected_hash {
-                        warn!(
-                            "The permit list file for {}, obtained from the provided remote url {}, does not match the expected hash {} (the observed hash was {})",
-                            chem.registry_key(),
-                            rpath,
-                            expected_hash,
-                            hash.to_string()
+                    // only one of the processes sharing ALEVIN_FRY_HOME downloads the
+                    // file; the others wait for it and then use it
+                    let lock = FileLock::for_file(&expected_file_path)?;
+                    let downloaded_by_other = expected_file_path.is_file();
+                    if downloaded_by_other {
+                        info!(
+                            "The permit list {} was downloaded by another process; Using it.",
+                            expected_file_path.display()
                         );
+                    } else {
+                        // download the file, checking it against its registered hash
+                        download::download(
+                            rpath,
+                            &expected_file_path,
+                            integrity::expected_hash(&expected_file_path),
+                        )?;
+                    }
+                    drop(lock);
+                    // a truncated download, or one that doesn't match the registered
+                    // hash, is downloaded again once, and otherwise fails
+                    let verified = integrity::ensure_intact(
+                        af_home,
+                        &expected_file_path,
+                        Some(rpath),
+                        "permit list",
+                    )
+                    .with_context(|| {
+                        format!(
+                            "The permit list of {} obtained from {} could not be verified",
+                            chem.registry_key(),
+                            rpath
+                        )
+                    })?;
+                    if downloaded_by_other {
+                        Ok(PermitListResult::AlreadyPresent(verified))
+                    } else {
+                        Ok(PermitListResult::DownloadSuccessful(verified))
                     }
-                    Ok(PermitListResult::DownloadSuccessful(expected_file_path))
                 }
                 _ => {
                     error!(
@@ -571,7 +651,7 @@ pub fn parse_resource_json_file(p: &Path, url: Option<&str>) -> Result<Value> {
     if !resource_exists {
         if let Some(dl_url) = url {
             // download the custom_chemistries.json file if needed
-            prog_utils::download_to_file(dl_url, p)?;
+            prog_utils::download_if_absent(dl_url, p)?;
         } else {
             bail!(
                 "could not find resource {}, and no remote url was provided",
@@ -596,5 +676,22 @@ pub fn parse_resource_json_file(p: &Path, url: Option<&str>) -> Result<Value> {
     })
 }
 
+/// Return the reverse complement of a nucleotide (barcode) sequence, given as a
+/// string or as bytes (e.g. read from a FASTQ file). Characters other than `A`,
+/// `C`, `G`, `T` (in either case) are mapped to `N`.
+pub fn reverse_complement(seq: impl AsRef<[u8]>) -> String {
+    seq.as_ref()
+        .iter()
+        .rev()
+        .map(|b| match b {
+            b'A' | b'a' => 'T',
+            b'C' | b'c' => 'G',
+            b'G' | b'g' => 'C',
+            b'T' | b't' => 'A',
+            _ => 'N',
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests;
@@ -126,3 +126,50 @@ fn test_custom_general_geometry_is_accepted_for_piscem() {
         other => panic!("expected custom chemistry, found {:?}", other),
     }
 }
+
+#[test]
+fn reverse_complement_handles_barcodes() {
+    assert_eq!(reverse_complement("ACGTTG"), "CAACGT");
+    assert_eq!(reverse_complement("acgN"), "NCGT");
+    assert_eq!(reverse_complement(""), "");
+    assert_eq!(reverse_complement(b"AACGN"), "NCGTT");
+}
+
+fn write_registry(dir: &Path, json: &str) -> PathBuf {
+    let p = dir.join("chemistries.json");
+    std::fs::write(&p, json).expect("failed to write registry");
+    p
+}
+
+#[test]
+fn atac_chemistry_resolves_builtins_and_registry_entries() {
+    let td = tempfile::tempdir().expect("failed to create tempdir");
+    let reg = write_registry(
+        td.path(),
+        r#"{
+          "sci-atac": {"geometry": "1{b[20]}2{r:}", "expected_ori": "fw",
+                       "meta": {"protocol_type": "atac", "barcode_ori": "forward"}},
+          "my-rna": {"geometry": "1{b[16]u[12]x:}2{r:}", "expected_ori": "fw"}
+        }"#,
+    );
+
+    for (s, ac) in [
+        ("10x-v1", AtacChemistry::TenxV11),
+        ("10xv2", AtacChemistry::TenxV2),
+        ("10x-arc-atac-v1", AtacChemistry::TenxMulti),
+    ] {
+        let c = Chemistry::from_atac_str(&reg, s).expect("builtin should resolve");
+        assert_eq!(c, Chemistry::Atac(ac));
+    }
+
+    match Chemistry::from_atac_str(&reg, "sci-atac").expect("ATAC entry should resolve") {
+        Chemistry::Custom(cc) => assert_eq!(cc.geometry(), "1{b[20]}2{r:}"),
+        other => panic!("expected custom chemistry, found {:?}", other),
+    }
+    assert!(Chemistry::from_atac_str(&reg, "my-rna").is_err());
+    assert!(Chemistry::from_atac_str(&reg, "flerb").is_err());
+
+    // ATAC chemistries are not accepted for RNA quantification
+    let piscem_idx = IndexType::Piscem(PathBuf::new());
+    assert!(Chemistry::from_str(&piscem_idx, &reg, "sci-atac").is_err());
+}
@@ -0,0 +1,748 @@
+//! A minimal writer for the UCSC bigWig format.
+//!
+//! The file is laid out as described in the bbi file format specification
+//! (Kent et al., 2010): a fixed header, one header per zoom level, a total
+//! summary, a B+ tree mapping chromosome names to ids, the zlib-compressed
+//! data blocks (bedGraph sections) with their R-tree index, and finally the
+//! data and index of each zoom level. Every offset is resolved while writing
+//! and the header is patched at the end, so a single pass over the data is
+//! all that's needed.
+
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const BPT_MAGIC: u32 = 0x78CA_8C91;
+const CIRTREE_MAGIC: u32 = 0x2468_ACE0;
+const BBI_VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const ZOOM_HEADER_SIZE: u64 = 24;
+const SUMMARY_SIZE: u64 = 40;
+/// maximum number of records in one compressed data block
+const ITEMS_PER_SLOT: usize = 1024;
+/// fan-out of the chromosome B+ tree and of the R-tree indices
+const BLOCK_SIZE: usize = 256;
+/// the most zoom levels that will be written
+const MAX_ZOOM_LEVELS: usize = 10;
+/// each zoom level summarizes this many times more bases than the previous one
+const ZOOM_FACTOR: u32 = 4;
+/// bedGraph section type
+const SECTION_BEDGRAPH: u8 = 1;
+
+/// A run of bases `[start, end)` sharing the same value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Interval {
+    pub start: u32,
+    pub end: u32,
+    pub value: f32,
+}
+
+/// Summary statistics over a set of bases.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Summary {
+    bases: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self {
+            bases: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            sum_squares: 0.0,
+        }
+    }
+}
+
+impl Summary {
+    fn add(&mut self, value: f64, bases: u64) {
+        self.bases += bases;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value * bases as f64;
+        self.sum_squares += value * value * bases as f64;
+    }
+}
+
+/// One summarized bucket of a zoom level.
+struct ZoomRecord {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    summary: Summary,
+}
+
+/// The genomic extent and file location of one data block.
+struct BlockEntry {
+    start_chrom: u32,
+    start_base: u32,
+    end_chrom: u32,
+    end_base: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// Tracks the current offset of the output, as `BufWriter` can't report it
+/// without flushing.
+struct CountingWriter<W: Write> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn zlib(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+/// Split `len` items into consecutive `(first, count)` groups of at most `size`.
+fn chunks(len: usize, size: usize) -> Vec<(usize, usize)> {
+    (0..len)
+        .step_by(size)
+        .map(|i| (i, size.min(len - i)))
+        .collect()
+}
+
+/// Group the nodes of each tree level under the nodes of the level above,
+/// until a single root remains. `levels[0]` holds the leaves, as ranges of
+/// `len` items; every later level holds ranges of the nodes below it.
+fn tree_levels(len: usize, block_size: usize) -> Vec<Vec<(usize, usize)>> {
+    let mut levels = vec![chunks(len, block_size)];
+    while levels.last().is_some_and(|l| l.len() > 1) {
+        let below = levels.last().map_or(0, |l| l.len());
+        levels.push(chunks(below, block_size));
+    }
+    levels
+}
+
+/// Write the chromosome B+ tree for `chroms` (already sorted by name, so that
+/// the position in the slice is the chromosome id).
+fn write_chrom_tree<W: Write>(
+    w: &mut CountingWriter<W>,
+    chroms: &[(String, u32)],
+) -> anyhow::Result<()> {
+    let key_size = chroms
+        .iter()
+        .map(|(n, _)| n.len())
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let block_size = chroms.len().clamp(1, BLOCK_SIZE);
+
+    w.write_all(&BPT_MAGIC.to_le_bytes())?;
+    w.write_all(&(block_size as u32).to_le_bytes())?;
+    w.write_all(&(key_size as u32).to_le_bytes())?;
+    w.write_all(&8u32.to_le_bytes())?;
+    w.write_all(&(chroms.len() as u64).to_le_bytes())?;
+    w.write_all(&0u64.to_le_bytes())?;
+
+    let key = |name: &str| {
+        let mut k = name.as_bytes().to_vec();
+        k.resize(key_size, 0);
+        k
+    };
+    let levels = tree_levels(chroms.len(), block_size);
+    // the first chromosome under each node, level by level
+    let mut firsts: Vec<Vec<usize>> = vec![levels[0].iter().map(|(s, _)| *s).collect()];
+    for li in 1..levels.len() {
+        let f = levels[li].iter().map(|(s, _)| firsts[li - 1][*s]).collect();
+        firsts.push(f);
+    }
+    // leaf and internal items are both a key plus 8 bytes
+    let node_size = |n: usize| (4 + n * (key_size + 8)) as u64;
+
+    // write from the root down; the children of a level start right after it
+    let mut level_start = w.pos;
+    for li in (0..levels.len()).rev() {
+        let this_size: u64 = levels[li].iter().map(|(_, n)| node_size(*n)).sum();
+        let mut child_offset = level_start + this_size;
+        for (s, n) in &levels[li] {
+            w.write_all(&[u8::from(li == 0), 0u8])?;
+            w.write_all(&(*n as u16).to_le_bytes())?;
+            for c in *s..*s + *n {
+                if li == 0 {
+                    let (name, size) = &chroms[c];
+                    w.write_all(&key(name))?;
+                    w.write_all(&(c as u32).to_le_bytes())?;
+                    w.write_all(&size.to_le_bytes())?;
+                } else {
+                    w.write_all(&key(&chroms[firsts[li - 1][c]].0))?;
+                    w.write_all(&child_offset.to_le_bytes())?;
+                    child_offset += node_size(levels[li - 1][c].1);
+                }
+            }
+        }
+        level_start = w.pos;
+    }
+    Ok(())
+}
+
+/// Write an R-tree (cirTree) index over `entries`, which must be sorted by
+/// genomic position. `end_file_offset` is the end of the indexed data.
+fn write_index<W: Write>(
+    w: &mut CountingWriter<W>,
+    entries: &[BlockEntry],
+    end_file_offset: u64,
+) -> anyhow::Result<()> {
+    let bounds = |es: &[BlockEntry]| -> (u32, u32, u32, u32) {
+        let Some(first) = es.first() else {
+            return (0, 0, 0, 0);
+        };
+        let (ec, eb) = es
+            .iter()
+            .map(|e| (e.end_chrom, e.end_base))
+            .max()
+            .unwrap_or((0, 0));
+        (first.start_chrom, first.start_base, ec, eb)
+    };
+    let (sc, sb, ec, eb) = bounds(entries);
+    w.write_all(&CIRTREE_MAGIC.to_le_bytes())?;
+    w.write_all(&(BLOCK_SIZE as u32).to_le_bytes())?;
+    w.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for v in [sc, sb, ec, eb] {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    w.write_all(&end_file_offset.to_le_bytes())?;
+    w.write_all(&(ITEMS_PER_SLOT as u32).to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+
+    if entries.is_empty() {
+        // an empty leaf so that readers find no blocks
+        w.write_all(&[1u8, 0u8])?;
+        w.write_all(&0u16.to_le_bytes())?;
+        return Ok(());
+    }
+
+    let levels = tree_levels(entries.len(), BLOCK_SIZE);
+    // the entry range spanned by every node, level by level
+    let mut spans: Vec<Vec<(usize, usize)>> =
+        vec![levels[0].iter().map(|(s, n)| (*s, *s + *n)).collect()];
+    for li in 1..levels.len() {
+        let below = &spans[li - 1];
+        let span = levels[li]
+            .iter()
+            .map(|(s, n)| (below[*s].0, below[*s + *n - 1].1))
+            .collect();
+        spans.push(span);
+    }
+    let node_size = |li: usize, n: usize| -> u64 {
+        if li == 0 {
+            4 + 32 * n as u64
+        } else {
+            4 + 24 * n as u64
+        }
+    };
+
+    let mut level_start = w.pos;
+    for li in (0..levels.len()).rev() {
+        if li == 0 {
+            for (s, n) in &levels[0] {
+                w.write_all(&[1u8, 0u8])?;
+                w.write_all(&(*n as u16).to_le_bytes())?;
+                for e in &entries[*s..*s + *n] {
+                    for v in [e.start_chrom, e.start_base, e.end_chrom, e.end_base] {
+                        w.write_all(&v.to_le_bytes())?;
+                    }
+                    w.write_all(&e.offset.to_le_bytes())?;
+                    w.write_all(&e.size.to_le_bytes())?;
+                }
+            }
+        } else {
+            let this_size: u64 = levels[li].iter().map(|(_, n)| node_size(li, *n)).sum();
+            let mut child_offset = level_start + this_size;
+            for (s, n) in &levels[li] {
+                w.write_all(&[0u8, 0u8])?;
+                w.write_all(&(*n as u16).to_le_bytes())?;
+                for c in *s..*s + *n {
+                    let (es, ee) = spans[li - 1][c];
+                    let (sc, sb, ec, eb) = bounds(&entries[es..ee]);
+                    for v in [sc, sb, ec, eb] {
+                        w.write_all(&v.to_le_bytes())?;
+                    }
+                    w.write_all(&child_offset.to_le_bytes())?;
+                    child_offset += node_size(li - 1, levels[li - 1][c].1);
+                }
+            }
+        }
+        level_start = w.pos;
+    }
+    Ok(())
+}
+
+/// Summarize `intervals` into buckets of `reduction` bases.
+fn zoom_records(
+    chroms: &[(String, u32)],
+    intervals: &HashMap<String, Vec<Interval>>,
+    reduction: u32,
+) -> Vec<ZoomRecord> {
+    let mut records = Vec::new();
+    for (chrom_id, (name, size)) in chroms.iter().enumerate() {
+        let Some(ivs) = intervals.get(name) else {
+            continue;
+        };
+        let mut current: Option<ZoomRecord> = None;
+        for iv in ivs {
+            let mut pos = iv.start;
+            while pos < iv.end {
+                let bucket_start = pos - pos % reduction;
+                let bucket_end = bucket_start.saturating_add(reduction).min(*size);
+                let seg_end = iv.end.min(bucket_end);
+                if current.as_ref().is_none_or(|r| r.start != bucket_start) {
+                    if let Some(r) = current.take() {
+                        records.push(r);
+                    }
+                    current = Some(ZoomRecord {
+                        chrom_id: chrom_id as u32,
+                        start: bucket_start,
+                        end: bucket_end,
+                        summary: Summary::default(),
+                    });
+                }
+                if let Some(r) = current.as_mut() {
+                    r.summary.add(iv.value as f64, (seg_end - pos) as u64);
+                }
+                if seg_end <= pos {
+                    break;
+                }
+                pos = seg_end;
+            }
+        }
+        if let Some(r) = current.take() {
+            records.push(r);
+        }
+    }
+    records
+}
+
+/// Write the records of one chromosome-sorted stream as compressed blocks,
+/// returning the index entries of the blocks and the largest uncompressed size.
+fn write_blocks<W: Write, T>(
+    w: &mut CountingWriter<W>,
+    items: &[T],
+    chrom_of: impl Fn(&T) -> u32,
+    extent: impl Fn(&T) -> (u32, u32),
+    encode: impl Fn(u32, &[T], &mut Vec<u8>),
+) -> anyhow::Result<(Vec<BlockEntry>, usize)> {
+    let mut entries = Vec::new();
+    let mut max_size = 0;
+    let mut i = 0;
+    while i < items.len() {
+        let chrom = chrom_of(&items[i]);
+        let mut j = i;
+        while j < items.len() && j - i < ITEMS_PER_SLOT && chrom_of(&items[j]) == chrom {
+            j += 1;
+        }
+        let block = &items[i..j];
+        let mut raw = Vec::new();
+        encode(chrom, block, &mut raw);
+        max_size = max_size.max(raw.len());
+        let compressed = zlib(&raw)?;
+        let offset = w.pos;
+        w.write_all(&compressed)?;
+        let end_base = block.iter().map(|it| extent(it).1).max().unwrap_or(0);
+        entries.push(BlockEntry {
+            start_chrom: chrom,
+            start_base: extent(&block[0]).0,
+            end_chrom: chrom,
+            end_base,
+            offset,
+            size: compressed.len() as u64,
+        });
+        i = j;
+    }
+    Ok((entries, max_size))
+}
+
+/// Write `intervals` (sorted, non-overlapping runs keyed by chromosome name) to
+/// the bigWig file `out`. `chrom_sizes` lists every chromosome of the assembly;
+/// intervals on chromosomes not listed there are an error. `base_reduction` is
+/// the number of bases summarized by each record of the first zoom level.
+pub(crate) fn write_bigwig(
+    out: &Path,
+    chrom_sizes: &[(String, u32)],
+    intervals: &HashMap<String, Vec<Interval>>,
+    base_reduction: u32,
+) -> anyhow::Result<()> {
+    if chrom_sizes.is_empty() {
+        bail!("at least one chromosome is needed to write a bigWig file");
+    }
+    let mut chroms = chrom_sizes.to_vec();
+    chroms.sort_by(|a, b| a.0.cmp(&b.0));
+    chroms.dedup_by(|a, b| a.0 == b.0);
+    let chrom_ids: HashMap<&str, u32> = chroms
+        .iter()
+        .enumerate()
+        .map(|(i, (n, _))| (n.as_str(), i as u32))
+        .collect();
+    for name in intervals.keys() {
+        if !chrom_ids.contains_key(name.as_str()) {
+            bail!("no size is known for chromosome {}", name);
+        }
+    }
+
+    // all data records, in chromosome id order
+    let mut records: Vec<(u32, Interval)> = Vec::new();
+    let mut total = Summary::default();
+    for (chrom_id, (name, _)) in chroms.iter().enumerate() {
+        if let Some(ivs) = intervals.get(name) {
+            for iv in ivs {
+                total.add(iv.value as f64, (iv.end - iv.start) as u64);
+                records.push((chrom_id as u32, *iv));
+            }
+        }
+    }
+    if total.bases == 0 {
+        total.min = 0.0;
+        total.max = 0.0;
+    }
+
+    // keep only zoom levels that at least halve the number of records of the
+    // previous level; coarser reductions are tried until the chromosomes fit
+    let mut zooms: Vec<(u32, Vec<ZoomRecord>)> = Vec::new();
+    let mut reduction = base_reduction.max(1);
+    let mut prev_count = records.len();
+    let max_chrom = chroms.iter().map(|(_, s)| *s).max().unwrap_or(0);
+    while zooms.len() < MAX_ZOOM_LEVELS && prev_count > 1 && reduction <= max_chrom {
+        let zr = zoom_records(&chroms, intervals, reduction);
+        if zr.len() * 2 <= prev_count {
+            prev_count = zr.len();
+            zooms.push((reduction, zr));
+        }
+        match reduction.checked_mul(ZOOM_FACTOR) {
+            Some(r) => reduction = r,
+            None => break,
+        }
+    }
+
+    let f = File::create(out).with_context(|| format!("could not create {}", out.display()))?;
+    let mut w = CountingWriter {
+        inner: BufWriter::new(f),
+        pos: 0,
+    };
+    let preamble = HEADER_SIZE + ZOOM_HEADER_SIZE * zooms.len() as u64 + SUMMARY_SIZE;
+    w.write_all(&vec![0u8; preamble as usize])?;
+
+    let chrom_tree_offset = w.pos;
+    write_chrom_tree(&mut w, &chroms)?;
+
+    let full_data_offset = w.pos;
+    w.write_all(&0u64.to_le_bytes())?; // block count, patched below
+    let (entries, mut max_block) = write_blocks(
+        &mut w,
+        &records,
+        |r| r.0,
+        |r| (r.1.start, r.1.end),
+        |chrom, block, buf| {
+            buf.extend_from_slice(&chrom.to_le_bytes());
+            buf.extend_from_slice(&block[0].1.start.to_le_bytes());
+            buf.extend_from_slice(&block[block.len() - 1].1.end.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes()); // item step
+            buf.extend_from_slice(&0u32.to_le_bytes()); // item span
+            buf.push(SECTION_BEDGRAPH);
+            buf.push(0);
+            buf.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            for (_, iv) in block {
+                buf.extend_from_slice(&iv.start.to_le_bytes());
+                buf.extend_from_slice(&iv.end.to_le_bytes());
+                buf.extend_from_slice(&iv.value.to_le_bytes());
+            }
+        },
+    )?;
+    let full_index_offset = w.pos;
+    write_index(&mut w, &entries, full_index_offset)?;
+
+    let mut zoom_headers = Vec::with_capacity(zooms.len());
+    for (reduction, zr) in &zooms {
+        let data_offset = w.pos;
+        w.write_all(&(zr.len() as u32).to_le_bytes())?;
+        let (zentries, zmax) = write_blocks(
+            &mut w,
+            zr,
+            |r| r.chrom_id,
+            |r| (r.start, r.end),
+            |_, block, buf| {
+                for r in block {
+                    buf.extend_from_slice(&r.chrom_id.to_le_bytes());
+                    buf.extend_from_slice(&r.start.to_le_bytes());
+                    buf.extend_from_slice(&r.end.to_le_bytes());
+                    buf.extend_from_slice(&(r.summary.bases as u32).to_le_bytes());
+                    for v in [
+                        r.summary.min,
+                        r.summary.max,
+                        r.summary.sum,
+                        r.summary.sum_squares,
+                    ] {
+                        buf.extend_from_slice(&(v as f32).to_le_bytes());
+                    }
+                }
+            },
+        )?;
+        max_block = max_block.max(zmax);
+        let index_offset = w.pos;
+        write_index(&mut w, &zentries, index_offset)?;
+        zoom_headers.push((*reduction, data_offset, index_offset));
+    }
+    // the file ends with the magic number, as with UCSC tools
+    w.write_all(&BIGWIG_MAGIC.to_le_bytes())?;
+    w.flush()?;
+
+    // now that every offset is known, fill in the header
+    let mut f = w
+        .inner
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("could not write {}", out.display()))?;
+    f.seek(SeekFrom::Start(0))?;
+    let mut h = Vec::with_capacity(preamble as usize);
+    h.extend_from_slice(&BIGWIG_MAGIC.to_le_bytes());
+    h.extend_from_slice(&BBI_VERSION.to_le_bytes());
+    h.extend_from_slice(&(zooms.len() as u16).to_le_bytes());
+    h.extend_from_slice(&chrom_tree_offset.to_le_bytes());
+    h.extend_from_slice(&full_data_offset.to_le_bytes());
+    h.extend_from_slice(&full_index_offset.to_le_bytes());
+    h.extend_from_slice(&0u16.to_le_bytes()); // field count
+    h.extend_from_slice(&0u16.to_le_bytes()); // defined field count
+    h.extend_from_slice(&0u64.to_le_bytes()); // autoSql offset
+    let summary_offset = HEADER_SIZE + ZOOM_HEADER_SIZE * zooms.len() as u64;
+    h.extend_from_slice(&summary_offset.to_le_bytes());
+    h.extend_from_slice(&(max_block as u32).to_le_bytes());
+    h.extend_from_slice(&0u64.to_le_bytes()); // extension offset
+    for (reduction, data_offset, index_offset) in &zoom_headers {
+        h.extend_from_slice(&reduction.to_le_bytes());
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h.extend_from_slice(&data_offset.to_le_bytes());
+        h.extend_from_slice(&index_offset.to_le_bytes());
+    }
+    h.extend_from_slice(&total.bases.to_le_bytes());
+    for v in [total.min, total.max, total.sum, total.sum_squares] {
+        h.extend_from_slice(&v.to_le_bytes());
+    }
+    f.write_all(&h)?;
+    f.seek(SeekFrom::Start(full_data_offset))?;
+    f.write_all(&(entries.len() as u64).to_le_bytes())?;
+    f.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn u16_at(b: &[u8], o: usize) -> u16 {
+        u16::from_le_bytes(b[o..o + 2].try_into().unwrap())
+    }
+    fn u32_at(b: &[u8], o: usize) -> u32 {
+        u32::from_le_bytes(b[o..o + 4].try_into().unwrap())
+    }
+    fn u64_at(b: &[u8], o: usize) -> u64 {
+        u64::from_le_bytes(b[o..o + 8].try_into().unwrap())
+    }
+
+    /// Follow the R-tree from its root down to the leaves, collecting
+    /// (offset, size) of every data block.
+    fn index_blocks(b: &[u8], index_offset: usize) -> Vec<(usize, usize)> {
+        assert_eq!(u32_at(b, index_offset), CIRTREE_MAGIC);
+        let mut out = Vec::new();
+        let mut stack = vec![index_offset + 48];
+        while let Some(node) = stack.pop() {
+            let is_leaf = b[node] == 1;
+            let n = u16_at(b, node + 2) as usize;
+            for i in 0..n {
+                if is_leaf {
+                    let item = node + 4 + 32 * i;
+                    out.push((u64_at(b, item + 16) as usize, u64_at(b, item + 24) as usize));
+                } else {
+                    let item = node + 4 + 24 * i;
+                    stack.push(u64_at(b, item + 16) as usize);
+                }
+            }
+        }
+        out.sort_unstable();
+        out
+    }
+
+    fn inflate(b: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        flate2::read::ZlibDecoder::new(b)
+            .read_to_end(&mut out)
+            .expect("valid zlib block");
+        out
+    }
+
+    /// Decode every bedGraph record of the file as (chrom id, start, end, value).
+    fn read_records(b: &[u8]) -> Vec<(u32, u32, u32, f32)> {
+        let index_offset = u64_at(b, 24) as usize;
+        let mut records = Vec::new();
+        for (off, size) in index_blocks(b, index_offset) {
+            let raw = inflate(&b[off..off + size]);
+            let chrom = u32_at(&raw, 0);
+            assert_eq!(raw[20], SECTION_BEDGRAPH);
+            let n = u16_at(&raw, 22) as usize;
+            for i in 0..n {
+                let o = 24 + 12 * i;
+                let v = f32::from_le_bytes(raw[o + 8..o + 12].try_into().unwrap());
+                records.push((chrom, u32_at(&raw, o), u32_at(&raw, o + 4), v));
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn written_file_round_trips() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let out = td.path().join("t.bw");
+        let chroms = vec![("chr2".to_string(), 50_000), ("chr1".to_string(), 100_000)];
+        let mut intervals = HashMap::new();
+        // enough records on chr1 to span several blocks
+        let chr1: Vec<Interval> = (0..3000)
+            .map(|i| Interval {
+                start: i * 20,
+                end: i * 20 + 10,
+                value: (i % 7) as f32,
+            })
+            .collect();
+        intervals.insert("chr1".to_string(), chr1.clone());
+        intervals.insert(
+            "chr2".to_string(),
+            vec![Interval {
+                start: 5,
+                end: 15,
+                value: 2.5,
+            }],
+        );
+        write_bigwig(&out, &chroms, &intervals, 40).expect("write bigwig");
+
+        let b = std::fs::read(&out).expect("read bigwig");
+        assert_eq!(u32_at(&b, 0), BIGWIG_MAGIC);
+        assert_eq!(u32_at(&b, b.len() - 4), BIGWIG_MAGIC);
+        assert!(u16_at(&b, 6) > 0, "expected zoom levels");
+
+        // chromosome tree: a single leaf, sorted by name
+        let ct = u64_at(&b, 8) as usize;
+        assert_eq!(u32_at(&b, ct), BPT_MAGIC);
+        let key_size = u32_at(&b, ct + 8) as usize;
+        assert_eq!(b[ct + 32], 1);
+        assert_eq!(u16_at(&b, ct + 34), 2);
+        let first = ct + 36;
+        assert_eq!(&b[first..first + key_size], b"chr1");
+        assert_eq!(u32_at(&b, first + key_size + 4), 100_000);
+
+        // the data block count and every record
+        assert_eq!(u64_at(&b, u64_at(&b, 16) as usize), 4);
+        let records = read_records(&b);
+        assert_eq!(records.len(), 3001);
+        assert_eq!(records[0], (0, 0, 10, 0.0));
+        assert_eq!(records[2999], (0, 59_980, 59_990, (2999 % 7) as f32));
+        assert_eq!(records[3000], (1, 5, 15, 2.5));
+
+        // the total summary
+        let summary = u64_at(&b, 44) as usize;
+        let bases = u64_at(&b, summary);
+        assert_eq!(bases, 3000 * 10 + 10);
+    }
+
+    #[test]
+    fn zoom_records_summarize_buckets() {
+        let chroms = vec![("c".to_string(), 250)];
+        let mut intervals = HashMap::new();
+        intervals.insert(
+            "c".to_string(),
+            vec![
+                Interval {
+                    start: 90,
+                    end: 110,
+                    value: 1.0,
+                },
+                Interval {
+                    start: 200,
+                    end: 250,
+                    value: 3.0,
+                },
+            ],
+        );
+        let zr = zoom_records(&chroms, &intervals, 100);
+        let got: Vec<(u32, u32, u64, f64)> = zr
+            .iter()
+            .map(|r| (r.start, r.end, r.summary.bases, r.summary.sum))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (0, 100, 10, 10.0),
+                (100, 200, 10, 10.0),
+                (200, 250, 50, 150.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn large_trees_have_multiple_levels() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let out = td.path().join("t.bw");
+        // more chromosomes than fit in one B+ tree node, and more data blocks
+        // than fit in one R-tree node
+        let chroms: Vec<(String, u32)> = (0..300)
+            .map(|i| (format!("c{:03}", i), 1_000_000))
+            .collect();
+        let mut intervals = HashMap::new();
+        let n = (BLOCK_SIZE + 10) * ITEMS_PER_SLOT;
+        intervals.insert(
+            "c000".to_string(),
+            (0..n as u32)
+                .map(|i| Interval {
+                    start: i * 2,
+                    end: i * 2 + 1,
+                    value: 1.0,
+                })
+                .collect::<Vec<_>>(),
+        );
+        intervals.insert(
+            "c299".to_string(),
+            vec![Interval {
+                start: 0,
+                end: 1,
+                value: 4.0,
+            }],
+        );
+        write_bigwig(&out, &chroms, &intervals, 10).expect("write bigwig");
+        let b = std::fs::read(&out).expect("read bigwig");
+
+        // the B+ tree root is an internal node whose second child holds c256..
+        let ct = u64_at(&b, 8) as usize;
+        let key_size = u32_at(&b, ct + 8) as usize;
+        assert_eq!(b[ct + 32], 0);
+        assert_eq!(u16_at(&b, ct + 34), 2);
+        let second = ct + 36 + (key_size + 8);
+        assert_eq!(&b[second..second + key_size], b"c256");
+        let child = u64_at(&b, second + key_size) as usize;
+        assert_eq!(b[child], 1);
+        assert_eq!(u16_at(&b, child + 2), 44);
+        assert_eq!(&b[child + 4..child + 4 + key_size], b"c256");
+        assert_eq!(u32_at(&b, child + 4 + key_size), 256);
+
+        let records = read_records(&b);
+        assert_eq!(records.len(), n + 1);
+        assert_eq!(records[n], (299, 0, 1, 4.0));
+    }
+}
@@ -0,0 +1,355 @@
+//! Chemistry registries layered by precedence.
+//!
+//! The user registry, `ALEVIN_FRY_HOME/chemistries.json`, always comes first, and is
+//! the only one `simpleaf` writes to. It is followed by the read-only registries
+//! listed (`:`-separated, by decreasing precedence) in `SIMPLEAF_CHEMISTRY_PATH`, e.g.
+//! a registry published by a core facility on shared storage. Each of these is either
+//! a directory holding a `chemistries.json` and, optionally, the `plist` directory of
+//! its cached files, or the path of the registry file itself.
+
+use crate::utils::af_utils::parse_resource_json_file;
+use crate::utils::chem_utils::CustomChemistry;
+use crate::utils::constants::{CHEMISTRIES_PATH, CHEMISTRIES_URL, CHEMISTRY_PATH_VAR};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// The name of the user layer.
+pub const USER_LAYER: &str = "user";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryLayer {
+    /// `user` for the user registry, or the path listed in `SIMPLEAF_CHEMISTRY_PATH`.
+    pub name: String,
+    pub registry: PathBuf,
+    /// where the files of the registry entries are cached.
+    pub plist_dir: PathBuf,
+}
+
+impl RegistryLayer {
+    pub fn is_user(&self) -> bool {
+        self.name == USER_LAYER
+    }
+
+    /// The registry entries of this layer, if its registry exists. With `fetch_upstream`,
+    /// an absent user registry is downloaded from the upstream repository.
+    fn entries(&self, fetch_upstream: bool) -> Result<Option<serde_json::Map<String, Value>>> {
+        let v = if self.is_user() && fetch_upstream {
+            parse_resource_json_file(&self.registry, Some(CHEMISTRIES_URL))?
+        } else if self.registry.is_file() {
+            parse_resource_json_file(&self.registry, None)?
+        } else {
+            return Ok(None);
+        };
+        match v {
+            Value::Object(entries) => Ok(Some(entries)),
+            _ => anyhow::bail!(
+                "The chemistry registry {} is not a JSON object.",
+                self.registry.display()
+            ),
+        }
+    }
+}
+
+/// The ordered layers of chemistry registries, by decreasing precedence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChemistryRegistry {
+    layers: Vec<RegistryLayer>,
+}
+
+impl ChemistryRegistry {
+    /// The user registry `user_registry` (i.e. `ALEVIN_FRY_HOME/chemistries.json`),
+    /// followed by the registries listed in `SIMPLEAF_CHEMISTRY_PATH`.
+    pub fn layered(user_registry: &Path) -> ChemistryRegistry {
+        ChemistryRegistry::with_chemistry_path(
+            user_registry,
+            std::env::var_os(CHEMISTRY_PATH_VAR).as_deref(),
+        )
+    }
+
+    /// The user registry `user_registry`, followed by the registries listed in the
+    /// `:`-separated `chemistry_path`.
+    pub fn with_chemistry_path(
+        user_registry: &Path,
+        chemistry_path: Option<&OsStr>,
+    ) -> ChemistryRegistry {
+        let user_dir = user_registry.parent().unwrap_or(Path::new("."));
+        let mut layers = vec![RegistryLayer {
+            name: String::from(USER_LAYER),
+            registry: user_registry.to_path_buf(),
+            plist_dir: user_dir.join("plist"),
+        }];
+        for p in chemistry_path
+            .map(std::env::split_paths)
+            .into_iter()
+            .flatten()
+        {
+            if p.as_os_str().is_empty() {
+                continue;
+            }
+            let (registry, dir) = if p.is_dir() {
+                (p.join(CHEMISTRIES_PATH), p.clone())
+            } else {
+                let dir = p.parent().unwrap_or(Path::new(".")).to_path_buf();
+                (p.clone(), dir)
+            };
+            if !registry.is_file() {
+                warn!(
+                    "The chemistry registry {} listed in {} does not exist; Ignoring it.",
+                    registry.display(),
+                    CHEMISTRY_PATH_VAR
+                );
+                continue;
+            }
+            if layers.iter().any(|l| l.registry == registry) {
+                continue;
+            }
+            layers.push(RegistryLayer {
+                name: p.display().to_string(),
+                registry,
+                plist_dir: dir.join("plist"),
+            });
+        }
+        ChemistryRegistry { layers }
+    }
+
+    /// Whether to download the user registry if it is absent: unless other registries
+    /// are layered under it (e.g. on machines without internet access), a missing user
+    /// registry is obtained from the upstream repository, as when there were no layers.
+    fn fetch_upstream(&self) -> bool {
+        self.layers.len() == 1
+    }
+
+    pub fn layers(&self) -> &[RegistryLayer] {
+        &self.layers
+    }
+
+    /// Whether any of the registries exists.
+    pub fn exists(&self) -> bool {
+        self.layers.iter().any(|l| l.registry.is_file())
+    }
+
+    /// The registry entry of `key` in the layer of highest precedence that has it,
+    /// along with that layer.
+    pub fn lookup_value(&self, key: &str) -> Result<Option<(&RegistryLayer, Value)>> {
+        for layer in &self.layers {
+            let entries = layer
+                .entries(self.fetch_upstream())
+                .with_context(|| format!("couldn't parse {}", layer.registry.display()))?;
+            if let Some(v) = entries.and_then(|mut e| e.remove(key)) {
+                return Ok(Some((layer, v)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The chemistry that `alias` is an alias of, in the layer of highest precedence
+    /// listing it among the `aliases` of an entry.
+    pub fn resolve_alias(&self, alias: &str) -> Result<Option<String>> {
+        for layer in &self.layers {
+            let entries = layer
+                .entries(self.fetch_upstream())
+                .with_context(|| format!("couldn't parse {}", layer.registry.display()))?;
+            for (k, v) in entries.into_iter().flatten() {
+                let aliases = v.get("aliases").and_then(Value::as_array);
+                if aliases.is_some_and(|a| a.iter().any(|x| x.as_str() == Some(alias))) {
+                    return Ok(Some(k));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The registered chemistry `key`, resolved through the layers, along with the
+    /// layer that supplied it. A `key` that is not the name of a registered chemistry
+    /// is looked up as an alias, in which case the chemistry is named as registered.
+    pub fn lookup(&self, key: &str) -> Result<Option<(CustomChemistry, &RegistryLayer)>> {
+        let mut found = self.lookup_value(key)?.map(|f| (key.to_string(), f));
+        if found.is_none()
+            && let Some(canonical) = self.resolve_alias(key)?
+        {
+            found = self.lookup_value(&canonical)?.map(|f| (canonical, f));
+        }
+        let Some((key, (layer, v))) = found else {
+            return Ok(None);
+        };
+        let mut chem: CustomChemistry = serde_json::from_value(v).with_context(|| {
+            format!(
+                "The entry of chemistry {} in {} is invalid",
+                key,
+                layer.registry.display()
+            )
+        })?;
+        chem.name = key;
+        Ok(Some((chem, layer)))
+    }
+
+    /// Every registered chemistry, resolved through the layers, along with the layer
+    /// that supplied it.
+    pub fn entries(&self) -> Result<BTreeMap<String, (CustomChemistry, &RegistryLayer)>> {
+        let mut resolved = BTreeMap::new();
+        for layer in &self.layers {
+            let Some(entries) = layer
+                .entries(self.fetch_upstream())
+                .with_context(|| format!("couldn't parse {}", layer.registry.display()))?
+            else {
+                continue;
+            };
+            for (k, v) in entries {
+                if resolved.contains_key(&k) {
+                    continue;
+                }
+                let mut chem: CustomChemistry = serde_json::from_value(v).with_context(|| {
+                    format!(
+                        "The entry of chemistry {} in {} is invalid",
+                        k,
+                        layer.registry.display()
+                    )
+                })?;
+                chem.name = k.clone();
+                resolved.insert(k, (chem, layer));
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// The cached file `name` in the `plist` directory of the first layer that has it.
+    /// Cached files are named after their content hash, so the copy of any layer will do.
+    pub fn find_cached_file(&self, name: &str) -> Option<PathBuf> {
+        self.layers
+            .iter()
+            .map(|l| l.plist_dir.join(name))
+            .find(|p| p.is_file())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::ffi::OsString;
+    use std::fs;
+
+    fn write_registry(dir: &Path, v: Value) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(CHEMISTRIES_PATH), v.to_string()).unwrap();
+    }
+
+    #[test]
+    fn lookups_resolve_through_the_layers_by_precedence() {
+        let td = tempfile::tempdir().unwrap();
+        let (user, team, system) = (
+            td.path().join("user"),
+            td.path().join("team"),
+            td.path().join("system"),
+        );
+        write_registry(
+            &user,
+            json!({ "mine": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" } }),
+        );
+        write_registry(
+            &team,
+            json!({
+                "shared": { "geometry": "1{b[16]u[10]x:}2{r:}", "version": "0.2.0", "plist_name": "pl" },
+                "mine": { "geometry": "1{b[12]u[8]x:}2{r:}", "version": "0.9.0" }
+            }),
+        );
+        write_registry(
+            &system,
+            json!({ "shared": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0" } }),
+        );
+        fs::create_dir_all(team.join("plist")).unwrap();
+        fs::write(team.join("plist").join("pl"), "AAAA\n").unwrap();
+
+        let chem_path = std::env::join_paths([
+            team.clone(),
+            td.path().join("missing"),
+            system.join(CHEMISTRIES_PATH),
+        ])
+        .unwrap();
+        let registry = ChemistryRegistry::with_chemistry_path(
+            &user.join(CHEMISTRIES_PATH),
+            Some(chem_path.as_os_str()),
+        );
+        assert_eq!(
+            registry
+                .layers()
+                .iter()
+                .map(|l| l.registry.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                user.join(CHEMISTRIES_PATH),
+                team.join(CHEMISTRIES_PATH),
+                system.join(CHEMISTRIES_PATH)
+            ]
+        );
+
+        let (mine, layer) = registry.lookup("mine").unwrap().unwrap();
+        assert_eq!(mine.version(), "0.1.0");
+        assert!(layer.is_user());
+        let (shared, layer) = registry.lookup("shared").unwrap().unwrap();
+        assert_eq!(shared.version(), "0.2.0");
+        assert_eq!(layer.name, team.display().to_string());
+        assert!(registry.lookup("unknown").unwrap().is_none());
+
+        let entries = registry.entries().unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["mine", "shared"]);
+        assert_eq!(
+            registry.find_cached_file("pl"),
+            Some(team.join("plist").join("pl"))
+        );
+
+        let user_only =
+            ChemistryRegistry::with_chemistry_path(&user.join(CHEMISTRIES_PATH), None::<&OsStr>);
+        assert_eq!(user_only.layers().len(), 1);
+        let empty = OsString::new();
+        let user_only = ChemistryRegistry::with_chemistry_path(
+            &user.join(CHEMISTRIES_PATH),
+            Some(empty.as_os_str()),
+        );
+        assert_eq!(user_only.layers().len(), 1);
+    }
+
+    #[test]
+    fn aliases_resolve_to_the_registered_chemistry() {
+        let td = tempfile::tempdir().unwrap();
+        let (user, team) = (td.path().join("user"), td.path().join("team"));
+        write_registry(
+            &user,
+            json!({
+                "10xv3": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0", "aliases": ["chromium_v3"] },
+                "old_flex": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0", "aliases": ["flex"] }
+            }),
+        );
+        write_registry(
+            &team,
+            json!({
+                "flex": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.2.0" },
+                "team_v3": { "geometry": "1{b[16]u[12]x:}2{r:}", "version": "0.1.0", "aliases": ["chromium_v3", "tv3"] }
+            }),
+        );
+        let chem_path = std::env::join_paths([team.clone()]).unwrap();
+        let registry = ChemistryRegistry::with_chemistry_path(
+            &user.join(CHEMISTRIES_PATH),
+            Some(chem_path.as_os_str()),
+        );
+
+        let (chem, layer) = registry.lookup("chromium_v3").unwrap().unwrap();
+        assert_eq!(chem.name(), "10xv3");
+        assert!(layer.is_user());
+        let (chem, _) = registry.lookup("tv3").unwrap().unwrap();
+        assert_eq!(chem.name(), "team_v3");
+        // a registered name takes precedence over an alias of another chemistry
+        let (chem, _) = registry.lookup("flex").unwrap().unwrap();
+        assert_eq!(chem.name(), "flex");
+        assert_eq!(
+            registry.resolve_alias("chromium_v3").unwrap().as_deref(),
+            Some("10xv3")
+        );
+        assert!(registry.lookup("chromium").unwrap().is_none());
+    }
+}
@@ -9,6 +9,7 @@ use std::io::Read;
 use std::path::Path;
 use strum::EnumIter;
 use strum::IntoEnumIterator;
+use tracing::{info, warn};
 
 // TODO: Change to main repo when we are ready
 
@@ -19,6 +20,7 @@ static EXPECTED_ORI_KEY: &str = "expected_ori";
 
 pub(crate) static LOCAL_PL_PATH_KEY: &str = "plist_name";
 pub(crate) static REMOTE_PL_URL_KEY: &str = "remote_url";
+pub(crate) static TRANSLATION_LIST_KEY: &str = "translation_list";
 
 pub trait QueryInRegistry {
     fn registry_key(&self) -> &str;
@@ -161,6 +163,32 @@ pub struct ProbeSetInfo {
     pub remote_url: Option<String>,
 }
 
+/// Info for fetching the cell barcode translation list of a chemistry whose
+/// libraries use different cell barcode sets (e.g. the feature barcoding and
+/// gene expression libraries of 10x 3' v3). The list has two columns, the
+/// barcode to translate and the barcode it translates to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TranslationListInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plist_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_url: Option<String>,
+}
+
+/// Marks a chemistry that should no longer be used, e.g. one that was renamed or
+/// withdrawn upstream. It stays registered, but using it warns with the replacement.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeprecationInfo {
+    /// The version of the chemistry (or of simpleaf) from which it is deprecated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    /// The name of the chemistry to use instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
 /// A CustomChemistry is a description of a chemistry that is not
 /// covered under the different built-in chemistries.  It defines the
 /// relevant information about how a chemistry should be defined including
@@ -186,6 +214,14 @@ pub struct CustomChemistry {
     /// Probe sets keyed by organism (for Flex protocols)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub probe_sets: Option<HashMap<String, ProbeSetInfo>>,
+    /// Cell barcode translation list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub translation_list: Option<TranslationListInfo>,
+    /// Other names this chemistry can be referred to by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deprecated: Option<DeprecationInfo>,
 }
 
 /// The key to use to query a custom chemistry
@@ -209,6 +245,9 @@ impl CustomChemistry {
             meta: None,
             sample_bc_list: None,
             probe_sets: None,
+            translation_list: None,
+            aliases: Vec::new(),
+            deprecated: None,
         })
     }
     pub fn geometry(&self) -> &str {
@@ -243,6 +282,23 @@ impl CustomChemistry {
         &self.meta
     }
 
+    /// The names of all of the files of this chemistry that are cached in
+    /// `ALEVIN_FRY_HOME/plist`: its permit list, sample barcode list, probe sets and
+    /// translation list.
+    pub fn plist_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.plist_name.iter().map(String::as_str).collect();
+        if let Some(sbc) = &self.sample_bc_list {
+            names.extend(sbc.plist_name.as_deref());
+        }
+        if let Some(probe_sets) = &self.probe_sets {
+            names.extend(probe_sets.values().filter_map(|p| p.plist_name.as_deref()));
+        }
+        if let Some(tl) = &self.translation_list {
+            names.extend(tl.plist_name.as_deref());
+        }
+        names
+    }
+
     /// Get the protocol type from meta.protocol_type, defaulting to StandardRna.
     pub fn protocol_type(&self) -> ProtocolType {
         self.meta
@@ -256,6 +312,28 @@ impl CustomChemistry {
     pub fn is_flex_gex(&self) -> bool {
         self.protocol_type() == ProtocolType::FlexGex
     }
+
+    /// Report how the name `requested` resolved to this chemistry: that it is an alias
+    /// of it, and that the chemistry is deprecated, along with its replacement.
+    pub fn log_resolution(&self, requested: &str) {
+        if requested != self.name() {
+            info!("{} is an alias of the chemistry {}", requested, self.name());
+        }
+        let Some(dep) = &self.deprecated else {
+            return;
+        };
+        let mut msg = format!("The chemistry {} is deprecated", self.name());
+        if let Some(since) = &dep.since {
+            msg.push_str(&format!(" since {}", since));
+        }
+        if let Some(replacement) = &dep.replaced_by {
+            msg.push_str(&format!("; please use {} instead", replacement));
+        }
+        if let Some(message) = &dep.message {
+            msg.push_str(&format!(" ({})", message));
+        }
+        warn!("{}.", msg);
+    }
 }
 
 impl fmt::Display for CustomChemistry {
@@ -270,6 +348,62 @@ impl fmt::Display for CustomChemistry {
             writeln!(f, "{}\t: {}", REMOTE_PL_URL_KEY, remote_pl_url)?;
         }
 
+        if let Some(sbc) = &self.sample_bc_list {
+            writeln!(
+                f,
+                "sample_bc_list\t: {}",
+                sbc.plist_name.as_deref().unwrap_or("-")
+            )?;
+            if let Some(url) = &sbc.remote_url {
+                writeln!(f, "  remote_url\t: {}", url)?;
+            }
+            if let Some(ori) = &sbc.sample_bc_ori {
+                writeln!(f, "  sample_bc_ori\t: {}", ori)?;
+            }
+        }
+        if let Some(probe_sets) = &self.probe_sets {
+            let mut organisms: Vec<&String> = probe_sets.keys().collect();
+            organisms.sort();
+            for organism in organisms {
+                let ps = &probe_sets[organism];
+                writeln!(
+                    f,
+                    "probe_set ({})\t: {} ({})",
+                    organism,
+                    ps.name,
+                    ps.plist_name.as_deref().unwrap_or("-")
+                )?;
+            }
+        }
+        if let Some(tl) = &self.translation_list {
+            writeln!(
+                f,
+                "{}\t: {}",
+                TRANSLATION_LIST_KEY,
+                tl.plist_name.as_deref().unwrap_or("-")
+            )?;
+            if let Some(url) = &tl.remote_url {
+                writeln!(f, "  remote_url\t: {}", url)?;
+            }
+        }
+
+        if !self.aliases.is_empty() {
+            writeln!(f, "aliases\t: {}", self.aliases.join(", "))?;
+        }
+        if let Some(dep) = &self.deprecated {
+            writeln!(
+                f,
+                "deprecated\t: since {}",
+                dep.since.as_deref().unwrap_or("-")
+            )?;
+            if let Some(replacement) = &dep.replaced_by {
+                writeln!(f, "  replaced_by\t: {}", replacement)?;
+            }
+            if let Some(message) = &dep.message {
+                writeln!(f, "  message\t: {}", message)?;
+            }
+        }
+
         if let Some(serde_json::Value::Object(meta)) = self.meta()
             && !meta.is_empty()
         {
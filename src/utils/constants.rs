@@ -6,6 +6,16 @@
 pub(crate) static CUSTOM_CHEMISTRIES_PATH: &str = "custom_chemistries.json";
 
 pub(crate) static CHEMISTRIES_PATH: &str = "chemistries.json";
+/// The history of the changes of the chemistry registry, next to it in ALEVIN_FRY_HOME.
+pub(crate) static CHEMISTRIES_HISTORY_PATH: &str = "chemistries.history.jsonl";
+pub(crate) static CHEMISTRIES_LOCK_PATH: &str = ".chemistries.lock";
+pub(crate) static VERIFIED_FILES_PATH: &str = ".verified_files.json";
+pub(crate) static URL_REWRITES_VAR: &str = "SIMPLEAF_URL_REWRITES";
+pub(crate) static DOWNLOAD_MIRROR_VAR: &str = "SIMPLEAF_DOWNLOAD_MIRROR";
+pub(crate) static DOWNLOAD_RETRIES_VAR: &str = "SIMPLEAF_DOWNLOAD_RETRIES";
+/// The environment variable listing the read-only chemistry registries layered under
+/// the user registry (see `utils::chem_registry`).
+pub(crate) static CHEMISTRY_PATH_VAR: &str = "SIMPLEAF_CHEMISTRY_PATH";
 pub(crate) static CHEMISTRIES_URL: &str =
     "https://raw.githubusercontent.com/COMBINE-lab/simpleaf/dev/resources/chemistries.json";
 
@@ -0,0 +1,651 @@
+//! Downloads of remote resources (chemistry registries, permit lists and other cached
+//! files, the protocol estuary).
+//!
+//! A download
+//! * fails on HTTP error statuses, after retrying (with exponential backoff) those
+//!   that may be transient, as well as connection failures and timeouts;
+//! * is written to a hidden `.<name>.part` file next to its destination, from which an
+//!   interrupted download is resumed with a `Range` request, and which is only renamed
+//!   to the destination once complete (and, if a hash is expected, verified);
+//! * is only resumed if the partial download is known to be of the same version of the
+//!   remote file: its ETag or Last-Modified date (kept in `.<name>.part.validator`) is
+//!   sent as `If-Range`, so that the server sends the whole file again if it changed.
+//!   Without either, a partial download is only resumed if a hash is expected, and
+//!   otherwise started over;
+//! * logs its progress for large files;
+//! * goes through the proxy set in `ALL_PROXY`, `HTTPS_PROXY` or `HTTP_PROXY`, except
+//!   for the hosts listed in `NO_PROXY`.
+//!
+//! URLs can be redirected, e.g. to an internal mirror or a local copy of the files, by
+//! listing URL prefixes and their replacements in the JSON file named by
+//! `SIMPLEAF_URL_REWRITES`, or by setting `SIMPLEAF_DOWNLOAD_MIRROR` to a prefix under
+//! which the URLs are mirrored by host and path. Both accept `file://` URLs.
+
+use crate::core::io::FileLock;
+use crate::utils::constants::{DOWNLOAD_MIRROR_VAR, DOWNLOAD_RETRIES_VAR, URL_REWRITES_VAR};
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// The number of times a failed download is retried by default.
+const DEFAULT_RETRIES: u32 = 4;
+/// The delay before the first retry, doubled for every further one.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Downloads at least this large log their progress.
+const PROGRESS_MIN_BYTES: u64 = 16 * 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The redirections applied to URLs before they are downloaded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UrlRewrites {
+    /// URL prefixes and their replacements, longest prefix first.
+    prefixes: Vec<(String, String)>,
+    /// the prefix under which URLs not matching any of `prefixes` are mirrored.
+    mirror: Option<String>,
+}
+
+impl UrlRewrites {
+    /// The rewrites configured by `SIMPLEAF_URL_REWRITES` and `SIMPLEAF_DOWNLOAD_MIRROR`.
+    pub fn from_env() -> Result<UrlRewrites> {
+        let prefixes = match std::env::var_os(URL_REWRITES_VAR) {
+            Some(p) if !p.is_empty() => {
+                let p = PathBuf::from(p);
+                let content = fs::read_to_string(&p).with_context(|| {
+                    format!(
+                        "Could not read the URL rewrites {} (set by {})",
+                        p.display(),
+                        URL_REWRITES_VAR
+                    )
+                })?;
+                serde_json::from_str::<BTreeMap<String, String>>(&content).with_context(|| {
+                    format!(
+                        "The URL rewrites {} (set by {}) should be a JSON object mapping URL prefixes to their replacements",
+                        p.display(),
+                        URL_REWRITES_VAR
+                    )
+                })?
+            }
+            _ => BTreeMap::new(),
+        };
+        let mirror = std::env::var(DOWNLOAD_MIRROR_VAR)
+            .ok()
+            .filter(|m| !m.is_empty());
+        Ok(UrlRewrites::new(prefixes, mirror))
+    }
+
+    pub fn new(prefixes: BTreeMap<String, String>, mirror: Option<String>) -> UrlRewrites {
+        let mut prefixes = prefixes.into_iter().collect::<Vec<_>>();
+        prefixes.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        UrlRewrites { prefixes, mirror }
+    }
+
+    /// The URL `url` is downloaded from: with the replacement of the longest matching
+    /// prefix, or else under the mirror prefix, if any.
+    pub fn rewrite(&self, url: &str) -> String {
+        if let Some((from, to)) = self.prefixes.iter().find(|(from, _)| url.starts_with(from)) {
+            return format!("{}{}", to, &url[from.len()..]);
+        }
+        match (&self.mirror, url.split_once("://")) {
+            (Some(mirror), Some((_, rest))) => {
+                format!("{}/{}", mirror.trim_end_matches('/'), rest)
+            }
+            _ => url.to_string(),
+        }
+    }
+}
+
+/// The file holding the ETag or Last-Modified date of the partial download `part`.
+fn validator_path(part: &Path) -> PathBuf {
+    let name = part.file_name().unwrap_or_default().to_string_lossy();
+    part.with_file_name(format!("{}.validator", name))
+}
+
+/// The value identifying the version of the file sent in `response`, as accepted by
+/// `If-Range`: a strong ETag, or else the Last-Modified date.
+fn response_validator(response: &ureq::http::Response<ureq::Body>) -> Option<String> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    header("etag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header("last-modified"))
+}
+
+fn remove_if_exists(p: &Path) -> std::io::Result<()> {
+    match fs::remove_file(p) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The local path of a `file://` URL.
+fn file_url_path(url: &str) -> Option<PathBuf> {
+    url.strip_prefix("file://").map(PathBuf::from)
+}
+
+/// The failure of a download attempt, and whether it is worth retrying.
+struct AttemptError {
+    error: anyhow::Error,
+    retry: bool,
+}
+
+impl AttemptError {
+    fn transient(error: anyhow::Error) -> AttemptError {
+        AttemptError { error, retry: true }
+    }
+
+    fn fatal(error: anyhow::Error) -> AttemptError {
+        AttemptError {
+            error,
+            retry: false,
+        }
+    }
+}
+
+impl From<std::io::Error> for AttemptError {
+    fn from(e: std::io::Error) -> AttemptError {
+        AttemptError::transient(e.into())
+    }
+}
+
+fn is_transient_status(status: u16) -> bool {
+    matches!(status, 408 | 425 | 429 | 500..=599)
+}
+
+pub struct Downloader {
+    agent: ureq::Agent,
+    rewrites: UrlRewrites,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Downloader {
+    /// A downloader with the rewrites and number of retries (`SIMPLEAF_DOWNLOAD_RETRIES`)
+    /// configured in the environment.
+    pub fn from_env() -> Result<Downloader> {
+        let retries = match std::env::var(DOWNLOAD_RETRIES_VAR) {
+            Ok(r) => r.parse().with_context(|| {
+                format!(
+                    "{} should be a number of retries, not {}",
+                    DOWNLOAD_RETRIES_VAR, r
+                )
+            })?,
+            Err(_) => DEFAULT_RETRIES,
+        };
+        Ok(Downloader::new(
+            UrlRewrites::from_env()?,
+            retries,
+            DEFAULT_BACKOFF,
+        ))
+    }
+
+    pub fn new(rewrites: UrlRewrites, retries: u32, backoff: Duration) -> Downloader {
+        let config = ureq::Agent::config_builder()
+            .timeout_connect(Some(Duration::from_secs(30)))
+            .timeout_recv_response(Some(Duration::from_secs(120)))
+            .max_redirects(10)
+            // the status is checked by `attempt`, to tell transient errors apart
+            .http_status_as_error(false)
+            .proxy(ureq::Proxy::try_from_env())
+            .build();
+        Downloader {
+            agent: ureq::Agent::new_with_config(config),
+            rewrites,
+            retries,
+            backoff,
+        }
+    }
+
+    /// Download `url` to `dest`, failing if the Blake3 hash of the download is not
+    /// `expected_hash`, if given. Returns the hash of the download.
+    pub fn download(
+        &self,
+        url: &str,
+        dest: &Path,
+        expected_hash: Option<&str>,
+    ) -> Result<blake3::Hash> {
+        let src = self.rewrite(url);
+        debug!(
+            "Downloading file from {} and writing to file {}",
+            src,
+            dest.display()
+        );
+        let name = dest
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file path", dest.display()))?
+            .to_string_lossy();
+        let part = dest.with_file_name(format!(".{}.part", name));
+        // the partial download is kept between attempts (and runs) to be resumed, so
+        // only one process may write it at a time
+        let _lock = FileLock::for_file(&part)?;
+
+        if let Some(local) = file_url_path(&src) {
+            fs::copy(&local, &part).with_context(|| {
+                format!("could not copy {} to {}", local.display(), part.display())
+            })?;
+        } else {
+            self.fetch(&src, &part, expected_hash.is_some())?;
+        }
+        remove_if_exists(&validator_path(&part))?;
+
+        let mut hasher = blake3::Hasher::new();
+        hasher
+            .update_mmap(&part)
+            .with_context(|| format!("could not read {}", part.display()))?;
+        let hash = hasher.finalize();
+        if let Some(expected) = expected_hash
+            && hash.to_string() != expected
+        {
+            fs::remove_file(&part)?;
+            bail!(
+                "The file downloaded from {} has the Blake3 hash {}, but {} was expected; It may have changed upstream.",
+                src,
+                hash,
+                expected
+            );
+        }
+        fs::rename(&part, dest).with_context(|| {
+            format!(
+                "could not atomically rename {} to {}",
+                part.display(),
+                dest.display()
+            )
+        })?;
+        Ok(hash)
+    }
+
+    fn rewrite(&self, url: &str) -> String {
+        let src = self.rewrites.rewrite(url);
+        if src != url {
+            info!("Downloading {} from {}", url, src);
+        }
+        src
+    }
+
+    /// Read the JSON document at `url`.
+    pub fn read_json(&self, url: &str) -> Result<serde_json::Value> {
+        let tmp = tempfile::tempdir()?;
+        let dest = tmp.path().join("document.json");
+        self.download(url, &dest, None)?;
+        crate::core::io::read_json_file(&dest)
+    }
+
+    /// Download `url` into `part`, retrying the attempts that fail transiently. If
+    /// `verified`, the download is checked against its expected hash afterwards.
+    fn fetch(&self, url: &str, part: &Path, verified: bool) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(url, part, verified) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.retry && attempt < self.retries => {
+                    let delay = self
+                        .backoff
+                        .saturating_mul(1 << attempt.min(16))
+                        .min(MAX_BACKOFF);
+                    attempt += 1;
+                    warn!(
+                        "Downloading {} failed ({:#}); Retrying in {}s ({} of {})",
+                        url,
+                        e.error,
+                        delay.as_secs(),
+                        attempt,
+                        self.retries
+                    );
+                    std::thread::sleep(delay);
+                }
+                Err(e) => {
+                    return Err(e.error.context(format!("could not download {}", url)));
+                }
+            }
+        }
+    }
+
+    /// A single request for `url`, resuming the partial download in `part`, if any.
+    fn attempt(&self, url: &str, part: &Path, verified: bool) -> Result<(), AttemptError> {
+        let validator_file = validator_path(part);
+        let mut offset = fs::metadata(part).map_or(0, |m| m.len());
+        let validator = if offset > 0 {
+            fs::read_to_string(&validator_file).ok()
+        } else {
+            None
+        };
+        if offset > 0 && validator.is_none() && !verified {
+            // nothing tells whether the remote file changed since the partial download
+            info!(
+                "Discarding the partial download of {}, as it cannot be checked against the remote file",
+                url
+            );
+            fs::remove_file(part)?;
+            offset = 0;
+        }
+        let mut request = self.agent.get(url);
+        if offset > 0 {
+            request = request.header("Range", format!("bytes={}-", offset));
+            if let Some(v) = validator {
+                request = request.header("If-Range", v);
+            }
+        }
+        let mut response = request
+            .call()
+            .map_err(|e| AttemptError::transient(anyhow!(e)))?;
+
+        let status = response.status();
+        let append = match status.as_u16() {
+            206 if offset > 0 => {
+                info!("Resuming the download of {} at byte {}", url, offset);
+                true
+            }
+            // the server ignored the range, or the file changed, so the download starts over
+            200..=299 => false,
+            // the partial download doesn't fit the file any more
+            416 if offset > 0 => {
+                fs::remove_file(part)?;
+                return Err(AttemptError::transient(anyhow!(
+                    "the partial download does not match the file; starting over"
+                )));
+            }
+            s => {
+                let error = anyhow!(
+                    "the server responded with HTTP status {} {}",
+                    s,
+                    status.canonical_reason().unwrap_or("")
+                );
+                return Err(if is_transient_status(s) {
+                    AttemptError::transient(error)
+                } else {
+                    AttemptError::fatal(error)
+                });
+            }
+        };
+        let expected_len = response
+            .headers()
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if !append {
+            match response_validator(&response) {
+                Some(v) => fs::write(&validator_file, v)?,
+                None => remove_if_exists(&validator_file)?,
+            }
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(part)
+            .map_err(|e| {
+                AttemptError::fatal(
+                    anyhow!(e).context(format!("could not open {}", part.display())),
+                )
+            })?;
+        let mut ofile = std::io::BufWriter::new(file);
+        let start = if append { offset } else { 0 };
+        let total = expected_len.map(|l| l + start);
+        let mut reader = response.body_mut().as_reader();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0u64;
+        let mut last_report = Instant::now();
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            ofile.write_all(&buf[..n])?;
+            received += n as u64;
+            if let Some(total) = total
+                && total >= PROGRESS_MIN_BYTES
+                && last_report.elapsed() >= PROGRESS_INTERVAL
+            {
+                let done = start + received;
+                info!(
+                    "Downloaded {:.1} of {:.1} MiB ({}%) of {}",
+                    done as f64 / 1_048_576.0,
+                    total as f64 / 1_048_576.0,
+                    done * 100 / total,
+                    url
+                );
+                last_report = Instant::now();
+            }
+        }
+        ofile.flush()?;
+        if let Some(len) = expected_len
+            && received < len
+        {
+            return Err(AttemptError::transient(anyhow!(
+                "the connection was closed after {} of {} bytes",
+                received,
+                len
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Download `url` to `dest` with the configuration of the environment, failing if the
+/// Blake3 hash of the download is not `expected_hash`, if given. Returns the hash of
+/// the download.
+pub fn download(url: &str, dest: &Path, expected_hash: Option<&str>) -> Result<blake3::Hash> {
+    Downloader::from_env()?.download(url, dest, expected_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn urls_are_rewritten_by_longest_prefix_then_mirrored() {
+        let rewrites = UrlRewrites::new(
+            BTreeMap::from([
+                (
+                    String::from("https://umd.box.com/"),
+                    String::from("https://mirror.example.org/box/"),
+                ),
+                (
+                    String::from("https://umd.box.com/shared/static/"),
+                    String::from("file:///data/simpleaf/plist/"),
+                ),
+            ]),
+            Some(String::from("https://mirror.example.org/all/")),
+        );
+        assert_eq!(
+            rewrites.rewrite("https://umd.box.com/shared/static/abc.txt"),
+            "file:///data/simpleaf/plist/abc.txt"
+        );
+        assert_eq!(
+            rewrites.rewrite("https://umd.box.com/other/abc.txt"),
+            "https://mirror.example.org/box/other/abc.txt"
+        );
+        assert_eq!(
+            rewrites
+                .rewrite("https://raw.githubusercontent.com/COMBINE-lab/simpleaf/chemistries.json"),
+            "https://mirror.example.org/all/raw.githubusercontent.com/COMBINE-lab/simpleaf/chemistries.json"
+        );
+        assert_eq!(
+            UrlRewrites::default().rewrite("https://example.org/a"),
+            "https://example.org/a"
+        );
+        assert_eq!(
+            file_url_path("file:///data/a.txt"),
+            Some(PathBuf::from("/data/a.txt"))
+        );
+    }
+
+    type SeenHeaders = Arc<Mutex<Vec<Option<String>>>>;
+
+    /// Serve the canned `responses` to successive connections, recording the `Range`
+    /// and `If-Range` headers of each request.
+    fn serve(responses: Vec<Vec<u8>>) -> (String, SeenHeaders, SeenHeaders) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.txt", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let if_ranges = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&ranges);
+        let seen_if = Arc::clone(&if_ranges);
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut range, mut if_range) = (None, None);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((k, v)) = line.split_once(':') {
+                        if k.eq_ignore_ascii_case("range") {
+                            range = Some(v.trim().to_string());
+                        } else if k.eq_ignore_ascii_case("if-range") {
+                            if_range = Some(v.trim().to_string());
+                        }
+                    }
+                }
+                seen.lock().unwrap().push(range);
+                seen_if.lock().unwrap().push(if_range);
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, ranges, if_ranges)
+    }
+
+    fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut r = format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\n{}\r\n",
+            status, headers
+        )
+        .into_bytes();
+        r.extend_from_slice(body);
+        r
+    }
+
+    #[test]
+    fn downloads_retry_transient_failures_and_resume() {
+        let content = b"AAAACCCCGG";
+        let (url, ranges, _) = serve(vec![
+            response("503 Service Unavailable", "Content-Length: 0\r\n", b""),
+            // the connection is closed after 4 of the 10 bytes
+            response("200 OK", "Content-Length: 10\r\n", &content[..4]),
+            response(
+                "206 Partial Content",
+                "Content-Length: 6\r\nContent-Range: bytes 4-9/10\r\n",
+                &content[4..],
+            ),
+        ]);
+        let td = tempfile::tempdir().unwrap();
+        let dest = td.path().join("file.txt");
+        let downloader = Downloader::new(UrlRewrites::default(), 3, Duration::ZERO);
+        let expected = blake3::hash(content).to_string();
+        let hash = downloader.download(&url, &dest, Some(&expected)).unwrap();
+        assert_eq!(hash.to_string(), expected);
+        assert_eq!(fs::read(&dest).unwrap(), content);
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            vec![None, None, Some(String::from("bytes=4-"))]
+        );
+        assert!(!td.path().join(".file.txt.part").exists());
+    }
+
+    #[test]
+    fn resumed_downloads_are_checked_against_the_remote_version() {
+        let td = tempfile::tempdir().unwrap();
+        let dest = td.path().join("file.txt");
+        let part = td.path().join(".file.txt.part");
+        let downloader = Downloader::new(UrlRewrites::default(), 3, Duration::ZERO);
+
+        // the file changes upstream between the interrupted and the resumed request,
+        // so the server answers the `If-Range` request with the whole new version
+        let (url, ranges, if_ranges) = serve(vec![
+            response(
+                "200 OK",
+                "Content-Length: 10\r\nETag: \"v1\"\r\n",
+                &b"AAAACCCCGG"[..4],
+            ),
+            response(
+                "200 OK",
+                "Content-Length: 10\r\nETag: \"v2\"\r\n",
+                b"TTTTGGGGAA",
+            ),
+        ]);
+        downloader.download(&url, &dest, None).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"TTTTGGGGAA");
+        assert_eq!(
+            *ranges.lock().unwrap(),
+            vec![None, Some(String::from("bytes=4-"))]
+        );
+        assert_eq!(
+            *if_ranges.lock().unwrap(),
+            vec![None, Some(String::from("\"v1\""))]
+        );
+        assert!(!part.exists());
+        assert!(!validator_path(&part).exists());
+
+        // a partial download left by an earlier run, with nothing to tell which version
+        // of the file it is of, is started over when there is no hash to check
+        fs::write(&part, b"OLD").unwrap();
+        let (url, ranges, _) = serve(vec![response(
+            "200 OK",
+            "Content-Length: 10\r\n",
+            b"CCCCAAAATT",
+        )]);
+        downloader.download(&url, &dest, None).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"CCCCAAAATT");
+        assert_eq!(*ranges.lock().unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn downloads_fail_on_http_errors_and_hash_mismatches() {
+        let td = tempfile::tempdir().unwrap();
+        let dest = td.path().join("file.txt");
+        let downloader = Downloader::new(UrlRewrites::default(), 3, Duration::ZERO);
+
+        let (url, ranges, _) = serve(vec![response(
+            "404 Not Found",
+            "Content-Length: 0\r\n",
+            b"",
+        )]);
+        let err = downloader.download(&url, &dest, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("404"));
+        // not retried
+        assert_eq!(ranges.lock().unwrap().len(), 1);
+        assert!(!dest.exists());
+
+        let (url, _, _) = serve(vec![response("200 OK", "Content-Length: 4\r\n", b"AAAA")]);
+        let expected = blake3::hash(b"CCCC").to_string();
+        assert!(downloader.download(&url, &dest, Some(&expected)).is_err());
+        assert!(!dest.exists());
+
+        // file:// urls, e.g. from a rewrite, are copied
+        let src = td.path().join("src.txt");
+        fs::write(&src, b"GGGG").unwrap();
+        let local = Downloader::new(
+            UrlRewrites::new(
+                BTreeMap::from([(
+                    String::from("https://example.org/"),
+                    format!("file://{}/", td.path().display()),
+                )]),
+                None,
+            ),
+            0,
+            Duration::ZERO,
+        );
+        local
+            .download("https://example.org/src.txt", &dest, None)
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"GGGG");
+    }
+}
@@ -0,0 +1,224 @@
+//! Verification of the files cached for registered chemistries (permit lists, sample
+//! barcode lists, probe sets and translation lists) before they are used.
+//!
+//! These files are named by the Blake3 hash of their contents, so a truncated download
+//! or a corrupted copy is detected by hashing them again. To keep this cheap, the
+//! hashes are remembered in `ALEVIN_FRY_HOME/.verified_files.json`, along with the size
+//! and modification time of the files they were computed from, and only computed again
+//! once these change.
+
+use crate::core::io::{FileLock, write_json_pretty_atomic};
+use crate::utils::constants::VERIFIED_FILES_PATH;
+use crate::utils::download;
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{debug, info, warn};
+
+/// The hash of a file, along with the size and modification time it had when hashed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct FileStamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    hash: String,
+}
+
+impl FileStamp {
+    fn is_current(&self, md: &fs::Metadata) -> bool {
+        let (size, secs, nanos) = stamp_of(md);
+        self.size == size && self.mtime_secs == secs && self.mtime_nanos == nanos
+    }
+}
+
+fn stamp_of(md: &fs::Metadata) -> (u64, u64, u32) {
+    let mtime = md
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    (md.len(), mtime.as_secs(), mtime.subsec_nanos())
+}
+
+type VerifiedFiles = BTreeMap<String, FileStamp>;
+
+/// The verified files of `af_home`; a missing or unreadable record is empty, as
+/// it only spares hashing the files again.
+fn read_verified(af_home: &Path) -> VerifiedFiles {
+    fs::read_to_string(af_home.join(VERIFIED_FILES_PATH))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+fn record_verified(af_home: &Path, key: String, stamp: FileStamp) -> Result<()> {
+    let verified_p = af_home.join(VERIFIED_FILES_PATH);
+    let _lock = FileLock::for_file(&verified_p)?;
+    let mut verified = read_verified(af_home);
+    // forget the files that have since been removed
+    verified.retain(|k, _| Path::new(k).is_file());
+    verified.insert(key, stamp);
+    write_json_pretty_atomic(&verified_p, &verified)
+}
+
+/// The hash a cached file must have: its name, without extension, if that is a
+/// Blake3 hash. Files named otherwise cannot be verified.
+pub fn expected_hash(path: &Path) -> Option<&str> {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .filter(|s| blake3::Hash::from_hex(s).is_ok())
+}
+
+/// The Blake3 hash of the file `path`, as recorded in `af_home` if the file has not
+/// changed since it was last hashed.
+fn file_hash(af_home: &Path, path: &Path) -> Result<String> {
+    let md = fs::metadata(path).with_context(|| format!("could not read {}", path.display()))?;
+    let key = fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string();
+    if let Some(stamp) = read_verified(af_home).get(&key)
+        && stamp.is_current(&md)
+    {
+        return Ok(stamp.hash.clone());
+    }
+
+    debug!("Computing the Blake3 hash of {}", path.display());
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_mmap(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let hash = hasher.finalize().to_string();
+    let (size, mtime_secs, mtime_nanos) = stamp_of(&md);
+    let stamp = FileStamp {
+        size,
+        mtime_secs,
+        mtime_nanos,
+        hash: hash.clone(),
+    };
+    if let Err(e) = record_verified(af_home, key, stamp) {
+        // the file will simply be hashed again next time
+        debug!("Could not record the hash of {}: {:#}", path.display(), e);
+    }
+    Ok(hash)
+}
+
+/// Verify the cached file `path` before it is used as the `what` of a chemistry.
+///
+/// If its contents don't match the hash it is named by, it is downloaded again from
+/// `remote`, into `path` itself if it is under `af_home`, or else (e.g. for a file
+/// cached by a read-only registry layer) into `af_home/plist`. Without a `remote`,
+/// or if the new download doesn't match either, this fails. Returns the path of the
+/// verified file.
+pub fn ensure_intact(
+    af_home: &Path,
+    path: &Path,
+    remote: Option<&str>,
+    what: &str,
+) -> Result<PathBuf> {
+    let Some(expected) = expected_hash(path) else {
+        return Ok(path.to_path_buf());
+    };
+    let observed = file_hash(af_home, path)?;
+    if observed == expected {
+        return Ok(path.to_path_buf());
+    }
+    let Some(remote) = remote else {
+        bail!(
+            "The {} {} is corrupted: its Blake3 hash is {}, but it should be {}. As it has no remote URL to download it again from, please remove it and register the {} again.",
+            what,
+            path.display(),
+            observed,
+            expected,
+            what
+        );
+    };
+    warn!(
+        "The {} {} is corrupted (its Blake3 hash is {}, but it should be {}); Downloading it again from {}.",
+        what,
+        path.display(),
+        observed,
+        expected,
+        remote
+    );
+
+    let dest = if path.starts_with(af_home) {
+        path.to_path_buf()
+    } else {
+        af_home
+            .join("plist")
+            .join(path.file_name().unwrap_or_default())
+    };
+    let _lock = FileLock::for_file(&dest)?;
+    // another process may have downloaded it again in the meantime
+    if dest.is_file() && file_hash(af_home, &dest)? == expected {
+        info!(
+            "The {} {} was downloaded again by another process; Using it.",
+            what,
+            dest.display()
+        );
+        return Ok(dest);
+    }
+    if let Some(dir) = dest.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("could not create {}", dir.display()))?;
+    }
+    download::download(remote, &dest, Some(expected)).with_context(|| {
+        format!(
+            "Could not download the {} again; Cannot proceed with it.",
+            what
+        )
+    })?;
+    // record the hash of the new download
+    file_hash(af_home, &dest)?;
+    info!("Downloaded the {} again to {}", what, dest.display());
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_files_are_verified_against_their_name() {
+        let td = tempfile::tempdir().unwrap();
+        let af_home = td.path();
+        let plist = af_home.join("plist");
+        fs::create_dir_all(&plist).unwrap();
+        let content = b"AAAA\nCCCC\n";
+        let path = plist.join(blake3::hash(content).to_string());
+        fs::write(&path, content).unwrap();
+
+        assert_eq!(
+            ensure_intact(af_home, &path, None, "permit list").unwrap(),
+            path
+        );
+        let verified = read_verified(af_home);
+        assert_eq!(verified.len(), 1);
+        let (key, stamp) = verified.into_iter().next().unwrap();
+
+        // an unchanged file is not hashed again: a recorded hash is trusted
+        let mut forged = stamp.clone();
+        forged.hash = blake3::hash(b"other").to_string();
+        record_verified(af_home, key.clone(), forged).unwrap();
+        assert!(ensure_intact(af_home, &path, None, "permit list").is_err());
+        record_verified(af_home, key, stamp).unwrap();
+        assert!(ensure_intact(af_home, &path, None, "permit list").is_ok());
+
+        // a truncated file is hashed again, and fails without a remote url
+        fs::write(&path, b"AAAA\n").unwrap();
+        let err = ensure_intact(af_home, &path, None, "permit list").unwrap_err();
+        assert!(format!("{:#}", err).contains("is corrupted"));
+
+        // files not named by a hash can't be verified
+        let unnamed = plist.join("sample_bc_list.txt");
+        fs::write(&unnamed, b"AAAA\n").unwrap();
+        assert_eq!(
+            ensure_intact(af_home, &unnamed, None, "sample barcode list").unwrap(),
+            unnamed
+        );
+    }
+}
@@ -0,0 +1,541 @@
+//! Helpers for working with the matrix-market output written by `alevin-fry quant --use-mtx`.
+//!
+//! An alevin-fry quantification directory (`af_quant`) holds the count matrix under
+//! `alevin/` (`quants_mat.mtx`, with cells as rows, plus `quants_mat_rows.txt` and
+//! `quants_mat_cols.txt`) alongside the per-cell `featureDump.txt` and the JSON
+//! metadata that downstream consumers (e.g. the anndata conversion) expect to find.
+
+use anyhow::{Context, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// metadata files copied verbatim when a quantification directory is subset.
+const QUANT_DIR_META_FILES: &[&str] = &[
+    "generate_permit_list.json",
+    "collate.json",
+    "quant.json",
+    "simpleaf_map_info.json",
+    "gene_id_to_name.tsv",
+];
+
+/// the per-cell statistics written by `alevin-fry quant`; a header line followed
+/// by one line per row of the count matrix.
+const FEATURE_DUMP_FILE: &str = "featureDump.txt";
+
+/// The count matrix, row and column names of an alevin-fry quantification directory.
+pub(crate) fn quant_mat_paths(quant_dir: &Path) -> (PathBuf, PathBuf, PathBuf) {
+    let alevin = quant_dir.join("alevin");
+    (
+        alevin.join("quants_mat.mtx"),
+        alevin.join("quants_mat_rows.txt"),
+        alevin.join("quants_mat_cols.txt"),
+    )
+}
+
+/// Read the barcodes (the first tab-separated column) of `quants_mat_rows.txt`.
+pub(crate) fn read_row_barcodes(rows_path: &Path) -> anyhow::Result<Vec<String>> {
+    let f = std::fs::File::open(rows_path)
+        .with_context(|| format!("could not open {}", rows_path.display()))?;
+    let mut barcodes = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line.with_context(|| format!("could not read {}", rows_path.display()))?;
+        let bc = line.split('\t').next().unwrap_or_default();
+        barcodes.push(bc.to_string());
+    }
+    Ok(barcodes)
+}
+
+/// Parse the `nrows ncols nnz` size line of a matrix-market file, skipping the
+/// header and comment lines. Returns the dimensions and the remaining lines.
+fn read_mtx_header<B: BufRead>(
+    lines: &mut std::io::Lines<B>,
+    mtx_path: &Path,
+) -> anyhow::Result<(String, usize, usize, usize)> {
+    let mut header = String::new();
+    for line in lines.by_ref() {
+        let line = line.with_context(|| format!("could not read {}", mtx_path.display()))?;
+        if line.starts_with('%') {
+            header.push_str(&line);
+            header.push('\n');
+            continue;
+        }
+        let dims: Vec<usize> = line
+            .split_whitespace()
+            .map(|t| t.parse::<usize>())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("invalid size line in {}", mtx_path.display()))?;
+        if dims.len() != 3 {
+            bail!("invalid size line in {}: {}", mtx_path.display(), line);
+        }
+        return Ok((header, dims[0], dims[1], dims[2]));
+    }
+    bail!(
+        "{} does not contain a matrix-market size line",
+        mtx_path.display()
+    )
+}
+
+fn parse_entry(line: &str, mtx_path: &Path) -> anyhow::Result<(usize, usize, f64)> {
+    let mut toks = line.split_whitespace();
+    let (Some(r), Some(c), Some(v)) = (toks.next(), toks.next(), toks.next()) else {
+        bail!("invalid entry in {}: {}", mtx_path.display(), line);
+    };
+    let r: usize = r
+        .parse()
+        .with_context(|| format!("invalid row index in {}", mtx_path.display()))?;
+    let c: usize = c
+        .parse()
+        .with_context(|| format!("invalid column index in {}", mtx_path.display()))?;
+    let v: f64 = v
+        .parse()
+        .with_context(|| format!("invalid value in {}", mtx_path.display()))?;
+    if r == 0 || c == 0 {
+        bail!(
+            "matrix-market indices must be 1-based in {}",
+            mtx_path.display()
+        );
+    }
+    Ok((r, c, v))
+}
+
+/// Sum the entries of each row (cell) of a matrix-market file.
+pub(crate) fn mtx_row_sums(mtx_path: &Path) -> anyhow::Result<Vec<f64>> {
+    let f = std::fs::File::open(mtx_path)
+        .with_context(|| format!("could not open {}", mtx_path.display()))?;
+    let mut lines = BufReader::new(f).lines();
+    let (_header, nrows, _ncols, _nnz) = read_mtx_header(&mut lines, mtx_path)?;
+    let mut sums = vec![0.0f64; nrows];
+    for line in lines {
+        let line = line.with_context(|| format!("could not read {}", mtx_path.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (r, _c, v) = parse_entry(&line, mtx_path)?;
+        let s = sums
+            .get_mut(r - 1)
+            .with_context(|| format!("row index {} out of bounds in {}", r, mtx_path.display()))?;
+        *s += v;
+    }
+    Ok(sums)
+}
+
+/// Call `f` with the (0-based) row, column and value of every entry of a matrix-market
+/// file, in file order. Returns the number of rows and columns of the matrix.
+pub(crate) fn for_each_mtx_entry(
+    mtx_path: &Path,
+    mut f: impl FnMut(usize, usize, f64) -> anyhow::Result<()>,
+) -> anyhow::Result<(usize, usize)> {
+    let file = std::fs::File::open(mtx_path)
+        .with_context(|| format!("could not open {}", mtx_path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+    let (_header, nrows, ncols, _nnz) = read_mtx_header(&mut lines, mtx_path)?;
+    for line in lines {
+        let line = line.with_context(|| format!("could not read {}", mtx_path.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (r, c, v) = parse_entry(&line, mtx_path)?;
+        if r > nrows || c > ncols {
+            bail!(
+                "entry ({}, {}) out of bounds in {}",
+                r,
+                c,
+                mtx_path.display()
+            );
+        }
+        f(r - 1, c - 1, v)?;
+    }
+    Ok((nrows, ncols))
+}
+
+/// Read a matrix-market file into a dense, row-major matrix. Only meant for
+/// matrices with few columns (e.g. antibody or hashtag counts).
+pub(crate) fn read_mtx_dense(mtx_path: &Path) -> anyhow::Result<Vec<Vec<f64>>> {
+    let f = std::fs::File::open(mtx_path)
+        .with_context(|| format!("could not open {}", mtx_path.display()))?;
+    let mut lines = BufReader::new(f).lines();
+    let (_header, nrows, ncols, _nnz) = read_mtx_header(&mut lines, mtx_path)?;
+    let mut mat = vec![vec![0.0f64; ncols]; nrows];
+    for line in lines {
+        let line = line.with_context(|| format!("could not read {}", mtx_path.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (r, c, v) = parse_entry(&line, mtx_path)?;
+        let cell = mat
+            .get_mut(r - 1)
+            .and_then(|row| row.get_mut(c - 1))
+            .with_context(|| {
+                format!(
+                    "entry ({}, {}) out of bounds in {}",
+                    r,
+                    c,
+                    mtx_path.display()
+                )
+            })?;
+        *cell += v;
+    }
+    Ok(mat)
+}
+
+/// Read a numeric column (by header name) of the `featureDump.txt` of a
+/// quantification directory, one value per row of the count matrix. Returns
+/// `None` if the file or the column is missing.
+pub(crate) fn read_feature_dump_column(
+    quant_dir: &Path,
+    column: &str,
+) -> anyhow::Result<Option<Vec<f64>>> {
+    let dump = quant_dir.join(FEATURE_DUMP_FILE);
+    if !dump.is_file() {
+        return Ok(None);
+    }
+    let f =
+        std::fs::File::open(&dump).with_context(|| format!("could not open {}", dump.display()))?;
+    let mut lines = BufReader::new(f).lines();
+    let Some(header) = lines.next() else {
+        return Ok(None);
+    };
+    let header = header.with_context(|| format!("could not read {}", dump.display()))?;
+    let Some(idx) = header.split('\t').position(|h| h == column) else {
+        return Ok(None);
+    };
+    let mut values = Vec::new();
+    for line in lines {
+        let line = line.with_context(|| format!("could not read {}", dump.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let v = line
+            .split('\t')
+            .nth(idx)
+            .and_then(|t| t.parse::<f64>().ok())
+            .with_context(|| format!("invalid {} value in {}: {}", column, dump.display(), line))?;
+        values.push(v);
+    }
+    Ok(Some(values))
+}
+
+/// Write a copy of the quantification directory `src` to `dst` that only retains the
+/// rows (cells) in `keep` (0-based indices, in output order). If `row_names` is given,
+/// it replaces the rows file of the subset (and must have the same length as `keep`).
+pub(crate) fn subset_quant_dir(
+    src: &Path,
+    dst: &Path,
+    keep: &[usize],
+    row_names: Option<&[String]>,
+) -> anyhow::Result<()> {
+    if let Some(names) = row_names
+        && names.len() != keep.len()
+    {
+        bail!(
+            "{} row names were provided for {} retained rows",
+            names.len(),
+            keep.len()
+        );
+    }
+    let (src_mtx, src_rows, src_cols) = quant_mat_paths(src);
+    let (dst_mtx, dst_rows, dst_cols) = quant_mat_paths(dst);
+    let dst_alevin = dst.join("alevin");
+    std::fs::create_dir_all(&dst_alevin)
+        .with_context(|| format!("could not create {}", dst_alevin.display()))?;
+
+    for fname in QUANT_DIR_META_FILES {
+        let p = src.join(fname);
+        if p.is_file() {
+            std::fs::copy(&p, dst.join(fname))
+                .with_context(|| format!("could not copy {}", p.display()))?;
+        }
+    }
+    std::fs::copy(&src_cols, &dst_cols)
+        .with_context(|| format!("could not copy {}", src_cols.display()))?;
+
+    // rows file
+    let rows_content = std::fs::read_to_string(&src_rows)
+        .with_context(|| format!("could not read {}", src_rows.display()))?;
+    let src_row_lines: Vec<&str> = rows_content.lines().collect();
+    let mut rows_w = BufWriter::new(
+        std::fs::File::create(&dst_rows)
+            .with_context(|| format!("could not create {}", dst_rows.display()))?,
+    );
+    for (i, r) in keep.iter().enumerate() {
+        match row_names {
+            Some(names) => writeln!(rows_w, "{}", names[i])?,
+            None => {
+                let l = src_row_lines.get(*r).with_context(|| {
+                    format!("row {} out of bounds in {}", r, src_rows.display())
+                })?;
+                writeln!(rows_w, "{}", l)?;
+            }
+        }
+    }
+    rows_w.flush()?;
+
+    // per-cell statistics, if present
+    let src_dump = src.join(FEATURE_DUMP_FILE);
+    if src_dump.is_file() {
+        let dump_content = std::fs::read_to_string(&src_dump)
+            .with_context(|| format!("could not read {}", src_dump.display()))?;
+        let mut dump_lines = dump_content.lines();
+        let dump_header = dump_lines.next().unwrap_or_default();
+        let dump_rows: Vec<&str> = dump_lines.collect();
+        let dst_dump = dst.join(FEATURE_DUMP_FILE);
+        let mut dump_w = BufWriter::new(
+            std::fs::File::create(&dst_dump)
+                .with_context(|| format!("could not create {}", dst_dump.display()))?,
+        );
+        writeln!(dump_w, "{}", dump_header)?;
+        for r in keep {
+            let l = dump_rows
+                .get(*r)
+                .with_context(|| format!("row {} out of bounds in {}", r, src_dump.display()))?;
+            writeln!(dump_w, "{}", l)?;
+        }
+        dump_w.flush()?;
+    }
+
+    // count matrix; map old (1-based) row to new (1-based) row
+    let f = std::fs::File::open(&src_mtx)
+        .with_context(|| format!("could not open {}", src_mtx.display()))?;
+    let mut lines = BufReader::new(f).lines();
+    let (header, nrows, ncols, _nnz) = read_mtx_header(&mut lines, &src_mtx)?;
+    let mut new_index = vec![0usize; nrows];
+    for (i, r) in keep.iter().enumerate() {
+        let slot = new_index
+            .get_mut(*r)
+            .with_context(|| format!("row {} out of bounds in {}", r, src_mtx.display()))?;
+        *slot = i + 1;
+    }
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line.with_context(|| format!("could not read {}", src_mtx.display()))?;
+        if line.is_empty() {
+            continue;
+        }
+        let (r, c, _v) = parse_entry(&line, &src_mtx)?;
+        let nr = *new_index
+            .get(r - 1)
+            .with_context(|| format!("row index {} out of bounds in {}", r, src_mtx.display()))?;
+        if nr > 0 {
+            let v = line.split_whitespace().nth(2).unwrap_or("0").to_string();
+            entries.push((nr, c, v));
+        }
+    }
+    entries.sort_by_key(|(r, c, _)| (*r, *c));
+
+    let mut mtx_w = BufWriter::new(
+        std::fs::File::create(&dst_mtx)
+            .with_context(|| format!("could not create {}", dst_mtx.display()))?,
+    );
+    write!(mtx_w, "{}", header)?;
+    writeln!(mtx_w, "{}\t{}\t{}", keep.len(), ncols, entries.len())?;
+    for (r, c, v) in entries {
+        writeln!(mtx_w, "{}\t{}\t{}", r, c, v)?;
+    }
+    mtx_w.flush()?;
+    Ok(())
+}
+
+/// Rename the rows (cells) of the quantification directory `quant_dir` in place, to
+/// `names` (one per row). Rows given the same name are merged: their counts are summed,
+/// and their per-cell statistics are those of the first of them. Returns the number of
+/// rows that were merged into another.
+pub(crate) fn rename_quant_rows(quant_dir: &Path, names: &[String]) -> anyhow::Result<usize> {
+    let (mtx, rows, _cols) = quant_mat_paths(quant_dir);
+    let f =
+        std::fs::File::open(&mtx).with_context(|| format!("could not open {}", mtx.display()))?;
+    let (header, nrows, ncols, _nnz) = read_mtx_header(&mut BufReader::new(f).lines(), &mtx)?;
+    if nrows != names.len() {
+        bail!(
+            "{} row names were provided for the {} rows of {}",
+            names.len(),
+            nrows,
+            mtx.display()
+        );
+    }
+
+    // the new index of every row, and the first (original) row of every new row
+    let mut new_index = Vec::with_capacity(nrows);
+    let mut first_rows = Vec::new();
+    let mut index_of: HashMap<&str, usize> = HashMap::new();
+    for (i, name) in names.iter().enumerate() {
+        let ni = *index_of.entry(name).or_insert_with(|| {
+            first_rows.push(i);
+            first_rows.len() - 1
+        });
+        new_index.push(ni);
+    }
+    let num_merged = nrows - first_rows.len();
+
+    // the counts only change if rows were merged
+    if num_merged > 0 {
+        let mut entries: BTreeMap<(usize, usize), f64> = BTreeMap::new();
+        for_each_mtx_entry(&mtx, |r, c, v| {
+            *entries.entry((new_index[r], c)).or_default() += v;
+            Ok(())
+        })?;
+        let tmp = mtx.with_extension("mtx.tmp");
+        let mut mtx_w = BufWriter::new(
+            std::fs::File::create(&tmp)
+                .with_context(|| format!("could not create {}", tmp.display()))?,
+        );
+        write!(mtx_w, "{}", header)?;
+        writeln!(mtx_w, "{}\t{}\t{}", first_rows.len(), ncols, entries.len())?;
+        for ((r, c), v) in entries {
+            writeln!(mtx_w, "{}\t{}\t{}", r + 1, c + 1, v)?;
+        }
+        mtx_w.flush()?;
+        drop(mtx_w);
+        std::fs::rename(&tmp, &mtx)
+            .with_context(|| format!("could not replace {}", mtx.display()))?;
+    }
+
+    let mut rows_w = BufWriter::new(
+        std::fs::File::create(&rows)
+            .with_context(|| format!("could not create {}", rows.display()))?,
+    );
+    for r in &first_rows {
+        writeln!(rows_w, "{}", names[*r])?;
+    }
+    rows_w.flush()?;
+
+    // per-cell statistics, if present
+    let dump = quant_dir.join(FEATURE_DUMP_FILE);
+    if dump.is_file() {
+        let dump_content = std::fs::read_to_string(&dump)
+            .with_context(|| format!("could not read {}", dump.display()))?;
+        let mut dump_lines = dump_content.lines();
+        let dump_header = dump_lines.next().unwrap_or_default();
+        let dump_rows: Vec<&str> = dump_lines.collect();
+        let mut dump_w = BufWriter::new(
+            std::fs::File::create(&dump)
+                .with_context(|| format!("could not create {}", dump.display()))?,
+        );
+        writeln!(dump_w, "{}", dump_header)?;
+        for r in &first_rows {
+            let l = dump_rows
+                .get(*r)
+                .with_context(|| format!("row {} out of bounds in {}", r, dump.display()))?;
+            let stats = l.split_once('\t').map(|(_, s)| s).unwrap_or_default();
+            writeln!(dump_w, "{}\t{}", names[*r], stats)?;
+        }
+        dump_w.flush()?;
+    }
+    Ok(num_merged)
+}
+
+/// Write the count matrix of the quantification directory `quant_dir` for tests, with
+/// the row names `rows`, the column names `cols` and the (1-based) `(row, column, count)`
+/// `entries`.
+#[cfg(test)]
+pub(crate) fn write_test_quant_mat(
+    quant_dir: &Path,
+    rows: &[impl AsRef<str>],
+    cols: &[&str],
+    entries: &[(usize, usize, f64)],
+) {
+    let (mtx, rows_path, cols_path) = quant_mat_paths(quant_dir);
+    std::fs::create_dir_all(quant_dir.join("alevin")).expect("failed to create alevin dir");
+    let mut content = format!(
+        "%%MatrixMarket matrix coordinate real general\n{}\t{}\t{}\n",
+        rows.len(),
+        cols.len(),
+        entries.len()
+    );
+    for (r, c, v) in entries {
+        content.push_str(&format!("{}\t{}\t{}\n", r, c, v));
+    }
+    std::fs::write(&mtx, content).expect("failed to write mtx");
+    let rows: String = rows.iter().map(|r| format!("{}\n", r.as_ref())).collect();
+    std::fs::write(&rows_path, rows).expect("failed to write rows");
+    let cols: String = cols.iter().map(|c| format!("{}\n", c)).collect();
+    std::fs::write(&cols_path, cols).expect("failed to write cols");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_quant_dir(dir: &Path) {
+        write_test_quant_mat(
+            dir,
+            &["AAAA", "CCCC", "GGGG"],
+            &["g1", "g2"],
+            &[(1, 1, 2.0), (1, 2, 1.0), (2, 2, 5.0), (3, 1, 1.5)],
+        );
+        fs::write(dir.join("quant.json"), "{}").expect("failed to write quant.json");
+        fs::write(
+            dir.join("featureDump.txt"),
+            "CB\tCorrectedReads\tMappedReads\nAAAA\t10\t8\nCCCC\t20\t15\nGGGG\t5\t2\n",
+        )
+        .expect("failed to write featureDump.txt");
+    }
+
+    #[test]
+    fn row_sums_accumulate_per_cell() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        write_quant_dir(td.path());
+        let (mtx, rows, _) = quant_mat_paths(td.path());
+        assert_eq!(mtx_row_sums(&mtx).expect("row sums"), vec![3.0, 5.0, 1.5]);
+        assert_eq!(
+            read_row_barcodes(&rows).expect("rows"),
+            vec!["AAAA", "CCCC", "GGGG"]
+        );
+    }
+
+    #[test]
+    fn dense_matrix_matches_entries() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        write_quant_dir(td.path());
+        let (mtx, _, _) = quant_mat_paths(td.path());
+        assert_eq!(
+            read_mtx_dense(&mtx).expect("dense matrix"),
+            vec![vec![2.0, 1.0], vec![0.0, 5.0], vec![1.5, 0.0]]
+        );
+    }
+
+    #[test]
+    fn subset_keeps_requested_rows_in_order() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        let src = td.path().join("src");
+        let dst = td.path().join("dst");
+        write_quant_dir(&src);
+        let names = vec!["x3".to_string(), "x1".to_string()];
+        subset_quant_dir(&src, &dst, &[2, 0], Some(&names)).expect("subset");
+
+        let (mtx, rows, cols) = quant_mat_paths(&dst);
+        assert_eq!(mtx_row_sums(&mtx).expect("row sums"), vec![1.5, 3.0]);
+        assert_eq!(fs::read_to_string(rows).expect("rows"), "x3\nx1\n");
+        assert_eq!(fs::read_to_string(cols).expect("cols"), "g1\ng2\n");
+        assert!(dst.join("quant.json").is_file());
+        assert_eq!(
+            read_feature_dump_column(&dst, "MappedReads").expect("feature dump"),
+            Some(vec![2.0, 8.0])
+        );
+        assert_eq!(
+            read_feature_dump_column(&dst, "Missing").expect("feature dump"),
+            None
+        );
+    }
+
+    #[test]
+    fn renamed_rows_with_the_same_name_are_merged() {
+        let td = tempfile::tempdir().expect("failed to create tempdir");
+        write_quant_dir(td.path());
+        let names = vec!["TTTT".to_string(), "CCCC".to_string(), "TTTT".to_string()];
+        assert_eq!(rename_quant_rows(td.path(), &names).expect("rename"), 1);
+
+        let (mtx, rows, _cols) = quant_mat_paths(td.path());
+        assert_eq!(fs::read_to_string(rows).expect("rows"), "TTTT\nCCCC\n");
+        assert_eq!(
+            read_mtx_dense(&mtx).expect("dense matrix"),
+            vec![vec![3.5, 1.0], vec![0.0, 5.0]]
+        );
+        assert_eq!(
+            fs::read_to_string(td.path().join("featureDump.txt")).expect("feature dump"),
+            "CB\tCorrectedReads\tMappedReads\nTTTT\t10\t8\nCCCC\t20\t15\n"
+        );
+        assert!(rename_quant_rows(td.path(), &names).is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use anyhow::{Context, Result, anyhow, bail};
+use crate::core::io;
+use crate::utils::download;
+use anyhow::{Context, Result, anyhow};
 use cmd_lib::run_fun;
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
@@ -8,8 +10,7 @@ use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::LazyLock;
-use tracing::{debug, error, info, warn};
-use ureq::ResponseExt;
+use tracing::{error, info, warn};
 use which::which;
 
 use file_requirements::{FileRequirementBuildError, FileRequirementBuilder};
@@ -45,97 +46,43 @@ pub fn shell<S: AsRef<OsStr>>(cmd: S) -> Command {
     command
 }
 
-/// NOTE: the body of the JSON object we fetch cannot exceed 10MB
-/// this is a limitation put in place by `ureq` (see : https://docs.rs/ureq/3.0.0-rc4/ureq/struct.Body.html#method.read_json)
+/// Read the JSON document at `url` (see [`download`] for how it is obtained).
 pub fn read_json_from_remote_url<T: AsRef<str>>(url: T) -> Result<serde_json::Value> {
-    let url = url.as_ref();
-
-    let config = ureq::Agent::config_builder()
-        .timeout_recv_response(Some(std::time::Duration::from_secs(120)))
-        .max_redirects(10)
-        .build();
-    let agent = ureq::Agent::new_with_config(config);
-    let response = agent.get(url).call();
-
-    match response {
-        Ok(mut response) => {
-            if response.status().is_success() {
-                return Ok(response.body_mut().read_json()?);
-            } else {
-                let c = response
-                    .status()
-                    .canonical_reason()
-                    .unwrap_or("UNKNOWN FAILURE");
-                warn!(
-                    "Obtained status code {} from final url {}",
-                    c,
-                    response.get_uri()
-                );
-            }
-        }
-        Err(e) => {
-            bail!("could not obtain content from {} because {:#?}", url, e);
-        }
-    }
-    bail!("control should not reach here");
+    download::Downloader::from_env()?.read_json(url.as_ref())
 }
 
+/// Download `url` to `file_path` (see [`download`]), and return the Blake3 hash of
+/// the downloaded file.
 pub fn download_to_file_compute_hash<T: AsRef<str>>(
     url: T,
     file_path: &Path,
 ) -> Result<blake3::Hash> {
-    let url = url.as_ref();
-    download_to_file(url, file_path)
-        .with_context(|| format!("failed to download the file from {}", url))?;
-    let new_file = std::fs::File::open(file_path)?;
-    let reader = std::io::BufReader::new(new_file);
-    let mut hasher = blake3::Hasher::new();
-    hasher.update_reader(reader)?;
-    Ok(hasher.finalize())
+    download::download(url.as_ref(), file_path, None)
 }
 
-pub fn download_to_file<T: AsRef<str>>(url: T, file_path: &Path) -> Result<()> {
-    let url = url.as_ref();
-
-    debug!(
-        "Downloading file from {} and writing to file {}",
-        url,
-        file_path.display()
-    );
-
-    let config = ureq::Agent::config_builder()
-        .timeout_recv_response(Some(std::time::Duration::from_secs(120)))
-        .max_redirects(10)
-        .build();
-    let agent = ureq::Agent::new_with_config(config);
-    let response = agent.get(url).call();
-
-    match response {
-        Ok(mut response) => {
-            if response.status().is_success() {
-                let mut req = response.body_mut().as_reader();
-                let f = std::fs::File::create(file_path)
-                    .with_context(|| format!("could not create file {}", file_path.display()))?;
-                let mut ofile = std::io::BufWriter::new(f);
-                std::io::copy(&mut req, &mut ofile)?;
-            } else {
-                let c = response
-                    .status()
-                    .canonical_reason()
-                    .unwrap_or("UNKNOWN FAILURE");
-                warn!(
-                    "Obtained status code {} from final url {}",
-                    c,
-                    response.get_uri()
-                );
-            }
-        }
-        Err(e) => {
-            bail!("could not obtain content from {} because {:#?}", url, e);
-        }
+/// Download `url` to `file_path` unless the file is present. The download holds the
+/// lock of `file_path`, so that when several processes need the same file, only one of
+/// them downloads it, and the others reuse it once they obtain the lock.
+/// Returns whether the file was downloaded.
+pub fn download_if_absent<T: AsRef<str>>(url: T, file_path: &Path) -> Result<bool> {
+    if file_path.is_file() {
+        return Ok(false);
+    }
+    let _lock = io::FileLock::for_file(file_path)?;
+    if file_path.is_file() {
+        info!(
+            "{} was downloaded by another process; Using it.",
+            file_path.display()
+        );
+        return Ok(false);
     }
+    download_to_file(url, file_path)?;
+    Ok(true)
+}
 
-    Ok(())
+/// Download `url` to `file_path` (see [`download`]).
+pub fn download_to_file<T: AsRef<str>>(url: T, file_path: &Path) -> Result<()> {
+    download::download(url.as_ref(), file_path, None).map(|_| ())
 }
 
 pub fn get_cmd_line_string(prog: &std::process::Command) -> String {
@@ -521,8 +468,8 @@ pub fn inspect_af_home(af_home_path: &Path) -> anyhow::Result<serde_json::Value>
 
 #[cfg(test)]
 mod tests {
+    use super::{check_files_exist, check_piscem_index_files, download_if_absent};
     use cmd_lib::run_fun;
-    use super::{check_files_exist, check_piscem_index_files};
     use std::fs;
     use tempfile::tempdir;
 
@@ -535,6 +482,17 @@ mod tests {
         check_files_exist(&files).expect("duplicate terms should not fail legacy file checks");
     }
 
+    #[test]
+    fn download_if_absent_reuses_a_present_file() {
+        let td = tempdir().expect("failed to create tempdir");
+        let file = td.path().join("pl.txt");
+        fs::write(&file, "AAAA\n").expect("failed to write test file");
+        let downloaded = download_if_absent("http://invalid.invalid/pl.txt", &file)
+            .expect("a present file should not be downloaded");
+        assert!(!downloaded);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "AAAA\n");
+    }
+
     #[test]
     fn check_piscem_index_files_accepts_cpp_layout() {
         let td = tempdir().expect("failed to create tempdir");
@@ -13,6 +13,13 @@ fn snapshot_cases() -> Vec<(&'static str, Vec<&'static str>)> {
             "simpleaf_multiplex_quant___help.txt",
             vec!["multiplex-quant", "--help"],
         ),
+        ("simpleaf_multiome___help.txt", vec!["multiome", "--help"]),
+        ("simpleaf_hto_demux___help.txt", vec!["hto-demux", "--help"]),
+        ("simpleaf_spatial___help.txt", vec!["spatial", "--help"]),
+        (
+            "simpleaf_spatial_bin___help.txt",
+            vec!["spatial", "bin", "--help"],
+        ),
         ("simpleaf_chemistry___help.txt", vec!["chemistry", "--help"]),
         (
             "simpleaf_chemistry_add___help.txt",
@@ -30,6 +37,10 @@ fn snapshot_cases() -> Vec<(&'static str, Vec<&'static str>)> {
             "simpleaf_chemistry_lookup___help.txt",
             vec!["chemistry", "lookup", "--help"],
         ),
+        (
+            "simpleaf_chemistry_alias___help.txt",
+            vec!["chemistry", "alias", "--help"],
+        ),
         (
             "simpleaf_chemistry_refresh___help.txt",
             vec!["chemistry", "refresh", "--help"],
@@ -38,6 +49,63 @@ fn snapshot_cases() -> Vec<(&'static str, Vec<&'static str>)> {
             "simpleaf_chemistry_fetch___help.txt",
             vec!["chemistry", "fetch", "--help"],
         ),
+        (
+            "simpleaf_chemistry_validate___help.txt",
+            vec!["chemistry", "validate", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_export___help.txt",
+            vec!["chemistry", "export", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_import___help.txt",
+            vec!["chemistry", "import", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_history___help.txt",
+            vec!["chemistry", "history", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_rollback___help.txt",
+            vec!["chemistry", "rollback", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_probe_set___help.txt",
+            vec!["chemistry", "probe-set", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_probe_set_add___help.txt",
+            vec!["chemistry", "probe-set", "add", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_probe_set_remove___help.txt",
+            vec!["chemistry", "probe-set", "remove", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_sample_bc_set___help.txt",
+            vec!["chemistry", "sample-bc", "set", "--help"],
+        ),
+        (
+            "simpleaf_chemistry_translation_list_set___help.txt",
+            vec!["chemistry", "translation-list", "set", "--help"],
+        ),
+        ("simpleaf_cache___help.txt", vec!["cache", "--help"]),
+        (
+            "simpleaf_cache_list___help.txt",
+            vec!["cache", "list", "--help"],
+        ),
+        (
+            "simpleaf_cache_verify___help.txt",
+            vec!["cache", "verify", "--help"],
+        ),
+        (
+            "simpleaf_cache_clean___help.txt",
+            vec!["cache", "clean", "--help"],
+        ),
+        (
+            "simpleaf_cache_size___help.txt",
+            vec!["cache", "size", "--help"],
+        ),
         ("simpleaf_inspect___help.txt", vec!["inspect", "--help"]),
         ("simpleaf_set_paths___help.txt", vec!["set-paths", "--help"]),
         (
@@ -74,6 +142,14 @@ fn snapshot_cases() -> Vec<(&'static str, Vec<&'static str>)> {
             "simpleaf_atac_process___help.txt",
             vec!["atac", "process", "--help"],
         ),
+        (
+            "simpleaf_atac_gene_activity___help.txt",
+            vec!["atac", "gene-activity", "--help"],
+        ),
+        (
+            "simpleaf_atac_coverage___help.txt",
+            vec!["atac", "coverage", "--help"],
+        ),
     ]
 }
 